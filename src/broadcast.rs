@@ -1,52 +1,900 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use itertools::{Either, Itertools};
-use pnet::datalink::interfaces;
-use pnet::ipnetwork::IpNetwork;
+use log::warn;
+use pnet::datalink::{interfaces, NetworkInterface};
+use pnet::ipnetwork::{IpNetwork, Ipv4Network};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 
-#[derive(Debug)]
+use crate::config::{program_data, Options};
+use crate::ignore::glob_matches;
+use crate::transport::{listening_port, Encryption};
+
+use self::mapping::external_address;
+
+pub use self::announce::spawn_announcer;
+pub use self::dht::spawn_dht;
+pub use self::discover::DiscoverCommand;
+pub use self::global::spawn_global_discovery;
+pub use self::listen::spawn_listener;
+pub use self::mapping::spawn_port_mapping;
+pub use self::mdns::spawn_mdns;
+pub use self::monitor::{spawn_interface_monitor, InterfaceMonitor};
+pub use self::peers::{spawn_peer_cache, spawn_peer_expiry, Peer, PeerEvent, PeerTable};
+pub use self::server::DiscoveryServerCommand;
+
+mod announce;
+mod dht;
+mod discover;
+mod global;
+mod limit;
+mod listen;
+mod mapping;
+mod mdns;
+mod monitor;
+mod peers;
+mod relay;
+mod server;
+
+/// Name every announcement starts with, packets from other programs are ignored.
+pub const PROTOCOL_NAME: &str = "simple-sync";
+/// Raised when announcements change in a way older releases can't read. Adding a field that
+/// older releases may ignore keeps the version.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Transports this release can sync over, most preferred first. Each one enabled is announced as a
+/// `transport:` capability, followed by `=` and its port where that is not the sync port.
+pub const TRANSPORTS: &[&str] = &["quic", "tcp"];
+/// Capability announcing how a device encrypts connections, followed by `:` and the
+/// [`Encryption`](crate::transport::Encryption). Devices that don't announce it use TLS.
+pub const ENCRYPTION_CAPABILITY: &str = "encryption";
+/// Capability of a device that answers queries.
+pub const QUERY_CAPABILITY: &str = "query";
+/// Capability of a device that relays announcements that ask for it.
+pub const RELAY_CAPABILITY: &str = "relay";
+/// Capability of a device that waits on a relay for devices that can't dial it, followed by `=`
+/// and the relay's `host:port`.
+pub const RELAY_SERVER_CAPABILITY: &str = "relay-server";
+
+/// Capability announcing an address a device accepts connections on besides its addresses and
+/// port, followed by `=` and the `address:port`. It is announced once for each such address.
+pub const LISTEN_CAPABILITY: &str = "listen";
+
+/// Largest announcement that fits in a single UDP datagram on an IPv6 network without fragmenting.
+pub const MAX_PACKET_SIZE: u64 = 1452;
+
+/// Shortest time between announcements, whatever the configured interval.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// Announcements are sent up to this fraction of the interval earlier or later.
+const ANNOUNCE_JITTER: f64 = 0.2;
+/// Longest time between attempts while announcements keep failing.
+const MAX_ANNOUNCE_BACKOFF: Duration = Duration::from_secs(600);
+/// Announcements sent [`MIN_ANNOUNCE_INTERVAL`] apart after the network changes, in case the
+/// first ones are lost while it settles.
+const ANNOUNCE_BURST: u32 = 3;
+
+/// Announcement that tells other devices on the network how to reach this one.
+///
+/// On the network it is a CBOR map keyed by field name, so fields that a newer release added are
+/// skipped and fields it dropped fall back to their defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BroadcastPacket {
-    protocol_name: &'static str,
+    protocol_name: String,
+    version: u32,
     device_id: String,
     device_name: String,
     retransmit: bool,
     port: u16,
-    addresses: Vec<IpAddr>
-}
-
-// impl BroadcastPacket {
-//     pub fn new(device_id: String, device_name: String, retransmit: bool, port: u16, addresses: Vec<IpAddr>) -> Self {
-//         // BroadcastPacket {
-//         //     protocol_name: PROTOCOL_NAME,
-//         //     device_id,
-//         //     device_name,
-//         //     retransmit,
-//         //     port,
-//         //     addresses
-//         // }
-//     }
-//
-//     pub fn get_ip_addrs() -> (Vec<Ipv4Addr>, Vec<Ipv6Addr>) {
-//         interfaces()
-//             .into_iter()
-//             .filter(|e| e.is_up() && !e.is_loopback() && !e.ips.is_empty())
-//             .flat_map(|e| e.ips)
-//             .partition_map(|e| match e {
-//                 IpNetwork::V4(x) => Either::Left(x.ip()),
-//                 IpNetwork::V6(x) => Either::Right(x.ip())
-//             })
-//     }
-// }
-
-// impl Default for BroadcastPacket {
-//     fn default() -> Self {
-//         BroadcastPacket {
-//             protocol_name: PROTOCOL_NAME,
-//             device_id,
-//             device_name,
-//             retransmit,
-//             port,
-//             addresses
-//         }
-//     }
-// }
\ No newline at end of file
+    addresses: Vec<IpAddr>,
+    /// Asks every device that hears it to answer straight away with an announcement of its own.
+    #[serde(default)]
+    query: bool,
+    /// Public address the device's gateway forwards to its sync port, for devices outside its
+    /// network.
+    #[serde(default)]
+    external_address: Option<SocketAddr>,
+    /// Address a discovery server or DHT node saw the packet coming from, which the device
+    /// itself may not know, such as the public address of a device behind a NAT.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    observed_address: Option<IpAddr>,
+    /// Milliseconds since the Unix epoch when the packet was made, announcements of a device only
+    /// move forward in time so a recorded one can't be sent again later.
+    #[serde(default)]
+    timestamp: u64,
+    /// Random, tells apart packets made in the same millisecond.
+    #[serde(default)]
+    nonce: u64,
+    /// What the device supports, such as `transport:tcp`, so peers pick a mutual transport and
+    /// features before connecting. Names a release doesn't know are kept and never picked.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    capabilities: Vec<String>,
+    /// Hex encoded Ed25519 key of the device that signed the packet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    /// Hex encoded signature of the signed fields, made with the device key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+/// The parts of an announcement its signature covers. The rest is changed on the way, by relays
+/// clearing `retransmit` and by servers adding the observed address. Signing another field needs
+/// a new protocol version, as older releases would check the signature without it.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    protocol_name: &'a str,
+    version: u32,
+    device_id: &'a str,
+    device_name: &'a str,
+    port: u16,
+    addresses: &'a [IpAddr],
+    external_address: Option<SocketAddr>,
+    timestamp: u64,
+    nonce: u64,
+    capabilities: &'a [String],
+    public_key: Option<&'a str>,
+}
+
+/// The fields every version of the announcement has, read first to tell announcements this
+/// release can't understand apart from malformed ones.
+#[derive(Debug, Deserialize)]
+struct PacketHeader {
+    protocol_name: String,
+    version: u32,
+}
+
+impl PacketHeader {
+    fn check(self) -> Result<(), PacketError> {
+        if self.protocol_name != PROTOCOL_NAME {
+            return Err(PacketError::UnknownProtocol(self.protocol_name));
+        }
+        if self.version > PROTOCOL_VERSION {
+            return Err(PacketError::UnsupportedVersion(self.version));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum PacketError {
+    Encode(ciborium::ser::Error<io::Error>),
+    Decode(ciborium::de::Error<io::Error>),
+    Json(serde_json::Error),
+    TooLarge(usize),
+    UnknownProtocol(String),
+    UnsupportedVersion(u32),
+    BadSignature,
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Encode(e) => write!(f, "unable to encode announcement: {}", e),
+            PacketError::Decode(e) => write!(f, "invalid announcement: {}", e),
+            PacketError::Json(e) => write!(f, "invalid announcement JSON: {}", e),
+            PacketError::TooLarge(size) => write!(f, "announcement of {} bytes, larger than {} bytes", size,
+                MAX_PACKET_SIZE),
+            PacketError::UnknownProtocol(name) => write!(f, "announcement for unknown protocol `{}`", name),
+            PacketError::UnsupportedVersion(version) => write!(f, "announcement from protocol version {}, newer \
+                than version {} of this release", version, PROTOCOL_VERSION),
+            PacketError::BadSignature => write!(f, "announcement with an invalid signature"),
+        }
+    }
+}
+
+impl BroadcastPacket {
+    pub fn new(device_id: String, device_name: String, retransmit: bool, port: u16, addresses: Vec<IpAddr>) -> Self {
+        BroadcastPacket {
+            protocol_name: PROTOCOL_NAME.to_string(),
+            version: PROTOCOL_VERSION,
+            device_id,
+            device_name,
+            retransmit,
+            port,
+            addresses,
+            query: false,
+            external_address: None,
+            observed_address: None,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64)
+                .unwrap_or_default(),
+            nonce: rand::random(),
+            capabilities: Vec::new(),
+            public_key: None,
+            signature: None,
+        }
+    }
+
+    /// Announces this device with the addresses of the interfaces that discovery may use, signed
+    /// with the device key.
+    pub fn from_options(options: &Options) -> Self {
+        let (ipv4, ipv6) = get_ip_addrs(options);
+        let mut addresses: Vec<IpAddr> = ipv4.into_iter().map(IpAddr::V4).chain(ipv6.into_iter().map(IpAddr::V6))
+            .collect();
+        addresses.sort_by_key(|ip| reach(*ip));
+        let packet = Self::new(options.device_id().to_string(), options.device_name(), options.retransmit(),
+            options.port(), addresses);
+        let mut packet = BroadcastPacket {
+            external_address: external_address().filter(|address| options.ip_versions().allows(address.ip())),
+            capabilities: capabilities(options),
+            ..packet
+        };
+        if let Err(e) = packet.sign(&program_data().signing_key()) {
+            warn!("Unable to sign announcement: {}", e);
+        }
+        packet
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    pub fn retransmit(&self) -> bool {
+        self.retransmit
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.addresses
+    }
+
+    pub fn external_address(&self) -> Option<SocketAddr> {
+        self.external_address
+    }
+
+    pub fn observed_address(&self) -> Option<IpAddr> {
+        self.observed_address
+    }
+
+    /// The observed address ahead of the announced ones.
+    pub fn reachable_addresses(&self) -> Vec<IpAddr> {
+        let observed = self.observed_address.filter(|observed| !self.addresses.contains(observed));
+        observed.into_iter().chain(self.addresses.iter().copied()).collect()
+    }
+
+    /// When the packet was made, which a replayed packet can't change without breaking its
+    /// signature.
+    pub fn timestamp(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    pub fn with_capabilities(self, capabilities: Vec<String>) -> Self {
+        BroadcastPacket { capabilities, ..self }
+    }
+
+    /// The transport this device prefers most among the ones both devices enable.
+    pub fn best_transport(&self, options: &Options) -> Option<&'static str> {
+        mutual_transports(&capabilities(options), &self.capabilities).first().copied()
+    }
+
+    /// Hex encoded key the packet was signed with, `None` if it is not signed.
+    pub fn public_key(&self) -> Option<&str> {
+        self.public_key.as_deref()
+    }
+
+    pub fn query(&self) -> bool {
+        self.query
+    }
+
+    /// A copy that also asks other devices to announce themselves, sent by a device that just
+    /// started or joined a network.
+    pub fn as_query(&self) -> Self {
+        BroadcastPacket { query: true, ..self.clone() }
+    }
+
+    /// A copy to relay to other networks, which is not relayed again. Answers to a relayed query
+    /// would go to the relay, so the copy does not ask for them.
+    pub fn relayed(&self) -> Self {
+        BroadcastPacket { retransmit: false, query: false, ..self.clone() }
+    }
+
+    /// Records the address the packet was seen coming from, leaving the signed addresses as they
+    /// are.
+    pub fn add_observed_address(&mut self, address: IpAddr) {
+        self.observed_address = Some(address);
+    }
+
+    /// A copy without the observed address, which the signature doesn't cover, for a packet that
+    /// didn't come through a discovery server or DHT node, the only ones that add it.
+    pub fn without_observed_address(self) -> Self {
+        BroadcastPacket { observed_address: None, ..self }
+    }
+
+    /// Signs the packet with `key`, so that peers that saw the key before can tell it apart from
+    /// a packet sent by another device claiming the same id.
+    pub fn sign(&mut self, key: &SigningKey) -> Result<(), PacketError> {
+        self.public_key = Some(hex::encode(key.verifying_key().as_bytes()));
+        let signature = key.sign(&self.signed_bytes()?);
+        self.signature = Some(hex::encode(signature.to_bytes()));
+        Ok(())
+    }
+
+    /// Checks the signature of a signed packet against the key it carries. An unsigned packet,
+    /// such as one from mDNS, passes.
+    fn verify(&self) -> Result<(), PacketError> {
+        let (key, signature) = match (&self.public_key, &self.signature) {
+            (Some(key), Some(signature)) => (key, signature),
+            (None, None) => return Ok(()),
+            _ => return Err(PacketError::BadSignature),
+        };
+        let mut key_bytes = [0; 32];
+        let mut signature_bytes = [0; 64];
+        hex::decode_to_slice(key, &mut key_bytes).map_err(|_| PacketError::BadSignature)?;
+        hex::decode_to_slice(signature, &mut signature_bytes).map_err(|_| PacketError::BadSignature)?;
+        VerifyingKey::from_bytes(&key_bytes).map_err(|_| PacketError::BadSignature)?
+            .verify_strict(&self.signed_bytes()?, &Signature::from_bytes(&signature_bytes))
+            .map_err(|_| PacketError::BadSignature)
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let fields = SignedFields {
+            protocol_name: &self.protocol_name,
+            version: self.version,
+            device_id: &self.device_id,
+            device_name: &self.device_name,
+            port: self.port,
+            addresses: &self.addresses,
+            external_address: self.external_address,
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+            capabilities: &self.capabilities,
+            public_key: self.public_key.as_deref(),
+        };
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&fields, &mut bytes).map_err(PacketError::Encode)?;
+        Ok(bytes)
+    }
+
+    /// Encodes the packet as sent on the network.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes).map_err(PacketError::Encode)?;
+        if bytes.len() as u64 > MAX_PACKET_SIZE {
+            return Err(PacketError::TooLarge(bytes.len()));
+        }
+        Ok(bytes)
+    }
+
+    /// Decodes a packet from the network, rejecting it before it is allocated for if it would not
+    /// fit in a datagram.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() as u64 > MAX_PACKET_SIZE {
+            return Err(PacketError::TooLarge(bytes.len()));
+        }
+        ciborium::from_reader::<PacketHeader, _>(bytes).map_err(PacketError::Decode)?.check()?;
+        let packet: Self = ciborium::from_reader(bytes).map_err(PacketError::Decode)?;
+        packet.verify()?;
+        Ok(packet)
+    }
+
+    /// Encodes the packet as readable JSON, for debugging.
+    pub fn to_json(&self) -> Result<String, PacketError> {
+        serde_json::to_string_pretty(self).map_err(PacketError::Json)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, PacketError> {
+        serde_json::from_str::<PacketHeader>(json).map_err(PacketError::Json)?.check()?;
+        let packet: Self = serde_json::from_str(json).map_err(PacketError::Json)?;
+        packet.verify()?;
+        Ok(packet)
+    }
+}
+
+impl Default for BroadcastPacket {
+    fn default() -> Self {
+        Self::from_options(&Options::default())
+    }
+}
+
+
+/// When the next `BroadcastPacket` is sent. Each delay is randomly varied so that devices started
+/// together drift apart, and doubles for every consecutive failure to send.
+#[derive(Debug)]
+pub struct AnnounceSchedule {
+    interval: Duration,
+    failures: u32,
+    burst: u32,
+}
+
+impl AnnounceSchedule {
+    pub fn new(options: &Options) -> Self {
+        AnnounceSchedule { interval: Self::interval(options), failures: 0, burst: 0 }
+    }
+
+    /// Picks up a changed announce interval, keeping the current backoff.
+    pub fn update(&mut self, options: &Options) {
+        self.interval = Self::interval(options);
+    }
+
+    fn interval(options: &Options) -> Duration {
+        options.announce_interval().max(MIN_ANNOUNCE_INTERVAL)
+    }
+
+    pub fn next_delay(&self) -> Duration {
+        if self.burst > 0 && self.failures == 0 {
+            return MIN_ANNOUNCE_INTERVAL;
+        }
+        let delay = self.interval.saturating_mul(1 << self.failures.min(16))
+            .min(MAX_ANNOUNCE_BACKOFF.max(self.interval));
+        delay.mul_f64(1.0 + rand::thread_rng().gen_range(-ANNOUNCE_JITTER..=ANNOUNCE_JITTER))
+    }
+
+    /// Sends the next few announcements in quick succession.
+    pub fn burst(&mut self) {
+        self.burst = ANNOUNCE_BURST;
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.burst = self.burst.saturating_sub(1);
+    }
+
+    pub fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+}
+
+/// Restricts discovery to a single network interface, given by name or by one of its addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BindInterface {
+    Name(String),
+    Address(IpAddr),
+}
+
+impl BindInterface {
+    /// Whether `ip` on `interface` may be used. Binding to an address only allows that address.
+    pub fn includes(&self, interface: &NetworkInterface, ip: IpAddr) -> bool {
+        match self {
+            BindInterface::Name(name) => &interface.name == name,
+            BindInterface::Address(address) => *address == ip,
+        }
+    }
+}
+
+impl Display for BindInterface {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BindInterface::Name(name) => write!(f, "{}", name),
+            BindInterface::Address(address) => write!(f, "{}", address),
+        }
+    }
+}
+
+impl FromStr for BindInterface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("interface name or address is empty".to_string());
+        }
+        Ok(s.parse().map(BindInterface::Address).unwrap_or_else(|_| BindInterface::Name(s.to_string())))
+    }
+}
+
+impl TryFrom<String> for BindInterface {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<BindInterface> for String {
+    fn from(bind: BindInterface) -> Self {
+        bind.to_string()
+    }
+}
+
+/// Selects interfaces by a name pattern with `*` and `?`, such as `veth*`, or addresses by subnet,
+/// such as `fe80::/10`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum InterfaceFilter {
+    Name(String),
+    Network(IpNetwork),
+}
+
+impl InterfaceFilter {
+    pub fn matches(&self, interface: &NetworkInterface, ip: IpAddr) -> bool {
+        match self {
+            InterfaceFilter::Name(pattern) => glob_matches(pattern, &interface.name),
+            InterfaceFilter::Network(network) => network.contains(ip),
+        }
+    }
+}
+
+impl Display for InterfaceFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InterfaceFilter::Name(pattern) => write!(f, "{}", pattern),
+            InterfaceFilter::Network(network) => write!(f, "{}", network),
+        }
+    }
+}
+
+impl FromStr for InterfaceFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("interface pattern or subnet is empty".to_string());
+        }
+        Ok(s.parse().map(InterfaceFilter::Network).unwrap_or_else(|_| InterfaceFilter::Name(s.to_string())))
+    }
+}
+
+impl TryFrom<String> for InterfaceFilter {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<InterfaceFilter> for String {
+    fn from(filter: InterfaceFilter) -> Self {
+        filter.to_string()
+    }
+}
+
+/// Whether `ip` on `interface` may be used for discovery: it is allowed by the bind interface, by
+/// any of the included interfaces if there are some, and by none of the excluded ones.
+fn is_allowed(options: &Options, interface: &NetworkInterface, ip: IpAddr) -> bool {
+    options.ip_versions().allows(ip)
+        && options.bind_interface().is_none_or(|bind| bind.includes(interface, ip))
+        && (options.include_interfaces().is_empty()
+            || options.include_interfaces().iter().any(|filter| filter.matches(interface, ip)))
+        && !options.exclude_interfaces().iter().any(|filter| filter.matches(interface, ip))
+}
+
+/// An interface that is up, with the addresses discovery may use on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddrs {
+    pub name: String,
+    /// The index used as the scope of IPv6 link-local addresses and multicast groups.
+    pub index: u32,
+    pub multicast: bool,
+    pub ipv4: Vec<Ipv4Addr>,
+    /// The IPv4 subnets on this interface, with the addresses above.
+    pub ipv4_networks: Vec<Ipv4Network>,
+    /// Directed broadcast addresses of the IPv4 subnets on this interface.
+    pub ipv4_broadcast: Vec<Ipv4Addr>,
+    pub ipv6: Vec<Ipv6Addr>,
+}
+
+/// Interfaces that are up and have addresses, restricted to the ones the options allow.
+pub fn get_interfaces(options: &Options) -> Vec<InterfaceAddrs> {
+    interfaces()
+        .into_iter()
+        .filter(|e| e.is_up() && !e.is_loopback() && !e.ips.is_empty())
+        .filter_map(|e| {
+            let (networks_v4, ipv6): (Vec<Ipv4Network>, Vec<Ipv6Addr>) = e.ips.iter()
+                .filter(|network| is_allowed(options, &e, network.ip()))
+                .partition_map(|network| match network {
+                    IpNetwork::V4(x) => Either::Left(*x),
+                    IpNetwork::V6(x) => Either::Right(x.ip())
+                });
+            if networks_v4.is_empty() && ipv6.is_empty() {
+                return None;
+            }
+            Some(InterfaceAddrs {
+                name: e.name.clone(),
+                index: e.index,
+                multicast: e.is_multicast(),
+                ipv4: networks_v4.iter().map(|network| network.ip()).collect(),
+                // A /31 or /32 has no broadcast address.
+                ipv4_broadcast: networks_v4.iter().filter(|network| network.prefix() < 31)
+                    .map(|network| network.broadcast()).collect(),
+                ipv4_networks: networks_v4,
+                ipv6,
+            })
+        })
+        .collect()
+}
+
+impl InterfaceAddrs {
+    /// Whether a packet from `source` was most likely received on this interface, either because the
+    /// source is on one of its subnets or because it is scoped to it.
+    pub fn is_source_of(&self, source: &SocketAddr) -> bool {
+        match source {
+            SocketAddr::V4(source) => self.ipv4_networks.iter().any(|network| network.contains(*source.ip())),
+            SocketAddr::V6(source) => source.scope_id() == self.index,
+        }
+    }
+}
+
+/// Interfaces that multicast announcements can be sent and received on.
+pub fn get_multicast_interfaces(options: &Options) -> Vec<InterfaceAddrs> {
+    get_interfaces(options).into_iter().filter(|interface| interface.multicast).collect()
+}
+
+/// Addresses to announce on the interfaces that are up, restricted to the ones the options allow
+/// and most routable first.
+///
+/// IPv6 addresses that are still tentative or are deprecated are left out, as connections to
+/// them fail or soon will.
+pub fn get_ip_addrs(options: &Options) -> (Vec<Ipv4Addr>, Vec<Ipv6Addr>) {
+    let unusable = unusable_ipv6_addrs();
+    let (mut ipv4, mut ipv6) = get_interfaces(options).into_iter()
+        .fold((Vec::new(), Vec::new()), |(mut ipv4, mut ipv6), interface| {
+            ipv4.extend(interface.ipv4);
+            ipv6.extend(interface.ipv6.into_iter().filter(|ip| !unusable.contains(ip)));
+            (ipv4, ipv6)
+        });
+    let skipped = |ip: IpAddr| match reach(ip) {
+        Reach::LinkLocal => options.skip_link_local(),
+        Reach::SharedNat => options.skip_cgnat(),
+        _ => false,
+    };
+    ipv4.retain(|ip| !skipped(IpAddr::V4(*ip)));
+    ipv6.retain(|ip| !skipped(IpAddr::V6(*ip)));
+    ipv4.sort_by_key(|ip| reach(IpAddr::V4(*ip)));
+    ipv6.sort_by_key(|ip| reach(IpAddr::V6(*ip)));
+    (ipv4, ipv6)
+}
+
+/// What this device announces it supports with the current options.
+pub fn capabilities(options: &Options) -> Vec<String> {
+    let transports = TRANSPORTS.iter().filter_map(|transport| match *transport {
+        "quic" => (options.quic() && options.encryption() == Encryption::Tls)
+            .then(|| format!("transport:quic={}", options.quic_port())),
+        "tcp" if listening_port(options) != options.port() =>
+            Some(format!("transport:tcp={}", listening_port(options))),
+        transport => Some(format!("transport:{}", transport)),
+    });
+    let listen = options.listen_addresses().iter().filter(|address| options.ip_versions().allows(address.ip()))
+        .map(|address| format!("{}={}", LISTEN_CAPABILITY, address));
+    let encryption = format!("{}:{}", ENCRYPTION_CAPABILITY, options.encryption());
+    let features = Some(QUERY_CAPABILITY).into_iter().chain(options.relay().then_some(RELAY_CAPABILITY));
+    let relay_server = options.relay_server().map(|relay| format!("{}={}", RELAY_SERVER_CAPABILITY, relay));
+    transports.chain(Some(encryption)).chain(features.map(str::to_string)).chain(relay_server).chain(listen).collect()
+}
+
+/// The addresses a device with `capabilities` accepts connections on besides its addresses and port.
+pub fn listen_addresses(capabilities: &[String]) -> Vec<SocketAddr> {
+    let prefix = format!("{}=", LISTEN_CAPABILITY);
+    capabilities.iter().filter_map(|capability| capability.strip_prefix(&prefix)?.parse().ok()).collect()
+}
+
+/// The value of `capability` in `capabilities`, empty if it has none, or `None` if it is missing.
+pub fn capability_value<'a>(capabilities: &'a [String], capability: &str) -> Option<&'a str> {
+    capabilities.iter().find_map(|supported| match supported.split_once('=') {
+        Some((name, value)) if name == capability => Some(value),
+        None if supported == capability => Some(""),
+        _ => None,
+    })
+}
+
+/// How a device with `capabilities` encrypts connections, `None` if this release doesn't know the
+/// way it announced.
+pub fn announced_encryption(capabilities: &[String]) -> Option<Encryption> {
+    let prefix = format!("{}:", ENCRYPTION_CAPABILITY);
+    match capabilities.iter().find_map(|capability| capability.strip_prefix(&prefix)) {
+        Some(encryption) => encryption.parse().ok(),
+        None => Some(Encryption::Tls),
+    }
+}
+
+/// The transports in both sets of capabilities, most preferred first.
+pub fn mutual_transports(own: &[String], peer: &[String]) -> Vec<&'static str> {
+    TRANSPORTS.iter().copied().filter(|transport| {
+        let capability = format!("transport:{}", transport);
+        capability_value(own, &capability).is_some() && capability_value(peer, &capability).is_some()
+    }).collect()
+}
+
+/// How far an address can be reached from, in the order peers should try them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reach {
+    Global,
+    /// A private network, such as `192.168.0.0/16` or an IPv6 unique local address.
+    Private,
+    /// Carrier-grade NAT, shared between the customers of one ISP.
+    SharedNat,
+    LinkLocal,
+}
+
+pub fn reach(ip: IpAddr) -> Reach {
+    match ip {
+        IpAddr::V4(ip) if ip.is_link_local() => Reach::LinkLocal,
+        IpAddr::V4(ip) if ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64 => Reach::SharedNat,
+        IpAddr::V4(ip) if ip.is_private() => Reach::Private,
+        IpAddr::V6(ip) if ip.is_unicast_link_local() => Reach::LinkLocal,
+        IpAddr::V6(ip) if ip.is_unique_local() => Reach::Private,
+        _ => Reach::Global,
+    }
+}
+
+/// IPv6 addresses still being checked for duplicates or being phased out, which the kernel
+/// lists with the tentative, deprecated or duplicate address flags.
+#[cfg(target_os = "linux")]
+fn unusable_ipv6_addrs() -> Vec<Ipv6Addr> {
+    const UNUSABLE_FLAGS: u32 = 0x08 | 0x20 | 0x40;
+    let addresses = std::fs::read_to_string("/proc/net/if_inet6").unwrap_or_default();
+    addresses.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u32::from_str_radix(fields.get(4)?, 16).ok()?;
+        let address = u128::from_str_radix(fields.first()?, 16).ok()?;
+        (flags & UNUSABLE_FLAGS != 0).then(|| Ipv6Addr::from(address))
+    }).collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unusable_ipv6_addrs() -> Vec<Ipv6Addr> {
+    Vec::new()
+}
+
+/// Creates the socket announcements to the IPv4 multicast group are sent from, limited to
+/// `multicast-ttl` hops.
+pub fn announce_socket_v4(options: &Options) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_multicast_ttl_v4(options.multicast_ttl().into())?;
+    socket.set_broadcast(options.broadcast_fallback())?;
+    let address = match options.bind_interface() {
+        Some(BindInterface::Address(IpAddr::V4(address))) => {
+            socket.set_multicast_if_v4(address)?;
+            *address
+        }
+        _ => Ipv4Addr::UNSPECIFIED,
+    };
+    socket.bind(&SocketAddr::from((address, 0)).into())?;
+    Ok(socket.into())
+}
+
+/// Creates the socket announcements to the IPv6 multicast group are sent from, limited to
+/// `multicast-hops` hops.
+pub fn announce_socket_v6(options: &Options) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_multicast_hops_v6(options.multicast_hops().into())?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> BroadcastPacket {
+        BroadcastPacket::new(
+            "5752cd3e-7bf9-4675-8378-f46ea962a772".to_string(),
+            "laptop".to_string(),
+            true,
+            11529,
+            vec!["192.0.2.2".parse().unwrap(), "fe80::1".parse().unwrap()],
+        )
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let packet = packet();
+        let bytes = packet.to_bytes().unwrap();
+        assert!(bytes.len() as u64 <= MAX_PACKET_SIZE);
+        assert_eq!(BroadcastPacket::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let packet = packet();
+        let json = packet.to_json().unwrap();
+        assert!(json.contains("\"device_name\": \"laptop\""));
+        assert_eq!(BroadcastPacket::from_json(&json).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        let mut packet = packet();
+        packet.protocol_name = "other".to_string();
+        let bytes = packet.to_bytes().unwrap();
+        assert!(matches!(BroadcastPacket::from_bytes(&bytes), Err(PacketError::UnknownProtocol(name)) if name == "other"));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut packet = packet();
+        packet.version = PROTOCOL_VERSION + 1;
+        let bytes = packet.to_bytes().unwrap();
+        assert!(matches!(BroadcastPacket::from_bytes(&bytes), Err(PacketError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn ignores_unknown_fields() {
+        let packet = packet();
+        let mut value = ciborium::Value::serialized(&packet).unwrap();
+        if let ciborium::Value::Map(fields) = &mut value {
+            fields.push(("added_later".into(), true.into()));
+        }
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).unwrap();
+        assert_eq!(BroadcastPacket::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let bytes = packet().to_bytes().unwrap();
+        assert!(BroadcastPacket::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_packets() {
+        let mut packet = packet();
+        packet.device_name = "x".repeat(MAX_PACKET_SIZE as usize);
+        assert!(packet.to_bytes().is_err());
+    }
+
+    #[test]
+    fn signed_packets_survive_relaying() {
+        let mut packet = packet();
+        packet.sign(&SigningKey::from_bytes(&[7; 32])).unwrap();
+        let mut relayed = packet.relayed();
+        relayed.add_observed_address("203.0.113.7".parse().unwrap());
+        assert_eq!(BroadcastPacket::from_bytes(&relayed.to_bytes().unwrap()).unwrap(), relayed);
+        assert_eq!(relayed.reachable_addresses()[0], "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(relayed.without_observed_address().reachable_addresses(), packet.reachable_addresses());
+    }
+
+    #[test]
+    fn rejects_tampered_packets() {
+        let mut packet = packet();
+        packet.sign(&SigningKey::from_bytes(&[7; 32])).unwrap();
+        packet.addresses = vec!["198.51.100.1".parse().unwrap()];
+        let bytes = packet.to_bytes().unwrap();
+        assert!(matches!(BroadcastPacket::from_bytes(&bytes), Err(PacketError::BadSignature)));
+    }
+
+    #[test]
+    fn most_routable_addresses_first() {
+        let mut addresses: Vec<IpAddr> = ["fe80::1", "100.64.1.1", "192.168.1.2", "2001:db8::1", "fd00::2"]
+            .iter().map(|ip| ip.parse().unwrap()).collect();
+        addresses.sort_by_key(|ip| reach(*ip));
+        let sorted: Vec<String> = addresses.iter().map(IpAddr::to_string).collect();
+        assert_eq!(sorted, ["2001:db8::1", "192.168.1.2", "fd00::2", "100.64.1.1", "fe80::1"]);
+    }
+
+    #[test]
+    fn best_transport_is_mutual() {
+        let options = Options::default();
+        assert_eq!(packet().best_transport(&options), None);
+        let capabilities = vec!["transport:carrier-pigeon".to_string(), "transport:quic=11532".to_string(),
+            "transport:tcp".to_string()];
+        let packet = packet().with_capabilities(capabilities);
+        assert_eq!(packet.best_transport(&options), Some("tcp"));
+        assert_eq!(capability_value(packet.capabilities(), "transport:quic"), Some("11532"));
+    }
+
+    #[test]
+    fn encryption_defaults_to_tls() {
+        assert_eq!(announced_encryption(&[]), Some(Encryption::Tls));
+        assert_eq!(announced_encryption(&capabilities(&Options::default())), Some(Encryption::Tls));
+        assert_eq!(announced_encryption(&["encryption:noise".to_string()]), Some(Encryption::Noise));
+        assert_eq!(announced_encryption(&["encryption:rot13".to_string()]), None);
+    }
+
+    #[test]
+    fn relayed_queries_are_not_answered() {
+        let relayed = packet().as_query().relayed();
+        assert!(!relayed.query());
+        assert!(!relayed.retransmit());
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt::Display;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread::{spawn, JoinHandle};
+
+use log::{debug, warn};
+use socket2::SockRef;
+
+use crate::broadcast::{
+    announce_socket_v4, announce_socket_v6, get_interfaces, get_multicast_interfaces, AnnounceSchedule, BindInterface,
+    BroadcastPacket, InterfaceAddrs, InterfaceMonitor,
+};
+use crate::config::{ConfigHandle, Options};
+
+/// Spawns a thread that announces this device to the multicast groups every announce interval,
+/// using the options current at the time of each announcement. The interfaces changing, or the
+/// system waking from sleep, triggers a burst of announcements straight away, so peers learn the
+/// new addresses.
+///
+/// The first announcement, and the first one of each burst, is a query that the devices already
+/// on the network answer, so they are found without waiting for their next announcement.
+pub fn spawn_announcer(config: ConfigHandle, interfaces: InterfaceMonitor) -> JoinHandle<()> {
+    spawn(move || {
+        let mut sockets = Sockets::default();
+        let mut schedule = AnnounceSchedule::new(&config.current());
+        let mut generation = interfaces.generation();
+        let mut query = true;
+        loop {
+            let options = config.current();
+            schedule.update(&options);
+            if options.local_discovery() {
+                if announce(&options, &mut sockets, query) {
+                    schedule.succeeded();
+                    query = false;
+                } else {
+                    schedule.failed();
+                }
+            }
+            let changed = interfaces.wait_for_change(generation, schedule.next_delay());
+            if changed != generation {
+                query = true;
+                schedule.burst();
+            }
+            generation = changed;
+        }
+    })
+}
+
+/// Sockets are kept between announcements and recreated after an error or when the options
+/// they were set up with change.
+#[derive(Default)]
+pub struct Sockets {
+    options: Option<(u8, u8, bool, Option<BindInterface>)>,
+    /// Keeps this host from hearing what is sent, for packets it already received.
+    no_loopback: bool,
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl Sockets {
+    /// Sockets whose packets are not looped back to this host.
+    pub fn without_loopback() -> Self {
+        Sockets { no_loopback: true, ..Sockets::default() }
+    }
+
+    fn update(&mut self, options: &Options) {
+        let current = Some((options.multicast_ttl(), options.multicast_hops(), options.broadcast_fallback(),
+            options.bind_interface().cloned()));
+        if self.options != current {
+            *self = Sockets { options: current, no_loopback: self.no_loopback, v4: None, v6: None };
+        }
+    }
+
+    fn v4(&mut self, options: &Options) -> io::Result<&UdpSocket> {
+        let no_loopback = self.no_loopback;
+        socket(&mut self.v4, || announce_socket_v4(options).and_then(|socket| {
+            SockRef::from(&socket).set_multicast_loop_v4(!no_loopback).map(|()| socket)
+        }))
+    }
+
+    fn v6(&mut self, options: &Options) -> io::Result<&UdpSocket> {
+        let no_loopback = self.no_loopback;
+        socket(&mut self.v6, || announce_socket_v6(options).and_then(|socket| {
+            SockRef::from(&socket).set_multicast_loop_v6(!no_loopback).map(|()| socket)
+        }))
+    }
+}
+
+/// Sends one announcement on every interface, returning whether any of them succeeded.
+fn announce(options: &Options, sockets: &mut Sockets, query: bool) -> bool {
+    let packet = BroadcastPacket::from_options(options);
+    let packet = if query { packet.as_query() } else { packet };
+    match packet.to_bytes() {
+        Ok(bytes) => send(options, sockets, &bytes, |_| false),
+        Err(e) => {
+            warn!("Unable to encode announcement: {}", e);
+            false
+        }
+    }
+}
+
+/// Sends an encoded packet to the multicast groups, and to the broadcast addresses if enabled, on
+/// every interface that is not `skipped`. Returns whether any of them succeeded.
+pub fn send(options: &Options, sockets: &mut Sockets, bytes: &[u8], skipped: impl Fn(&InterfaceAddrs) -> bool) -> bool {
+    sockets.update(options);
+    let group_v4 = SocketAddr::from((options.multicast_ipv4(), options.port()));
+    let group_v6 = SocketAddr::from((options.multicast_ipv6(), options.port()));
+    let mut sent = false;
+    if options.broadcast_fallback() {
+        for interface in get_interfaces(options).iter().filter(|interface| !skipped(interface)) {
+            for address in &interface.ipv4_broadcast {
+                let to = SocketAddr::from((*address, options.port()));
+                let result = sockets.v4(options).and_then(|socket| socket.send_to(bytes, to));
+                sent |= report(result, &mut sockets.v4, &interface.name, to);
+            }
+        }
+    }
+
+    // Multicast only leaves through one interface, so each one is selected in turn.
+    for interface in get_multicast_interfaces(options).iter().filter(|interface| !skipped(interface)) {
+        if let Some(address) = interface.ipv4.first() {
+            let result = sockets.v4(options)
+                .and_then(|socket| SockRef::from(socket).set_multicast_if_v4(address).map(|()| socket))
+                .and_then(|socket| socket.send_to(bytes, group_v4));
+            sent |= report(result, &mut sockets.v4, &interface.name, group_v4);
+        }
+        if !interface.ipv6.is_empty() {
+            let result = sockets.v6(options)
+                .and_then(|socket| SockRef::from(socket).set_multicast_if_v6(interface.index).map(|()| socket))
+                .and_then(|socket| socket.send_to(bytes, group_v6));
+            sent |= report(result, &mut sockets.v6, &interface.name, group_v6);
+        }
+    }
+    sent
+}
+
+fn socket(socket: &mut Option<UdpSocket>, create: impl FnOnce() -> io::Result<UdpSocket>) -> io::Result<&UdpSocket> {
+    if socket.is_none() {
+        *socket = Some(create()?);
+    }
+    Ok(socket.as_ref().expect("socket was just created"))
+}
+
+fn report(result: io::Result<usize>, socket: &mut Option<UdpSocket>, from: impl Display, to: SocketAddr) -> bool {
+    match result {
+        Ok(_) => {
+            debug!("Announced on {} to {}", from, to);
+            true
+        }
+        Err(e) => {
+            warn!("Unable to announce on {} to {}: {}", from, to, e);
+            *socket = None;
+            false
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Formatter};
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bincode::Options as _;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::broadcast::{BroadcastPacket, PeerTable, PROTOCOL_NAME};
+use crate::config::{ConfigHandle, IpVersions, Options};
+
+/// Contacts kept in each bucket, and how many of the nodes closest to a key store its value.
+const BUCKET_SIZE: usize = 8;
+/// Requests sent at once while looking up a key.
+const LOOKUP_PARALLELISM: usize = 3;
+/// How long a node has to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time between publishing this device's announcement, and looking up the peers not seen since.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(300);
+/// How long a node keeps a published announcement, three publish intervals.
+const VALUE_LIFETIME: Duration = Duration::from_secs(900);
+/// How long a contact may go unheard before a new node replaces it in a full bucket.
+const CONTACT_TIMEOUT: Duration = Duration::from_secs(900);
+/// How often a waiting node checks whether the options it was set up with changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to wait before retrying after the socket could not be set up.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Largest message, enough for a full bucket of contacts or an announcement.
+const MAX_MESSAGE_SIZE: u64 = 8 * 1024;
+
+/// Identifies a node in the DHT and the key a value is stored under, closeness between the two
+/// being their XOR distance.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NodeId([u8; 32]);
+
+impl NodeId {
+    fn random() -> Self {
+        NodeId(rand::random())
+    }
+
+    /// The key a device publishes its announcement under.
+    pub fn for_device(device_id: &str) -> Self {
+        NodeId(Sha256::new().chain_update(PROTOCOL_NAME).chain_update(device_id).finalize().into())
+    }
+
+    fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0; 32];
+        for (byte, (a, b)) in distance.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            *byte = a ^ b;
+        }
+        NodeId(distance)
+    }
+
+    /// Index of the bucket `other` belongs in, the number of leading bits the two share.
+    fn bucket(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let byte = distance.0.iter().position(|byte| *byte != 0)?;
+        Some(byte * 8 + distance.0[byte].leading_zeros() as usize)
+    }
+}
+
+impl Debug for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Contact {
+    id: NodeId,
+    address: SocketAddr,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    protocol_name: String,
+    /// Chosen by the requester and repeated in the response.
+    transaction: u64,
+    sender: NodeId,
+    body: Body,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Body {
+    Ping,
+    Pong,
+    FindNode(NodeId),
+    Nodes(Vec<Contact>),
+    Store { key: NodeId, packet: Vec<u8> },
+    Stored,
+    /// Answered with the value, or with the closest nodes known if it is not stored.
+    FindValue(NodeId),
+    /// An encoded announcement and the address it was published from.
+    Value { packet: Vec<u8>, source: SocketAddr },
+}
+
+impl Body {
+    fn is_response(&self) -> bool {
+        matches!(self, Body::Pong | Body::Nodes(_) | Body::Stored | Body::Value { .. })
+    }
+}
+
+/// Nodes known to this one, in buckets by how many leading bits of their id they share with it.
+#[derive(Debug)]
+struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<(Contact, Instant)>>,
+}
+
+impl RoutingTable {
+    fn new(own: NodeId) -> Self {
+        RoutingTable { own, buckets: vec![Vec::new(); 256] }
+    }
+
+    /// Records that `contact` was heard from. A full bucket only takes it in place of a contact
+    /// that stopped answering.
+    fn insert(&mut self, contact: Contact) {
+        let bucket = match self.own.bucket(&contact.id) {
+            Some(index) => &mut self.buckets[index],
+            None => return,
+        };
+        bucket.retain(|(known, _)| known.id != contact.id);
+        if bucket.len() >= BUCKET_SIZE {
+            match bucket.iter().position(|(_, seen)| seen.elapsed() > CONTACT_TIMEOUT) {
+                Some(stale) => {
+                    bucket.remove(stale);
+                }
+                None => return,
+            }
+        }
+        bucket.push((contact, Instant::now()));
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.own.bucket(id) {
+            self.buckets[index].retain(|(known, _)| known.id != *id);
+        }
+    }
+
+    /// Up to `count` known nodes, closest to `target` first.
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flatten().map(|(contact, _)| *contact).collect();
+        contacts.sort_by_key(|contact| contact.id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+/// Announcements stored on this node, with the address each was published from and when.
+type Values = HashMap<NodeId, (Vec<u8>, SocketAddr, Instant)>;
+
+/// A DHT node on its own socket, shared between the thread answering requests and the one
+/// publishing and looking up announcements.
+struct Node {
+    own: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    values: Mutex<Values>,
+    /// Where to deliver the responses to requests still waiting for them.
+    pending: Mutex<HashMap<u64, Sender<(Contact, Body)>>>,
+    /// Nodes at addresses of other IP versions are never sent to.
+    versions: IpVersions,
+    stopped: AtomicBool,
+}
+
+impl Node {
+    /// Binds a node to `port` on every IPv4 and IPv6 address, or only the IPv6 ones where IPv4
+    /// isn't in `versions`.
+    fn bind(port: u16, versions: IpVersions) -> io::Result<Node> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(!versions.ipv4)?;
+        socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+        let socket: UdpSocket = socket.into();
+        socket.set_read_timeout(Some(RELOAD_INTERVAL))?;
+
+        let own = NodeId::random();
+        Ok(Node {
+            own,
+            socket,
+            table: Mutex::new(RoutingTable::new(own)),
+            values: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            versions,
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    fn send(&self, address: SocketAddr, transaction: u64, body: Body) {
+        if !self.versions.allows(address.ip()) {
+            return;
+        }
+        let message = Message { protocol_name: PROTOCOL_NAME.to_string(), transaction, sender: self.own, body };
+        let bytes = match message_format().serialize(&message) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Unable to encode DHT message: {}", e);
+                return;
+            }
+        };
+        // The socket is IPv6, IPv4 nodes are reached at their mapped address.
+        let to = match address {
+            SocketAddr::V4(v4) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+            address => address,
+        };
+        if let Err(e) = self.socket.send_to(&bytes, to) {
+            debug!("Unable to send DHT message to {}: {}", address, e);
+        }
+    }
+
+    /// Answers requests and hands responses to the requests waiting for them, until stopped.
+    fn receive(&self) {
+        let mut buffer = vec![0; MAX_MESSAGE_SIZE as usize];
+        while !self.stopped.load(Ordering::Relaxed) {
+            let (size, source) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    debug!("Unable to receive DHT message: {}", e);
+                    continue;
+                }
+            };
+            let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+            let message = match message_format().deserialize::<Message>(&buffer[..size]) {
+                Ok(message) if message.protocol_name == PROTOCOL_NAME => message,
+                Ok(message) => {
+                    debug!("Ignoring DHT message for unknown protocol `{}` from {}", message.protocol_name, source);
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Ignoring invalid DHT message from {}: {}", source, e);
+                    continue;
+                }
+            };
+
+            let contact = Contact { id: message.sender, address: source };
+            self.table.lock().expect("DHT lock poisoned").insert(contact);
+            if message.body.is_response() {
+                if let Some(waiting) = self.pending.lock().expect("DHT lock poisoned").get(&message.transaction) {
+                    let _ = waiting.send((contact, message.body));
+                }
+            } else if let Some(response) = self.answer(message.body, source) {
+                self.send(source, message.transaction, response);
+            }
+        }
+    }
+
+    fn answer(&self, request: Body, source: SocketAddr) -> Option<Body> {
+        match request {
+            Body::Ping => Some(Body::Pong),
+            Body::FindNode(target) => Some(Body::Nodes(self.closest(&target))),
+            Body::Store { key, packet } => {
+                match BroadcastPacket::from_bytes(&packet) {
+                    Ok(decoded) if NodeId::for_device(decoded.device_id()) == key => {
+                        self.values.lock().expect("DHT lock poisoned").insert(key, (packet, source, Instant::now()));
+                        Some(Body::Stored)
+                    }
+                    Ok(decoded) => {
+                        debug!("Ignoring announcement of {} stored under another key by {}", decoded.device_id(),
+                            source);
+                        None
+                    }
+                    Err(e) => {
+                        debug!("Ignoring {} stored by {}", e, source);
+                        None
+                    }
+                }
+            }
+            Body::FindValue(key) => {
+                let mut values = self.values.lock().expect("DHT lock poisoned");
+                values.retain(|_, (_, _, stored)| stored.elapsed() < VALUE_LIFETIME);
+                match values.get(&key) {
+                    Some((packet, source, _)) => Some(Body::Value { packet: packet.clone(), source: *source }),
+                    None => Some(Body::Nodes(self.closest(&key))),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn closest(&self, target: &NodeId) -> Vec<Contact> {
+        self.table.lock().expect("DHT lock poisoned").closest(target, BUCKET_SIZE)
+    }
+
+    /// Sends a request to each node, returning the responses that arrived in time. Nodes that did
+    /// not answer are forgotten.
+    fn request(&self, contacts: &[Contact], body: impl Fn() -> Body) -> Vec<(Contact, Body)> {
+        let (sender, receiver) = channel();
+        let transactions: Vec<u64> = contacts.iter().map(|_| rand::random()).collect();
+        {
+            let mut pending = self.pending.lock().expect("DHT lock poisoned");
+            for transaction in &transactions {
+                pending.insert(*transaction, sender.clone());
+            }
+        }
+        for (contact, transaction) in contacts.iter().zip(&transactions) {
+            self.send(contact.address, *transaction, body());
+        }
+
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let mut responses = Vec::new();
+        while responses.len() < contacts.len() {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(response) => responses.push(response),
+                Err(_) => break,
+            }
+        }
+
+        let mut pending = self.pending.lock().expect("DHT lock poisoned");
+        for transaction in &transactions {
+            pending.remove(transaction);
+        }
+        drop(pending);
+        let mut table = self.table.lock().expect("DHT lock poisoned");
+        for contact in contacts {
+            if responses.iter().all(|(responder, _)| responder.address != contact.address) {
+                table.remove(&contact.id);
+            }
+        }
+        responses
+    }
+
+    /// Finds the nodes closest to `target` by asking the closest known nodes for closer ones, or
+    /// the value stored under it if `find_value`.
+    fn lookup(&self, target: NodeId, find_value: bool) -> (Vec<Contact>, Option<(Vec<u8>, SocketAddr)>) {
+        let mut closest = self.closest(&target);
+        let mut queried = HashSet::new();
+        loop {
+            let batch: Vec<Contact> = closest.iter().filter(|contact| !queried.contains(&contact.id))
+                .take(LOOKUP_PARALLELISM).copied().collect();
+            if batch.is_empty() {
+                return (closest, None);
+            }
+            queried.extend(batch.iter().map(|contact| contact.id));
+
+            let responses = self.request(&batch,
+                || if find_value { Body::FindValue(target) } else { Body::FindNode(target) });
+            closest.retain(|contact| !batch.contains(contact)
+                || responses.iter().any(|(responder, _)| responder.id == contact.id));
+            for (_, response) in responses {
+                match response {
+                    Body::Value { packet, source } if find_value => return (closest, Some((packet, source))),
+                    Body::Nodes(nodes) => {
+                        for node in nodes {
+                            if node.id != self.own && closest.iter().all(|known| known.id != node.id) {
+                                closest.push(node);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            closest.sort_by_key(|contact| contact.id.distance(&target));
+            closest.truncate(BUCKET_SIZE);
+        }
+    }
+
+    /// Contacts the bootstrap nodes and looks up this node's own id, which fills the buckets
+    /// closest to it.
+    fn bootstrap(&self, bootstrap: &[String]) {
+        let contacts: Vec<Contact> = bootstrap.iter().flat_map(|node| match node.to_socket_addrs() {
+            Ok(addresses) => addresses.collect(),
+            Err(e) => {
+                warn!("Unable to resolve DHT bootstrap node {}: {}", node, e);
+                Vec::new()
+            }
+        }).map(|address| Contact { id: self.own, address }).collect();
+        // The ids of bootstrap nodes are unknown until they answer, so they are never forgotten.
+        let _ = self.request(&contacts, || Body::Ping);
+        self.lookup(self.own, false);
+        debug!("DHT node {:?} knows {} nodes", self.own, self.table.lock().expect("DHT lock poisoned").len());
+    }
+
+    /// Stores this device's announcement on the nodes closest to its key.
+    fn publish(&self, options: &Options) {
+        let packet = match BroadcastPacket::from_options(options).to_bytes() {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Unable to encode announcement for the DHT: {}", e);
+                return;
+            }
+        };
+        let (closest, _) = self.lookup(NodeId::for_device(&options.device_id().to_string()), false);
+        if closest.is_empty() {
+            // The first node of a network has no one to publish on until others join through it.
+            debug!("No DHT nodes known to publish this device on");
+            return;
+        }
+        let stored = self.request(&closest, || Body::Store {
+            key: NodeId::for_device(&options.device_id().to_string()),
+            packet: packet.clone(),
+        }).len();
+        match stored {
+            0 => warn!("Unable to publish this device in the DHT, no nodes answered"),
+            stored => debug!("Published this device on {} DHT nodes", stored),
+        }
+    }
+
+    /// Looks up every configured device that was not heard from since the last lookup.
+    fn find_devices(&self, options: &Options, peers: &PeerTable) {
+        for device in options.devices() {
+            let device_id = device.id.to_string();
+            let recently_seen = peers.get(&device_id)
+                .is_some_and(|peer| peer.last_seen.elapsed().is_ok_and(|elapsed| elapsed < PUBLISH_INTERVAL));
+            if device.id == options.device_id() || recently_seen {
+                continue;
+            }
+
+            let (packet, source) = match self.lookup(NodeId::for_device(&device_id), true) {
+                (_, Some(value)) => value,
+                (_, None) => {
+                    debug!("Device {} is not published in the DHT", device_id);
+                    continue;
+                }
+            };
+            let mut packet = match BroadcastPacket::from_bytes(&packet) {
+                Ok(packet) if packet.device_id() == device_id => packet,
+                Ok(packet) => {
+                    debug!("Ignoring announcement of {} published for {}", packet.device_id(), device_id);
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Ignoring {} published for {}", e, device_id);
+                    continue;
+                }
+            };
+            packet.add_observed_address(source.ip());
+            // A device outside the local network is reached through its gateway's mapping, or at
+            // the address it published from.
+            let address = packet.external_address().unwrap_or_else(|| SocketAddr::new(source.ip(), packet.port()));
+            if peers.update(&packet, address, VALUE_LIFETIME) {
+                info!("Discovered device {} ({}) at {} with the DHT", packet.device_name(), packet.device_id(),
+                    address);
+            }
+        }
+    }
+}
+
+/// Spawns a thread that runs a DHT node while it is enabled, publishing this device's
+/// announcement under the hash of its device id and looking up the configured devices that were
+/// not found otherwise.
+pub fn spawn_dht(config: ConfigHandle, peers: PeerTable) -> JoinHandle<()> {
+    spawn(move || loop {
+        let options = config.current();
+        if !options.dht() {
+            sleep(RELOAD_INTERVAL);
+            continue;
+        }
+        let node = match Node::bind(options.dht_port(), options.ip_versions()) {
+            Ok(node) => Arc::new(node),
+            Err(e) => {
+                warn!("Unable to start DHT node on port {}: {}", options.dht_port(), e);
+                sleep(RETRY_INTERVAL);
+                continue;
+            }
+        };
+        info!("DHT node listening on port {}", options.dht_port());
+        let receiver = {
+            let node = node.clone();
+            spawn(move || node.receive())
+        };
+
+        let key = node_key(&options);
+        while node_key(&config.current()) == key {
+            let options = config.current();
+            node.bootstrap(options.dht_bootstrap());
+            node.publish(&options);
+            node.find_devices(&options, &peers);
+
+            let started = Instant::now();
+            while started.elapsed() < PUBLISH_INTERVAL && node_key(&config.current()) == key {
+                sleep(RELOAD_INTERVAL);
+            }
+        }
+        node.stopped.store(true, Ordering::Relaxed);
+        let _ = receiver.join();
+        debug!("DHT options changed, starting the node again");
+    })
+}
+
+/// The parts of the options the node's socket is set up from.
+fn node_key(options: &Options) -> impl PartialEq {
+    (options.dht(), options.dht_port(), options.ip_versions())
+}
+
+fn message_format() -> impl bincode::Options {
+    bincode::options().with_limit(MAX_MESSAGE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first: u8) -> NodeId {
+        let mut id = [0; 32];
+        id[0] = first;
+        NodeId(id)
+    }
+
+    #[test]
+    fn bucket_is_shared_prefix_length() {
+        assert_eq!(id(0).bucket(&id(0)), None);
+        assert_eq!(id(0).bucket(&id(0x80)), Some(0));
+        assert_eq!(id(0).bucket(&id(0x01)), Some(7));
+    }
+
+    #[test]
+    fn closest_contacts_by_xor_distance() {
+        let mut table = RoutingTable::new(id(0));
+        for first in [0x80, 0x40, 0x41, 0x01] {
+            table.insert(Contact { id: id(first), address: SocketAddr::from(([127, 0, 0, 1], u16::from(first))) });
+        }
+        let closest: Vec<NodeId> = table.closest(&id(0x43), 3).iter().map(|contact| contact.id).collect();
+        assert_eq!(closest, vec![id(0x41), id(0x40), id(0x01)]);
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+use structopt::StructOpt;
+
+use crate::broadcast::{spawn_announcer, spawn_interface_monitor, spawn_listener, spawn_mdns, Peer, PeerEvent, PeerTable};
+use crate::config::ConfigHandle;
+
+const DEFAULT_TIMEOUT: &str = "5s";
+
+/// Listens for devices on the local network for a while and prints each one found. A query is
+/// sent first, so devices answer without waiting for their next announcement.
+#[derive(Debug, StructOpt)]
+pub struct DiscoverCommand {
+    /// How long to listen, such as `5s`, `500ms` or `1m`.
+    #[structopt(long, default_value = DEFAULT_TIMEOUT, parse(try_from_str = parse_duration), value_name("DURATION"))]
+    timeout: Duration,
+
+    /// Print each device as a line of JSON.
+    #[structopt(long)]
+    json: bool,
+}
+
+impl DiscoverCommand {
+    pub fn run(&self, config: ConfigHandle) {
+        let peers = PeerTable::new();
+        let events = peers.subscribe();
+        let interfaces = spawn_interface_monitor(config.clone(), peers.clone());
+        spawn_announcer(config.clone(), interfaces.clone());
+        spawn_listener(config.clone(), peers.clone(), interfaces);
+        spawn_mdns(config, peers);
+
+        let deadline = Instant::now() + self.timeout;
+        let mut found = 0;
+        while let Ok(event) = events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            if let PeerEvent::Found(peer) = event {
+                self.print(&peer);
+                found += 1;
+            }
+        }
+        if found == 0 && !self.json {
+            eprintln!("No devices found within {:?}", self.timeout);
+        }
+    }
+
+    fn print(&self, peer: &Peer) {
+        if self.json {
+            let device = json!({
+                "device_id": peer.device_id,
+                "device_name": peer.device_name,
+                "addresses": peer.addresses,
+                "port": peer.port,
+                "source": peer.source(),
+            });
+            println!("{}", device);
+        } else {
+            let addresses: Vec<String> = peer.addresses.iter().map(ToString::to_string).collect();
+            println!("{}  {}  {}  port {}", peer.device_id, peer.device_name, addresses.join(", "), peer.port);
+        }
+    }
+}
+
+/// Parses a duration given in seconds, optionally with a `ms`, `s` or `m` suffix.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid duration `{}`", value))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(format!("unknown unit `{}` in duration `{}`, use ms, s or m", unit, value)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("invalid duration `{}`: {}", value, e))
+}
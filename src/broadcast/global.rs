@@ -0,0 +1,162 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use ureq::{Agent, AgentBuilder, Proxy, Request};
+use url::Url;
+
+use crate::broadcast::{BroadcastPacket, PacketError, PeerTable};
+use crate::config::{ConfigHandle, Options};
+
+/// Path on the discovery server that devices post their announcement to.
+pub const ANNOUNCE_PATH: &str = "v1/announce";
+/// Path on the discovery server that a device id, appended as the last segment, is looked up at.
+pub const LOOKUP_PATH: &str = "v1/lookup";
+/// What the token a server requires follows in the `Authorization` header.
+pub const BEARER_PREFIX: &str = "Bearer ";
+/// Time between registrations, the server forgets a device that stops registering.
+pub const REGISTER_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How often a waiting client checks whether the server it was set up with changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    Http(Box<ureq::Error>),
+    Io(io::Error),
+    Packet(PacketError),
+}
+
+impl Display for DiscoveryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::Http(e) => write!(f, "discovery server request failed: {}", e),
+            DiscoveryError::Io(e) => write!(f, "unable to read discovery server response: {}", e),
+            DiscoveryError::Packet(e) => write!(f, "discovery server sent an {}", e),
+        }
+    }
+}
+
+/// Spawns a thread that registers this device with the global discovery server, if one is
+/// configured, and looks up the configured devices that were not found on the local network.
+///
+/// The server also records the address each registration came from, so a device behind a NAT
+/// is found at its public address.
+pub fn spawn_global_discovery(config: ConfigHandle, peers: PeerTable) -> JoinHandle<()> {
+    spawn(move || {
+        loop {
+            let options = config.current();
+            let server = options.global_discovery_server().cloned();
+            let agent = match agent(&options) {
+                Ok(agent) => Some(agent),
+                Err(e) => {
+                    warn!("Unable to use SOCKS proxy {}, not using the global discovery server: {}",
+                        options.socks_proxy().map(ToString::to_string).unwrap_or_default(), e);
+                    None
+                }
+            };
+            if let (Some(server), Some(agent)) = (&server, &agent) {
+                if server.scheme() != "https" {
+                    warn!("Global discovery server {} does not use HTTPS", server);
+                }
+                let token = options.global_discovery_token();
+                match register(agent, server, token, &BroadcastPacket::from_options(&options)) {
+                    Ok(()) => debug!("Registered with global discovery server {}", server),
+                    Err(e) => warn!("Unable to register with {}: {}", server, e),
+                }
+                find_devices(agent, server, token, &options, &peers);
+            }
+
+            let started = Instant::now();
+            while started.elapsed() < REGISTER_INTERVAL
+                && config.current().global_discovery_server() == server.as_ref()
+                && config.current().global_discovery_token() == options.global_discovery_token()
+                && config.current().socks_proxy() == options.socks_proxy() {
+                sleep(RELOAD_INTERVAL);
+            }
+        }
+    })
+}
+
+/// A client for the discovery server, which goes through the SOCKS proxy if one is configured.
+fn agent(options: &Options) -> Result<Agent, Box<ureq::Error>> {
+    let mut builder = AgentBuilder::new().timeout(REQUEST_TIMEOUT);
+    if let Some(proxy) = options.socks_proxy() {
+        builder = builder.proxy(Proxy::new(proxy.url().as_str()).map_err(Box::new)?);
+    }
+    Ok(builder.build())
+}
+
+/// Looks up every configured device that was not heard from since the last lookup, either on the
+/// local network or on the server.
+fn find_devices(agent: &Agent, server: &Url, token: Option<&str>, options: &Options, peers: &PeerTable) {
+    for device in options.devices() {
+        let device_id = device.id.to_string();
+        let recently_seen = peers.get(&device_id)
+            .is_some_and(|peer| peer.last_seen.elapsed().is_ok_and(|elapsed| elapsed < REGISTER_INTERVAL));
+        if device.id == options.device_id() || recently_seen {
+            continue;
+        }
+
+        match lookup(agent, server, token, &device_id) {
+            Ok(Some(packet)) => {
+                // A device outside the local network is reached through its gateway's mapping.
+                let source = match (packet.external_address(), packet.reachable_addresses().first()) {
+                    (Some(external), _) => external,
+                    (None, Some(address)) => SocketAddr::new(*address, packet.port()),
+                    (None, None) => continue,
+                };
+                // Registrations are renewed every interval, a lookup stays valid for two.
+                if peers.update(&packet, source, REGISTER_INTERVAL * 2) {
+                    info!("Discovered device {} ({}) at {} with global discovery", packet.device_name(),
+                        packet.device_id(), source);
+                }
+            }
+            Ok(None) => debug!("Device {} is not registered with {}", device_id, server),
+            Err(e) => warn!("Unable to look up device {} on {}: {}", device_id, server, e),
+        }
+    }
+}
+
+/// Sends this device's announcement to `server`, with the token it requires if one is given.
+pub fn register(agent: &Agent, server: &Url, token: Option<&str>, packet: &BroadcastPacket)
+    -> Result<(), DiscoveryError> {
+    let json = packet.to_json().map_err(DiscoveryError::Packet)?;
+    authorized(agent.post(endpoint(server, &[ANNOUNCE_PATH]).as_str()), token)
+        .set("Content-Type", "application/json")
+        .send_string(&json)
+        .map_err(|e| DiscoveryError::Http(Box::new(e)))?;
+    Ok(())
+}
+
+/// The announcement `device_id` last registered with `server`, or `None` if it is not registered.
+pub fn lookup(agent: &Agent, server: &Url, token: Option<&str>, device_id: &str)
+    -> Result<Option<BroadcastPacket>, DiscoveryError> {
+    let response = match authorized(agent.get(endpoint(server, &[LOOKUP_PATH, device_id]).as_str()), token).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => return Ok(None),
+        Err(e) => return Err(DiscoveryError::Http(Box::new(e))),
+    };
+    let json = response.into_string().map_err(DiscoveryError::Io)?;
+    BroadcastPacket::from_json(&json).map(Some).map_err(DiscoveryError::Packet)
+}
+
+fn authorized(request: Request, token: Option<&str>) -> Request {
+    match token {
+        Some(token) => request.set("Authorization", &format!("{}{}", BEARER_PREFIX, token)),
+        None => request,
+    }
+}
+
+/// Appends `path` to the server URL, keeping any path the server is hosted under.
+fn endpoint(server: &Url, path: &[&str]) -> Url {
+    let mut url = server.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().extend(path.iter().flat_map(|path| path.split('/')));
+    }
+    url
+}
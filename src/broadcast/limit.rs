@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+/// Announcements taken per second from all sources together, bounding the work that packets
+/// with spoofed sources can cause.
+const MAX_PACKETS_PER_SECOND: u32 = 200;
+/// Announcements taken per second from a single address.
+const MAX_PACKETS_PER_SOURCE: u32 = 20;
+/// Announcements taken per second for a single device id, which arrive once per interface and
+/// address family, and again through relays.
+const MAX_PACKETS_PER_DEVICE: u32 = 10;
+/// Shortest time between two reports of dropped announcements.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counts packets per key in one second windows, taking up to `limit` per key in each.
+#[derive(Debug)]
+struct RateLimit<K> {
+    limit: u32,
+    window: Instant,
+    counts: HashMap<K, u32>,
+}
+
+impl<K: Hash + Eq> RateLimit<K> {
+    fn new(limit: u32) -> Self {
+        RateLimit { limit, window: Instant::now(), counts: HashMap::new() }
+    }
+
+    fn allow(&mut self, key: K) -> bool {
+        if self.window.elapsed() >= Duration::from_secs(1) {
+            self.window = Instant::now();
+            self.counts.clear();
+        }
+        let count = self.counts.entry(key).or_insert(0);
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+/// Limits on the announcements the listeners take, shared between them, so that a misbehaving or
+/// malicious host can't keep them busy or flood the peer table.
+#[derive(Debug)]
+pub struct ListenLimits {
+    total: RateLimit<()>,
+    sources: RateLimit<IpAddr>,
+    devices: RateLimit<String>,
+    dropped: u64,
+    unreported: u64,
+    reported: Option<Instant>,
+}
+
+impl ListenLimits {
+    pub fn new() -> Self {
+        ListenLimits {
+            total: RateLimit::new(MAX_PACKETS_PER_SECOND),
+            sources: RateLimit::new(MAX_PACKETS_PER_SOURCE),
+            devices: RateLimit::new(MAX_PACKETS_PER_DEVICE),
+            dropped: 0,
+            unreported: 0,
+            reported: None,
+        }
+    }
+
+    /// Whether a packet from `source` is decoded, checked before spending any work on it.
+    pub fn allow_source(&mut self, source: IpAddr) -> bool {
+        let allowed = self.sources.allow(source) && self.total.allow(());
+        if !allowed {
+            self.drop_packet();
+        }
+        allowed
+    }
+
+    /// Whether an announcement of `device_id` is recorded.
+    pub fn allow_device(&mut self, device_id: &str) -> bool {
+        let allowed = self.devices.allow(device_id.to_string());
+        if !allowed {
+            self.drop_packet();
+        }
+        allowed
+    }
+
+    fn drop_packet(&mut self) {
+        self.dropped += 1;
+        self.unreported += 1;
+        if self.reported.is_none_or(|reported| reported.elapsed() >= REPORT_INTERVAL) {
+            warn!("Dropped {} announcements over the rate limit, {} in total", self.unreported, self.dropped);
+            self.unreported = 0;
+            self.reported = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_source_separately() {
+        let mut limits = ListenLimits::new();
+        let flooding: IpAddr = "192.0.2.2".parse().unwrap();
+        for _ in 0..MAX_PACKETS_PER_SOURCE {
+            assert!(limits.allow_source(flooding));
+        }
+        assert!(!limits.allow_source(flooding));
+        assert!(limits.allow_source("192.0.2.3".parse().unwrap()));
+        assert_eq!(limits.dropped, 1);
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::broadcast::limit::ListenLimits;
+use crate::broadcast::peers::EXPIRY_ANNOUNCEMENTS;
+use crate::broadcast::relay::Relay;
+use crate::broadcast::{get_multicast_interfaces, BroadcastPacket, InterfaceMonitor, PeerTable, MAX_PACKET_SIZE};
+use crate::config::{ConfigHandle, IpVersions, Options};
+
+/// How often a waiting listener checks whether the options it was set up with changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to wait before retrying after the socket could not be set up.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Shortest time between answering two queries from the same device.
+const MIN_ANSWER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Spawns threads that join the IPv4 and IPv6 multicast groups and record every announcement
+/// from another device in `peers`, relaying the ones that ask for it. The groups are joined again
+/// whenever the interfaces change.
+pub fn spawn_listener(config: ConfigHandle, peers: PeerTable, interfaces: InterfaceMonitor) -> Vec<JoinHandle<()>> {
+    let relay = Arc::new(Mutex::new(Relay::new()));
+    let limits = Arc::new(Mutex::new(ListenLimits::new()));
+    vec![
+        spawn_family(config.clone(), peers.clone(), relay.clone(), limits.clone(), interfaces.clone(),
+            |versions| versions.ipv4, listen_socket_v4),
+        spawn_family(config, peers, relay, limits, interfaces, |versions| versions.ipv6, listen_socket_v6),
+    ]
+}
+
+type ListenSocket = fn(&Options) -> io::Result<UdpSocket>;
+
+/// The parts of the options and the interfaces a listening socket is set up from.
+fn socket_key(options: &Options, interfaces: &InterfaceMonitor) -> impl PartialEq {
+    (options.local_discovery(), options.ip_versions(), options.port(), options.multicast_ipv4(),
+        options.multicast_ipv6(), options.bind_interface().cloned(), options.include_interfaces().to_vec(), options.exclude_interfaces().to_vec(),
+        interfaces.generation())
+}
+
+fn spawn_family(
+    config: ConfigHandle,
+    peers: PeerTable,
+    relay: Arc<Mutex<Relay>>,
+    limits: Arc<Mutex<ListenLimits>>,
+    interfaces: InterfaceMonitor,
+    enabled: fn(&IpVersions) -> bool,
+    listen_socket: ListenSocket,
+) -> JoinHandle<()> {
+    spawn(move || {
+        let own_id = config.current().device_id().to_string();
+        let mut answered = HashMap::new();
+        loop {
+            let options = config.current();
+            if !options.local_discovery() || !enabled(&options.ip_versions()) {
+                sleep(RELOAD_INTERVAL);
+                continue;
+            }
+            let socket = match listen_socket(&options).and_then(|socket| {
+                socket.set_read_timeout(Some(RELOAD_INTERVAL)).map(|()| socket)
+            }) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("Unable to listen for announcements: {}", e);
+                    sleep(RETRY_INTERVAL);
+                    continue;
+                }
+            };
+
+            let key = socket_key(&options, &interfaces);
+            while socket_key(&config.current(), &interfaces) == key {
+                let options = config.current();
+                let lifetime = options.announce_interval() * EXPIRY_ANNOUNCEMENTS;
+                if let Some((packet, source)) = receive(&socket, &peers, &limits, &own_id, lifetime) {
+                    if packet.best_transport(&options).is_none() {
+                        debug!("Device {} enables none of the transports this one does", packet.device_id());
+                    }
+                    answer(&socket, &options, &packet, source, &mut answered);
+                    relay.lock().expect("relay lock poisoned").relay(&options, &packet, source);
+                }
+            }
+            debug!("Discovery options or interfaces changed, listening again");
+        }
+    })
+}
+
+/// Waits for one announcement and records it for `lifetime`, returning it if it came from another
+/// device and is within the limits.
+fn receive(socket: &UdpSocket, peers: &PeerTable, limits: &Mutex<ListenLimits>, own_id: &str, lifetime: Duration)
+    -> Option<(BroadcastPacket, SocketAddr)> {
+    let mut buffer = [0; MAX_PACKET_SIZE as usize];
+    let (len, source) = match socket.recv_from(&mut buffer) {
+        Ok(received) => received,
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return None,
+        Err(e) => {
+            warn!("Unable to receive announcement: {}", e);
+            return None;
+        }
+    };
+
+    if !limits.lock().expect("limits lock poisoned").allow_source(source.ip()) {
+        return None;
+    }
+
+    let mut packet = match BroadcastPacket::from_bytes(&buffer[..len]) {
+        Ok(packet) => packet,
+        Err(e) => {
+            debug!("Ignoring packet from {}: {}", source, e);
+            return None;
+        }
+    };
+    // Multicast is looped back, so this device hears its own announcements.
+    if packet.device_id() == own_id
+        || !limits.lock().expect("limits lock poisoned").allow_device(packet.device_id()) {
+        return None;
+    }
+    // Heard straight from the device, so anything an observed address says was added on the way.
+    if let Some(observed) = packet.observed_address() {
+        debug!("Dropping the address {} added to the announcement of device {} from {}", observed,
+            packet.device_id(), source);
+        packet = packet.without_observed_address();
+    }
+    if peers.update(&packet, source, lifetime) {
+        info!("Discovered device {} ({}) at {}", packet.device_name(), packet.device_id(), source);
+    }
+    Some((packet, source))
+}
+
+/// Answers a query with an announcement sent straight to the listening port of the device that
+/// asked, from the listening socket so it leaves through the interface the query came in on.
+fn answer(socket: &UdpSocket, options: &Options, packet: &BroadcastPacket, source: SocketAddr,
+    answered: &mut HashMap<String, Instant>) {
+    if !packet.query() {
+        return;
+    }
+    answered.retain(|_, time| time.elapsed() < MIN_ANSWER_INTERVAL);
+    if answered.contains_key(packet.device_id()) {
+        return;
+    }
+    answered.insert(packet.device_id().to_string(), Instant::now());
+
+    // Keeps the scope of a link-local source.
+    let mut to = source;
+    to.set_port(packet.port());
+    let bytes = match BroadcastPacket::from_options(options).to_bytes() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("Unable to encode announcement: {}", e);
+            return;
+        }
+    };
+    match socket.send_to(&bytes, to) {
+        Ok(_) => debug!("Answered query from {} at {}", packet.device_id(), to),
+        Err(e) => warn!("Unable to answer query from {} at {}: {}", packet.device_id(), to, e),
+    }
+}
+
+fn listen_socket_v4(options: &Options) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, options.port())).into())?;
+
+    let mut joined = false;
+    for interface in get_multicast_interfaces(options) {
+        if let Some(address) = interface.ipv4.first() {
+            match socket.join_multicast_v4(&options.multicast_ipv4(), address) {
+                Ok(()) => joined = true,
+                Err(e) => warn!("Unable to join {} on {}: {}", options.multicast_ipv4(), interface.name, e),
+            }
+        }
+    }
+    if !joined {
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no IPv4 interface joined the multicast group"));
+    }
+    Ok(socket.into())
+}
+
+fn listen_socket_v6(options: &Options) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, options.port())).into())?;
+    // Link-local groups such as ff02::134 exist separately on every link, so each interface joins.
+    let mut joined = false;
+    for interface in get_multicast_interfaces(options) {
+        if interface.ipv6.is_empty() {
+            continue;
+        }
+        match socket.join_multicast_v6(&options.multicast_ipv6(), interface.index) {
+            Ok(()) => joined = true,
+            Err(e) => warn!("Unable to join {} on {}: {}", options.multicast_ipv6(), interface.name, e),
+        }
+    }
+    if !joined {
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no IPv6 interface joined the multicast group"));
+    }
+    Ok(socket.into())
+}
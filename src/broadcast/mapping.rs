@@ -0,0 +1,234 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::RwLock;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use igd_next::{search_gateway, Gateway, PortMappingProtocol, SearchOptions};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use natpmp::{Natpmp, Protocol, Response};
+
+use crate::config::{ConfigHandle, Options};
+use crate::transport::listening_port;
+
+/// Description the gateway shows for the mapping.
+const MAPPING_DESCRIPTION: &str = "simple-sync";
+/// How long a mapping is asked for, it is renewed halfway through.
+const MAPPING_LEASE: Duration = Duration::from_secs(3600);
+/// How long to look for a UPnP gateway before trying NAT-PMP.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long to wait before trying again after no gateway mapped the port.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+/// How often a mapping thread checks whether the options it was set up with changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    static ref EXTERNAL_ADDRESS: RwLock<Option<SocketAddr>> = RwLock::new(None);
+}
+
+/// The address the gateway forwards to the sync port, if a port mapping is in place.
+pub fn external_address() -> Option<SocketAddr> {
+    *EXTERNAL_ADDRESS.read().expect("external address lock poisoned")
+}
+
+fn set_external_address(address: Option<SocketAddr>) {
+    *EXTERNAL_ADDRESS.write().expect("external address lock poisoned") = address;
+}
+
+#[derive(Debug)]
+pub enum MappingError {
+    Upnp(igd_next::Error),
+    NatPmp(natpmp::Error),
+    Io(io::Error),
+}
+
+impl Display for MappingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::Upnp(e) => write!(f, "UPnP failed: {}", e),
+            MappingError::NatPmp(e) => write!(f, "NAT-PMP failed: {}", e),
+            MappingError::Io(e) => write!(f, "unable to find the local address facing the gateway: {}", e),
+        }
+    }
+}
+
+/// A port forwarded by the gateway, which has to be renewed before its lease runs out.
+enum Mapping {
+    Upnp { gateway: Gateway, external: SocketAddr, local_port: u16 },
+    NatPmp { external: SocketAddr, local_port: u16 },
+}
+
+impl Mapping {
+    fn external(&self) -> SocketAddr {
+        match self {
+            Mapping::Upnp { external, .. } | Mapping::NatPmp { external, .. } => *external,
+        }
+    }
+
+    fn local_port(&self) -> u16 {
+        match self {
+            Mapping::Upnp { local_port, .. } | Mapping::NatPmp { local_port, .. } => *local_port,
+        }
+    }
+
+    /// Asks the gateway to forward the same external port again.
+    fn renew(&self) -> Result<Mapping, MappingError> {
+        match self {
+            Mapping::Upnp { gateway, external, local_port } =>
+                map_upnp_port(gateway.clone(), *local_port, external.port()),
+            Mapping::NatPmp { external, local_port } => map_natpmp(*local_port, external.port(), MAPPING_LEASE),
+        }
+    }
+
+    fn remove(self) {
+        let result = match &self {
+            Mapping::Upnp { gateway, external, .. } => gateway.remove_port(PortMappingProtocol::TCP, external.port())
+                .map_err(|e| MappingError::Upnp(e.into())),
+            // A mapping with no lifetime is deleted.
+            Mapping::NatPmp { local_port, .. } => map_natpmp(*local_port, 0, Duration::ZERO).map(|_| ()),
+        };
+        match result {
+            Ok(()) => info!("Removed the port mapping for {}", self.external()),
+            Err(e) => debug!("Unable to remove the port mapping for {}: {}", self.external(), e),
+        }
+    }
+}
+
+/// Spawns a thread that keeps the sync port forwarded on the local gateway while port mapping is
+/// enabled, trying UPnP and then NAT-PMP. The mapped address is announced by every device that
+/// asks for [`external_address`].
+pub fn spawn_port_mapping(config: ConfigHandle) -> JoinHandle<()> {
+    spawn(move || {
+        let mut mapping: Option<Mapping> = None;
+        loop {
+            let options = config.current();
+            let port = mapped_port(&options);
+            if let Some(previous) = mapping.take_if(|mapping| Some(mapping.local_port()) != port) {
+                set_external_address(None);
+                previous.remove();
+            }
+            let port = match port {
+                Some(port) => port,
+                None => {
+                    sleep(RELOAD_INTERVAL);
+                    continue;
+                }
+            };
+
+            let result = match &mapping {
+                Some(mapping) => mapping.renew(),
+                None => map_port(port),
+            };
+            let wait = match result {
+                Ok(current) => {
+                    if mapping.as_ref().map(Mapping::external) != Some(current.external()) {
+                        info!("Gateway forwards {} to port {}", current.external(), port);
+                    }
+                    set_external_address(Some(current.external()));
+                    mapping = Some(current);
+                    MAPPING_LEASE / 2
+                }
+                Err(e) => {
+                    warn!("Unable to map port {} on the gateway: {}", port, e);
+                    set_external_address(None);
+                    mapping = None;
+                    RETRY_INTERVAL
+                }
+            };
+
+            let started = Instant::now();
+            while started.elapsed() < wait && mapped_port(&config.current()) == Some(port) {
+                sleep(RELOAD_INTERVAL);
+            }
+        }
+    })
+}
+
+/// The port to forward, if port mapping is enabled.
+fn mapped_port(options: &Options) -> Option<u16> {
+    options.port_mapping().then(|| listening_port(options))
+}
+
+/// Forwards `port` with the first gateway that supports UPnP or NAT-PMP.
+fn map_port(port: u16) -> Result<Mapping, MappingError> {
+    let mut search = SearchOptions::default();
+    search.timeout = Some(SEARCH_TIMEOUT);
+    let upnp = search_gateway(search).map_err(|e| MappingError::Upnp(e.into()))
+        .and_then(|gateway| map_upnp_port(gateway, port, port));
+    match upnp {
+        Ok(mapping) => Ok(mapping),
+        Err(e) => {
+            debug!("{}, trying NAT-PMP", e);
+            map_natpmp(port, port, MAPPING_LEASE)
+        }
+    }
+}
+
+/// Forwards `external_port` to `local_port`, or any free external port if the gateway already
+/// uses that one.
+fn map_upnp_port(gateway: Gateway, local_port: u16, external_port: u16) -> Result<Mapping, MappingError> {
+    let local = SocketAddr::new(local_address(gateway.addr)?, local_port);
+    let lease = MAPPING_LEASE.as_secs() as u32;
+    let external_port = match gateway.add_port(PortMappingProtocol::TCP, external_port, local, lease,
+        MAPPING_DESCRIPTION) {
+        Ok(()) => external_port,
+        Err(e) => {
+            debug!("Unable to map external port {}: {}, trying any port", external_port, e);
+            gateway.add_any_port(PortMappingProtocol::TCP, local, lease, MAPPING_DESCRIPTION)
+                .map_err(|e| MappingError::Upnp(e.into()))?
+        }
+    };
+    let ip = gateway.get_external_ip().map_err(|e| MappingError::Upnp(e.into()))?;
+    Ok(Mapping::Upnp { gateway, external: SocketAddr::new(ip, external_port), local_port })
+}
+
+/// Forwards `external_port` to `local_port` with the default gateway for `lifetime`.
+fn map_natpmp(local_port: u16, external_port: u16, lifetime: Duration) -> Result<Mapping, MappingError> {
+    let mut natpmp = Natpmp::new().map_err(MappingError::NatPmp)?;
+    natpmp.send_public_address_request().map_err(MappingError::NatPmp)?;
+    let ip = match natpmp_response(&mut natpmp)? {
+        Response::Gateway(response) => IpAddr::V4(*response.public_address()),
+        response => return Err(MappingError::NatPmp(unexpected(response))),
+    };
+
+    natpmp.send_port_mapping_request(Protocol::TCP, local_port, external_port, lifetime.as_secs() as u32)
+        .map_err(MappingError::NatPmp)?;
+    match natpmp_response(&mut natpmp)? {
+        Response::TCP(response) => Ok(Mapping::NatPmp {
+            external: SocketAddr::new(ip, response.public_port()),
+            local_port,
+        }),
+        response => Err(MappingError::NatPmp(unexpected(response))),
+    }
+}
+
+/// Waits for the response to the pending request, which is sent again until the gateway answers.
+fn natpmp_response(natpmp: &mut Natpmp) -> Result<Response, MappingError> {
+    loop {
+        match natpmp.read_response_or_retry() {
+            Err(natpmp::Error::NATPMP_TRYAGAIN) => {
+                sleep(natpmp.get_natpmp_request_timeout().unwrap_or(Duration::from_millis(50)));
+            }
+            result => return result.map_err(MappingError::NatPmp),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> natpmp::Error {
+    debug!("Unexpected NAT-PMP response {:?}", response);
+    natpmp::Error::NATPMP_ERR_UNSUPPORTEDOPCODE
+}
+
+/// The address of the interface that traffic to `gateway` leaves from, which the gateway forwards
+/// to.
+fn local_address(gateway: SocketAddr) -> Result<IpAddr, MappingError> {
+    let bind = match gateway {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind).map_err(MappingError::Io)?;
+    socket.connect(gateway).map_err(MappingError::Io)?;
+    socket.local_addr().map(|address| address.ip()).map_err(MappingError::Io)
+}
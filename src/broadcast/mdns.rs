@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use mdns_sd::{IfKind, RecvTimeoutError, ResolvedService, ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::broadcast::{capabilities, get_ip_addrs, BindInterface, BroadcastPacket, PeerTable};
+use crate::config::{program_data, ConfigHandle, Options};
+
+/// DNS-SD service type that every instance advertises and browses for.
+pub const SERVICE_TYPE: &str = "_simple-sync._tcp.local.";
+
+/// TXT record properties holding the device id and name.
+const ID_PROPERTY: &str = "id";
+const NAME_PROPERTY: &str = "name";
+/// TXT record property holding the capabilities, separated by commas.
+const CAPABILITIES_PROPERTY: &str = "capabilities";
+/// TXT record properties holding the device key and its signature of the other properties, with
+/// the time and nonce the service was signed with.
+const KEY_PROPERTY: &str = "key";
+const SIGNATURE_PROPERTY: &str = "signature";
+const TIMESTAMP_PROPERTY: &str = "timestamp";
+const NONCE_PROPERTY: &str = "nonce";
+
+/// How often a browsing thread checks whether the options it was set up with changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to wait before retrying after the mDNS responder could not be started.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a service is kept without hearing from it, the TTL of DNS-SD service records. A
+/// device that leaves normally removes its service sooner.
+const SERVICE_LIFETIME: Duration = Duration::from_secs(75 * 60);
+/// How often the service is signed again, well before peers find its signature too old.
+const SIGN_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Spawns a thread that advertises this device with mDNS and records every other instance it
+/// browses in `peers`, alongside the multicast announcements. Services are signed like the
+/// announcements, except for their addresses, which are the ones the service resolved to.
+pub fn spawn_mdns(config: ConfigHandle, peers: PeerTable) -> JoinHandle<()> {
+    spawn(move || {
+        let own_id = config.current().device_id().to_string();
+        loop {
+            let options = config.current();
+            if !options.mdns() {
+                sleep(RELOAD_INTERVAL);
+                continue;
+            }
+            let (daemon, events, mut signed) = match start(&options) {
+                Ok(started) => started,
+                Err(e) => {
+                    warn!("Unable to start mDNS: {}", e);
+                    sleep(RETRY_INTERVAL);
+                    continue;
+                }
+            };
+
+            let key = service_key(&options);
+            while service_key(&config.current()) == key {
+                match events.recv_timeout(RELOAD_INTERVAL) {
+                    Ok(event) => receive(event, &peers, &own_id),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if signed.elapsed() >= SIGN_INTERVAL {
+                    // Registering again announces the new signature without removing the service.
+                    if let Err(e) = register(&daemon, &options) {
+                        warn!("Unable to advertise over mDNS again: {}", e);
+                    }
+                    signed = Instant::now();
+                }
+            }
+            if let Err(e) = daemon.shutdown() {
+                debug!("Unable to stop mDNS: {}", e);
+            }
+            debug!("mDNS options changed, advertising again");
+        }
+    })
+}
+
+/// The parts of the options the advertised service is set up from.
+fn service_key(options: &Options) -> impl PartialEq {
+    (options.mdns(), options.port(), options.device_name(), capabilities(options), options.bind_interface().cloned(),
+        options.include_interfaces().to_vec(), options.exclude_interfaces().to_vec())
+}
+
+/// Registers this device and starts browsing for others, returning when the service was signed.
+fn start(options: &Options) -> mdns_sd::Result<(ServiceDaemon, mdns_sd::Receiver<ServiceEvent>, Instant)> {
+    let daemon = ServiceDaemon::new()?;
+    if let Some(bind) = options.bind_interface() {
+        daemon.disable_interface(IfKind::All)?;
+        daemon.enable_interface(match bind {
+            BindInterface::Name(name) => IfKind::Name(name.clone()),
+            BindInterface::Address(address) => IfKind::Addr(*address),
+        })?;
+    }
+    register(&daemon, options)?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    Ok((daemon, events, Instant::now()))
+}
+
+/// Advertises this device, signed with the device key.
+fn register(daemon: &ServiceDaemon, options: &Options) -> mdns_sd::Result<()> {
+    let (ipv4, ipv6) = get_ip_addrs(options);
+    let addresses: Vec<IpAddr> = ipv4.into_iter().map(IpAddr::from).chain(ipv6.into_iter().map(IpAddr::from)).collect();
+    let device_id = options.device_id().to_string();
+    let mut signed = BroadcastPacket::new(device_id.clone(), options.device_name(), false, options.port(), Vec::new())
+        .with_capabilities(capabilities(options));
+    if let Err(e) = signed.sign(&program_data().signing_key()) {
+        warn!("Unable to sign mDNS service: {}", e);
+    }
+    let mut properties: HashMap<String, String> = vec![
+        (ID_PROPERTY.to_string(), device_id.clone()),
+        (NAME_PROPERTY.to_string(), options.device_name()),
+        (CAPABILITIES_PROPERTY.to_string(), capabilities(options).join(",")),
+        (TIMESTAMP_PROPERTY.to_string(), signed.timestamp.to_string()),
+        (NONCE_PROPERTY.to_string(), signed.nonce.to_string()),
+    ].into_iter().collect();
+    if let (Some(key), Some(signature)) = (signed.public_key, signed.signature) {
+        properties.insert(KEY_PROPERTY.to_string(), key);
+        properties.insert(SIGNATURE_PROPERTY.to_string(), signature);
+    }
+    // The device id is unique and a valid host name, unlike the device name.
+    let host_name = format!("{}.local.", device_id);
+    let service = ServiceInfo::new(SERVICE_TYPE, &device_id, &host_name, addresses.as_slice(), options.port(),
+        properties)?;
+    let service = if addresses.is_empty() { service.enable_addr_auto() } else { service };
+    daemon.register(service)
+}
+
+fn receive(event: ServiceEvent, peers: &PeerTable, own_id: &str) {
+    let service = match event {
+        ServiceEvent::ServiceResolved(service) => service,
+        ServiceEvent::ServiceRemoved(_, fullname) => {
+            // The instance name is the device id.
+            let device_id = fullname.strip_suffix(SERVICE_TYPE).unwrap_or(&fullname).trim_end_matches('.');
+            if let Some(peer) = peers.remove(device_id) {
+                info!("Lost device {} ({}), its mDNS service was removed", peer.device_name, peer.device_id);
+            }
+            return;
+        }
+        event => {
+            debug!("mDNS event {:?}", event);
+            return;
+        }
+    };
+    let packet = match to_packet(&service) {
+        Some(packet) => packet,
+        None => {
+            debug!("Ignoring mDNS service {} without a device id or a valid signature", service.fullname);
+            return;
+        }
+    };
+    // The browser also finds the service this device advertises.
+    if packet.device_id() == own_id {
+        return;
+    }
+
+    let source = match packet.addresses().first() {
+        Some(address) => SocketAddr::new(*address, packet.port()),
+        None => {
+            debug!("Ignoring mDNS service {} without addresses", service.fullname);
+            return;
+        }
+    };
+    if peers.update(&packet, source, SERVICE_LIFETIME) {
+        info!("Discovered device {} ({}) at {} with mDNS", packet.device_name(), packet.device_id(), source);
+    }
+}
+
+/// Reads a resolved service the same way as an announcement, IPv4 addresses first. `None` if its
+/// signature doesn't check out, the addresses are left out of the signature.
+fn to_packet(service: &ResolvedService) -> Option<BroadcastPacket> {
+    let property = |name| service.get_property_val_str(name).map(str::to_string);
+    let device_id = property(ID_PROPERTY)?;
+    let device_name = property(NAME_PROPERTY).unwrap_or_else(|| device_id.clone());
+    let capabilities = property(CAPABILITIES_PROPERTY).unwrap_or_default()
+        .split(',').filter(|capability| !capability.is_empty()).map(str::to_string).collect();
+    let mut packet = BroadcastPacket {
+        timestamp: property(TIMESTAMP_PROPERTY)?.parse().ok()?,
+        nonce: property(NONCE_PROPERTY)?.parse().ok()?,
+        public_key: property(KEY_PROPERTY),
+        signature: property(SIGNATURE_PROPERTY),
+        ..BroadcastPacket::new(device_id, device_name, false, service.get_port(), Vec::new())
+            .with_capabilities(capabilities)
+    };
+    packet.verify().ok()?;
+    let mut addresses: Vec<IpAddr> = service.get_addresses().iter().map(|address| address.to_ip_addr()).collect();
+    addresses.sort_by_key(|address| (address.is_ipv6(), *address));
+    packet.addresses = addresses;
+    Some(packet)
+}
@@ -0,0 +1,123 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::info;
+
+use crate::broadcast::{get_interfaces, InterfaceAddrs, PeerTable};
+use crate::config::ConfigHandle;
+
+/// How often the network interfaces are checked for changes.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(5);
+/// How much longer than the monitor interval a check has to take to count as waking from sleep.
+const WAKE_THRESHOLD: Duration = Duration::from_secs(10);
+/// How long peers are kept after waking from sleep, for them to answer the announcements sent
+/// on waking once the network is back.
+const WAKE_GRACE: Duration = Duration::from_secs(30);
+
+/// Counts how often the network interfaces discovery uses changed, such as when a VPN connects
+/// or a laptop is docked, so that sockets set up for the old interfaces are set up again. Waking
+/// from sleep counts as a change, since the network may be another one or have forgotten the
+/// multicast group memberships.
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceMonitor {
+    generation: Arc<(Mutex<u64>, Condvar)>,
+}
+
+impl InterfaceMonitor {
+    /// Changes every time the interfaces do.
+    pub fn generation(&self) -> u64 {
+        *self.generation.0.lock().expect("interface monitor lock poisoned")
+    }
+
+    /// Waits until the interfaces change from `generation` or `timeout` passes, returning the
+    /// generation at that point.
+    pub fn wait_for_change(&self, generation: u64, timeout: Duration) -> u64 {
+        let (current, changed) = &*self.generation;
+        let deadline = Instant::now() + timeout;
+        let mut current = current.lock().expect("interface monitor lock poisoned");
+        while *current == generation {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            current = changed.wait_timeout(current, remaining).expect("interface monitor lock poisoned").0;
+        }
+        *current
+    }
+
+    fn changed(&self) {
+        let (current, changed) = &*self.generation;
+        *current.lock().expect("interface monitor lock poisoned") += 1;
+        changed.notify_all();
+    }
+}
+
+/// Spawns a thread that checks the interfaces allowed by the current options and reports every
+/// change through the returned monitor. Waking from sleep is reported as well, keeping the
+/// entries in `peers` long enough for them to be heard from again.
+pub fn spawn_interface_monitor(config: ConfigHandle, peers: PeerTable) -> InterfaceMonitor {
+    let monitor = InterfaceMonitor::default();
+    let changes = monitor.clone();
+    spawn(move || {
+        let mut interfaces = get_interfaces(&config.current());
+        loop {
+            let (monotonic, wall) = (Instant::now(), SystemTime::now());
+            sleep(MONITOR_INTERVAL);
+            let woke = slept(monotonic.elapsed(), wall.elapsed().ok());
+            if let Some(slept) = woke {
+                info!("Woke up after about {}s, announcing again", slept.as_secs());
+                peers.refresh(WAKE_GRACE);
+            }
+
+            let current = get_interfaces(&config.current());
+            let interfaces_changed = current != interfaces;
+            if interfaces_changed {
+                log_changes(&interfaces, &current);
+                interfaces = current;
+            }
+            if interfaces_changed || woke.is_some() {
+                changes.changed();
+            }
+        }
+    });
+    monitor
+}
+
+/// How long the system was asleep during a check that took `monotonic` by the monotonic clock
+/// and `wall` by the wall clock, if long enough to count. The monotonic clock stops while the
+/// system is suspended, and jumps ahead when the process or virtual machine was paused instead.
+fn slept(monotonic: Duration, wall: Option<Duration>) -> Option<Duration> {
+    let slept = monotonic.max(wall.unwrap_or_default()).saturating_sub(MONITOR_INTERVAL);
+    (slept >= WAKE_THRESHOLD).then_some(slept)
+}
+
+fn log_changes(previous: &[InterfaceAddrs], current: &[InterfaceAddrs]) {
+    for interface in current {
+        match previous.iter().find(|previous| previous.name == interface.name) {
+            None => info!("Interface {} is up with addresses {:?} {:?}", interface.name, interface.ipv4,
+                interface.ipv6),
+            Some(previous) if previous != interface => info!("Interface {} now has addresses {:?} {:?}",
+                interface.name, interface.ipv4, interface.ipv6),
+            Some(_) => {}
+        }
+    }
+    for interface in previous.iter().filter(|previous| current.iter().all(|current| current.name != previous.name)) {
+        info!("Interface {} is down", interface.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_clock_jumps() {
+        let hour = Duration::from_secs(3600);
+        assert_eq!(slept(MONITOR_INTERVAL, Some(MONITOR_INTERVAL)), None);
+        assert_eq!(slept(MONITOR_INTERVAL, Some(MONITOR_INTERVAL + hour)), Some(hour));
+        assert_eq!(slept(MONITOR_INTERVAL + hour, Some(MONITOR_INTERVAL)), Some(hour));
+        // The wall clock being set back is not sleep.
+        assert_eq!(slept(MONITOR_INTERVAL, None), None);
+    }
+}
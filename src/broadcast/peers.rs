@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+
+use crate::broadcast::BroadcastPacket;
+use crate::config::{device_id_of, CachedPeer, ProgramData};
+
+/// How often the known peers are saved to the program data.
+const CACHE_INTERVAL: Duration = Duration::from_secs(300);
+/// How often peers are checked for having expired.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Announcements in a row a peer may miss before it is lost, assuming it announces itself as often
+/// as this device does.
+pub const EXPIRY_ANNOUNCEMENTS: u32 = 3;
+/// How far the clocks of two devices may be apart before their signed announcements are too old
+/// or too far ahead to accept.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// A device found by announcing itself on the local network, with mDNS or on the discovery server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub device_id: String,
+    pub device_name: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+    /// Public address forwarded to the peer by its gateway, if it mapped one.
+    pub external_address: Option<SocketAddr>,
+    /// Hex encoded key the peer signs its announcements with, `None` if it never signed one.
+    pub public_key: Option<String>,
+    /// What the peer announced it supports.
+    pub capabilities: Vec<String>,
+    /// Where announcements came from and when each was last heard, most recent first. A device on
+    /// several interfaces, or announcing over IPv4 and IPv6, is heard from each of them.
+    pub sources: Vec<(SocketAddr, SystemTime)>,
+    pub last_seen: SystemTime,
+    /// When the peer is lost unless it is heard from again.
+    pub expires: SystemTime,
+}
+
+impl Peer {
+    /// Where the last announcement came from, reachable even if `addresses` is incomplete.
+    pub fn source(&self) -> SocketAddr {
+        self.sources[0].0
+    }
+}
+
+/// A change to the peers that upper layers react to, such as closing the connection to a peer
+/// that was lost.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Found(Peer),
+    /// A known peer announced a different name, port, addresses or capabilities.
+    Updated(Peer),
+    Lost(Peer),
+}
+
+/// What is known about a device's signed announcements, kept after the peer is lost.
+#[derive(Debug, Clone, Default)]
+struct Identity {
+    /// Timestamp and nonce of the latest signed announcement.
+    latest: Option<(SystemTime, u64)>,
+}
+
+/// Why an announcement is not taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejection {
+    Unsigned,
+    /// Signed with another key than the one the device id is derived from.
+    WrongKey,
+    Stale,
+    Replayed,
+    /// The very same announcement came before, as it does when heard over both IPv4 and IPv6,
+    /// and as it would when recorded and sent again with other unsigned fields.
+    Duplicate,
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Unsigned => write!(f, "it is not signed"),
+            Rejection::WrongKey => write!(f, "it is not signed with the device's key"),
+            Rejection::Stale => write!(f, "it was made too long ago or ahead of this device's clock"),
+            Rejection::Replayed => write!(f, "a later one was already received"),
+            Rejection::Duplicate => write!(f, "it was already received"),
+        }
+    }
+}
+
+/// Devices discovered so far, keyed by device id and shared between threads.
+#[derive(Debug, Clone, Default)]
+pub struct PeerTable {
+    peers: Arc<RwLock<HashMap<String, Peer>>>,
+    identities: Arc<Mutex<HashMap<String, Identity>>>,
+    subscribers: Arc<Mutex<Vec<Sender<PeerEvent>>>>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a receiver that gets every peer found or lost from now on.
+    pub fn subscribe(&self) -> Receiver<PeerEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().expect("peer table lock poisoned").push(sender);
+        receiver
+    }
+
+    /// Records an announcement that stays valid for `lifetime`, returning whether it came from a
+    /// device not seen before. Announcements of a known device are merged into its entry.
+    ///
+    /// Announcements that aren't signed with the key the device id is derived from are ignored,
+    /// as anyone could make them to lure peers to another address. So are announcements older
+    /// than `lifetime` and older than the latest one from the device, which could be recorded ones
+    /// sent again.
+    pub fn update(&self, packet: &BroadcastPacket, source: SocketAddr, lifetime: Duration) -> bool {
+        match self.check(packet, lifetime) {
+            Ok(()) => {}
+            Err(rejection @ Rejection::WrongKey) => {
+                warn!("Ignoring announcement of device {} from {}, {}", packet.device_id(), source, rejection);
+                return false;
+            }
+            Err(rejection) => {
+                debug!("Ignoring announcement of device {} from {}, {}", packet.device_id(), source, rejection);
+                return false;
+            }
+        }
+
+        let now = SystemTime::now();
+        let expires = now + lifetime;
+        let mut peers = self.peers.write().expect("peer table lock poisoned");
+        if let Some(peer) = peers.get_mut(packet.device_id()) {
+            let announced = (peer.device_name.clone(), peer.port, peer.addresses.clone(), peer.capabilities.clone());
+            peer.device_name = packet.device_name().to_string();
+            peer.port = packet.port();
+            peer.addresses = packet.reachable_addresses();
+            peer.external_address = packet.external_address();
+            peer.public_key = packet.public_key().map(str::to_string);
+            peer.capabilities = packet.capabilities().to_vec();
+            peer.sources.retain(|(address, seen)| *address != source && *seen + lifetime > now);
+            peer.sources.insert(0, (source, now));
+            peer.last_seen = now;
+            peer.expires = peer.expires.max(expires);
+            if announced != (peer.device_name.clone(), peer.port, peer.addresses.clone(), peer.capabilities.clone()) {
+                let peer = peer.clone();
+                drop(peers);
+                self.send(PeerEvent::Updated(peer));
+            }
+            return false;
+        }
+
+        let peer = Peer {
+            device_id: packet.device_id().to_string(),
+            device_name: packet.device_name().to_string(),
+            port: packet.port(),
+            addresses: packet.reachable_addresses(),
+            external_address: packet.external_address(),
+            public_key: packet.public_key().map(str::to_string),
+            capabilities: packet.capabilities().to_vec(),
+            sources: vec![(source, now)],
+            last_seen: now,
+            expires,
+        };
+        peers.insert(peer.device_id.clone(), peer.clone());
+        drop(peers);
+        self.send(PeerEvent::Found(peer));
+        true
+    }
+
+    fn check(&self, packet: &BroadcastPacket, lifetime: Duration) -> Result<(), Rejection> {
+        let key = packet.public_key().ok_or(Rejection::Unsigned)?;
+        match hex::decode(key) {
+            Ok(key) if device_id_of(&key).to_string() == packet.device_id() => {}
+            _ => return Err(Rejection::WrongKey),
+        }
+
+        let now = SystemTime::now();
+        let made = packet.timestamp();
+        if made > now + MAX_CLOCK_SKEW || made + lifetime + MAX_CLOCK_SKEW < now {
+            return Err(Rejection::Stale);
+        }
+        let mut identities = self.identities.lock().expect("peer table lock poisoned");
+        let identity = identities.entry(packet.device_id().to_string()).or_default();
+        match identity.latest {
+            Some(latest) if latest == (made, packet.nonce()) => return Err(Rejection::Duplicate),
+            Some((latest, _)) if made < latest => return Err(Rejection::Replayed),
+            _ => identity.latest = Some((made, packet.nonce())),
+        }
+        Ok(())
+    }
+
+    /// Forgets a peer that said it is leaving.
+    pub fn remove(&self, device_id: &str) -> Option<Peer> {
+        let peer = self.peers.write().expect("peer table lock poisoned").remove(device_id)?;
+        self.send(PeerEvent::Lost(peer.clone()));
+        Some(peer)
+    }
+
+    /// Keeps every peer for at least `grace` from now, so that peers whose entries ran out while
+    /// the system was asleep have time to answer again before they are forgotten.
+    pub fn refresh(&self, grace: Duration) {
+        let expires = SystemTime::now() + grace;
+        for peer in self.peers.write().expect("peer table lock poisoned").values_mut() {
+            peer.expires = peer.expires.max(expires);
+        }
+    }
+
+    /// Forgets the peers that were not heard from in time, returning them.
+    pub fn expire(&self) -> Vec<Peer> {
+        let now = SystemTime::now();
+        let mut peers = self.peers.write().expect("peer table lock poisoned");
+        let expired: Vec<String> = peers.values().filter(|peer| peer.expires <= now)
+            .map(|peer| peer.device_id.clone()).collect();
+        let expired: Vec<Peer> = expired.iter().filter_map(|device_id| peers.remove(device_id)).collect();
+        drop(peers);
+
+        for peer in &expired {
+            self.send(PeerEvent::Lost(peer.clone()));
+        }
+        expired
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<Peer> {
+        self.peers.read().expect("peer table lock poisoned").get(device_id).cloned()
+    }
+
+    /// A snapshot of every known peer.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.read().expect("peer table lock poisoned").values().cloned().collect()
+    }
+
+    fn send(&self, event: PeerEvent) {
+        self.subscribers.lock().expect("peer table lock poisoned")
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+impl From<&Peer> for CachedPeer {
+    fn from(peer: &Peer) -> Self {
+        let mut addresses = peer.addresses.clone();
+        if !addresses.contains(&peer.source().ip()) {
+            addresses.insert(0, peer.source().ip());
+        }
+        CachedPeer {
+            device_id: peer.device_id.clone(),
+            device_name: peer.device_name.clone(),
+            port: peer.port,
+            addresses,
+            public_key: peer.public_key.clone(),
+            last_seen: peer.last_seen.duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Spawns a thread that periodically saves the peers in `peers` to the program data, so their
+/// addresses are known after a restart.
+pub fn spawn_peer_cache(peers: PeerTable) -> JoinHandle<()> {
+    spawn(move || {
+        let mut saved = Vec::new();
+        loop {
+            sleep(CACHE_INTERVAL);
+            let cached: Vec<CachedPeer> = peers.peers().iter().map(CachedPeer::from).collect();
+            if cached != saved {
+                ProgramData::cache_peers(&cached);
+                saved = cached;
+            }
+        }
+    })
+}
+
+/// Spawns a thread that forgets the peers that stopped announcing themselves.
+pub fn spawn_peer_expiry(peers: PeerTable) -> JoinHandle<()> {
+    spawn(move || loop {
+        sleep(EXPIRY_INTERVAL);
+        for peer in peers.expire() {
+            info!("Lost device {} ({}), last seen at {}", peer.device_name, peer.device_id, peer.source());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    const LIFETIME: Duration = Duration::from_secs(90);
+
+    /// An announcement of the device with the key `device`, signed with the key `key`.
+    fn signed_by(device: u8, key: u8) -> BroadcastPacket {
+        let device_id = device_id_of(SigningKey::from_bytes(&[device; 32]).verifying_key().as_bytes());
+        let mut packet = BroadcastPacket::new(device_id.to_string(), "laptop".to_string(), false, 11529,
+            vec!["192.0.2.2".parse().unwrap()]);
+        packet.sign(&SigningKey::from_bytes(&[key; 32])).unwrap();
+        packet
+    }
+
+    fn signed(key: u8) -> BroadcastPacket {
+        signed_by(key, key)
+    }
+
+    fn source(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 2], port))
+    }
+
+    #[test]
+    fn rejects_announcements_not_signed_with_the_device_key() {
+        let peers = PeerTable::new();
+        assert_eq!(peers.check(&signed_by(1, 2), LIFETIME), Err(Rejection::WrongKey));
+        let unsigned = BroadcastPacket::new(signed(1).device_id().to_string(), "laptop".to_string(), false, 11529,
+            vec!["192.0.2.2".parse().unwrap()]);
+        assert_eq!(peers.check(&unsigned, LIFETIME), Err(Rejection::Unsigned));
+        assert!(peers.update(&signed(1), source(1), LIFETIME));
+    }
+
+    #[test]
+    fn rejects_older_announcements() {
+        let peers = PeerTable::new();
+        let older = signed(1);
+        sleep(Duration::from_millis(2));
+        assert_eq!(peers.check(&signed(1), LIFETIME), Ok(()));
+        assert_eq!(peers.check(&older, LIFETIME), Err(Rejection::Replayed));
+    }
+
+    #[test]
+    fn refuses_the_same_announcement_from_another_source() {
+        let peers = PeerTable::new();
+        let packet = signed(1);
+        assert!(peers.update(&packet, source(1), LIFETIME));
+        // Whoever sends it again would otherwise be dialed at the address it came from.
+        assert_eq!(peers.check(&packet, LIFETIME), Err(Rejection::Duplicate));
+        peers.update(&packet, source(2), LIFETIME);
+        let peer = peers.get(packet.device_id()).unwrap();
+        assert_eq!((peer.sources.len(), peer.source()), (1, source(1)));
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::broadcast::announce::{send, Sockets};
+use crate::broadcast::BroadcastPacket;
+use crate::config::Options;
+
+/// Shortest time between relaying two announcements of the same device, the copies a device
+/// sends over IPv4 and IPv6 or on several interfaces are only relayed once.
+const MIN_RELAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Relays announcements that ask for it to the networks they did not come from.
+///
+/// Relayed copies do not ask to be relayed again and are not looped back to this host, so an
+/// announcement crosses at most one relay and never circles between two of them.
+pub struct Relay {
+    sockets: Sockets,
+    relayed: HashMap<String, Instant>,
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Relay { sockets: Sockets::without_loopback(), relayed: HashMap::new() }
+    }
+
+    pub fn relay(&mut self, options: &Options, packet: &BroadcastPacket, source: SocketAddr) {
+        if !options.relay() || !packet.retransmit() {
+            return;
+        }
+        self.relayed.retain(|_, relayed| relayed.elapsed() < MIN_RELAY_INTERVAL);
+        if self.relayed.contains_key(packet.device_id()) {
+            return;
+        }
+
+        let bytes = match packet.relayed().to_bytes() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Unable to encode relayed announcement: {}", e);
+                return;
+            }
+        };
+        if send(options, &mut self.sockets, &bytes, |interface| interface.is_source_of(&source)) {
+            debug!("Relayed announcement of {} from {}", packet.device_id(), source);
+        }
+        self.relayed.insert(packet.device_id().to_string(), Instant::now());
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use structopt::StructOpt;
+use tiny_http::{Header, Method, Request, Response, Server};
+use uuid::Uuid;
+
+use crate::broadcast::global::{ANNOUNCE_PATH, BEARER_PREFIX, LOOKUP_PATH};
+use crate::broadcast::BroadcastPacket;
+use crate::config::Secret;
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:11530";
+/// Three registration intervals, so a single failed registration does not drop a device.
+const DEFAULT_EXPIRY: &str = "900";
+/// Largest announcement accepted, far more than any device with a handful of addresses needs.
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// Runs a global discovery server. Devices register their announcement with it and look up each
+/// other's addresses by device id.
+///
+/// The server speaks plain HTTP, put it behind a reverse proxy that terminates HTTPS.
+#[derive(Debug, StructOpt)]
+pub struct DiscoveryServerCommand {
+    /// Address to accept requests on.
+    #[structopt(long, default_value = DEFAULT_LISTEN)]
+    listen: SocketAddr,
+
+    /// Seconds after which a device that stopped registering is forgotten.
+    #[structopt(long, default_value = DEFAULT_EXPIRY, value_name("SECONDS"))]
+    expiry: u64,
+
+    /// Take the address a registration came from from the `X-Forwarded-For` header set by a
+    /// reverse proxy.
+    #[structopt(long)]
+    behind_proxy: bool,
+
+    /// Only take requests with this token, given as `keyring:<name>` to read it from the OS
+    /// keyring. Devices set it with `global-discovery-token`.
+    #[structopt(long, value_name("TOKEN"), env = "SIMPLE_SYNC_DISCOVERY_SERVER_TOKEN")]
+    token: Option<Secret>,
+}
+
+/// Last announcement of every registered device and when it was received.
+type Registrations = HashMap<String, (BroadcastPacket, Instant)>;
+
+impl DiscoveryServerCommand {
+    pub fn run(&self) -> io::Result<()> {
+        let mut token = self.token.clone();
+        if let Some(token) = &mut token {
+            token.resolve();
+            if token.value().is_none() {
+                return Err(io::Error::other("the token is not in the keyring"));
+            }
+        }
+        let server = Server::http(self.listen).map_err(|e| io::Error::other(e.to_string()))?;
+        info!("Discovery server listening on {}", self.listen);
+
+        let expiry = Duration::from_secs(self.expiry);
+        let mut registrations = Registrations::new();
+        for request in server.incoming_requests() {
+            registrations.retain(|_, (_, received)| received.elapsed() < expiry);
+            let method = request.method().clone();
+            let url = request.url().to_string();
+            if let Err(e) = self.handle(request, token.as_ref().and_then(Secret::value), &mut registrations) {
+                warn!("Unable to respond to {} {}: {}", method, url, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, mut request: Request, token: Option<&str>, registrations: &mut Registrations) -> io::Result<()> {
+        if token.is_some_and(|token| !authorized(&request, token)) {
+            return request.respond(Response::from_string("unauthorized").with_status_code(401));
+        }
+
+        let path: Vec<&str> = request.url().split('?').next().unwrap_or_default()
+            .split('/').filter(|segment| !segment.is_empty()).collect();
+        let announce: Vec<&str> = ANNOUNCE_PATH.split('/').collect();
+        let lookup: Vec<&str> = LOOKUP_PATH.split('/').collect();
+
+        let response = match (request.method(), path.as_slice()) {
+            (Method::Post, path) if path == announce.as_slice() => {
+                let source = self.source(&request);
+                match read_packet(&mut request) {
+                    Ok(mut packet) => {
+                        if let Some(source) = source {
+                            packet.add_observed_address(source);
+                        }
+                        debug!("Registered device {} at {:?}", packet.device_id(), packet.reachable_addresses());
+                        registrations.insert(packet.device_id().to_string(), (packet, Instant::now()));
+                        Response::empty(204).boxed()
+                    }
+                    Err((status, message)) => Response::from_string(message).with_status_code(status).boxed(),
+                }
+            }
+            (Method::Get, [prefix @ .., device_id]) if prefix == lookup.as_slice() => {
+                match registrations.get(*device_id).map(|(packet, _)| packet.to_json()) {
+                    Some(Ok(json)) => Response::from_string(json).with_header(json_header()).boxed(),
+                    Some(Err(e)) => Response::from_string(e.to_string()).with_status_code(500).boxed(),
+                    None => Response::from_string("device not registered").with_status_code(404).boxed(),
+                }
+            }
+            _ => Response::from_string("not found").with_status_code(404).boxed(),
+        };
+        request.respond(response)
+    }
+
+    /// The address a request came from, as seen by this server or by the reverse proxy.
+    fn source(&self, request: &Request) -> Option<IpAddr> {
+        if self.behind_proxy {
+            return request.headers().iter()
+                .find(|header| header.field.equiv("X-Forwarded-For"))
+                .and_then(|header| header.value.as_str().split(',').next())
+                .and_then(|address| address.trim().parse().ok());
+        }
+        request.remote_addr().map(SocketAddr::ip)
+    }
+}
+
+/// Whether `request` carries `token`.
+fn authorized(request: &Request, token: &str) -> bool {
+    request.headers().iter()
+        .filter(|header| header.field.equiv("Authorization"))
+        .any(|header| header.value.as_str().strip_prefix(BEARER_PREFIX) == Some(token))
+}
+
+/// Reads the announcement in a registration, or the status and message to reject it with.
+fn read_packet(request: &mut Request) -> Result<BroadcastPacket, (u16, String)> {
+    let mut json = String::new();
+    request.as_reader().take(MAX_BODY_SIZE + 1).read_to_string(&mut json)
+        .map_err(|e| (400, e.to_string()))?;
+    if json.len() as u64 > MAX_BODY_SIZE {
+        return Err((413, "announcement too large".to_string()));
+    }
+
+    let packet = BroadcastPacket::from_json(&json).map_err(|e| (400, e.to_string()))?;
+    if Uuid::parse_str(packet.device_id()).is_err() {
+        return Err((400, format!("invalid device id `{}`", packet.device_id())));
+    }
+    Ok(packet)
+}
+
+fn json_header() -> Header {
+    Header::from_bytes("Content-Type", "application/json").expect("valid header")
+}
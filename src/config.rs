@@ -1,7 +1,11 @@
+use std::env::var_os;
 use std::ffi::OsString;
-use std::fs::{create_dir_all, read_to_string, write};
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::path::PathBuf;
+use std::fmt::Debug;
+use std::fs::{create_dir_all, read_to_string};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 use clap::ArgMatches;
 use directories::ProjectDirs;
@@ -9,21 +13,110 @@ use lazy_static::lazy_static;
 use log::warn;
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
+use toml::value::{Table, Value};
+use url::Url;
 use uuid::Uuid;
 
+use crate::broadcast::{BindInterface, DiscoverCommand, DiscoveryServerCommand, InterfaceFilter};
+use crate::protocol::Compression;
+use crate::transfer::Chunking;
+use crate::transport::{Encryption, RelayCommand, SocksProxy, StatusCommand};
 use crate::PROJECT_NAME;
 
-const CONFIG_FILE: &'static str = "config.toml";
-const PROGRAM_DATA: &'static str = "data.toml";
+pub use self::data::{device_id_of, index_path, CachedPeer, ProgramData};
+pub use self::device::{BandwidthLimits, Device};
+pub use self::dump::dump_config;
+pub use self::edit::ConfigCommand;
+pub use self::format::ConfigFormat;
+pub use self::generate::generate_config;
+pub use self::folder::{ConflictPolicy, Folder, FolderCommand, FolderMode, FolderSettings, SymlinkPolicy,
+    Versioning, WindowsNames};
+pub use self::reload::ConfigHandle;
+pub use self::secret::{Secret, SecretCommand};
+pub use self::setup::first_run_setup;
+pub use self::source::Source;
+pub use self::validate::InvalidConfig;
+
+use self::atomic::{restore_backup, write_atomic};
+use self::env::FromEnv;
+use self::format::CONFIG_EXTENSIONS;
+use self::include::{fragments, merge_tables};
+use self::lock::FileLock;
+use self::migrate::migrate;
+use self::source::from_file;
+use self::validate::validate;
+
+mod atomic;
+mod data;
+mod device;
+mod dump;
+mod edit;
+mod env;
+mod folder;
+mod format;
+mod generate;
+mod include;
+mod lock;
+mod migrate;
+mod preserve;
+mod reload;
+mod secret;
+mod setup;
+mod source;
+mod validate;
+
+const CONFIG_FILE: &str = "config";
+const PROGRAM_DATA: &str = "data.toml";
+const INDEX: &str = "index";
+const ENV_PREFIX: &str = "SIMPLE_SYNC";
+
+/// Looks up the environment variable with a name.
+pub type Env<'a> = dyn Fn(&str) -> Option<OsString> + 'a;
+
+const DEFAULT_PORT: &str = "11529";
+const DEFAULT_MULTICAST_IPV4: &str = "224.0.0.134";
+const DEFAULT_MULTICAST_IPV6: &str = "ff02::134";
+const DEFAULT_MULTICAST_TTL: &str = "1";
+const DEFAULT_SCAN_INTERVAL: &str = "3600";
+const DEFAULT_WATCH: &str = "true";
+const DEFAULT_MAX_SCAN_MBPS: &str = "0";
+const DEFAULT_SCAN_HASHERS: &str = "0";
+const DEFAULT_SCAN_THREADS: &str = "0";
+const DEFAULT_LOW_PRIORITY_SCAN: &str = "true";
+const DEFAULT_ANNOUNCE_INTERVAL: &str = "30";
+const DEFAULT_BANDWIDTH: &str = "0";
+const DEFAULT_PULL_REQUESTS: &str = "16";
+const DEFAULT_CHUNKING: &str = "fixed";
+const DEFAULT_SYMLINKS: &str = "skip";
+const DEFAULT_MODIFIED_WINDOW: &str = "2";
+const DEFAULT_AUTO_NORMALIZE: &str = "true";
+const DEFAULT_WINDOWS_NAMES: &str = "escape";
+const DEFAULT_VERSIONING: &str = "none";
+const DEFAULT_KEEP_VERSIONS: &str = "5";
+const DEFAULT_MAX_VERSION_AGE: &str = "365";
+const DEFAULT_MAX_VERSIONS_MB: &str = "0";
+const DEFAULT_CONFLICT_POLICY: &str = "keep-both";
+const DEFAULT_LOCAL_DISCOVERY: &str = "true";
+const DEFAULT_DHT_PORT: &str = "11531";
+const DEFAULT_QUIC_PORT: &str = "11532";
+const DEFAULT_STATUS_ADDRESS: &str = "127.0.0.1:11533";
+const DEFAULT_ENCRYPTION: &str = "tls";
+const DEFAULT_COMPRESSION: &str = "zstd";
 
 lazy_static! {
-    static ref DEVICE_ID: String = Uuid::new_v4().to_string();
+    static ref DATA: Result<ProgramData, String> = ProgramData::load();
     static ref DEVICE_NAME: OsString = get_hostname();
 }
 
 macro_rules! options_string {
     ($name:expr) => {
-        str::replace(stringify!($name).split(".").last().unwrap_or_default(), "_", "-")
+        str::replace(stringify!($name).split('.').last().unwrap_or_default(), "_", "-")
+    }
+}
+
+macro_rules! env_string {
+    ($name:expr) => {
+        format!("{}_{}", ENV_PREFIX, str::replace(&options_string!($name), "-", "_").to_uppercase())
     }
 }
 
@@ -44,8 +137,34 @@ macro_rules! merge_with {
         }
 
         impl $name {
-            fn merge_with(&mut self, other: $name, matches: &ArgMatches) {
-                $(if !matches.is_present(options_string!(self.$field_name)) {
+            fn option_names() -> Vec<String> {
+                vec![$(options_string!(self.$field_name),)*]
+            }
+
+            fn sources(matches: &ArgMatches, env: &Env, file_keys: &[String]) -> Vec<(String, Source)> {
+                vec![$(Source::of(options_string!(self.$field_name), env_string!(self.$field_name), matches, env,
+                    file_keys),)*]
+            }
+
+            fn values(&self) -> Vec<String> {
+                vec![$(format!("{:?}", self.$field_name),)*]
+            }
+
+            /// Sets the options not given on the command line that are set in the environment, in
+            /// the variables [`env_string`] names.
+            fn merge_env(&mut self, matches: &ArgMatches, env: &Env) {
+                $(if matches.occurrences_of(options_string!(self.$field_name)) == 0 {
+                    let name = env_string!(self.$field_name);
+                    match env(&name).and_then(|value| <$field_type as FromEnv>::from_env(&value)) {
+                        Some(Ok(value)) => self.$field_name = value,
+                        Some(Err(e)) => warn!("Ignoring {}, {}", name, e),
+                        None => {}
+                    }
+                })*
+            }
+
+            fn merge_with(&mut self, other: $name, sources: &[(String, Source)]) {
+                $(if from_file(sources, &options_string!(self.$field_name)) {
                     self.$field_name = other.$field_name;
                 })*
             }
@@ -62,59 +181,667 @@ merge_with! {
         #[serde(skip)]
         config_file: Option<PathBuf>,
 
-        #[structopt(long, short = "i", default_value = &DEVICE_ID)]
+        /// Format of the config file, detected from its extension if not given.
+        #[structopt(long, value_name("FORMAT"))]
+        #[serde(skip)]
+        config_format: Option<ConfigFormat>,
+
+        /// The id of this device, the one in its program data unless given.
+        #[structopt(long, short = "i")]
         #[serde(skip_serializing)]
-        device_id: Uuid,
+        device_id: Option<Uuid>,
 
         #[structopt(long, short = "n", default_value_os = &DEVICE_NAME)]
-        #[serde(alias = "device_name")]
+        #[serde(alias = "device_name", with = "os_string")]
         set_device_name: OsString,
 
-        #[structopt(long, short = "p", default_value = "11529")]
+        #[structopt(long, short = "p", default_value = DEFAULT_PORT)]
         #[serde(skip_serializing)]
         port: u16,
 
-        #[structopt(long, short = "h", default_value = "244.0.0.134")]
+        /// Accept connections on a free port picked by the system when the port is taken by
+        /// another program, announcing that port instead.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        port_fallback: bool,
+
+        /// Also accept connections on each of these addresses, such as `10.8.0.2:11530` on a VPN,
+        /// announced along with the port.
+        #[structopt(long, value_name("ADDRESS:PORT"), number_of_values = 1, use_delimiter = true)]
+        #[serde(skip_serializing)]
+        listen_addresses: Vec<SocketAddr>,
+
+        /// UDP port to sync over QUIC on, when QUIC is enabled.
+        #[structopt(long, default_value = DEFAULT_QUIC_PORT)]
+        #[serde(skip_serializing)]
+        quic_port: u16,
+
+        /// Address the running process serves the status of its connections on, for the `status`
+        /// command. Anyone who can reach it sees the devices and their traffic, so keep it on a
+        /// loopback address.
+        #[structopt(long, default_value = DEFAULT_STATUS_ADDRESS, value_name("ADDRESS:PORT"))]
+        #[serde(skip_serializing)]
+        status_address: SocketAddr,
+
+        /// How connections are encrypted, `tls` or `noise`. Noise keys connections on the device
+        /// key alone, without certificates, but leaves out QUIC. Devices only connect to devices
+        /// that encrypt the same way.
+        #[structopt(long, default_value = DEFAULT_ENCRYPTION)]
+        #[serde(skip_serializing)]
+        encryption: Encryption,
+
+        /// How to compress transfers, `zstd`, `lz4` or `none`. Devices use the first compression
+        /// both support, and data that looks compressed already is sent as it is.
+        #[structopt(long, default_value = DEFAULT_COMPRESSION)]
+        #[serde(skip_serializing)]
+        compression: Compression,
+
+        #[structopt(long, short = "h", default_value = DEFAULT_MULTICAST_IPV4)]
         #[serde(skip_serializing)]
         multicast_ipv4: Ipv4Addr,
 
-        #[structopt(long, short = "H", default_value = "ff02::134")]
+        #[structopt(long, short = "H", default_value = DEFAULT_MULTICAST_IPV6)]
         #[serde(skip_serializing)]
         multicast_ipv6: Ipv6Addr,
 
+        /// How many routers IPv4 announcements may cross, 1 keeps them on the local network.
+        #[structopt(long, default_value = DEFAULT_MULTICAST_TTL)]
+        #[serde(skip_serializing)]
+        multicast_ttl: u8,
+
+        /// How many routers IPv6 announcements may cross, 1 keeps them on the local network.
+        #[structopt(long, default_value = DEFAULT_MULTICAST_TTL)]
+        #[serde(skip_serializing)]
+        multicast_hops: u8,
+
+        /// Seconds between announcements on the local network, randomly varied so that devices
+        /// do not announce in lockstep.
+        #[structopt(long, default_value = DEFAULT_ANNOUNCE_INTERVAL, value_name("SECONDS"))]
+        #[serde(skip_serializing)]
+        announce_interval: u64,
+
+        /// Seconds between full scans of each folder, which find the changes watching missed, or
+        /// 0 to only scan when starting.
+        #[structopt(long, default_value = DEFAULT_SCAN_INTERVAL, value_name("SECONDS"))]
+        #[serde(skip_serializing)]
+        scan_interval: u64,
+
+        /// Watch folders for changes so they sync within seconds, rather than waiting for the next
+        /// scan.
+        #[structopt(long, default_value = DEFAULT_WATCH, parse(try_from_str), value_name("BOOL"))]
+        #[serde(skip_serializing)]
+        watch: bool,
+
+        /// Megabytes per second scans read at most across all folders, 0 for unlimited.
+        #[structopt(long, default_value = DEFAULT_MAX_SCAN_MBPS, value_name("MBPS"))]
+        #[serde(skip_serializing)]
+        max_scan_mbps: u32,
+
+        /// Files hashed at once across all folders, 0 for one for each processor.
+        #[structopt(long, default_value = DEFAULT_SCAN_HASHERS, value_name("COUNT"))]
+        #[serde(skip_serializing)]
+        scan_hashers: u32,
+
+        /// Directories read and files hashed at once in a scan of a folder, 0 for one for each
+        /// processor.
+        #[structopt(long, default_value = DEFAULT_SCAN_THREADS, value_name("COUNT"))]
+        #[serde(skip_serializing)]
+        scan_threads: u32,
+
+        /// Scan at a low processor and disk priority where the system has them, so that the
+        /// first scan of a large folder leaves the machine usable.
+        #[structopt(long, default_value = DEFAULT_LOW_PRIORITY_SCAN, parse(try_from_str), value_name("BOOL"))]
+        #[serde(skip_serializing)]
+        low_priority_scan: bool,
+
+        #[structopt(long, value_name("PATTERN"), number_of_values = 1, use_delimiter = true)]
+        #[serde(skip_serializing)]
+        ignore: Vec<String>,
+
+        #[structopt(long, default_value = DEFAULT_BANDWIDTH, value_name("KBPS"))]
+        #[serde(skip_serializing)]
+        max_send_kbps: u32,
+
+        #[structopt(long, default_value = DEFAULT_BANDWIDTH, value_name("KBPS"))]
+        #[serde(skip_serializing)]
+        max_recv_kbps: u32,
+
+        /// Blocks requested from a device at once for each file being pulled, more keeping fast
+        /// and distant links busy.
+        #[structopt(long, default_value = DEFAULT_PULL_REQUESTS, value_name("BLOCKS"))]
+        #[serde(skip_serializing)]
+        pull_requests: u32,
+
+        /// How files are split into blocks, `fixed` or `fastcdc` to cut them where the content
+        /// says, so that bytes inserted or removed in the middle of a file only change the blocks
+        /// around them.
+        #[structopt(long, default_value = DEFAULT_CHUNKING)]
+        #[serde(skip_serializing)]
+        chunking: Chunking,
+
+        /// What is synced of symbolic links, `skip` to leave them out, `link` for the links
+        /// themselves or `follow` for what they point to within the folder.
+        #[structopt(long, default_value = DEFAULT_SYMLINKS, value_name("POLICY"))]
+        #[serde(skip_serializing)]
+        symlinks: SymlinkPolicy,
+
+        /// Neither sync the permissions of files nor take a change to them as a change, for file
+        /// systems such as FAT that have none or devices whose systems don't share them.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        ignore_permissions: bool,
+
+        /// Sync the extended attributes of files, such as the tags, quarantine and resource forks
+        /// of macOS.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        xattrs: bool,
+
+        /// Rename files whose names aren't in the Unicode form the index keeps them in, NFC, to
+        /// it, so that a name macOS wrote decomposed is the same file on Linux. Files that aren't
+        /// are left out.
+        #[structopt(long, default_value = DEFAULT_AUTO_NORMALIZE, parse(try_from_str), value_name("BOOL"))]
+        #[serde(skip_serializing)]
+        auto_normalize: bool,
+
+        /// How the files whose names Windows doesn't allow, such as `CON`, `a:b` or ones ending in
+        /// a dot, are kept on it, escape to write their characters as look-alikes or skip to leave
+        /// them out.
+        #[structopt(long, default_value = DEFAULT_WINDOWS_NAMES, value_name("SCHEME"))]
+        #[serde(skip_serializing)]
+        windows_names: WindowsNames,
+
+        /// Seconds by which the modification times of a file may differ and still be the same,
+        /// for file systems that keep them coarsely and devices whose clocks drift apart.
+        #[structopt(long, default_value = DEFAULT_MODIFIED_WINDOW, value_name("SECONDS"))]
+        #[serde(skip_serializing)]
+        modified_window: u64,
+
+        #[structopt(long, default_value = DEFAULT_VERSIONING)]
+        #[serde(skip_serializing)]
+        versioning: Versioning,
+
+        /// Old copies of each file kept with `trash` versioning, 0 to keep all of them.
+        #[structopt(long, default_value = DEFAULT_KEEP_VERSIONS, value_name("COUNT"))]
+        #[serde(skip_serializing)]
+        keep_versions: u32,
+
+        /// Days old copies are kept with `staggered` versioning, 0 to keep them for good.
+        #[structopt(long, default_value = DEFAULT_MAX_VERSION_AGE, value_name("DAYS"))]
+        #[serde(skip_serializing)]
+        max_version_age: u64,
+
+        /// Megabytes the old copies of the files of a folder may take up, the oldest removed
+        /// past that, 0 for no limit.
+        #[structopt(long, default_value = DEFAULT_MAX_VERSIONS_MB, value_name("MB"))]
+        #[serde(skip_serializing)]
+        max_versions_mb: u64,
+
+        /// How a file changed on two devices apart is resolved, `keep-both` to keep the other
+        /// version next to it as a conflict copy, `newest-wins`, `largest-wins` or
+        /// `prefer-device:<id>` for the version made by that device.
+        #[structopt(long, default_value = DEFAULT_CONFLICT_POLICY, value_name("POLICY"))]
+        #[serde(skip_serializing)]
+        conflict_policy: ConflictPolicy,
+
+        /// Only use this network interface, given by name or address, for discovery and transfers.
+        #[structopt(long, value_name("NAME|ADDRESS"))]
+        #[serde(skip_serializing)]
+        bind_interface: Option<BindInterface>,
+
+        /// Only use interfaces with a name matching one of these patterns, such as `eth*`, or the
+        /// addresses in one of these subnets, such as `192.168.0.0/16`.
+        #[structopt(long, value_name("PATTERN|SUBNET"), number_of_values = 1, use_delimiter = true)]
+        #[serde(skip_serializing)]
+        include_interfaces: Vec<InterfaceFilter>,
+
+        /// Never use interfaces with a name matching one of these patterns, such as `docker0` or
+        /// `veth*`, or the addresses in one of these subnets, such as `fe80::/10`.
+        #[structopt(long, value_name("PATTERN|SUBNET"), number_of_values = 1, use_delimiter = true)]
+        #[serde(skip_serializing)]
+        exclude_interfaces: Vec<InterfaceFilter>,
+
+        /// Announce this device and listen for peers on the local network.
+        #[structopt(long, default_value = DEFAULT_LOCAL_DISCOVERY, parse(try_from_str), value_name("BOOL"))]
+        #[serde(skip_serializing)]
+        local_discovery: bool,
+
+        /// Register this device with this discovery server and look up peers on it, so that devices
+        /// find each other across the internet.
+        #[structopt(long, value_name("URL"))]
+        #[serde(skip_serializing)]
+        global_discovery_server: Option<Url>,
+
+        /// Token the discovery server requires of devices that register with and look up peers on
+        /// it, best given as `keyring:<name>` and stored with `secret set`.
+        #[structopt(long, value_name("TOKEN"))]
+        #[serde(skip_serializing)]
+        global_discovery_token: Option<Secret>,
+
+        /// Wait on this relay, such as `relay.example.com:11532`, for devices that can't dial this
+        /// device directly, and reach devices waiting on theirs through it.
+        #[structopt(long, value_name("HOST:PORT"))]
+        #[serde(skip_serializing)]
+        relay_server: Option<String>,
+
+        /// Password the relay requires of devices that wait on it, best given as `keyring:<name>`
+        /// and stored with `secret set`.
+        #[structopt(long, value_name("PASSWORD"))]
+        #[serde(skip_serializing)]
+        relay_password: Option<Secret>,
+
+        /// Make connections to devices, relays and the discovery server through this SOCKS5 proxy,
+        /// such as `socks5://127.0.0.1:9050` for Tor. Devices can set a proxy of their own, or
+        /// `none`, with `proxy` in their `[[device]]` table.
+        #[structopt(long, value_name("URL"))]
+        #[serde(skip_serializing)]
+        socks_proxy: Option<SocksProxy>,
+
+        /// UDP port the DHT node listens on.
+        #[structopt(long, default_value = DEFAULT_DHT_PORT)]
+        #[serde(skip_serializing)]
+        dht_port: u16,
+
+        /// DHT nodes to join the network through, such as `dht.example.com:11531`.
+        #[structopt(long, value_name("HOST:PORT"), number_of_values = 1, use_delimiter = true)]
+        #[serde(skip_serializing)]
+        dht_bootstrap: Vec<String>,
+
+        #[structopt(skip)]
+        #[serde(rename = "folder")]
+        folders: Vec<Folder>,
+
+        #[structopt(skip)]
+        #[serde(rename = "device")]
+        devices: Vec<Device>,
+
         // Flags
         #[structopt(long, short = "N")]
         #[serde(skip_serializing)]
         no_config_file: bool,
+
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        strict_config: bool,
+
+        /// Also announce to the broadcast address of every IPv4 subnet, for networks that filter
+        /// multicast.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        broadcast_fallback: bool,
+
+        /// Leave link-local addresses, such as `fe80::1` or `169.254.0.1`, out of announcements.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        skip_link_local: bool,
+
+        /// Leave carrier-grade NAT addresses, in `100.64.0.0/10`, out of announcements.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        skip_cgnat: bool,
+
+        /// Only use IPv4, for discovery and for connections, where IPv6 is broken or not allowed.
+        #[structopt(long, conflicts_with = "ipv6-only")]
+        #[serde(skip_serializing)]
+        ipv4_only: bool,
+
+        /// Only use IPv6, for discovery and for connections, where IPv4 is broken or not allowed.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        ipv6_only: bool,
+
+        /// Ask devices that hear this device's announcements to relay them to their other networks.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        retransmit: bool,
+
+        /// Relay announcements that ask for it to the other networks this device is on, so that
+        /// discovery crosses between them.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        relay: bool,
+
+        /// Also advertise this device and browse for peers with mDNS, as `_simple-sync._tcp`.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        mdns: bool,
+
+        /// Ask the local gateway to forward the sync port with UPnP or NAT-PMP, and announce the
+        /// external address it maps.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        port_mapping: bool,
+
+        /// Publish this device's address in a DHT shared with other devices and look up peers in
+        /// it, without depending on a discovery server.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        dht: bool,
+
+        /// Also sync over QUIC, preferred over TCP with devices that enable it too.
+        #[structopt(long)]
+        #[serde(skip_serializing)]
+        quic: bool,
+
+        /// Print the effective configuration and where each value came from, then exit.
+        #[structopt(long)]
+        #[serde(skip)]
+        dump_config: bool,
+
+        /// Write a config file with every option set to its default value, then exit.
+        #[structopt(long)]
+        #[serde(skip)]
+        generate_config: bool,
+
+        // Subcommands
+        #[structopt(subcommand)]
+        #[serde(skip)]
+        command: Option<Command>,
     }
 }
 
-impl Options {
-    fn from_args_with_conf() -> Self {
-        let mut from_args = Self::from_args();
+/// Which IP versions discovery and connections use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpVersions {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
 
-        if from_args.no_config_file {
-            return from_args
+impl IpVersions {
+    /// Whether `ip` is of a version in use, IPv4-mapped IPv6 addresses counting as IPv4.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(_) => self.ipv4,
+            IpAddr::V6(_) => self.ipv6,
         }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Sync the folders with the devices found, as when no command is given.
+    Run(RunCommand),
+    /// Read or modify the persistent configuration.
+    Config(ConfigCommand),
+    /// Manage the folders shared with other devices.
+    Folder(FolderCommand),
+    /// Manage secrets stored in the OS keyring.
+    Secret(SecretCommand),
+    /// List the devices found on the local network.
+    Discover(DiscoverCommand),
+    /// Run a global discovery server that devices register with and look up each other on.
+    DiscoveryServer(DiscoveryServerCommand),
+    /// Run a relay that joins up connections between devices that can't reach each other directly.
+    Relay(RelayCommand),
+    /// Show where the connection to every device stands, as the running process sees it.
+    Status(StatusCommand),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RunCommand {
+    /// Bring every folder up to date once and exit, with status 1 if one couldn't be and 2 if
+    /// one has conflicts left to resolve, for cron and scripts.
+    #[structopt(long)]
+    pub once: bool,
+}
 
-        let from_conf = match from_args.config_file {
-            None => match get_config_path() {
-                None => Self::default(),
-                Some(mut path) => {
-                    path.push(CONFIG_FILE);
-                    Self::from_conf(&path)
-                }
+impl Options {
+    /// Merges the parsed command line with the environment and the config file, also returning
+    /// where each option came from. Only options set in the config file and not on the command
+    /// line or in the environment are taken from the file. The device id is the one in the
+    /// program data where none is given.
+    pub fn load(matches: &ArgMatches) -> Result<(Self, Vec<(String, Source)>), InvalidConfig> {
+        let (mut options, sources) = Self::load_from(matches, &|name| var_os(name))?;
+        options.device_id = options.device_id.or_else(|| load_program_data().ok().map(ProgramData::device_id));
+        Ok((options, sources))
+    }
+
+    /// Like [`Options::load`], with the environment variables `env` looks up and without the
+    /// program data.
+    pub fn load_from(matches: &ArgMatches, env: &Env) -> Result<(Self, Vec<(String, Source)>), InvalidConfig> {
+        let mut from_args = Self::from_clap(matches);
+        from_args.merge_env(matches, env);
+
+        let (from_conf, file_keys) = match from_args.config_path() {
+            Some(path) if !from_args.no_config_file => {
+                Self::from_conf(&path, from_args.config_format(&path), from_args.strict_config)?
             }
-            Some(ref path) => Self::from_conf(path)
+            _ => (Self::default(), Vec::new()),
         };
 
-        let matches = Self::clap().get_matches();
-        from_args.merge_with(from_conf, &matches);
-        from_args
+        let sources = Self::sources(matches, env, &file_keys);
+        from_args.merge_with(from_conf, &sources);
+        if from_args.ipv4_only && from_args.ipv6_only {
+            warn!("Both ipv4-only and ipv6-only are set, using both IPv4 and IPv6");
+            from_args.ipv4_only = false;
+            from_args.ipv6_only = false;
+        }
+        from_args.resolve_secrets();
+        Ok((from_args, sources))
     }
 
-    fn deserialize_options(options: &str) -> Self {
-        match toml::from_str::<Options>(&options) {
+    fn resolve_secrets(&mut self) {
+        let folders = self.folders.iter_mut().filter_map(|folder| folder.password.as_mut());
+        for secret in folders.chain(&mut self.global_discovery_token).chain(&mut self.relay_password) {
+            secret.resolve();
+        }
+    }
+
+    pub fn device_id(&self) -> Uuid {
+        self.device_id.unwrap_or_default()
+    }
+
+    pub fn dump_config(&self) -> bool {
+        self.dump_config
+    }
+
+    pub fn generate_config(&self) -> bool {
+        self.generate_config
+    }
+
+    pub fn command(&self) -> Option<&Command> {
+        self.command.as_ref()
+    }
+
+    pub fn device_name(&self) -> String {
+        self.set_device_name.to_string_lossy().to_string()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn port_fallback(&self) -> bool {
+        self.port_fallback
+    }
+
+    pub fn listen_addresses(&self) -> &[SocketAddr] {
+        &self.listen_addresses
+    }
+
+    pub fn quic_port(&self) -> u16 {
+        self.quic_port
+    }
+
+    pub fn status_address(&self) -> SocketAddr {
+        self.status_address
+    }
+
+    pub fn encryption(&self) -> Encryption {
+        self.encryption
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn multicast_ipv4(&self) -> Ipv4Addr {
+        self.multicast_ipv4
+    }
+
+    pub fn multicast_ipv6(&self) -> Ipv6Addr {
+        self.multicast_ipv6
+    }
+
+    pub fn local_discovery(&self) -> bool {
+        self.local_discovery
+    }
+
+    pub fn broadcast_fallback(&self) -> bool {
+        self.broadcast_fallback
+    }
+
+    pub fn skip_link_local(&self) -> bool {
+        self.skip_link_local
+    }
+
+    pub fn skip_cgnat(&self) -> bool {
+        self.skip_cgnat
+    }
+
+    pub fn ip_versions(&self) -> IpVersions {
+        IpVersions { ipv4: !self.ipv6_only, ipv6: !self.ipv4_only }
+    }
+
+    pub fn retransmit(&self) -> bool {
+        self.retransmit
+    }
+
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
+
+    pub fn mdns(&self) -> bool {
+        self.mdns
+    }
+
+    pub fn port_mapping(&self) -> bool {
+        self.port_mapping
+    }
+
+    pub fn dht(&self) -> bool {
+        self.dht
+    }
+
+    pub fn quic(&self) -> bool {
+        self.quic
+    }
+
+    pub fn announce_interval(&self) -> Duration {
+        Duration::from_secs(self.announce_interval)
+    }
+
+    pub fn multicast_ttl(&self) -> u8 {
+        self.multicast_ttl
+    }
+
+    pub fn multicast_hops(&self) -> u8 {
+        self.multicast_hops
+    }
+
+    pub fn bind_interface(&self) -> Option<&BindInterface> {
+        self.bind_interface.as_ref()
+    }
+
+    pub fn include_interfaces(&self) -> &[InterfaceFilter] {
+        &self.include_interfaces
+    }
+
+    pub fn exclude_interfaces(&self) -> &[InterfaceFilter] {
+        &self.exclude_interfaces
+    }
+
+    pub fn global_discovery_server(&self) -> Option<&Url> {
+        self.global_discovery_server.as_ref()
+    }
+
+    pub fn global_discovery_token(&self) -> Option<&str> {
+        self.global_discovery_token.as_ref().and_then(Secret::value)
+    }
+
+    pub fn dht_port(&self) -> u16 {
+        self.dht_port
+    }
+
+    pub fn relay_server(&self) -> Option<&str> {
+        self.relay_server.as_deref()
+    }
+
+    pub fn relay_password(&self) -> Option<&str> {
+        self.relay_password.as_ref().and_then(Secret::value)
+    }
+
+    pub fn socks_proxy(&self) -> Option<&SocksProxy> {
+        self.socks_proxy.as_ref()
+    }
+
+    /// The proxy to connect to `device_id` through, the global one unless the device overrides it.
+    pub fn proxy_for(&self, device_id: &str) -> Option<&SocksProxy> {
+        match self.devices.iter().find(|device| device.id.to_string() == device_id).and_then(|device| device.proxy.as_ref()) {
+            Some(proxy) => proxy.socks(),
+            None => self.socks_proxy(),
+        }
+    }
+
+    pub fn dht_bootstrap(&self) -> &[String] {
+        &self.dht_bootstrap
+    }
+
+    pub fn folders(&self) -> &[Folder] {
+        &self.folders
+    }
+
+    pub fn max_scan_mbps(&self) -> u32 {
+        self.max_scan_mbps
+    }
+
+    pub fn scan_hashers(&self) -> u32 {
+        self.scan_hashers
+    }
+
+    pub fn scan_threads(&self) -> u32 {
+        self.scan_threads
+    }
+
+    pub fn low_priority_scan(&self) -> bool {
+        self.low_priority_scan
+    }
+
+    pub fn max_send_kbps(&self) -> u32 {
+        self.max_send_kbps
+    }
+
+    pub fn max_recv_kbps(&self) -> u32 {
+        self.max_recv_kbps
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// The configured device with the id `device_id`, the only devices that are synced with.
+    pub fn device(&self, device_id: &str) -> Option<&Device> {
+        self.devices.iter().find(|device| device.id.to_string() == device_id)
+    }
+
+    /// Limits for transfers with `device_id`, the global ones unless the device overrides them.
+    pub fn bandwidth_limits(&self, device_id: Uuid) -> BandwidthLimits {
+        match self.devices.iter().find(|device| device.id == device_id) {
+            Some(device) => device.limits(self),
+            None => BandwidthLimits { send_kbps: self.max_send_kbps, recv_kbps: self.max_recv_kbps },
+        }
+    }
+
+    pub fn config_path(&self) -> Option<PathBuf> {
+        self.config_file.clone().or_else(get_config_path)
+    }
+
+    pub fn config_format(&self, path: &Path) -> ConfigFormat {
+        self.config_format.unwrap_or_else(|| ConfigFormat::from_path(path))
+    }
+
+    fn deserialize_options(options: Table) -> Self {
+        match Value::Table(options).try_into::<Options>() {
             Ok(config) => config,
             Err(e) => {
                 warn!("Unable to deserialize config: {}", e);
@@ -123,23 +850,72 @@ impl Options {
         }
     }
 
-    fn serialize_options(&self) -> Option<String> {
-        let serialized = match toml::to_string(&self) {
-            Ok(id) => id,
+    /// Reads the config file followed by the fragments in its include directory.
+    fn from_conf(path: &PathBuf, format: ConfigFormat, strict: bool) -> Result<(Self, Vec<String>), InvalidConfig> {
+        let mut table = Self::read_conf_table(path, format, strict)?;
+        for fragment in fragments(path) {
+            let fragment_table = Self::read_conf_table(&fragment, ConfigFormat::from_path(&fragment), strict)?;
+            merge_tables(&mut table, fragment_table);
+        }
+
+        if table.is_empty() {
+            return Ok((Self::default(), Vec::new()));
+        }
+        let keys = table.keys().cloned().collect();
+        Ok((Self::deserialize_options(table), keys))
+    }
+
+    /// Reads, migrates and, in strict mode, validates a single config file.
+    fn read_conf_table(path: &PathBuf, format: ConfigFormat, strict: bool) -> Result<Table, InvalidConfig> {
+        // Exclusive because restoring a backup or migrating writes the file back.
+        let _lock = FileLock::exclusive(path);
+        let mut file = Self::read_conf(path);
+        if file.is_empty() {
+            return Ok(Table::new());
+        }
+
+        // A strict config reports the parse error instead of silently using the backup.
+        if !strict && format.parse(&file).is_err() {
+            if let Some(restored) = restore_backup(path, |contents| format.parse(contents).is_ok()) {
+                file = restored;
+            }
+        }
+
+        let file = Self::migrate_conf(path, format, file);
+        if strict {
+            let errors = validate(&file, format);
+            if !errors.is_empty() {
+                return Err(InvalidConfig::new(path.clone(), errors));
+            }
+        }
+        match format.parse(&file) {
+            Ok(table) => Ok(table),
             Err(e) => {
-                warn!("Failed to serialize Config: {}", e);
-                return None;
+                warn!("Unable to deserialize config: {}", e);
+                Ok(Table::new())
             }
-        };
-        Some(serialized)
+        }
     }
 
-    fn from_conf(path: &PathBuf) -> Self {
-        let file = Self::read_conf(path);
-        if file.is_empty() {
-            Self::default()
-        } else {
-            Self::deserialize_options(&file)
+    /// Upgrades an old config layout, writing the result back so the migration happens once.
+    fn migrate_conf(path: &Path, format: ConfigFormat, file: String) -> String {
+        let mut table = match format.parse(&file) {
+            Ok(table) => table,
+            Err(_) => return file,
+        };
+        if !migrate(&mut table) {
+            return file;
+        }
+
+        match format.update(&file, &table) {
+            Ok(migrated) => {
+                write_atomic(path, &migrated).unwrap_or_else(|e| warn!("Unable to write migrated config: {}", e));
+                migrated
+            }
+            Err(e) => {
+                warn!("Unable to serialize migrated config: {}", e);
+                file
+            }
         }
     }
 
@@ -152,34 +928,128 @@ impl Options {
             }.to_string()
         }
     }
-
-    fn write_to_file(&mut self, path: &PathBuf) {
-        if let Some(x) = self.serialize_options() {
-            write(path, x).unwrap_or_else(|e| warn!("Unable to write config: {}", e));
-        };
-    }
 }
 
 impl Default for Options {
     fn default() -> Self {
-        Self::from_args()
+        Options {
+            config_file: None,
+            config_format: None,
+            device_id: None,
+            set_device_name: DEVICE_NAME.clone(),
+            port: parse_default(DEFAULT_PORT),
+            port_fallback: false,
+            listen_addresses: Vec::new(),
+            quic_port: parse_default(DEFAULT_QUIC_PORT),
+            status_address: parse_default(DEFAULT_STATUS_ADDRESS),
+            encryption: parse_default(DEFAULT_ENCRYPTION),
+            compression: parse_default(DEFAULT_COMPRESSION),
+            multicast_ipv4: parse_default(DEFAULT_MULTICAST_IPV4),
+            multicast_ipv6: parse_default(DEFAULT_MULTICAST_IPV6),
+            multicast_ttl: parse_default(DEFAULT_MULTICAST_TTL),
+            multicast_hops: parse_default(DEFAULT_MULTICAST_TTL),
+            announce_interval: parse_default(DEFAULT_ANNOUNCE_INTERVAL),
+            scan_interval: parse_default(DEFAULT_SCAN_INTERVAL),
+            watch: parse_default(DEFAULT_WATCH),
+            max_scan_mbps: parse_default(DEFAULT_MAX_SCAN_MBPS),
+            scan_hashers: parse_default(DEFAULT_SCAN_HASHERS),
+            scan_threads: parse_default(DEFAULT_SCAN_THREADS),
+            low_priority_scan: parse_default(DEFAULT_LOW_PRIORITY_SCAN),
+            ignore: Vec::new(),
+            max_send_kbps: parse_default(DEFAULT_BANDWIDTH),
+            max_recv_kbps: parse_default(DEFAULT_BANDWIDTH),
+            pull_requests: parse_default(DEFAULT_PULL_REQUESTS),
+            chunking: parse_default(DEFAULT_CHUNKING),
+            symlinks: parse_default(DEFAULT_SYMLINKS),
+            ignore_permissions: false,
+            xattrs: false,
+            auto_normalize: parse_default(DEFAULT_AUTO_NORMALIZE),
+            windows_names: parse_default(DEFAULT_WINDOWS_NAMES),
+            modified_window: parse_default(DEFAULT_MODIFIED_WINDOW),
+            versioning: parse_default(DEFAULT_VERSIONING),
+            keep_versions: parse_default(DEFAULT_KEEP_VERSIONS),
+            max_version_age: parse_default(DEFAULT_MAX_VERSION_AGE),
+            max_versions_mb: parse_default(DEFAULT_MAX_VERSIONS_MB),
+            conflict_policy: parse_default(DEFAULT_CONFLICT_POLICY),
+            bind_interface: None,
+            include_interfaces: Vec::new(),
+            exclude_interfaces: Vec::new(),
+            global_discovery_server: None,
+            global_discovery_token: None,
+            relay_server: None,
+            relay_password: None,
+            socks_proxy: None,
+            dht_port: parse_default(DEFAULT_DHT_PORT),
+            dht_bootstrap: Vec::new(),
+            local_discovery: parse_default(DEFAULT_LOCAL_DISCOVERY),
+            folders: Vec::new(),
+            devices: Vec::new(),
+            no_config_file: false,
+            strict_config: false,
+            broadcast_fallback: false,
+            skip_link_local: false,
+            skip_cgnat: false,
+            ipv4_only: false,
+            ipv6_only: false,
+            retransmit: false,
+            relay: false,
+            mdns: false,
+            port_mapping: false,
+            dht: false,
+            quic: false,
+            dump_config: false,
+            generate_config: false,
+            command: None,
+        }
     }
 }
 
+fn parse_default<T: FromStr>(value: &str) -> T where T::Err: Debug {
+    value.parse().expect("invalid default option value")
+}
+
+/// The persisted identity of this device, or why it couldn't be loaded.
+pub fn load_program_data() -> Result<&'static ProgramData, &'static str> {
+    DATA.as_ref().map_err(String::as_str)
+}
+
+/// The persisted identity of this device, which [`load_program_data`] checked could be loaded
+/// before any command that needs it runs.
+pub fn program_data() -> &'static ProgramData {
+    DATA.as_ref().expect("program data is loaded before it is used")
+}
+
 fn get_config_path() -> Option<PathBuf> {
     if let Some(project_dir) = ProjectDirs::from("", "", PROJECT_NAME) {
         if !project_dir.config_dir().exists() {
             create_dir_all(project_dir.config_dir())
                 .unwrap_or_else(|e| warn!("Unable to create default directory: {}", e));
         }
-        let mut config_dir = project_dir.config_dir().to_path_buf();
-        config_dir.push(CONFIG_FILE);
-        return Some(config_dir);
+        // Use the first existing config file, falling back to TOML for a new one.
+        let config_dir = project_dir.config_dir();
+        let existing = CONFIG_EXTENSIONS.iter()
+            .map(|extension| config_dir.join(CONFIG_FILE).with_extension(extension))
+            .find(|path| path.exists());
+        return existing.or_else(|| Some(config_dir.join(CONFIG_FILE).with_extension(CONFIG_EXTENSIONS[0])));
     }
     warn!("No valid home directory!");
     None
 }
 
+mod os_string {
+    use std::ffi::OsString;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &OsString, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string_lossy())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OsString, D::Error> {
+        String::deserialize(deserializer).map(OsString::from)
+    }
+}
+
 fn get_hostname() -> OsString {
     match hostname::get() {
         Ok(hostname) => hostname,
@@ -189,3 +1059,40 @@ fn get_hostname() -> OsString {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn takes_the_command_line_over_the_environment_over_the_file_over_defaults() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        write(&path, "port = 3000\nquic-port = 3001\nannounce-interval = 9\nmulticast-ttl = 5\n").unwrap();
+        let env: HashMap<&str, &str> = [("SIMPLE_SYNC_QUIC_PORT", "4001"), ("SIMPLE_SYNC_ANNOUNCE_INTERVAL", "7"),
+            ("SIMPLE_SYNC_MDNS", "1"), ("SIMPLE_SYNC_DUMP_CONFIG", "true")].iter().copied().collect();
+
+        let matches = Options::clap().get_matches_from(vec![
+            "simple-sync", "--config-file", path.to_str().unwrap(), "--port", "2000", "--quic-port", "2001",
+        ]);
+        let (options, sources) = Options::load_from(&matches, &|name| env.get(name).map(OsString::from)).unwrap();
+
+        assert_eq!((options.port(), options.quic_port()), (2000, 2001));
+        assert_eq!(options.announce_interval(), Duration::from_secs(7));
+        assert_eq!(options.multicast_ttl(), 5);
+        assert!(options.mdns() && options.dump_config());
+        assert_eq!(options.device_id, None);
+        assert_eq!(options.scan_interval, parse_default::<u64>(DEFAULT_SCAN_INTERVAL));
+        let source = |name: &str| sources.iter().find(|(option, _)| option == name).map(|(_, source)| source.clone());
+        assert_eq!(source("port"), Some(Source::CommandLine));
+        assert_eq!(source("announce-interval"), Some(Source::Environment("SIMPLE_SYNC_ANNOUNCE_INTERVAL".to_string())));
+        assert_eq!(source("multicast-ttl"), Some(Source::ConfigFile));
+        assert_eq!(source("scan-interval"), Some(Source::Default));
+    }
+
+}
@@ -0,0 +1,94 @@
+use std::ffi::OsString;
+use std::fs::{copy, metadata, read_to_string, rename, set_permissions, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+/// Writes `contents` to a temporary file next to `path` and renames it into place, so that a crash
+/// never leaves a partially written file. The previous contents are kept in a `.bak` file.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let temp = with_suffix(path, "tmp");
+    {
+        let mut file = File::create(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+    }
+
+    if let Ok(metadata) = metadata(path) {
+        // Keep the permissions of the file being replaced, it may hold secrets.
+        set_permissions(&temp, metadata.permissions())?;
+        if let Err(e) = copy(path, backup_path(path)) {
+            warn!("Unable to back up {}: {}", path.display(), e);
+        }
+    }
+    rename(&temp, path)
+}
+
+/// Replaces a file that failed to parse with its backup, keeping the broken copy as `.broken`.
+/// Returns the restored contents if the backup passes `is_valid`.
+pub fn restore_backup(path: &Path, is_valid: impl Fn(&str) -> bool) -> Option<String> {
+    let backup = backup_path(path);
+    let contents = read_to_string(&backup).ok().filter(|contents| is_valid(contents))?;
+
+    // Moving the broken file out of the way first keeps it from replacing the good backup.
+    if let Err(e) = rename(path, with_suffix(path, "broken")) {
+        warn!("Unable to keep a copy of {}: {}", path.display(), e);
+    }
+    match write_atomic(path, &contents) {
+        Ok(()) => warn!("Restored {} from {}", path.display(), backup.display()),
+        Err(e) => warn!("Unable to restore {} from backup: {}", path.display(), e),
+    }
+    Some(contents)
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, "bak")
+}
+
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn backs_up_the_contents_it_replaces() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        write_atomic(&path, "port = 1\n").unwrap();
+        assert!(!backup_path(&path).exists());
+
+        write_atomic(&path, "port = 2\n").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "port = 2\n");
+        assert_eq!(read_to_string(backup_path(&path)).unwrap(), "port = 1\n");
+        assert!(!with_suffix(&path, "tmp").exists());
+    }
+
+    #[test]
+    fn restores_a_valid_backup_and_keeps_the_broken_file() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        write(&path, "port = ").unwrap();
+        assert_eq!(restore_backup(&path, |_| true), None);
+
+        write(backup_path(&path), "port = 1\n").unwrap();
+        assert_eq!(restore_backup(&path, |contents| contents.ends_with('\n')).as_deref(), Some("port = 1\n"));
+        assert_eq!(read_to_string(&path).unwrap(), "port = 1\n");
+        assert_eq!(read_to_string(with_suffix(&path, "broken")).unwrap(), "port = ");
+
+        write(&path, "port = ").unwrap();
+        write(backup_path(&path), "port = ").unwrap();
+        assert_eq!(restore_backup(&path, |contents| contents.ends_with('\n')), None);
+        assert_eq!(read_to_string(&path).unwrap(), "port = ");
+    }
+}
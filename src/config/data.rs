@@ -0,0 +1,270 @@
+use std::cmp::Reverse;
+use std::fs::{create_dir_all, read_to_string};
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use directories::ProjectDirs;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use log::{info, warn};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::{Builder, Uuid, Variant, Version};
+
+use crate::config::atomic::{restore_backup, write_atomic};
+use crate::config::lock::FileLock;
+use crate::config::{INDEX, PROGRAM_DATA};
+use crate::PROJECT_NAME;
+
+/// Most peers kept in the data file, the ones seen longest ago are dropped first.
+const MAX_CACHED_PEERS: usize = 256;
+
+/// State generated by the program itself and kept between runs, stored in `data.toml`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProgramData {
+    device_id: Uuid,
+    /// Seconds since the Unix epoch when this device identity was created.
+    first_seen: u64,
+    /// Hex encoded Ed25519 secret key identifying this device.
+    device_key: String,
+    /// Peers heard from in earlier runs, most recently seen first.
+    #[serde(default, rename = "peer", skip_serializing_if = "Vec::is_empty")]
+    peers: Vec<CachedPeer>,
+}
+
+/// Last known address of a peer, so that it can be contacted right after a restart instead of
+/// waiting for it to announce itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CachedPeer {
+    pub device_id: String,
+    pub device_name: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+    /// Hex encoded key the peer signs its announcements with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Seconds since the Unix epoch when the peer was last heard from.
+    pub last_seen: u64,
+}
+
+impl ProgramData {
+    /// Loads the program data, generating and persisting a new device identity on first run.
+    /// A data file that can't be read is restored from its backup, and it is an error where there
+    /// is none, since a new identity would break every peer's trust in this device.
+    pub fn load() -> Result<Self, String> {
+        let path = get_data_path();
+        // Two processes starting together must not both generate an identity.
+        let _lock = path.as_deref().map(FileLock::exclusive);
+        if let Some(mut data) = path.as_deref().map(Self::read).transpose()?.flatten() {
+            let derived = device_id_of(data.verifying_key().as_bytes());
+            if data.device_id != derived {
+                warn!("Device id {} isn't the one of the device key, changing it to {}, peers need to be told \
+                    the new id", data.device_id, derived);
+                data.device_id = derived;
+                if let Some(path) = &path {
+                    data.write(path);
+                }
+            }
+            return Ok(data);
+        }
+
+        let data = Self::generate();
+        match path {
+            Some(path) => {
+                data.write(&path);
+                info!("Created device identity {} in {}", data.device_id, path.display());
+            }
+            None => warn!("No data directory, device identity will not persist"),
+        }
+        Ok(data)
+    }
+
+    pub fn device_id(&self) -> Uuid {
+        self.device_id
+    }
+
+    pub fn first_seen(&self) -> u64 {
+        self.first_seen
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        self.decode_key().expect("device key checked when loaded")
+    }
+
+    fn decode_key(&self) -> Result<SigningKey, hex::FromHexError> {
+        let mut secret = [0; 32];
+        hex::decode_to_slice(&self.device_key, &mut secret)?;
+        Ok(SigningKey::from_bytes(&secret))
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key().verifying_key()
+    }
+
+    /// The peers cached when the program data was loaded.
+    pub fn cached_peers(&self) -> &[CachedPeer] {
+        &self.peers
+    }
+
+    /// Merges `peers` into the ones cached in the data file. Peers not heard from since keep
+    /// their last known address.
+    pub fn cache_peers(peers: &[CachedPeer]) {
+        let path = match get_data_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let _lock = FileLock::exclusive(&path);
+        let mut data = match Self::read(&path) {
+            Ok(Some(data)) => data,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Not caching peers: {}", e);
+                return;
+            }
+        };
+
+        data.peers.retain(|cached| peers.iter().all(|peer| peer.device_id != cached.device_id));
+        data.peers.extend(peers.iter().cloned());
+        data.peers.sort_by_key(|peer| Reverse(peer.last_seen));
+        data.peers.truncate(MAX_CACHED_PEERS);
+        data.write(&path);
+    }
+
+    fn generate() -> Self {
+        let first_seen = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+        let key = SigningKey::generate(&mut OsRng);
+        ProgramData {
+            device_id: device_id_of(key.verifying_key().as_bytes()),
+            first_seen,
+            device_key: hex::encode(key.to_bytes()),
+            peers: Vec::new(),
+        }
+    }
+
+    /// Reads the data file at `path`, `None` where there is none yet. One that doesn't parse is
+    /// replaced with its backup, which keeps the broken file from ever becoming the backup.
+    fn read(path: &Path) -> Result<Option<Self>, String> {
+        let file = match read_to_string(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("unable to read program data at {}: {}", path.display(), e)),
+        };
+        let e = match Self::parse(&file) {
+            Ok(data) => return Ok(Some(data)),
+            Err(e) => e,
+        };
+        warn!("Unable to deserialize program data at {}: {}", path.display(), e);
+        let restored = restore_backup(path, |contents| Self::parse(contents).is_ok());
+        match restored.map(|contents| Self::parse(&contents)) {
+            Some(Ok(data)) => Ok(Some(data)),
+            _ => Err(format!("program data at {} is invalid and has no valid backup, fix or remove it to \
+                generate a new device identity: {}", path.display(), e)),
+        }
+    }
+
+    /// The program data in `contents`, checking that it holds a device key.
+    fn parse(contents: &str) -> Result<Self, String> {
+        let data: ProgramData = toml::from_str(contents).map_err(|e| e.to_string())?;
+        data.decode_key().map_err(|e| format!("invalid device key: {}", e))?;
+        Ok(data)
+    }
+
+    fn write(&self, path: &Path) {
+        let serialized = match toml::to_string(self) {
+            Ok(serialized) => serialized,
+            Err(e) => {
+                warn!("Failed to serialize program data: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = write_atomic(path, &serialized) {
+            warn!("Unable to write program data: {}", e);
+            return;
+        }
+        restrict_permissions(path);
+    }
+}
+
+/// The id of the device with the public key `public_key`, the start of the key's SHA-256 hash laid
+/// out as a name-based UUID. A device can't claim an id without holding the key it is derived from.
+pub fn device_id_of(public_key: &[u8]) -> Uuid {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&Sha256::digest(public_key)[..16]);
+    Builder::from_bytes(bytes).set_variant(Variant::RFC4122).set_version(Version::Sha1).build()
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) {
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    set_permissions(path, Permissions::from_mode(0o600))
+        .unwrap_or_else(|e| warn!("Unable to restrict program data permissions: {}", e));
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) {}
+
+/// Where the file index is kept, next to the program data.
+pub fn index_path() -> Option<PathBuf> {
+    get_data_path().map(|path| path.with_file_name(INDEX))
+}
+
+fn get_data_path() -> Option<PathBuf> {
+    let project_dir = ProjectDirs::from("", "", PROJECT_NAME)?;
+    if !project_dir.data_dir().exists() {
+        create_dir_all(project_dir.data_dir())
+            .unwrap_or_else(|e| warn!("Unable to create data directory: {}", e));
+    }
+    Some(project_dir.data_dir().join(PROGRAM_DATA))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::config::atomic::backup_path;
+
+    #[test]
+    fn restores_broken_data_from_its_backup_rather_than_replacing_the_identity() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join(PROGRAM_DATA);
+        assert_eq!(ProgramData::read(&path).unwrap().map(|data| data.device_id), None);
+
+        let data = ProgramData::generate();
+        data.write(&path);
+        // A second write backs up the first.
+        data.write(&path);
+        write(&path, "device-id = ").unwrap();
+        assert_eq!(ProgramData::read(&path).unwrap().map(|data| data.device_id), Some(data.device_id));
+        assert_eq!(ProgramData::read(&path).unwrap().map(|data| data.device_id), Some(data.device_id));
+
+        write(&path, "device-id = ").unwrap();
+        write(backup_path(&path), "device-id = ").unwrap();
+        assert!(ProgramData::read(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "device-id = ");
+    }
+
+    #[test]
+    fn refuses_data_with_an_invalid_device_key() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join(PROGRAM_DATA);
+        let data = ProgramData { device_key: "not hex".to_string(), ..ProgramData::generate() };
+        data.write(&path);
+        assert!(ProgramData::read(&path).unwrap_err().contains("invalid device key"));
+    }
+
+    #[test]
+    fn derives_the_device_id_from_the_device_key() {
+        let data = ProgramData::generate();
+        assert_eq!(data.device_id(), device_id_of(data.verifying_key().as_bytes()));
+        assert_ne!(data.device_id(), device_id_of(ProgramData::generate().verifying_key().as_bytes()));
+    }
+}
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::Options;
+use crate::transport::DeviceProxy;
+
+/// Config file key holding the `[[device]]` table array.
+pub const DEVICE_KEY: &str = "device";
+
+/// A peer device with settings that differ from the global options.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Device {
+    pub id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_send_kbps: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_recv_kbps: Option<u32>,
+    /// How to connect to the device, where it differs from the global proxy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<DeviceProxy>,
+    /// The device only ever gets folders encrypted with their password, for one that can't be
+    /// trusted with what they hold, such as a server someone else runs.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub untrusted: bool,
+}
+
+pub(super) fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Transfer rate limits in kilobytes per second, 0 meaning unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    pub send_kbps: u32,
+    pub recv_kbps: u32,
+}
+
+impl Device {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.id.to_string())
+    }
+
+    /// The device limits, falling back to the global ones where it sets none.
+    pub fn limits(&self, options: &Options) -> BandwidthLimits {
+        BandwidthLimits {
+            send_kbps: self.max_send_kbps.unwrap_or(options.max_send_kbps),
+            recv_kbps: self.max_recv_kbps.unwrap_or(options.max_recv_kbps),
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use crate::config::source::Source;
+use crate::config::Options;
+
+/// Options that describe the invocation itself rather than configuration.
+const NOT_DUMPED: &[&str] = &["dump-config", "generate-config", "command"];
+
+const MAX_ALIGN: usize = 48;
+
+/// Renders the merged options, one per line, annotated with where each value came from.
+pub fn dump_config(options: &Options, sources: &[(String, Source)]) -> String {
+    let mut dump = match options.config_path() {
+        Some(path) => format!("# config file: {} ({})\n", path.display(), options.config_format(&path)),
+        None => "# no config file\n".to_string(),
+    };
+
+    let lines: Vec<(String, String)> = sources.iter()
+        .zip(options.values())
+        .filter(|((name, _), _)| !NOT_DUMPED.contains(&name.as_str()))
+        .map(|((name, source), value)| (format!("{} = {}", name, value), source.to_string()))
+        .collect();
+    // Align the annotations, without letting long values such as folders push everything over.
+    let width = lines.iter().map(|(line, _)| line.len()).filter(|len| *len <= MAX_ALIGN).max().unwrap_or_default();
+    for (line, source) in lines {
+        dump.push_str(&format!("{:width$}  # {}\n", line, source, width = width));
+    }
+    dump
+}
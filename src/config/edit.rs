@@ -0,0 +1,154 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_to_string;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use structopt::StructOpt;
+use toml::value::{Table, Value};
+
+use crate::config::atomic::write_atomic;
+use crate::config::format::{ConfigFormat, FormatError};
+use crate::config::lock::FileLock;
+use crate::config::migrate::migrate;
+use crate::config::validate::{is_config_key, validate_entry};
+use crate::config::Options;
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    /// Print the value of a key stored in the config file.
+    Get {
+        key: String,
+    },
+    /// Set a key in the config file, validating the value first.
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Remove a key from the config file.
+    Unset {
+        key: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum EditError {
+    NoConfigPath,
+    AlreadyExists(PathBuf),
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+    Format(FormatError),
+    Keyring(keyring::Error),
+    UnknownKey(String),
+    NotSet(String),
+    DuplicateFolder(String),
+    UnknownFolder(String),
+    InvalidValue { key: String, value: String, reason: String },
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::NoConfigPath => write!(f, "no config file path available"),
+            EditError::AlreadyExists(path) => write!(f, "config file {} already exists", path.display()),
+            EditError::Io(e) => write!(f, "unable to access config file: {}", e),
+            EditError::Parse(e) => write!(f, "unable to parse config file: {}", e),
+            EditError::Serialize(e) => write!(f, "unable to serialize config: {}", e),
+            EditError::Format(e) => write!(f, "unable to process config file: {}", e),
+            EditError::Keyring(e) => write!(f, "keyring error: {}", e),
+            EditError::UnknownKey(key) => write!(f, "unknown config key `{}`", key),
+            EditError::NotSet(key) => write!(f, "`{}` is not set in the config file", key),
+            EditError::DuplicateFolder(id) => write!(f, "a folder with id `{}` already exists", id),
+            EditError::UnknownFolder(id) => write!(f, "no folder with id `{}`", id),
+            EditError::InvalidValue { key, value, reason } => {
+                write!(f, "invalid value `{}` for `{}`: {}", value, key, reason)
+            }
+        }
+    }
+}
+
+impl ConfigCommand {
+    pub fn run(&self, options: &Options) -> Result<(), EditError> {
+        let path = options.config_path().ok_or(EditError::NoConfigPath)?;
+        let format = options.config_format(&path);
+        // Held until the edit is written so a concurrent change is not lost.
+        let _lock = match self {
+            ConfigCommand::Get { .. } => FileLock::shared(&path),
+            _ => FileLock::exclusive(&path),
+        };
+        let mut table = read_table(&path, format)?;
+
+        match self {
+            ConfigCommand::Get { key } => {
+                check_key(key)?;
+                match table.get(key) {
+                    Some(Value::String(value)) => println!("{}", value),
+                    Some(value) => println!("{}", value),
+                    None => return Err(EditError::NotSet(key.to_string())),
+                }
+                Ok(())
+            }
+            ConfigCommand::Set { key, value } => {
+                check_key(key)?;
+                table.insert(key.to_string(), validate_value(key, value)?);
+                write_table(&path, format, &table)
+            }
+            ConfigCommand::Unset { key } => {
+                check_key(key)?;
+                if table.remove(key).is_none() {
+                    return Err(EditError::NotSet(key.to_string()));
+                }
+                write_table(&path, format, &table)
+            }
+        }
+    }
+}
+
+fn check_key(key: &str) -> Result<(), EditError> {
+    if !is_config_key(key) {
+        return Err(EditError::UnknownKey(key.to_string()));
+    }
+    Ok(())
+}
+
+/// Interprets `value` as a TOML literal if possible, falling back to a plain string, and keeps
+/// the first interpretation that is valid for `key`.
+fn validate_value(key: &str, value: &str) -> Result<Value, EditError> {
+    let mut candidates = Vec::new();
+    if let Ok(mut literal) = toml::from_str::<Table>(&format!("value = {}", value)) {
+        candidates.extend(literal.remove("value"));
+    }
+    candidates.push(Value::String(value.to_string()));
+
+    let mut reason = String::new();
+    for candidate in candidates {
+        match validate_entry(key, candidate.clone()) {
+            Ok(()) => return Ok(candidate),
+            Err(e) => reason = e,
+        }
+    }
+
+    Err(EditError::InvalidValue { key: key.to_string(), value: value.to_string(), reason })
+}
+
+/// Reads the config file as a table upgraded to the current layout.
+pub(super) fn read_table(path: &Path, format: ConfigFormat) -> Result<Table, EditError> {
+    let mut table = match read_to_string(path) {
+        Ok(file) => format.parse(&file).map_err(EditError::Format)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Table::new(),
+        Err(e) => return Err(EditError::Io(e)),
+    };
+    migrate(&mut table);
+    Ok(table)
+}
+
+/// Writes `table` to the config file, keeping the comments and layout of a TOML file.
+pub(super) fn write_table(path: &Path, format: ConfigFormat, table: &Table) -> Result<(), EditError> {
+    let original = match read_to_string(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(EditError::Io(e)),
+    };
+    let serialized = format.update(&original, table).map_err(EditError::Format)?;
+    write_atomic(path, &serialized).map_err(EditError::Io)
+}
@@ -0,0 +1,95 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use uuid::Uuid;
+
+use crate::config::{Command, ConflictPolicy, Device, Folder, SymlinkPolicy, Versioning, WindowsNames};
+use crate::protocol::Compression;
+use crate::transfer::Chunking;
+use crate::transport::Encryption;
+
+/// The type of an option, with how it is read from the environment variable that sets it.
+pub trait FromEnv: Sized {
+    /// The value `value` sets the option to, or why it isn't one. `None` for the options that
+    /// can't be set in the environment.
+    fn from_env(value: &OsStr) -> Option<Result<Self, String>>;
+}
+
+fn parse<T: FromStr>(value: &OsStr) -> Result<T, String> where T::Err: Display {
+    let value = value.to_str().ok_or_else(|| "it isn't UTF-8".to_string())?;
+    value.parse().map_err(|e| format!("{:?} is invalid: {}", value, e))
+}
+
+/// A flag is set by any value other than an empty one, `0` or `false`.
+impl FromEnv for bool {
+    fn from_env(value: &OsStr) -> Option<Result<Self, String>> {
+        Some(Ok(!matches!(value.to_str(), Some("") | Some("0") | Some("false"))))
+    }
+}
+
+impl FromEnv for OsString {
+    fn from_env(value: &OsStr) -> Option<Result<Self, String>> {
+        Some(Ok(value.to_os_string()))
+    }
+}
+
+impl<T: FromStr> FromEnv for Option<T> where T::Err: Display {
+    fn from_env(value: &OsStr) -> Option<Result<Self, String>> {
+        Some(parse(value).map(Some))
+    }
+}
+
+/// A list is given with its values separated by commas, as on the command line.
+impl<T: FromStr> FromEnv for Vec<T> where T::Err: Display {
+    fn from_env(value: &OsStr) -> Option<Result<Self, String>> {
+        let value = match value.to_str() {
+            Some(value) => value,
+            None => return Some(Err("it isn't UTF-8".to_string())),
+        };
+        Some(value.split(',').map(|item| parse(OsStr::new(item))).collect())
+    }
+}
+
+macro_rules! from_str {
+    ($( $type:ty ),*) => {
+        $(impl FromEnv for $type {
+            fn from_env(value: &OsStr) -> Option<Result<Self, String>> {
+                Some(parse(value))
+            }
+        })*
+    }
+}
+
+from_str!(u8, u16, u32, u64, Uuid, Ipv4Addr, Ipv6Addr, SocketAddr, Encryption, Compression, Chunking, SymlinkPolicy,
+    WindowsNames, Versioning, ConflictPolicy);
+
+macro_rules! not_from_env {
+    ($( $type:ty ),*) => {
+        $(impl FromEnv for $type {
+            fn from_env(_value: &OsStr) -> Option<Result<Self, String>> {
+                None
+            }
+        })*
+    }
+}
+
+not_from_env!(Vec<Folder>, Vec<Device>, Option<Command>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_values_as_the_command_line_has_them() {
+        for (value, set) in [("", false), ("0", false), ("false", false), ("1", true), ("true", true), ("yes", true)] {
+            assert_eq!(bool::from_env(OsStr::new(value)), Some(Ok(set)), "{:?}", value);
+        }
+        assert_eq!(u16::from_env(OsStr::new("4001")), Some(Ok(4001)));
+        assert!(matches!(u16::from_env(OsStr::new("port")), Some(Err(_))));
+        assert_eq!(Vec::<String>::from_env(OsStr::new("a,b")), Some(Ok(vec!["a".to_string(), "b".to_string()])));
+        assert_eq!(Option::<String>::from_env(OsStr::new("a")), Some(Ok(Some("a".to_string()))));
+        assert!(Vec::<Folder>::from_env(OsStr::new("a")).is_none());
+    }
+}
@@ -0,0 +1,421 @@
+use std::env::current_dir;
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use structopt::StructOpt;
+use toml::value::Value;
+use uuid::Uuid;
+
+use crate::config::device::is_false;
+use crate::config::edit::{read_table, write_table, EditError};
+use crate::config::lock::FileLock;
+use crate::config::secret::Secret;
+use crate::config::Options;
+use crate::ignore::{IgnorePatterns, Selection};
+use crate::transfer::Chunking;
+
+/// Config file key holding the `[[folder]]` table array.
+pub const FOLDER_KEY: &str = "folder";
+
+macro_rules! folder_overrides {
+    (pub struct $name:ident => $resolved:ident {
+        $( $field_name:ident : $field_type:ty ),* $( , )?
+    }) => {
+        /// Global options that a folder may override in its `[[folder]]` table.
+        #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        pub struct $name {
+            $(
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub $field_name: Option<$field_type>,
+            )*
+        }
+
+        /// Effective settings of a folder once its overrides are applied over the global options.
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct $resolved {
+            $( pub $field_name: $field_type, )*
+        }
+
+        impl $name {
+            fn merge_over(&self, options: &Options) -> $resolved {
+                $resolved {
+                    $( $field_name: self.$field_name.clone().unwrap_or_else(|| options.$field_name.clone()), )*
+                }
+            }
+        }
+    }
+}
+
+folder_overrides! {
+    pub struct FolderOverrides => FolderSettings {
+        scan_interval: u64,
+        watch: bool,
+        ignore: Vec<String>,
+        max_send_kbps: u32,
+        max_recv_kbps: u32,
+        pull_requests: u32,
+        chunking: Chunking,
+        symlinks: SymlinkPolicy,
+        ignore_permissions: bool,
+        xattrs: bool,
+        auto_normalize: bool,
+        windows_names: WindowsNames,
+        modified_window: u64,
+        versioning: Versioning,
+        keep_versions: u32,
+        max_version_age: u64,
+        max_versions_mb: u64,
+        conflict_policy: ConflictPolicy,
+    }
+}
+
+/// A directory shared with other devices.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Folder {
+    pub id: String,
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub mode: FolderMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+    /// Patterns of the parts of the folder whose files this device pulls, all of them if empty.
+    /// Peers still tell it about the others.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub select: Vec<String>,
+    /// Neither scanned nor synced with peers until resumed, set with `folder pause`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub paused: bool,
+    #[serde(flatten)]
+    pub overrides: FolderOverrides,
+}
+
+impl Folder {
+    pub fn label(&self) -> String {
+        self.label.clone().unwrap_or_else(|| self.id.clone())
+    }
+
+    pub fn settings(&self, options: &Options) -> FolderSettings {
+        self.overrides.merge_over(options)
+    }
+
+    /// The `ignore` patterns that apply to this folder together with its `.ssignore` file.
+    pub fn ignore_patterns(&self, options: &Options) -> IgnorePatterns {
+        IgnorePatterns::for_folder(&self.path, &self.settings(options).ignore)
+    }
+
+    pub fn selection(&self) -> Selection {
+        Selection::new(&self.select)
+    }
+}
+
+/// Which direction changes are allowed to flow for a folder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FolderMode {
+    #[default]
+    SendReceive,
+    SendOnly,
+    ReceiveOnly,
+    /// Receives changes like receive-only, but keeps the files peers delete, so that the folder
+    /// gathers every file the others had rather than mirroring them.
+    Backup,
+}
+
+impl FolderMode {
+    /// Whether the files peers delete are kept, in place or, with versioning, as old copies.
+    pub fn keeps_deleted(self) -> bool {
+        self == FolderMode::Backup
+    }
+}
+
+impl Display for FolderMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FolderMode::SendReceive => write!(f, "send-receive"),
+            FolderMode::SendOnly => write!(f, "send-only"),
+            FolderMode::ReceiveOnly => write!(f, "receive-only"),
+            FolderMode::Backup => write!(f, "backup"),
+        }
+    }
+}
+
+impl FromStr for FolderMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "send-receive" => Ok(FolderMode::SendReceive),
+            "send-only" => Ok(FolderMode::SendOnly),
+            "receive-only" => Ok(FolderMode::ReceiveOnly),
+            "backup" => Ok(FolderMode::Backup),
+            _ => Err(format!("unknown folder mode `{}`", s)),
+        }
+    }
+}
+
+/// What happens to the previous copy of a file when it is replaced or deleted by a remote change,
+/// lost with `none` and otherwise moved to the `.sync-versions` directory of the folder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Versioning {
+    #[default]
+    None,
+    /// Keep the last `keep-versions` copies of each file.
+    Trash,
+    /// Keep every copy for an hour, then one an hour for a day, one a day for a month and one a
+    /// week until they are `max-version-age` days old.
+    Staggered,
+}
+
+impl Display for Versioning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Versioning::None => write!(f, "none"),
+            Versioning::Trash => write!(f, "trash"),
+            Versioning::Staggered => write!(f, "staggered"),
+        }
+    }
+}
+
+impl FromStr for Versioning {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Versioning::None),
+            "trash" => Ok(Versioning::Trash),
+            "staggered" => Ok(Versioning::Staggered),
+            _ => Err(format!("unknown versioning policy `{}`", s)),
+        }
+    }
+}
+
+/// What is synced of a symbolic link in a folder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Leave links out.
+    #[default]
+    Skip,
+    /// Sync the link itself, as the path it points to, where the system has links like POSIX.
+    Link,
+    /// Sync what the link points to as if it were there, unless that is outside the folder.
+    Follow,
+}
+
+impl Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SymlinkPolicy::Skip => write!(f, "skip"),
+            SymlinkPolicy::Link => write!(f, "link"),
+            SymlinkPolicy::Follow => write!(f, "follow"),
+        }
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "link" => Ok(SymlinkPolicy::Link),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            _ => Err(format!("unknown symlink policy `{}`", s)),
+        }
+    }
+}
+
+/// How a folder on Windows keeps the files whose names Windows doesn't allow, as POSIX systems
+/// do, such as `CON`, `a:b` or ones ending in a dot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WindowsNames {
+    /// Write the characters Windows doesn't allow as look-alikes it does, which are read back as
+    /// they were.
+    #[default]
+    Escape,
+    /// Leave the files out.
+    Skip,
+}
+
+impl Display for WindowsNames {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowsNames::Escape => write!(f, "escape"),
+            WindowsNames::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+impl FromStr for WindowsNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "escape" => Ok(WindowsNames::Escape),
+            "skip" => Ok(WindowsNames::Skip),
+            _ => Err(format!("unknown Windows names scheme `{}`", s)),
+        }
+    }
+}
+
+/// Prefix of the conflict policy that prefers the version of a device, followed by its id.
+const PREFER_DEVICE_PREFIX: &str = "prefer-device:";
+
+/// How a file changed on two devices apart is resolved.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the local version in place and the other next to it as a conflict copy.
+    #[default]
+    KeepBoth,
+    /// The version modified last wins.
+    NewestWins,
+    /// The larger version wins.
+    LargestWins,
+    /// The version made by the device with this id wins, keeping both if neither was.
+    PreferDevice(String),
+}
+
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::KeepBoth => write!(f, "keep-both"),
+            ConflictPolicy::NewestWins => write!(f, "newest-wins"),
+            ConflictPolicy::LargestWins => write!(f, "largest-wins"),
+            ConflictPolicy::PreferDevice(id) => write!(f, "{}{}", PREFER_DEVICE_PREFIX, id),
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "newest-wins" => Ok(ConflictPolicy::NewestWins),
+            "largest-wins" => Ok(ConflictPolicy::LargestWins),
+            _ => match s.strip_prefix(PREFER_DEVICE_PREFIX) {
+                Some(id) if !id.is_empty() => Ok(ConflictPolicy::PreferDevice(id.to_string())),
+                _ => Err(format!("unknown conflict policy `{}`", s)),
+            },
+        }
+    }
+}
+
+impl Serialize for ConflictPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ConflictPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum FolderCommand {
+    /// Add a folder to the config file.
+    Add {
+        path: PathBuf,
+        /// Unique identifier shared with other devices, generated if not given.
+        #[structopt(long)]
+        id: Option<String>,
+        #[structopt(long)]
+        label: Option<String>,
+        /// One of send-receive, send-only, receive-only or backup.
+        #[structopt(long, default_value = "send-receive")]
+        mode: FolderMode,
+        /// Only pull the files matching this pattern, such as `photos/2021/`, repeated for more.
+        #[structopt(long, number_of_values = 1)]
+        select: Vec<String>,
+    },
+    /// Remove a folder from the config file.
+    Remove {
+        id: String,
+    },
+    /// Stop scanning and syncing a folder, which a running sync takes up from the config file.
+    Pause {
+        id: String,
+    },
+    /// Scan and sync a paused folder again.
+    Resume {
+        id: String,
+    },
+    /// List the folders in the config file.
+    List,
+}
+
+impl FolderCommand {
+    pub fn run(&self, options: &Options) -> Result<(), EditError> {
+        let path = options.config_path().ok_or(EditError::NoConfigPath)?;
+        let format = options.config_format(&path);
+        let _lock = match self {
+            FolderCommand::List => FileLock::shared(&path),
+            _ => FileLock::exclusive(&path),
+        };
+        let mut table = read_table(&path, format)?;
+        let mut folders: Vec<Folder> = match table.remove(FOLDER_KEY) {
+            None => Vec::new(),
+            Some(value) => value.try_into().map_err(EditError::Parse)?,
+        };
+
+        match self {
+            FolderCommand::Add { path: folder_path, id, label, mode, select } => {
+                let id = id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+                if folders.iter().any(|folder| folder.id == id) {
+                    return Err(EditError::DuplicateFolder(id));
+                }
+                let folder_path = match current_dir() {
+                    Ok(dir) => dir.join(folder_path),
+                    Err(_) => folder_path.clone(),
+                };
+                let label = label.clone()
+                    .or_else(|| folder_path.file_name().map(|name| name.to_string_lossy().to_string()));
+                folders.push(Folder {
+                    id,
+                    path: folder_path,
+                    label,
+                    mode: *mode,
+                    password: None,
+                    select: select.clone(),
+                    paused: false,
+                    overrides: FolderOverrides::default(),
+                });
+            }
+            FolderCommand::Remove { id } => {
+                let count = folders.len();
+                folders.retain(|folder| &folder.id != id);
+                if folders.len() == count {
+                    return Err(EditError::UnknownFolder(id.to_string()));
+                }
+            }
+            FolderCommand::Pause { id } | FolderCommand::Resume { id } => {
+                let folder = folders.iter_mut().find(|folder| &folder.id == id)
+                    .ok_or_else(|| EditError::UnknownFolder(id.to_string()))?;
+                folder.paused = matches!(self, FolderCommand::Pause { .. });
+            }
+            FolderCommand::List => {
+                for folder in &folders {
+                    let paused = if folder.paused { " (paused)" } else { "" };
+                    println!("{}\t{}\t{}{}\t{}", folder.id, folder.label(), folder.mode, paused, folder.path.display());
+                }
+                return Ok(());
+            }
+        }
+
+        if !folders.is_empty() {
+            table.insert(FOLDER_KEY.to_string(), Value::try_from(folders).map_err(EditError::Serialize)?);
+        }
+        write_table(&path, format, &table)
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+
+use toml::value::{Table, Value};
+
+use crate::config::preserve::update_toml;
+
+/// File formats the config can be written in. All of them are read into the same TOML table so
+/// that migration, validation and editing behave identically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+/// Extensions that are tried, in order, when looking for the default config file.
+pub const CONFIG_EXTENSIONS: &[&str] = &["toml", "json", "yaml", "yml"];
+
+impl ConfigFormat {
+    /// Detects the format from the file extension, defaulting to TOML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    pub fn parse(&self, contents: &str) -> Result<Table, FormatError> {
+        let result = match self {
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+        };
+        result.map_err(|message| FormatError { format: *self, message })
+    }
+
+    pub fn serialize(&self, table: &Table) -> Result<String, FormatError> {
+        // Serializing through `Value` emits plain values before tables, as TOML requires.
+        let value = Value::Table(table.clone());
+        let result = match self {
+            ConfigFormat::Toml => toml::to_string(&value).map_err(|e| e.to_string()),
+            ConfigFormat::Json => serde_json::to_string_pretty(&value).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::to_string(&value).map_err(|e| e.to_string()),
+        };
+        result.map_err(|message| FormatError { format: *self, message })
+    }
+
+    /// Serializes `table` to replace the file contents `original`. TOML files keep their comments
+    /// and layout, other formats are written out again.
+    pub fn update(&self, original: &str, table: &Table) -> Result<String, FormatError> {
+        if *self == ConfigFormat::Toml {
+            if let Some(updated) = update_toml(original, table) {
+                return Ok(updated);
+            }
+        }
+        self.serialize(table)
+    }
+}
+
+impl Display for ConfigFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigFormat::Toml => write!(f, "toml"),
+            ConfigFormat::Json => write!(f, "json"),
+            ConfigFormat::Yaml => write!(f, "yaml"),
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            _ => Err(format!("unknown config format `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FormatError {
+    format: ConfigFormat,
+    message: String,
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.format, self.message)
+    }
+}
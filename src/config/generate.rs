@@ -0,0 +1,300 @@
+use std::path::PathBuf;
+
+use toml::value::{Table, Value};
+use uuid::Uuid;
+
+use crate::config::atomic::write_atomic;
+use crate::config::device::DEVICE_KEY;
+use crate::config::edit::EditError;
+use crate::config::folder::FOLDER_KEY;
+use crate::config::format::ConfigFormat;
+use crate::config::lock::FileLock;
+use crate::config::migrate::{CONFIG_VERSION, CONFIG_VERSION_KEY};
+use crate::config::Options;
+use crate::PROJECT_NAME;
+
+/// A config file entry written with a comment describing it.
+pub struct Entry {
+    key: &'static str,
+    value: Value,
+    description: &'static str,
+    commented_out: bool,
+}
+
+impl Entry {
+    pub fn new(key: &'static str, value: impl Into<Value>, description: &'static str) -> Self {
+        Entry { key, value: value.into(), description, commented_out: false }
+    }
+
+    /// Shows the value without setting it, for defaults that depend on the device.
+    pub fn commented_out(mut self) -> Self {
+        self.commented_out = true;
+        self
+    }
+
+    pub fn key(&self) -> &str {
+        self.key
+    }
+
+    /// Sets the entry to `value`, which is no longer commented out.
+    pub fn set(&mut self, value: impl Into<Value>) {
+        self.value = value.into();
+        self.commented_out = false;
+    }
+}
+
+/// Writes a config file with every option set to its default value and described, then returns
+/// where it was written. An existing config file is never replaced.
+pub fn generate_config(options: &Options) -> Result<PathBuf, EditError> {
+    let path = options.config_path().ok_or(EditError::NoConfigPath)?;
+    let _lock = FileLock::exclusive(&path);
+    if path.exists() {
+        return Err(EditError::AlreadyExists(path));
+    }
+
+    let entries = default_entries();
+    let format = options.config_format(&path);
+    let contents = match format {
+        ConfigFormat::Toml => commented_toml("written with every option set to its default value", &entries),
+        _ => format.serialize(&to_table(&entries)).map_err(EditError::Format)?,
+    };
+    write_atomic(&path, &contents).map_err(EditError::Io)?;
+    Ok(path)
+}
+
+/// Every option with its default value and a description of it.
+pub fn default_entries() -> Vec<Entry> {
+    let defaults = Options::default();
+    let mut example_folder = Table::new();
+    example_folder.insert("id".to_string(), Value::String("shared-folder-id".to_string()));
+    example_folder.insert("path".to_string(), Value::String("/path/to/folder".to_string()));
+    example_folder.insert("label".to_string(), Value::String("folder".to_string()));
+    example_folder.insert("mode".to_string(), Value::String("send-receive".to_string()));
+
+    let mut example_device = Table::new();
+    example_device.insert("id".to_string(), Value::String(Uuid::nil().to_string()));
+    example_device.insert("name".to_string(), Value::String("laptop".to_string()));
+    example_device.insert("max-send-kbps".to_string(), Value::Integer(1000));
+    example_device.insert("max-recv-kbps".to_string(), Value::Integer(1000));
+
+    vec![
+        Entry::new(CONFIG_VERSION_KEY, CONFIG_VERSION,
+            "Layout of this file, used to upgrade it after a new release."),
+        Entry::new("device-id", defaults.device_id().to_string(),
+            "Identifier of this device, generated on first run and kept in the program data.").commented_out(),
+        Entry::new("set-device-name", defaults.set_device_name.to_string_lossy().to_string(),
+            "Name shown to other devices, the host name by default.").commented_out(),
+        Entry::new("port", i64::from(defaults.port),
+            "Port used to announce this device and to transfer files."),
+        Entry::new("port-fallback", defaults.port_fallback,
+            "Accept connections on a free port picked by the system when the port is taken by another program,\n\
+            announcing that port instead."),
+        Entry::new("listen-addresses", Value::Array(Vec::new()),
+            "Also accept connections on each of these addresses, such as \"10.8.0.2:11530\" on a VPN, announced \
+            along with the port."),
+        Entry::new("quic-port", i64::from(defaults.quic_port),
+            "UDP port to transfer files over QUIC on, when QUIC is enabled."),
+        Entry::new("status-address", defaults.status_address.to_string(),
+            "Address the running process serves the status of its connections on, for the status command. Anyone \
+            who can reach it\nsees the devices and their traffic, so keep it on a loopback address."),
+        Entry::new("encryption", defaults.encryption.to_string(),
+            "How connections are encrypted, one of tls or noise. Noise needs no certificates but leaves out QUIC, \
+            and only connects to devices that use it too."),
+        Entry::new("compression", defaults.compression.to_string(),
+            "How to compress transfers, one of zstd, lz4 or none. Devices use the first compression both support."),
+        Entry::new("multicast-ipv4", defaults.multicast_ipv4.to_string(),
+            "IPv4 multicast group that announcements are sent to."),
+        Entry::new("multicast-ipv6", defaults.multicast_ipv6.to_string(),
+            "IPv6 multicast group that announcements are sent to."),
+        Entry::new("multicast-ttl", i64::from(defaults.multicast_ttl),
+            "How many routers IPv4 announcements may cross, 1 keeps them on the local network."),
+        Entry::new("multicast-hops", i64::from(defaults.multicast_hops),
+            "How many routers IPv6 announcements may cross, 1 keeps them on the local network."),
+        Entry::new("announce-interval", defaults.announce_interval as i64,
+            "Seconds between announcements on the local network, randomly varied by a few seconds."),
+        Entry::new("scan-interval", defaults.scan_interval as i64,
+            "Seconds between full rescans of each folder, which find the changes watching missed, or 0 to only scan \
+            when starting."),
+        Entry::new("watch", defaults.watch,
+            "Watch folders for changes so they sync within seconds, rather than waiting for the next scan."),
+        Entry::new("max-scan-mbps", i64::from(defaults.max_scan_mbps),
+            "Megabytes per second scans read at most across all folders, 0 for unlimited."),
+        Entry::new("scan-hashers", i64::from(defaults.scan_hashers),
+            "Files hashed at once across all folders, 0 for one for each processor."),
+        Entry::new("scan-threads", i64::from(defaults.scan_threads),
+            "Directories read and files hashed at once in a scan of a folder, 0 for one for each processor."),
+        Entry::new("low-priority-scan", defaults.low_priority_scan,
+            "Scan at a low processor and disk priority where the system has them, so that the first scan of a large \
+            folder\nleaves the machine usable."),
+        Entry::new("ignore", Value::Array(Vec::new()),
+            "Patterns of files that are never synced, such as \"*.tmp\" or \"build/\" for directories only, with `!` \
+            to include a file\nagain. Each folder can add its own patterns in a .ssignore file in its root, which can \
+            read more from\nanother file in the folder with `#include <path>`."),
+        Entry::new("max-send-kbps", i64::from(defaults.max_send_kbps),
+            "Upload limit in kilobytes per second, 0 for unlimited."),
+        Entry::new("max-recv-kbps", i64::from(defaults.max_recv_kbps),
+            "Download limit in kilobytes per second, 0 for unlimited."),
+        Entry::new("pull-requests", i64::from(defaults.pull_requests),
+            "Blocks requested from a device at once for each file being pulled, more keep fast and distant links busy."),
+        Entry::new("chunking", defaults.chunking.to_string(),
+            "How files are split into blocks, fixed or fastcdc to cut them where the content says, so that bytes \
+            inserted or\nremoved in the middle of a file only change the blocks around them."),
+        Entry::new("symlinks", defaults.symlinks.to_string(),
+            "What is synced of symbolic links, skip to leave them out, link for the links themselves or follow for \
+            what they\npoint to within the folder."),
+        Entry::new("ignore-permissions", defaults.ignore_permissions,
+            "Neither sync the permissions of files nor take a change to them as a change, for file systems such as \
+            FAT that\nhave none or devices whose systems don't share them."),
+        Entry::new("xattrs", defaults.xattrs,
+            "Sync the extended attributes of files, such as the tags, quarantine and resource forks of macOS."),
+        Entry::new("auto-normalize", defaults.auto_normalize,
+            "Rename files whose names aren't in the Unicode form the index keeps them in, NFC, to it, so that a name \
+            macOS\nwrote decomposed is the same file on Linux. Files that aren't are left out."),
+        Entry::new("windows-names", defaults.windows_names.to_string(),
+            "How the files whose names Windows doesn't allow, such as `CON`, `a:b` or ones ending in a dot, are kept \
+            on it,\nescape to write their characters as look-alikes or skip to leave them out."),
+        Entry::new("modified-window", defaults.modified_window as i64,
+            "Seconds by which the modification times of a file may differ and still be the same, for file systems \
+            that keep\nthem coarsely and devices whose clocks drift apart."),
+        Entry::new("versioning", defaults.versioning.to_string(),
+            "What happens to files replaced or deleted by a peer, none, or trash or staggered to move them to the \
+            .sync-versions\ndirectory of the folder. Staggered keeps every copy for an hour, then one an hour for \
+            a day, one a day for a\nmonth and one a week until max-version-age."),
+        Entry::new("keep-versions", i64::from(defaults.keep_versions),
+            "Old copies of each file kept with trash versioning, 0 to keep all of them."),
+        Entry::new("max-version-age", defaults.max_version_age as i64,
+            "Days old copies are kept with staggered versioning, 0 to keep them for good."),
+        Entry::new("max-versions-mb", defaults.max_versions_mb as i64,
+            "Megabytes the old copies of the files of a folder may take up, the oldest removed past that, 0 for \
+            no limit."),
+        Entry::new("conflict-policy", defaults.conflict_policy.to_string(),
+            "How a file changed on two devices apart is resolved, keep-both to keep the other version next to it as \
+            a\nconflict copy, newest-wins, largest-wins or prefer-device:<id> for the version made by that device."),
+        Entry::new("bind-interface", "eth0",
+            "Only use this network interface, given by name or address, all interfaces by default.").commented_out(),
+        Entry::new("include-interfaces", Value::Array(Vec::new()),
+            "Only use interfaces with a name matching one of these patterns, such as \"eth*\", or the addresses in \
+            one of these subnets,\nsuch as \"192.168.0.0/16\", all interfaces by default."),
+        Entry::new("exclude-interfaces", Value::Array(Vec::new()),
+            "Never use interfaces with a name matching one of these patterns or the addresses in one of these \
+            subnets,\nsuch as [\"docker*\", \"veth*\", \"fe80::/10\"] to skip container and link-local addresses."),
+        Entry::new("local-discovery", defaults.local_discovery,
+            "Announce this device and listen for peers on the local network."),
+        Entry::new("global-discovery-server", "https://discovery.example.com/",
+            "Register this device with this discovery server and look up peers on it, off by default.")
+            .commented_out(),
+        Entry::new("global-discovery-token", "keyring:discovery-token",
+            "Token the discovery server requires, if it does. Store it with `secret set discovery-token`.")
+            .commented_out(),
+        Entry::new("socks-proxy", "socks5://127.0.0.1:9050",
+            "Connect to devices, relays and the discovery server through this SOCKS5 proxy, such as Tor, off by \
+            default.\nA device can set a proxy of its own, or \"none\" to be connected to directly, with `proxy` in \
+            its [[device]] table.").commented_out(),
+        Entry::new("relay-server", "relay.example.com:11532",
+            "Wait on this relay for devices that can't dial this device directly, off by default. Devices reach \
+            each other through the relay the other waits on, without it being able to read what they sync.")
+            .commented_out(),
+        Entry::new("relay-password", "keyring:relay-password",
+            "Password the relay requires to wait on it, if it does. Store it with `secret set relay-password`.")
+            .commented_out(),
+        Entry::new("dht-port", i64::from(defaults.dht_port),
+            "UDP port the DHT node listens on."),
+        Entry::new("dht-bootstrap", Value::Array(Vec::new()),
+            "DHT nodes to join the network through, such as [\"dht.example.com:11531\"]."),
+        Entry::new("broadcast-fallback", defaults.broadcast_fallback,
+            "Also announce to the broadcast address of every IPv4 subnet, for networks that filter multicast."),
+        Entry::new("skip-link-local", defaults.skip_link_local,
+            "Leave link-local addresses, such as fe80::1 or 169.254.0.1, out of announcements."),
+        Entry::new("skip-cgnat", defaults.skip_cgnat,
+            "Leave carrier-grade NAT addresses, in 100.64.0.0/10, out of announcements."),
+        Entry::new("ipv4-only", defaults.ipv4_only,
+            "Only use IPv4, for discovery and for connections, where IPv6 is broken or not allowed."),
+        Entry::new("ipv6-only", defaults.ipv6_only,
+            "Only use IPv6, for discovery and for connections, where IPv4 is broken or not allowed."),
+        Entry::new("retransmit", defaults.retransmit,
+            "Ask devices that hear this device's announcements to relay them to their other networks."),
+        Entry::new("relay", defaults.relay,
+            "Relay announcements that ask for it to the other networks this device is on, such as between Wi-Fi \
+            and Ethernet."),
+        Entry::new("mdns", defaults.mdns,
+            "Also advertise this device and browse for peers with mDNS, for networks that only allow zeroconf."),
+        Entry::new("port-mapping", defaults.port_mapping,
+            "Ask the local gateway to forward the sync port with UPnP or NAT-PMP, so devices outside the \
+            network can connect."),
+        Entry::new("dht", defaults.dht,
+            "Publish this device's address in a DHT shared with other devices and look up peers in it."),
+        Entry::new("quic", defaults.quic,
+            "Also transfer files over QUIC, preferred over TCP with devices that enable it too."),
+        Entry::new(FOLDER_KEY, Value::Array(vec![Value::Table(example_folder)]),
+            "A folder shared with other devices, add folders with `folder add`.\nEach folder can also set its \
+            own scan-interval, watch, ignore, max-send-kbps, max-recv-kbps, symlinks,\nignore-permissions, xattrs, \
+            auto-normalize, windows-names, modified-window, versioning, keep-versions,\nmax-version-age, \
+            max-versions-mb and conflict-policy. Set `select = [\"photos/2021/\"]`\nto only pull the files matching these \
+            patterns, for a small disk.").commented_out(),
+        Entry::new(DEVICE_KEY, Value::Array(vec![Value::Table(example_device)]),
+            "Limits for transfers with a single peer device, replacing max-send-kbps and max-recv-kbps.\nSet \
+            `untrusted = true` for a device that only gets the folders that have a password, encrypted with it.")
+            .commented_out(),
+    ]
+}
+
+/// Renders `entries` as a TOML file, each preceded by its description. Tables are placed after
+/// the plain values, as TOML requires.
+pub fn commented_toml(written_by: &str, entries: &[Entry]) -> String {
+    let mut contents = format!("# Config file for {}, {}.\n", PROJECT_NAME, written_by);
+    contents.push_str("# Any option can also be set on the command line or with a SIMPLE_SYNC_ environment variable.\n");
+
+    let (tables, values): (Vec<&Entry>, Vec<&Entry>) = entries.iter().partition(|entry| is_table(&entry.value));
+    for entry in values.into_iter().chain(tables) {
+        let mut table = Table::new();
+        table.insert(entry.key.to_string(), entry.value.clone());
+        let rendered = toml::to_string(&Value::Table(table)).unwrap_or_default();
+
+        contents.push('\n');
+        for line in entry.description.lines() {
+            contents.push_str(&format!("# {}\n", line));
+        }
+        for line in rendered.lines().filter(|line| !line.is_empty()) {
+            if entry.commented_out {
+                contents.push_str("# ");
+            }
+            contents.push_str(line);
+            contents.push('\n');
+        }
+    }
+    contents
+}
+
+/// The entries that are set, for formats without comments.
+pub fn to_table(entries: &[Entry]) -> Table {
+    entries.iter()
+        .filter(|entry| !entry.commented_out)
+        .map(|entry| (entry.key.to_string(), entry.value.clone()))
+        .collect()
+}
+
+fn is_table(value: &Value) -> bool {
+    match value {
+        Value::Table(_) => true,
+        Value::Array(values) => !values.is_empty() && values.iter().all(Value::is_table),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::validate::is_config_key;
+
+    #[test]
+    fn describes_every_config_option() {
+        let entries = default_entries();
+        for name in Options::option_names().iter().filter(|name| is_config_key(name)) {
+            assert!(entries.iter().any(|entry| entry.key == name), "no default entry for `{}`", name);
+        }
+        for entry in entries.iter().filter(|entry| ![CONFIG_VERSION_KEY, FOLDER_KEY, DEVICE_KEY].contains(&entry.key)) {
+            assert!(Options::option_names().iter().any(|name| name == entry.key), "`{}` is not an option", entry.key);
+        }
+    }
+}
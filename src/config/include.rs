@@ -0,0 +1,91 @@
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use toml::value::{Table, Value};
+
+use crate::config::format::CONFIG_EXTENSIONS;
+
+/// The directory holding config fragments for `path`, `config.d` for `config.toml`.
+pub fn include_dir(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{}.d", stem))
+}
+
+/// Config fragments for `path` in the order they are merged.
+pub fn fragments(path: &Path) -> Vec<PathBuf> {
+    let dir = include_dir(path);
+    let entries = match read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut fragments: Vec<PathBuf> = entries
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.path()),
+            Err(e) => {
+                warn!("Unable to read entry in {}: {}", dir.display(), e);
+                None
+            }
+        })
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            path.extension().and_then(|extension| extension.to_str())
+                .is_some_and(|extension| CONFIG_EXTENSIONS.contains(&extension))
+        })
+        .collect();
+    fragments.sort();
+    fragments
+}
+
+/// Merges `other` over `base`. Arrays of tables, like `[[folder]]`, are appended so that each
+/// fragment can contribute its own entries, nested tables are merged and any other value replaces
+/// the one in `base`.
+pub fn merge_tables(base: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(other)) => merge_tables(base, other),
+            (Some(Value::Array(base)), Value::Array(other)) if other.iter().all(Value::is_table) => {
+                base.extend(other)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn table(contents: &str) -> Table {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn merges_fragments_in_order_of_their_names() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        let include = include_dir(&path);
+        assert_eq!(include, directory.path().join("config.d"));
+        create_dir(&include).unwrap();
+        for name in ["b.toml", "a.yaml", "notes.txt"] {
+            write(include.join(name), "").unwrap();
+        }
+        create_dir(include.join("c.toml")).unwrap();
+        assert_eq!(fragments(&path), vec![include.join("a.yaml"), include.join("b.toml")]);
+    }
+
+    #[test]
+    fn appends_arrays_of_tables_and_replaces_other_values() {
+        let mut base = table("port = 1\nignore = [\"a\"]\n[[folder]]\nid = \"a\"\n[nested]\nkept = 1\nreplaced = 1\n");
+        merge_tables(&mut base, table("port = 2\nignore = [\"b\"]\n[[folder]]\nid = \"b\"\n[nested]\nreplaced = 2\n"));
+        assert_eq!(base, table("port = 2\nignore = [\"b\"]\n[[folder]]\nid = \"a\"\n[[folder]]\nid = \"b\"\n\
+            [nested]\nkept = 1\nreplaced = 2\n"));
+    }
+}
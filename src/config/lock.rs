@@ -0,0 +1,53 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use log::warn;
+
+use crate::config::atomic::with_suffix;
+
+/// An advisory lock on a config or data file, held until dropped.
+///
+/// The lock is taken on a separate `.lock` file because writes replace the file itself. The
+/// operating system releases it when the process exits, so a crash never leaves a stale lock.
+pub struct FileLock {
+    file: Option<File>,
+}
+
+impl FileLock {
+    /// Locks `path` for reading, other readers may hold the lock at the same time.
+    pub fn shared(path: &Path) -> Self {
+        Self::acquire(path, File::lock_shared)
+    }
+
+    /// Locks `path` for reading and writing.
+    pub fn exclusive(path: &Path) -> Self {
+        Self::acquire(path, File::lock)
+    }
+
+    /// Failing to lock is not fatal, for example on read only file systems, so the caller
+    /// continues without it. A missing directory means there is no file to protect yet.
+    fn acquire(path: &Path, lock: fn(&File) -> io::Result<()>) -> Self {
+        let lock_path = with_suffix(path, "lock");
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+            .and_then(|file| lock(&file).map(|()| file));
+        match file {
+            Ok(file) => FileLock { file: Some(file) },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => FileLock { file: None },
+            Err(e) => {
+                warn!("Unable to lock {}: {}", lock_path.display(), e);
+                FileLock { file: None }
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            if let Err(e) = file.unlock() {
+                warn!("Unable to unlock config file: {}", e);
+            }
+        }
+    }
+}
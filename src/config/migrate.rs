@@ -0,0 +1,87 @@
+use log::{info, warn};
+use toml::value::{Table, Value};
+
+/// Config file key recording which layout the file was written with.
+pub const CONFIG_VERSION_KEY: &str = "config-version";
+
+/// The layout written by this version of the program.
+pub const CONFIG_VERSION: i64 = 1;
+
+/// Migrations in order, the one at index `n` upgrades a version `n` layout to version `n + 1`.
+const MIGRATIONS: &[fn(&mut Table)] = &[to_v1];
+
+/// Upgrades `table` to the current layout, returning whether anything was changed.
+pub fn migrate(table: &mut Table) -> bool {
+    let version = match table.get(CONFIG_VERSION_KEY) {
+        None => 0,
+        Some(Value::Integer(version)) => *version,
+        Some(value) => {
+            warn!("Ignoring invalid {}: {}", CONFIG_VERSION_KEY, value);
+            0
+        }
+    };
+
+    if version > CONFIG_VERSION {
+        warn!("Config file was written by a newer version (config version {}), some settings may be ignored", version);
+        return false;
+    }
+    if version == CONFIG_VERSION {
+        return false;
+    }
+
+    for migration in &MIGRATIONS[version.max(0) as usize..] {
+        migration(table);
+    }
+    table.insert(CONFIG_VERSION_KEY.to_string(), Value::Integer(CONFIG_VERSION));
+    info!("Migrated config file from version {} to {}", version, CONFIG_VERSION);
+    true
+}
+
+/// Version 0 files could use snake case keys, such as `device_name`.
+fn to_v1(table: &mut Table) {
+    rename(table, "device_name", "set-device-name");
+
+    let snake_case: Vec<String> = table.keys().filter(|key| key.contains('_')).cloned().collect();
+    for key in snake_case {
+        rename(table, &key, &key.replace('_', "-"));
+    }
+}
+
+fn rename(table: &mut Table, from: &str, to: &str) {
+    if let Some(value) = table.remove(from) {
+        if table.contains_key(to) {
+            warn!("Dropping `{}` from config file, `{}` is already set", from, to);
+        } else {
+            table.insert(to.to_string(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(contents: &str) -> Table {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn renames_snake_case_keys_of_unversioned_files() {
+        let mut migrated = table("device_name = \"laptop\"\nscan_interval = 60\nport = 1\nmax-send-kbps = 2\n\
+            max_send_kbps = 3\n");
+        assert!(migrate(&mut migrated));
+        assert_eq!(migrated, table(&format!(
+            "{} = {}\nset-device-name = \"laptop\"\nscan-interval = 60\nport = 1\nmax-send-kbps = 2\n",
+            CONFIG_VERSION_KEY, CONFIG_VERSION,
+        )));
+        assert!(!migrate(&mut migrated));
+    }
+
+    #[test]
+    fn leaves_files_of_newer_versions_alone() {
+        let newer = table(&format!("{} = {}\nscan_interval = 60\n", CONFIG_VERSION_KEY, CONFIG_VERSION + 1));
+        let mut migrated = newer.clone();
+        assert!(!migrate(&mut migrated));
+        assert_eq!(migrated, newer);
+    }
+}
@@ -0,0 +1,156 @@
+use toml::value::{Table, Value};
+use toml_edit::{ArrayOfTables, Document, Item, Key};
+
+/// Rewrites the TOML document `original` so that it holds `table`. Entries that did not change
+/// keep their comments, formatting and order, removed entries are dropped and new ones are
+/// appended. Returns `None` if `original` is not valid TOML.
+pub fn update_toml(original: &str, table: &Table) -> Option<String> {
+    let mut document: Document = original.parse().ok()?;
+    update_table(document.as_table_mut(), table);
+    Some(document.to_string())
+}
+
+fn update_table(existing: &mut toml_edit::Table, table: &Table) {
+    rename_keys(existing, table);
+    existing.retain(|key, _| table.contains_key(key));
+    for (key, value) in table {
+        match (existing.get_mut(key), value) {
+            (Some(Item::Table(existing)), Value::Table(table)) => update_table(existing, table),
+            (Some(Item::ArrayOfTables(existing)), Value::Array(tables)) if tables.iter().all(Value::is_table) => {
+                update_array_of_tables(existing, tables)
+            }
+            (Some(item), value) if to_value(item).as_ref() == Some(value) => {}
+            (Some(Item::Value(existing)), value) if !value.is_table() => {
+                // Keep the comment that follows the value on the same line.
+                if let Some(Item::Value(mut updated)) = to_item(key, value) {
+                    *updated.decor_mut() = existing.decor().clone();
+                    *existing = updated;
+                }
+            }
+            (_, value) => {
+                if let Some(item) = to_item(key, value) {
+                    existing.insert(key, item);
+                }
+            }
+        }
+    }
+}
+
+/// A removed key whose value reappears under a new key, like after a migration, is renamed in
+/// place so that it keeps its position and comments.
+fn rename_keys(existing: &mut toml_edit::Table, table: &Table) {
+    let added: Vec<&String> = table.keys().filter(|key| !existing.contains_key(key)).collect();
+    let mut renames: Vec<(String, String)> = Vec::new();
+    for (key, item) in existing.iter().filter(|(key, _)| !table.contains_key(*key)) {
+        let value = to_value(item);
+        let renamed = added.iter().find(|added| {
+            Some(&table[added.as_str()]) == value.as_ref() && renames.iter().all(|(_, to)| to != **added)
+        });
+        if let Some(renamed) = renamed {
+            renames.push((key.to_string(), renamed.to_string()));
+        }
+    }
+    if renames.is_empty() {
+        return;
+    }
+
+    // Tables only append, so every entry is reinserted to keep the order.
+    let keys: Vec<String> = existing.iter().map(|(key, _)| key.to_string()).collect();
+    for key in keys {
+        if let Some((key, item)) = existing.remove_entry(&key) {
+            let key = match renames.iter().find(|(from, _)| from == key.get()) {
+                Some((_, to)) => Key::new(to.as_str()).with_decor(key.decor().clone()),
+                None => key,
+            };
+            existing.insert_formatted(&key, item);
+        }
+    }
+}
+
+/// Entries that are unchanged are kept as they were, otherwise the entry at the same index is
+/// updated in place so that a changed folder keeps its comments.
+fn update_array_of_tables(existing: &mut ArrayOfTables, tables: &[Value]) {
+    let mut unused: Vec<Option<toml_edit::Table>> = existing.iter().cloned().map(Some).collect();
+    let mut updated = ArrayOfTables::new();
+    for (index, table) in tables.iter().filter_map(Value::as_table).enumerate() {
+        let unchanged = unused.iter().position(|entry| {
+            entry.as_ref().is_some_and(|entry| to_value(&Item::Table(entry.clone())).as_ref() == Some(&Value::Table(table.clone())))
+        });
+        let reused = unchanged.or_else(|| unused.get(index).filter(|entry| entry.is_some()).map(|_| index));
+
+        match reused.and_then(|index| unused[index].take()) {
+            Some(mut entry) => {
+                update_table(&mut entry, table);
+                updated.push(entry);
+            }
+            None => {
+                if let Some(Item::Table(entry)) = to_item("entry", &Value::Table(table.clone())) {
+                    updated.push(entry);
+                }
+            }
+        }
+    }
+    *existing = updated;
+}
+
+/// Converts an edited item back to a plain value by printing and parsing it.
+fn to_value(item: &Item) -> Option<Value> {
+    let mut document = Document::new();
+    document.insert("value", item.clone());
+    let mut table: Table = toml::from_str(&document.to_string()).ok()?;
+    table.remove("value")
+}
+
+/// Converts a plain value to an item that is placed after the existing tables, each table
+/// separated by an empty line.
+fn to_item(key: &str, value: &Value) -> Option<Item> {
+    let mut table = Table::new();
+    table.insert(key.to_string(), value.clone());
+    let mut document: Document = toml::to_string(&Value::Table(table)).ok()?.parse().ok()?;
+    let mut item = document.remove(key)?;
+    move_to_end(&mut item);
+    Some(item)
+}
+
+fn move_to_end(item: &mut Item) {
+    match item {
+        Item::Table(table) => move_table_to_end(table),
+        Item::ArrayOfTables(tables) => tables.iter_mut().for_each(move_table_to_end),
+        _ => {}
+    }
+}
+
+fn move_table_to_end(table: &mut toml_edit::Table) {
+    table.set_position(usize::MAX);
+    table.decor_mut().set_prefix("\n");
+    table.iter_mut().for_each(|(_, item)| move_to_end(item));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(contents: &str) -> Table {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn keeps_comments_and_order_of_what_is_kept() {
+        let original = "# The port.\nport = 1 # not the default\n\n# Scans.\nscan-interval = 60\nwatch = true\n\n\
+            # Shared.\n[[folder]]\n# Where it is.\npath = \"/a\"\n";
+        let updated = update_toml(original, &table(
+            "port = 2\nscan-interval = 60\nquic = true\n[[folder]]\npath = \"/b\"\n",
+        )).unwrap();
+        assert_eq!(updated, "# The port.\nport = 2 # not the default\n\n# Scans.\nscan-interval = 60\nquic = true\n\n\
+            # Shared.\n[[folder]]\n# Where it is.\npath = \"/b\"\n");
+        assert_eq!(update_toml("port = ", &Table::new()), None);
+    }
+
+    #[test]
+    fn renames_migrated_keys_in_place() {
+        let updated = update_toml("# Name.\ndevice_name = \"laptop\"\nport = 1\n", &table(
+            "set-device-name = \"laptop\"\nport = 1\n",
+        )).unwrap();
+        assert_eq!(updated, "# Name.\nset-device-name = \"laptop\"\nport = 1\n");
+    }
+}
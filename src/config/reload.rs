@@ -0,0 +1,104 @@
+use std::fs::metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use clap::ArgMatches;
+use log::{info, warn};
+
+use crate::config::include::{fragments, include_dir};
+use crate::config::Options;
+
+/// Shared view of the current `Options` that can be swapped out while the program is running.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    matches: Arc<ArgMatches<'static>>,
+    current: Arc<RwLock<Arc<Options>>>,
+    subscribers: Arc<Mutex<Vec<Sender<Arc<Options>>>>>,
+}
+
+impl ConfigHandle {
+    /// Creates a handle for `options`, keeping the command line `matches` they were loaded from
+    /// so that reloading merges the new config file with the same arguments.
+    pub fn new(options: Options, matches: ArgMatches<'static>) -> Self {
+        ConfigHandle {
+            matches: Arc::new(matches),
+            current: Arc::new(RwLock::new(Arc::new(options))),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Options> {
+        self.current.read().expect("config lock poisoned").clone()
+    }
+
+    /// Returns a receiver that gets the new `Options` every time the configuration is reloaded.
+    pub fn subscribe(&self) -> Receiver<Arc<Options>> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().expect("config lock poisoned").push(sender);
+        receiver
+    }
+
+    /// Re-runs the command line, environment and config file merge. A config that fails to load
+    /// keeps the previous options in place.
+    pub fn reload(&self) {
+        let mut options = match Options::load(&self.matches) {
+            Ok((options, _)) => options,
+            Err(e) => {
+                warn!("Keeping previous configuration: {}", e);
+                return;
+            }
+        };
+        options.command = None;
+
+        let options = Arc::new(options);
+        *self.current.write().expect("config lock poisoned") = options.clone();
+        self.subscribers.lock().expect("config lock poisoned")
+            .retain(|subscriber| subscriber.send(options.clone()).is_ok());
+        info!("Configuration reloaded");
+    }
+
+    /// Spawns a thread that reloads the configuration on SIGHUP or when the config file or any of
+    /// its fragments change.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        let handle = self.clone();
+        let hangup = Arc::new(AtomicBool::new(false));
+        register_hangup(&hangup);
+
+        spawn(move || {
+            let path = handle.current().config_path();
+            let mut modified = path.as_deref().map(modified_times);
+            loop {
+                sleep(interval);
+
+                let now_modified = path.as_deref().map(modified_times);
+                if hangup.swap(false, Ordering::Relaxed) || now_modified != modified {
+                    modified = now_modified;
+                    handle.reload();
+                }
+            }
+        })
+    }
+}
+
+fn modified_times(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut paths = vec![path.to_path_buf(), include_dir(path)];
+    paths.extend(fragments(path));
+    paths.into_iter().map(|path| {
+        let modified = metadata(&path).and_then(|metadata| metadata.modified()).ok();
+        (path, modified)
+    }).collect()
+}
+
+#[cfg(unix)]
+fn register_hangup(flag: &Arc<AtomicBool>) {
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, flag.clone()) {
+        warn!("Unable to listen for SIGHUP: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn register_hangup(_flag: &Arc<AtomicBool>) {}
@@ -0,0 +1,112 @@
+use std::convert::Infallible;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{stdin, BufRead};
+use std::str::FromStr;
+
+use keyring::Entry;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use structopt::StructOpt;
+
+use crate::config::edit::EditError;
+use crate::PROJECT_NAME;
+
+/// Prefix marking a config value as a reference to an entry in the OS keyring.
+const KEYRING_PREFIX: &str = "keyring:";
+
+/// A sensitive config value. The config file only holds `keyring:<name>`, the value itself is
+/// looked up in the OS keyring when the options are loaded.
+#[derive(Clone, PartialEq)]
+pub enum Secret {
+    Keyring { name: String, value: Option<String> },
+    Plain(String),
+}
+
+impl Secret {
+    /// Looks up the value of a keyring reference.
+    pub fn resolve(&mut self) {
+        match self {
+            Secret::Keyring { name, value } => match Entry::new(PROJECT_NAME, name).and_then(|entry| entry.get_password()) {
+                Ok(password) => *value = Some(password),
+                Err(e) => warn!("Unable to read secret `{}` from keyring: {}", name, e),
+            },
+            Secret::Plain(_) => warn!("Secret stored in plaintext config, consider `secret set` and `{}<name>`", KEYRING_PREFIX),
+        }
+    }
+
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            Secret::Keyring { value, .. } => value.as_deref(),
+            Secret::Plain(value) => Some(value),
+        }
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Keyring { name, .. } => write!(f, "Secret({}{})", KEYRING_PREFIX, name),
+            Secret::Plain(_) => write!(f, "Secret(<redacted>)"),
+        }
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Secret::Keyring { name, .. } => serializer.serialize_str(&format!("{}{}", KEYRING_PREFIX, name)),
+            Secret::Plain(value) => serializer.serialize_str(value),
+        }
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value.strip_prefix(KEYRING_PREFIX) {
+            Some(name) => Secret::Keyring { name: name.to_string(), value: None },
+            None => Secret::Plain(value.to_string()),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Ok(secret) = String::deserialize(deserializer)?.parse();
+        Ok(secret)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SecretCommand {
+    /// Store a secret in the OS keyring, reading the value from standard input. Reference it in
+    /// the config file as `keyring:<name>`.
+    Set {
+        name: String,
+    },
+    /// Remove a secret from the OS keyring.
+    Delete {
+        name: String,
+    },
+}
+
+impl SecretCommand {
+    pub fn run(&self) -> Result<(), EditError> {
+        match self {
+            SecretCommand::Set { name } => {
+                let mut value = String::new();
+                stdin().lock().read_line(&mut value).map_err(EditError::Io)?;
+                let value = value.trim_end_matches(&['\r', '\n'][..]);
+                entry(name)?.set_password(value).map_err(EditError::Keyring)?;
+                println!("{}{}", KEYRING_PREFIX, name);
+                Ok(())
+            }
+            SecretCommand::Delete { name } => entry(name)?.delete_password().map_err(EditError::Keyring),
+        }
+    }
+}
+
+fn entry(name: &str) -> Result<Entry, EditError> {
+    Entry::new(PROJECT_NAME, name).map_err(EditError::Keyring)
+}
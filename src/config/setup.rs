@@ -0,0 +1,144 @@
+use std::fmt::Display;
+use std::io::{self, stdin, stdout, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::info;
+use toml::value::{Table, Value};
+use uuid::Uuid;
+
+use crate::config::atomic::write_atomic;
+use crate::config::edit::EditError;
+use crate::config::folder::FOLDER_KEY;
+use crate::config::format::ConfigFormat;
+use crate::config::generate::{commented_toml, default_entries, to_table, Entry};
+use crate::config::include::fragments;
+use crate::config::lock::FileLock;
+use crate::config::Options;
+
+/// Asks for the basic settings and writes them to a new config file when the program is started
+/// in a terminal without one. Returns whether a config file was written.
+pub fn first_run_setup(options: &Options) -> Result<bool, EditError> {
+    let path = match options.config_path() {
+        Some(path) if !options.no_config_file => path,
+        _ => return Ok(false),
+    };
+    if path.exists() || !fragments(&path).is_empty() || !stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    let mut input = stdin().lock();
+    let mut output = stdout();
+    writeln!(output, "No config file found, creating {}.", path.display()).map_err(EditError::Io)?;
+    writeln!(output, "Press enter to accept the value in brackets.").map_err(EditError::Io)?;
+    let answers = Answers::ask(options, &mut input, &mut output).map_err(EditError::Io)?;
+
+    let mut entries = default_entries();
+    answers.fill_in(&mut entries);
+    let format = options.config_format(&path);
+    let contents = match format {
+        ConfigFormat::Toml => commented_toml("written by the first run setup", &entries),
+        _ => format.serialize(&to_table(&entries)).map_err(EditError::Format)?,
+    };
+
+    let _lock = FileLock::exclusive(&path);
+    write_atomic(&path, &contents).map_err(EditError::Io)?;
+    info!("Wrote config file {}", path.display());
+    Ok(true)
+}
+
+struct Answers {
+    device_name: String,
+    port: u16,
+    folder: Option<PathBuf>,
+    local_discovery: bool,
+}
+
+impl Answers {
+    fn ask(options: &Options, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<Self> {
+        let device_name = ask(input, output, "Device name", options.set_device_name.to_string_lossy().to_string())?;
+        let port = ask(input, output, "Port", options.port)?;
+        let folder = ask_folder(input, output)?;
+        let local_discovery = ask_yes_no(input, output, "Enable local discovery", options.local_discovery)?;
+        Ok(Answers { device_name, port, folder, local_discovery })
+    }
+
+    /// Sets the answered options among `entries`, leaving the others at their defaults.
+    fn fill_in(&self, entries: &mut [Entry]) {
+        let mut answer = |key: &str, value: Value| {
+            if let Some(entry) = entries.iter_mut().find(|entry| entry.key() == key) {
+                entry.set(value);
+            }
+        };
+        answer("set-device-name", Value::String(self.device_name.clone()));
+        answer("port", Value::Integer(i64::from(self.port)));
+        answer("local-discovery", Value::Boolean(self.local_discovery));
+
+        if let Some(path) = &self.folder {
+            let mut folder = Table::new();
+            folder.insert("id".to_string(), Value::String(Uuid::new_v4().to_string()));
+            folder.insert("path".to_string(), Value::String(path.to_string_lossy().to_string()));
+            if let Some(name) = path.file_name() {
+                folder.insert("label".to_string(), Value::String(name.to_string_lossy().to_string()));
+            }
+            answer(FOLDER_KEY, Value::Array(vec![Value::Table(folder)]));
+        }
+    }
+}
+
+/// Asks for a value until one parses, an empty answer or the end of input keeps `default`.
+fn ask<T>(input: &mut impl BufRead, output: &mut impl Write, question: &str, default: T) -> io::Result<T>
+where
+    T: FromStr + Display,
+    T::Err: Display,
+{
+    loop {
+        let answer = match read_answer(input, output, &format!("{} [{}]", question, default))? {
+            Some(answer) => answer,
+            None => return Ok(default),
+        };
+        match answer.parse() {
+            Ok(value) => return Ok(value),
+            Err(e) => writeln!(output, "Invalid value `{}`: {}", answer, e)?,
+        }
+    }
+}
+
+fn ask_yes_no(input: &mut impl BufRead, output: &mut impl Write, question: &str, default: bool) -> io::Result<bool> {
+    let choices = if default { "Y/n" } else { "y/N" };
+    loop {
+        let answer = match read_answer(input, output, &format!("{} [{}]", question, choices))? {
+            Some(answer) => answer,
+            None => return Ok(default),
+        };
+        match answer.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => writeln!(output, "Please answer yes or no")?,
+        }
+    }
+}
+
+fn ask_folder(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<Option<PathBuf>> {
+    loop {
+        let answer = match read_answer(input, output, "Folder to share, leave empty to skip")? {
+            Some(answer) => PathBuf::from(answer),
+            None => return Ok(None),
+        };
+        match answer.canonicalize() {
+            Ok(path) if path.is_dir() => return Ok(Some(path)),
+            _ => writeln!(output, "`{}` is not a directory", answer.display())?,
+        }
+    }
+}
+
+/// Prints `prompt` and reads a line, returning `None` for an empty answer or the end of input.
+fn read_answer(input: &mut impl BufRead, output: &mut impl Write, prompt: &str) -> io::Result<Option<String>> {
+    write!(output, "{}: ", prompt)?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { None } else { Some(answer.to_string()) })
+}
@@ -0,0 +1,48 @@
+use std::fmt::{self, Display, Formatter};
+
+use clap::ArgMatches;
+
+use crate::config::validate::option_name;
+use crate::config::Env;
+
+/// Where the effective value of an option came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    CommandLine,
+    Environment(String),
+    ConfigFile,
+    Default,
+}
+
+impl Source {
+    /// Precedence: command line > environment > config file > defaults.
+    pub fn of(name: String, variable: String, matches: &ArgMatches, env: &Env, file_keys: &[String])
+        -> (String, Source) {
+        let source = if matches.occurrences_of(&name) > 0 {
+            Source::CommandLine
+        } else if env(&variable).is_some() {
+            Source::Environment(variable)
+        } else if file_keys.iter().any(|key| option_name(key) == name) {
+            Source::ConfigFile
+        } else {
+            Source::Default
+        };
+        (name, source)
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::CommandLine => write!(f, "command line"),
+            Source::Environment(name) => write!(f, "environment ({})", name),
+            Source::ConfigFile => write!(f, "config file"),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Whether the option `name` should take its value from the config file.
+pub fn from_file(sources: &[(String, Source)], name: &str) -> bool {
+    sources.iter().any(|(option, source)| option == name && *source == Source::ConfigFile)
+}
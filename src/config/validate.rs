@@ -0,0 +1,151 @@
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+
+use toml::value::{Table, Value};
+
+use crate::config::device::DEVICE_KEY;
+use crate::config::folder::FOLDER_KEY;
+use crate::config::format::ConfigFormat;
+use crate::config::migrate::CONFIG_VERSION_KEY;
+use crate::config::Options;
+
+/// Keys that are accepted on the command line but have no meaning inside the config file.
+const NOT_PERSISTED: &[&str] = &[
+    "config-file", "config-format", "no-config-file", "strict-config", "dump-config", "generate-config",
+    "folders", "devices", "command",
+];
+
+/// Config file keys that differ from their option names.
+const KEY_ALIASES: &[&str] = &["device_name", FOLDER_KEY, DEVICE_KEY];
+
+/// A single problem found in a config file.
+#[derive(Debug)]
+pub struct ValidationError {
+    line: Option<usize>,
+    key: Option<String>,
+    message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "`{}`: ", key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// A config file that failed strict validation.
+#[derive(Debug)]
+pub struct InvalidConfig {
+    path: PathBuf,
+    errors: Vec<ValidationError>,
+}
+
+impl Display for InvalidConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid config file {}", self.path.display())?;
+        for error in &self.errors {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl InvalidConfig {
+    pub fn new(path: PathBuf, errors: Vec<ValidationError>) -> Self {
+        InvalidConfig { path, errors }
+    }
+}
+
+/// Checks whether `key` may appear in the config file.
+pub fn is_config_key(key: &str) -> bool {
+    !NOT_PERSISTED.contains(&key)
+        && (KEY_ALIASES.contains(&key) || Options::option_names().iter().any(|name| name == key))
+}
+
+/// Maps a config file key to the name of the option it sets.
+pub fn option_name(key: &str) -> &str {
+    match key {
+        FOLDER_KEY => "folders",
+        DEVICE_KEY => "devices",
+        "device_name" => "set-device-name",
+        key => key,
+    }
+}
+
+/// Validates every key in `contents` independently, so that all problems are reported at once.
+pub fn validate(contents: &str, format: ConfigFormat) -> Vec<ValidationError> {
+    let table = match format.parse(contents) {
+        Ok(table) => table,
+        // Syntax errors already carry their position in the message.
+        Err(e) => return vec![ValidationError { line: None, key: None, message: e.to_string() }],
+    };
+
+    let mut errors = Vec::new();
+    for (key, value) in table {
+        let line = key_line(contents, &key);
+        if key == CONFIG_VERSION_KEY {
+            if !value.is_integer() {
+                errors.push(ValidationError { line, key: Some(key), message: "expected an integer".to_string() });
+            }
+            continue;
+        }
+        if !is_config_key(&key) {
+            errors.push(ValidationError { line, key: Some(key), message: "unknown option".to_string() });
+            continue;
+        }
+        if let Err(message) = validate_entry(&key, value) {
+            errors.push(ValidationError { line, key: Some(key), message });
+        }
+    }
+    errors.sort_by_key(|error| error.line);
+    errors
+}
+
+/// Checks that a single `key = value` pair deserializes into `Options`.
+pub fn validate_entry(key: &str, value: Value) -> Result<(), String> {
+    let mut table = Table::new();
+    table.insert(key.to_string(), value);
+    Value::Table(table).try_into::<Options>().map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Finds the line defining a top level `key`, in any of the supported formats.
+fn key_line(contents: &str, key: &str) -> Option<usize> {
+    contents.lines().position(|line| {
+        let line = line.trim_start();
+        let name = line.split(['=', ':']).next().unwrap_or_default().trim();
+        line.len() > name.len() && name.trim_matches('"').trim_matches('\'') == key
+    }).map(|index| index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_invalid_key_with_its_line() {
+        let contents = "port = 1\nno-such-option = true\nport-fallback = \"sometimes\"\nconfig-file = \"a.toml\"\n\
+            config-version = \"one\"\n";
+        let errors = validate(contents, ConfigFormat::Toml);
+        let found: Vec<(Option<usize>, Option<&str>)> =
+            errors.iter().map(|error| (error.line, error.key.as_deref())).collect();
+        assert_eq!(found, vec![
+            (Some(2), Some("no-such-option")),
+            (Some(3), Some("port-fallback")),
+            (Some(4), Some("config-file")),
+            (Some(5), Some("config-version")),
+        ]);
+        assert!(validate("device_name = \"laptop\"\n", ConfigFormat::Toml).is_empty());
+    }
+
+    #[test]
+    fn reports_syntax_errors_without_a_key() {
+        let errors = validate("port = ", ConfigFormat::Toml);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key, None);
+    }
+}
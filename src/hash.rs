@@ -0,0 +1,279 @@
+use std::fs::{read_link, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::index::{permissions, FileEntry, FolderIndex, IndexError};
+use crate::scan::ScanSettings;
+use crate::transfer::{local_path, Chunking, FastCdc, FileBlocks, Rolling, BLOCK_SIZE};
+use crate::xattr::{self, Xattrs};
+
+/// BLAKE3 of a block or a whole file.
+pub type Hash = [u8; 32];
+
+/// The hash of `data`.
+pub fn hash(data: &[u8]) -> Hash {
+    blake3::hash(data).into()
+}
+
+/// Hashes a file block by block as it is read, along with the whole of it.
+#[derive(Debug, Clone)]
+pub struct BlockHasher {
+    whole: blake3::Hasher,
+    blocks: FileBlocks,
+}
+
+impl BlockHasher {
+    /// Hashes blocks of `block_size`, or of about that length cut where the content says if
+    /// `content_defined`.
+    pub fn new(block_size: u32, content_defined: bool) -> Self {
+        BlockHasher {
+            whole: blake3::Hasher::new(),
+            blocks: FileBlocks {
+                size: 0,
+                hash: Hash::default(),
+                block_size,
+                hashes: Vec::new(),
+                weak_hashes: Vec::new(),
+                // Marks the blocks as content defined until the first one comes.
+                offsets: if content_defined { vec![0] } else { Vec::new() },
+            },
+        }
+    }
+
+    /// Adds the next block of the file.
+    pub fn add(&mut self, block: &[u8]) {
+        self.whole.update(block);
+        if self.blocks.content_defined() {
+            if !self.blocks.hashes.is_empty() {
+                self.blocks.offsets.push(self.blocks.size);
+            }
+        } else {
+            self.blocks.weak_hashes.push(Rolling::new(block).digest());
+        }
+        self.blocks.size += block.len() as u64;
+        self.blocks.hashes.push(hash(block));
+    }
+
+    pub fn finish(mut self) -> FileBlocks {
+        if self.blocks.hashes.is_empty() {
+            self.blocks.offsets.clear();
+        }
+        self.blocks.hash = self.whole.finalize().into();
+        self.blocks
+    }
+}
+
+/// Splits what `reader` holds into blocks with `chunking` and hashes them.
+pub fn hash_blocks(reader: &mut impl Read, chunking: Chunking) -> io::Result<FileBlocks> {
+    let mut hasher = BlockHasher::new(BLOCK_SIZE, chunking == Chunking::Fastcdc);
+    match chunking {
+        Chunking::Fixed => {
+            let mut buffer = vec![0; BLOCK_SIZE as usize];
+            loop {
+                let read = read_full(reader, &mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.add(&buffer[..read]);
+            }
+        }
+        Chunking::Fastcdc => FastCdc::new(BLOCK_SIZE).split(reader, |block| {
+            hasher.add(block);
+            Ok(())
+        })?,
+    }
+    Ok(hasher.finish())
+}
+
+/// Reads `reader` back to check it against `blocks`, returning the blocks that don't match
+/// theirs. Where every block does but the whole file doesn't it isn't known which is wrong, so
+/// then all of them are returned.
+pub fn verify(reader: &mut impl Read, blocks: &FileBlocks) -> io::Result<Vec<usize>> {
+    let mut whole = blake3::Hasher::new();
+    let mut corrupt = Vec::new();
+    for index in 0..blocks.block_count() {
+        let mut data = vec![0; blocks.block(index).1 as usize];
+        let read = read_full(reader, &mut data)?;
+        whole.update(&data[..read]);
+        if read < data.len() || hash(&data) != blocks.hashes[index] {
+            corrupt.push(index);
+        }
+    }
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    whole.update(&rest);
+    if corrupt.is_empty() && Hash::from(whole.finalize()) != blocks.hash {
+        corrupt.extend(0..blocks.block_count());
+    }
+    Ok(corrupt)
+}
+
+/// Hashes the file at `path` in the folder at `root` and stores it in `index` as changed by this
+/// device, returning the new entry. A file the index already has as it is keeps its entry and
+/// returns `None`, and one with the size, time and inode it had when last hashed isn't read at
+/// all. The permissions and extended attributes a folder doesn't sync stay as the index has
+/// them, so changing them changes nothing.
+pub fn index_file(index: &FolderIndex, root: &Path, path: &str, settings: &ScanSettings)
+    -> Result<Option<FileEntry>, IndexError> {
+    let mut file = File::open(local_path(root, path))?;
+    let metadata = file.metadata()?;
+    let previous = index.get(path)?;
+    let blocks = match &previous {
+        Some(old) if old.symlink.is_none() && !old.deleted
+            && old.blocks.content_defined() == (settings.chunking == Chunking::Fastcdc)
+            && index.is_hashed(path, &metadata)? => old.blocks.clone(),
+        _ => {
+            let _hashing = settings.throttle.hashing();
+            let blocks = hash_blocks(&mut settings.throttle.reader(&mut file), settings.chunking)?;
+            // As it was before it was read, so that a change while it was is found next time.
+            index.record_hashed(path, &metadata)?;
+            blocks
+        }
+    };
+    let permissions = match &previous {
+        Some(old) if settings.ignore_permissions => old.permissions,
+        _ => permissions(&metadata),
+    };
+    let xattrs = match &previous {
+        _ if settings.xattrs => xattr::read(&file)?,
+        Some(old) => old.xattrs.clone(),
+        None => Xattrs::new(),
+    };
+    match previous {
+        Some(old) if old.blocks == blocks && old.permissions == permissions && old.xattrs == xattrs
+            && old.symlink.is_none() && !old.deleted => Ok(None),
+        previous => {
+            let entry = FileEntry {
+                permissions,
+                xattrs,
+                ..FileEntry::new(&metadata, blocks, &settings.device, previous.as_ref())
+            };
+            let sequence = index.insert(path, entry.clone())?;
+            Ok(Some(FileEntry { sequence, ..entry }))
+        }
+    }
+}
+
+/// Stores the symbolic link at `path` in the folder at `root` in `index` as changed by `device`,
+/// returning the new entry, or `None` for a link that still points where the index has it.
+pub fn index_link(index: &FolderIndex, root: &Path, path: &str, device: &str)
+    -> Result<Option<FileEntry>, IndexError> {
+    let link = local_path(root, path);
+    let metadata = link.symlink_metadata()?;
+    let target = read_link(&link)?.to_string_lossy().into_owned();
+    let blocks = hash_blocks(&mut target.as_bytes(), Chunking::Fixed)?;
+    let previous = index.get(path)?;
+    match previous {
+        Some(old) if old.symlink.as_ref() == Some(&target) && !old.deleted => Ok(None),
+        previous => {
+            let entry = FileEntry {
+                symlink: Some(target),
+                ..FileEntry::new(&metadata, blocks, device, previous.as_ref())
+            };
+            let sequence = index.insert(path, entry.clone())?;
+            Ok(Some(FileEntry { sequence, ..entry }))
+        }
+    }
+}
+
+/// Reads until `buffer` is full or the end of the file, returning how much was read.
+pub fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use crate::config::SymlinkPolicy;
+    use crate::ignore::{IgnorePatterns, Selection};
+    use crate::index::{set_permissions, Index};
+
+    use super::*;
+
+    #[test]
+    fn hashes_blocks_and_finds_the_ones_that_changed() {
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE as usize).map(|i| (i * 7 % 251) as u8).collect();
+        for chunking in [Chunking::Fixed, Chunking::Fastcdc] {
+            let blocks = hash_blocks(&mut data.as_slice(), chunking).unwrap();
+            assert_eq!((blocks.size, blocks.hash), (data.len() as u64, hash(&data)));
+            assert_eq!(blocks.content_defined(), chunking == Chunking::Fastcdc);
+            assert!(verify(&mut data.as_slice(), &blocks).unwrap().is_empty());
+
+            let mut changed = data.clone();
+            changed[10] ^= 1;
+            assert_eq!(verify(&mut changed.as_slice(), &blocks).unwrap(), vec![0]);
+            // Anything past the last block makes the whole file wrong.
+            changed = data.clone();
+            changed.push(0);
+            assert_eq!(verify(&mut changed.as_slice(), &blocks).unwrap().len(), blocks.block_count());
+        }
+        let empty = hash_blocks(&mut [].as_slice(), Chunking::Fastcdc).unwrap();
+        assert_eq!((empty.block_count(), empty.offsets.len()), (0, 0));
+    }
+
+    #[test]
+    fn indexes_files_that_changed() {
+        let directory = tempdir().unwrap();
+        write(directory.path().join("file"), b"contents").unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        let mut settings = ScanSettings {
+            ignore: IgnorePatterns::new::<&str>(&[]),
+            selection: Selection::default(),
+            chunking: Chunking::Fixed,
+            symlinks: SymlinkPolicy::Skip,
+            ignore_permissions: false,
+            xattrs: false,
+            auto_normalize: true,
+            device: "device".to_string(),
+            throttle: Default::default(),
+            threads: 1,
+        };
+        let index_file = |settings: &ScanSettings| index_file(&folder, directory.path(), "file", settings).unwrap();
+        let entry = index_file(&settings).unwrap();
+        assert_eq!((entry.sequence, entry.blocks.hash), (1, hash(b"contents")));
+        assert_eq!(folder.get("file").unwrap(), Some(entry));
+        assert!(index_file(&settings).is_none());
+
+        write(directory.path().join("file"), b"changed").unwrap();
+        let entry = index_file(&settings).unwrap();
+        assert_eq!((entry.sequence, entry.version.counter("device")), (2, 2));
+
+        // Changing only the permissions is a change, unless they are ignored.
+        let file = File::open(directory.path().join("file")).unwrap();
+        set_permissions(&file, 0o600).unwrap();
+        settings.ignore_permissions = true;
+        assert!(index_file(&settings).is_none());
+        settings.ignore_permissions = false;
+        assert_eq!(index_file(&settings).unwrap().permissions, permissions(&file.metadata().unwrap()));
+
+        // So is changing the extended attributes, where the file system has them and they are synced.
+        let xattrs = Xattrs::from([("user.tag".to_string(), b"red".to_vec())]);
+        xattr::write(&file, &xattrs).unwrap();
+        assert!(index_file(&settings).is_none());
+        settings.xattrs = true;
+        if !xattr::read(&file).unwrap().is_empty() {
+            assert_eq!(index_file(&settings).unwrap().xattrs, xattrs);
+        }
+
+        // A file that looks as it did when last hashed isn't read again.
+        let modified = file.metadata().unwrap().modified().unwrap();
+        write(directory.path().join("file"), b"chanced").unwrap();
+        File::options().write(true).open(directory.path().join("file")).unwrap().set_modified(modified).unwrap();
+        assert!(index_file(&settings).is_none());
+        settings.chunking = Chunking::Fastcdc;
+        assert_eq!(index_file(&settings).unwrap().blocks.hash, hash(b"chanced"));
+    }
+}
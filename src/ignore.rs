@@ -0,0 +1,294 @@
+use std::fs::read_to_string;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use log::warn;
+
+use crate::transfer::PARTIAL_PREFIX;
+use crate::versions::is_versions;
+
+/// File in the root of a folder listing patterns of files that are not synced, one per line.
+pub const IGNORE_FILE: &str = ".ssignore";
+/// Starts a line of an ignore file that reads the patterns of another file, relative to the root
+/// of the folder, in its place.
+const INCLUDE_DIRECTIVE: &str = "#include ";
+/// How deep files may include each other, which stops files that include themselves.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Ignore patterns in the style of `.gitignore`. A pattern without a `/` matches a file or
+/// directory name at any depth, otherwise it is matched against the path from the folder root.
+/// `*` and `?` match within a path component, `[a-z]` any one of the characters in it and `[!a-z]`
+/// any other, `**` matches any number of components and `\` matches the character after it as it
+/// is. A leading `!` includes files that an earlier pattern ignored, and a trailing `/` only
+/// matches directories. Ignoring a directory ignores everything in it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnorePatterns {
+    patterns: Vec<Pattern>,
+    /// The files in the folder the patterns were read from, relative to its root.
+    files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    negated: bool,
+    directory_only: bool,
+    components: Vec<String>,
+}
+
+impl IgnorePatterns {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Self {
+        IgnorePatterns {
+            patterns: patterns.iter().filter_map(|pattern| Pattern::parse(pattern.as_ref())).collect(),
+            files: Vec::new(),
+        }
+    }
+
+    /// The `patterns` from the config followed by the ones in the folder's `.ssignore` and the
+    /// files it includes, which take precedence.
+    pub fn for_folder<S: AsRef<str>>(root: &Path, patterns: &[S]) -> Self {
+        let mut ignore = Self::new(patterns);
+        ignore.read(root, Path::new(IGNORE_FILE), 0);
+        ignore
+    }
+
+    /// Adds the patterns in the file at `path` in the folder at `root`, and in the files it
+    /// includes where it does.
+    fn read(&mut self, root: &Path, path: &Path, depth: usize) {
+        let file = match read_to_string(root.join(path)) {
+            Ok(file) => file,
+            // Only the ignore file is optional.
+            Err(e) if e.kind() == io::ErrorKind::NotFound && depth == 0 => return,
+            Err(e) => return warn!("Unable to read {} in {}: {}", path.display(), root.display(), e),
+        };
+        self.files.push(path.to_path_buf());
+        for line in file.lines() {
+            match line.trim().strip_prefix(INCLUDE_DIRECTIVE).map(str::trim) {
+                Some(include) if depth >= MAX_INCLUDE_DEPTH || self.files.iter().any(|file| file == Path::new(include)) => {
+                    warn!("Not including {} again from {} in {}", include, path.display(), root.display());
+                }
+                Some(include) => self.read(root, Path::new(include), depth + 1),
+                None => self.patterns.extend(Pattern::parse(line)),
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether `path`, relative to the folder root, is one the patterns are read from, so they
+    /// change with it.
+    pub fn is_pattern_file(&self, path: &Path) -> bool {
+        path == Path::new(IGNORE_FILE) || self.files.iter().any(|file| file == path)
+    }
+
+    /// Whether `path`, relative to the folder root and a directory if `is_dir`, should not be
+    /// synced.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let components: Vec<String> = path.components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
+
+        // A file is ignored if it or any directory it is in is, the last matching pattern wins.
+        (1..=components.len()).any(|len| {
+            let prefix = &components[..len];
+            let directory = is_dir || len < components.len();
+            self.patterns.iter().rev().find(|pattern| pattern.matches(prefix, directory))
+                .is_some_and(|pattern| !pattern.negated)
+        })
+    }
+
+    /// Whether `path`, relative to the folder root and a directory if `is_dir`, is synced, so that
+    /// changes to it are sent and the ones peers make to it are taken. Neither the ignored files
+    /// nor the ones syncing keeps in the folder itself are.
+    pub fn is_synced(&self, path: &Path, is_dir: bool) -> bool {
+        !path.as_os_str().is_empty() && !is_internal(path) && !self.is_ignored(path, is_dir)
+    }
+}
+
+/// Whether `path`, relative to the folder root, is kept there by syncing itself, such as a file
+/// being pulled or the old copies of files.
+pub fn is_internal(path: &Path) -> bool {
+    let is_partial = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(PARTIAL_PREFIX));
+    is_partial || is_versions(path)
+}
+
+/// The parts of a folder a device pulls the files of, the rest only known of from what peers
+/// tell it. A file is selected where a pattern matches it or a directory it is in, the way an
+/// ignore pattern would ignore it, and all of them are with no patterns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selection {
+    patterns: IgnorePatterns,
+}
+
+impl Selection {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Self {
+        Selection { patterns: IgnorePatterns::new(patterns) }
+    }
+
+    pub fn is_everything(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether the file at `path`, relative to the folder root, is pulled.
+    pub fn is_selected(&self, path: &Path) -> bool {
+        self.is_everything() || self.patterns.is_ignored(path, false)
+    }
+}
+
+impl Pattern {
+    /// Parses a line of an ignore file, skipping blank lines and `#` comments.
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+
+        let directory_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        let anchored = line.contains('/');
+        let mut components: Vec<String> = line.split('/').filter(|component| !component.is_empty())
+            .map(str::to_string).collect();
+        if components.is_empty() {
+            return None;
+        }
+        if !anchored {
+            components.insert(0, "**".to_string());
+        }
+        Some(Pattern { negated, directory_only, components })
+    }
+
+    fn matches(&self, path: &[String], is_dir: bool) -> bool {
+        (is_dir || !self.directory_only) && match_components(&self.components, path)
+    }
+}
+
+fn match_components(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        // A trailing `**` matches what is in a directory rather than the directory itself.
+        Some((first, [])) if first == "**" => !path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_components(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path)) => {
+                let glob: Vec<char> = first.chars().collect();
+                let name: Vec<char> = name.chars().collect();
+                match_glob(&glob, &name) && match_components(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Whether `name` matches `glob`, a pattern with `*` and `?` like the ones in ignore files.
+pub fn glob_matches(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_glob(&glob, &name)
+}
+
+/// Matches a single path component against a glob with `*`, `?`, classes and escapes.
+fn match_glob(glob: &[char], name: &[char]) -> bool {
+    match glob.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| match_glob(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && match_glob(rest, &name[1..]),
+        Some(('\\', [c, rest @ ..])) => name.first() == Some(c) && match_glob(rest, &name[1..]),
+        Some(('[', rest)) => match (class(rest), name.first()) {
+            (Some((matches, rest)), Some(c)) => matches(*c) && match_glob(rest, &name[1..]),
+            (Some(_), None) => false,
+            // A `[` that isn't closed is just that.
+            (None, _) => name.first() == Some(&'[') && match_glob(rest, &name[1..]),
+        },
+        Some((c, rest)) => name.first() == Some(c) && match_glob(rest, &name[1..]),
+    }
+}
+
+/// The character class at the start of `glob`, just after its `[`, as whether a character is
+/// in it and the rest of the glob after its `]`.
+fn class(glob: &[char]) -> Option<(impl Fn(char) -> bool + '_, &[char])> {
+    let (negated, body) = match glob.first() {
+        Some('!' | '^') => (true, &glob[1..]),
+        _ => (false, glob),
+    };
+    // A `]` right at the start is in the class rather than closing it.
+    let end = body.iter().skip(1).position(|c| *c == ']')? + 1;
+    let (items, rest) = (&body[..end], &body[end + 1..]);
+    let matches = move |c: char| {
+        let mut index = 0;
+        let mut found = false;
+        while index < items.len() {
+            if items.get(index + 1) == Some(&'-') && index + 2 < items.len() {
+                found |= (items[index]..=items[index + 2]).contains(&c);
+                index += 3;
+            } else {
+                found |= items[index] == c;
+                index += 1;
+            }
+        }
+        found != negated
+    };
+    Some((matches, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn matches_like_gitignore() {
+        let ignore = IgnorePatterns::new(&["*.tmp", "!keep.tmp", "build/", "/docs/*.pdf", "[Tt]humbs.db", "\\!bang",
+            "cache[!0-9]", "**/logs/**"]);
+        let ignored = |path: &str| ignore.is_ignored(Path::new(path), false);
+        assert!(ignored("a.tmp") && ignored("dir/b.tmp") && !ignored("dir/keep.tmp"));
+        assert!(ignore.is_ignored(Path::new("src/build"), true) && !ignored("src/build"));
+        assert!(ignored("build/out.o"));
+        assert!(ignored("docs/manual.pdf") && !ignored("other/docs/manual.pdf"));
+        assert!(ignored("Thumbs.db") && ignored("dir/thumbs.db") && !ignored("xhumbs.db"));
+        assert!(ignored("!bang") && !ignored("bang"));
+        assert!(ignored("cachex") && !ignored("cache1"));
+        assert!(ignored("a/logs/today") && !ignored("logs"));
+        assert!(glob_matches("[a-c]?[]]", "bx]") && glob_matches("[x", "[x"));
+    }
+
+    #[test]
+    fn reads_the_ignore_file_and_the_files_it_includes() {
+        let directory = tempdir().unwrap();
+        let root = directory.path();
+        create_dir(root.join("shared")).unwrap();
+        write(root.join(IGNORE_FILE), "*.log\n#include shared/ignore\n# a comment\n").unwrap();
+        write(root.join("shared/ignore"), "!important.log\n*.bak\n#include .ssignore\n").unwrap();
+        let ignore = IgnorePatterns::for_folder(root, &["*.o"]);
+        let ignored = |path: &str| ignore.is_ignored(Path::new(path), false);
+        assert!(ignored("main.o") && ignored("debug.log") && ignored("old.bak") && !ignored("important.log"));
+        assert!(ignore.is_pattern_file(Path::new("shared/ignore")) && !ignore.is_pattern_file(Path::new("main.o")));
+        assert!(IgnorePatterns::default().is_pattern_file(Path::new(IGNORE_FILE)));
+
+        assert!(ignore.is_synced(Path::new("notes.txt"), false));
+        assert!(!ignore.is_synced(Path::new("dir/.ss-partial.notes.txt"), false));
+        assert!(!ignore.is_synced(Path::new(".sync-versions"), true));
+        assert!(!ignore.is_synced(Path::new("debug.log"), false));
+    }
+
+    #[test]
+    fn selects_subtrees() {
+        let selection = Selection::new(&["photos/2021/", "/notes.txt", "*.pdf", "!old.pdf"]);
+        let selected = |path: &str| selection.is_selected(Path::new(path));
+        assert!(selected("photos/2021/a.jpg") && selected("notes.txt") && selected("dir/new.pdf"));
+        assert!(!selected("photos/2020/a.jpg") && !selected("dir/notes.txt") && !selected("dir/old.pdf"));
+        assert!(Selection::default().is_selected(Path::new("anything")));
+    }
+}
@@ -0,0 +1,544 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, Metadata};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::{Db, Tree};
+
+use crate::ignore::IgnorePatterns;
+use crate::transfer::FileBlocks;
+use crate::xattr::Xattrs;
+
+pub use self::collision::{fold_case, is_case_insensitive};
+pub use self::conflict::{conflict_path, parse_timestamp, timestamp, Conflict, Resolution};
+pub use self::unicode::normalize;
+pub use self::version::{Comparison, VersionVector};
+
+mod collision;
+mod conflict;
+mod moves;
+mod unicode;
+mod version;
+
+/// Tree holding the last sequence of every folder, so that sequences keep growing after the
+/// files that had the last ones are removed.
+const SEQUENCES_TREE: &str = "sequences";
+
+/// What this device knows of the files in its folders, kept on disk between runs so that a
+/// restart neither scans every file again nor forgets what peers were already told.
+#[derive(Debug, Clone)]
+pub struct Index {
+    db: Db,
+    sequences: Tree,
+}
+
+/// The files of one folder in the [`Index`], keyed by their path relative to the folder root with
+/// `/` between components.
+#[derive(Debug, Clone)]
+pub struct FolderIndex {
+    id: String,
+    files: Tree,
+    /// The path of the file with every sequence, in order.
+    changes: Tree,
+    /// Every [`Conflict`], by the path of its copy.
+    conflicts: Tree,
+    /// The path of every file after it as [`fold_case`] folds it, to find the ones that only
+    /// differ in case.
+    folded: Tree,
+    /// What each file was like when it was last hashed, by path.
+    hashed: Tree,
+    sequences: Tree,
+}
+
+/// One version of a file as the index records it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub modified: SystemTime,
+    /// Unix permission bits, or `0o444` for a read-only file and `0o644` for any other where the
+    /// system has none.
+    pub permissions: u32,
+    pub blocks: FileBlocks,
+    /// Where a symbolic link points, for the entry of a link rather than a file, whose blocks
+    /// are then those of that path.
+    pub symlink: Option<String>,
+    /// Extended attributes, only kept for folders that sync them.
+    pub xattrs: Xattrs,
+    /// Where the file was moved from with the same contents, for a peer that has it there to move
+    /// it too rather than pull it again.
+    pub moved_from: Option<String>,
+    /// Whether the file was deleted, the entry kept so that peers delete it too, even ones that
+    /// only come back long after.
+    pub deleted: bool,
+    /// The device that made this version.
+    pub modified_by: String,
+    /// The changes this version was made after, which tell whether it replaces the version on
+    /// another device or conflicts with it.
+    pub version: VersionVector,
+    /// When the entry was last changed among the changes to its folder, set when it is stored,
+    /// so that a peer can ask for what changed since the last change it saw.
+    pub sequence: u64,
+}
+
+/// What a file was like when it was last hashed, which it is still taken to hold the same blocks
+/// as while it is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Hashed {
+    size: u64,
+    modified: SystemTime,
+    /// The inode of the file, 0 where the system has none, so that a file replaced by another
+    /// that only looks the same is hashed again.
+    inode: u64,
+}
+
+impl Hashed {
+    fn of(metadata: &Metadata) -> Self {
+        Hashed { size: metadata.len(), modified: metadata.modified().unwrap_or(UNIX_EPOCH), inode: inode(metadata) }
+    }
+}
+
+#[derive(Debug)]
+pub enum IndexError {
+    Store(sled::Error),
+    Encode(bincode::Error),
+    Read(io::Error),
+}
+
+impl Display for IndexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexError::Store(e) => write!(f, "unable to use the index: {}", e),
+            IndexError::Encode(e) => write!(f, "invalid index entry: {}", e),
+            IndexError::Read(e) => write!(f, "unable to read file: {}", e),
+        }
+    }
+}
+
+impl From<sled::Error> for IndexError {
+    fn from(e: sled::Error) -> Self {
+        IndexError::Store(e)
+    }
+}
+
+impl From<bincode::Error> for IndexError {
+    fn from(e: bincode::Error) -> Self {
+        IndexError::Encode(e)
+    }
+}
+
+impl From<io::Error> for IndexError {
+    fn from(e: io::Error) -> Self {
+        IndexError::Read(e)
+    }
+}
+
+impl From<TransactionError<IndexError>> for IndexError {
+    fn from(e: TransactionError<IndexError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => IndexError::Store(e),
+        }
+    }
+}
+
+impl FileEntry {
+    /// The entry of a file with `metadata` and `blocks`, changed by `modified_by` after the
+    /// version `previous`, if the file had one.
+    pub fn new(metadata: &Metadata, blocks: FileBlocks, modified_by: &str, previous: Option<&FileEntry>) -> Self {
+        let mut version = previous.map(|previous| previous.version.clone()).unwrap_or_default();
+        version.increment(modified_by);
+        FileEntry {
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+            permissions: permissions(metadata),
+            blocks,
+            symlink: None,
+            xattrs: Xattrs::new(),
+            moved_from: None,
+            deleted: false,
+            modified_by: modified_by.to_string(),
+            version,
+            sequence: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.blocks.size
+    }
+
+    /// Whether `other` has the same contents as this entry, if perhaps other permissions or time,
+    /// so that nothing needs pulling to receive it.
+    pub fn same_contents(&self, other: &FileEntry) -> bool {
+        self.blocks.hash == other.blocks.hash && self.blocks.size == other.blocks.size && self.symlink == other.symlink
+    }
+}
+
+impl Index {
+    pub fn open(path: &Path) -> Result<Index, IndexError> {
+        let db = sled::open(path)?;
+        let sequences = db.open_tree(SEQUENCES_TREE)?;
+        Ok(Index { db, sequences })
+    }
+
+    pub fn folder(&self, id: &str) -> Result<FolderIndex, IndexError> {
+        let folder = FolderIndex {
+            id: id.to_string(),
+            files: self.db.open_tree(format!("files/{}", id))?,
+            changes: self.db.open_tree(format!("changes/{}", id))?,
+            conflicts: self.db.open_tree(format!("conflicts/{}", id))?,
+            folded: self.db.open_tree(format!("folded/{}", id))?,
+            hashed: self.db.open_tree(format!("hashed/{}", id))?,
+            sequences: self.sequences.clone(),
+        };
+        // An index from before paths were folded has none of them.
+        if folder.folded.len() != folder.files.len() {
+            folder.folded.clear()?;
+            for path in folder.files.iter().keys() {
+                folder.folded.insert(collision::folded_key(&String::from_utf8_lossy(&path?)), &[])?;
+            }
+        }
+        Ok(folder)
+    }
+
+    /// Forgets everything about the folder `id`, once it is no longer shared.
+    pub fn remove_folder(&self, id: &str) -> Result<(), IndexError> {
+        self.db.drop_tree(format!("files/{}", id))?;
+        self.db.drop_tree(format!("changes/{}", id))?;
+        self.db.drop_tree(format!("conflicts/{}", id))?;
+        self.db.drop_tree(format!("folded/{}", id))?;
+        self.db.drop_tree(format!("hashed/{}", id))?;
+        self.sequences.remove(id)?;
+        Ok(())
+    }
+
+    /// Waits for every change so far to be on disk, which otherwise happens every half a second.
+    pub fn flush(&self) -> Result<(), IndexError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl FolderIndex {
+    pub fn get(&self, path: &str) -> Result<Option<FileEntry>, IndexError> {
+        Ok(self.files.get(path)?.map(|entry| bincode::deserialize(&entry)).transpose()?)
+    }
+
+    /// Stores `entry` for the file at `path` as the folder's next change, returning its sequence.
+    pub fn insert(&self, path: &str, entry: FileEntry) -> Result<u64, IndexError> {
+        let trees = (&self.files, &self.changes, &self.folded, &self.sequences);
+        let sequence = trees.transaction(|(files, changes, folded, sequences)| {
+            let sequence = sequences.get(&self.id)?.map_or(0, |last| decode_sequence(&last)) + 1;
+            if let Some(old) = files.get(path)? {
+                let old: FileEntry = bincode::deserialize(&old)
+                    .map_err(|e| ConflictableTransactionError::Abort(IndexError::from(e)))?;
+                changes.remove(&old.sequence.to_be_bytes())?;
+            }
+            let encoded = bincode::serialize(&FileEntry { sequence, ..entry.clone() })
+                .map_err(|e| ConflictableTransactionError::Abort(IndexError::from(e)))?;
+            files.insert(path, encoded)?;
+            changes.insert(&sequence.to_be_bytes(), path)?;
+            folded.insert(collision::folded_key(path), &[])?;
+            sequences.insert(self.id.as_str(), &sequence.to_be_bytes())?;
+            Ok(sequence)
+        })?;
+        Ok(sequence)
+    }
+
+    /// Takes note of `remote`, the entry a peer has for the file at `path`, once the file is as
+    /// it says, or deleted for a deleted one, or right away for a file the folder's selection
+    /// doesn't pull. A newer entry than the one here replaces it, and an older or equal one is
+    /// left out, as is one concurrent with it, which is a conflict to resolve first.
+    pub fn receive(&self, path: &str, remote: FileEntry) -> Result<Comparison, IndexError> {
+        let local = self.get(path)?;
+        let comparison = match &local {
+            Some(local) => remote.version.compare(&local.version),
+            None => Comparison::Newer,
+        };
+        if comparison == Comparison::Newer {
+            let mut version = remote.version.clone();
+            if let Some(local) = &local {
+                version.merge(&local.version);
+            }
+            self.insert(path, FileEntry { version, ..remote })?;
+        }
+        Ok(comparison)
+    }
+
+    /// Whether `remote` is a deletion of the file at `path` that a folder keeping deleted files
+    /// keeps the file for, rather than taking note of it. The entry here then stays as it is, older
+    /// than the deletion, so that the file isn't sent back to the peers that deleted it.
+    pub fn keeps(&self, path: &str, remote: &FileEntry) -> Result<bool, IndexError> {
+        Ok(remote.deleted && match self.get(path)? {
+            Some(local) => !local.deleted && remote.version.compare(&local.version) == Comparison::Newer,
+            None => false,
+        })
+    }
+
+    /// Like [`FolderIndex::receive`] for a change a peer made, which is turned down as `None`
+    /// where `ignore` says the file isn't synced here, so that it is neither written nor sent on.
+    pub fn receive_synced(&self, path: &str, remote: FileEntry, ignore: &IgnorePatterns)
+        -> Result<Option<Comparison>, IndexError> {
+        if !ignore.is_synced(Path::new(path), false) {
+            return Ok(None);
+        }
+        self.receive(path, remote).map(Some)
+    }
+
+    /// Takes note that the file at `path` was deleted by `device`, as a version made after the
+    /// last one so that it replaces it on peers, returning the entry left in its place.
+    pub fn delete(&self, path: &str, device: &str, time: SystemTime) -> Result<Option<FileEntry>, IndexError> {
+        let mut entry = match self.get(path)? {
+            Some(entry) if !entry.deleted => entry,
+            _ => return Ok(None),
+        };
+        entry.version.increment(device);
+        entry.modified_by = device.to_string();
+        entry.modified = time;
+        entry.moved_from = None;
+        entry.deleted = true;
+        self.hashed.remove(path)?;
+        let sequence = self.insert(path, entry.clone())?;
+        Ok(Some(FileEntry { sequence, ..entry }))
+    }
+
+    /// Whether the file at `path` with `metadata` is as it was when last hashed, so that it still
+    /// holds the blocks its entry has.
+    pub fn is_hashed(&self, path: &str, metadata: &Metadata) -> Result<bool, IndexError> {
+        match self.hashed.get(path)? {
+            Some(hashed) => Ok(bincode::deserialize::<Hashed>(&hashed)? == Hashed::of(metadata)),
+            None => Ok(false),
+        }
+    }
+
+    /// Takes note that the file at `path` was hashed when it was as `metadata` says, such as when
+    /// it is scanned or was just pulled.
+    pub fn record_hashed(&self, path: &str, metadata: &Metadata) -> Result<(), IndexError> {
+        self.hashed.insert(path, bincode::serialize(&Hashed::of(metadata))?)?;
+        Ok(())
+    }
+
+    /// Forgets the file at `path` altogether, as for one this device no longer syncs, which
+    /// peers aren't told about.
+    pub fn remove(&self, path: &str) -> Result<Option<FileEntry>, IndexError> {
+        let trees = (&self.files, &self.changes, &self.folded, &self.hashed);
+        let removed = trees.transaction(|(files, changes, folded, hashed)| {
+            let old = match files.remove(path)? {
+                Some(old) => old,
+                None => return Ok(None),
+            };
+            let old: FileEntry = bincode::deserialize(&old)
+                .map_err(|e| ConflictableTransactionError::Abort(IndexError::from(e)))?;
+            changes.remove(&old.sequence.to_be_bytes())?;
+            folded.remove(collision::folded_key(path))?;
+            hashed.remove(path)?;
+            Ok(Some(old))
+        })?;
+        Ok(removed)
+    }
+
+    /// The sequence of the last change to the folder, 0 before the first.
+    pub fn sequence(&self) -> Result<u64, IndexError> {
+        Ok(self.sequences.get(&self.id)?.map_or(0, |last| decode_sequence(&last)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Every file in the folder with its entry, by path.
+    pub fn entries(&self) -> impl Iterator<Item = Result<(String, FileEntry), IndexError>> {
+        self.files.iter().map(|item| {
+            let (path, entry) = item?;
+            Ok((String::from_utf8_lossy(&path).to_string(), bincode::deserialize(&entry)?))
+        })
+    }
+
+    /// The files changed after the change `sequence`, in the order they were last changed.
+    pub fn since(&self, sequence: u64) -> impl Iterator<Item = Result<(String, FileEntry), IndexError>> + '_ {
+        self.changes.range((sequence + 1).to_be_bytes()..).filter_map(move |item| {
+            let path = match item {
+                Ok((_, path)) => String::from_utf8_lossy(&path).to_string(),
+                Err(e) => return Some(Err(e.into())),
+            };
+            // A file removed since the change was read is left out.
+            self.get(&path).transpose().map(|entry| entry.map(|entry| (path, entry)))
+        })
+    }
+}
+
+fn decode_sequence(bytes: &[u8]) -> u64 {
+    let mut sequence = [0; 8];
+    sequence.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(sequence)
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// The permission bits the index records for a file with `metadata`.
+#[cfg(unix)]
+pub fn permissions(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+/// The permission bits the index records for a file with `metadata`.
+#[cfg(not(unix))]
+pub fn permissions(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+/// Whether the modification times `a` and `b` are at most `window` apart, and so taken as the
+/// same time.
+pub fn same_time(a: SystemTime, b: SystemTime, window: Duration) -> bool {
+    a.duration_since(b).unwrap_or_else(|e| e.duration()) <= window
+}
+
+/// Gives `file` the permission bits the index records, of which only whether it is written to
+/// is kept where the system has no others.
+#[cfg(unix)]
+pub fn set_permissions(file: &File, permissions: u32) -> io::Result<()> {
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+
+    file.set_permissions(Permissions::from_mode(permissions & 0o7777))
+}
+
+/// Gives `file` the permission bits the index records, of which only whether it is written to
+/// is kept where the system has no others.
+#[cfg(not(unix))]
+pub fn set_permissions(file: &File, permissions: u32) -> io::Result<()> {
+    let mut current = file.metadata()?.permissions();
+    current.set_readonly(permissions & 0o222 == 0);
+    file.set_permissions(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::hash::hash;
+
+    use super::*;
+
+    fn blocks(data: &[u8]) -> FileBlocks {
+        FileBlocks {
+            size: data.len() as u64,
+            hash: hash(data),
+            block_size: 4,
+            hashes: data.chunks(4).map(hash).collect(),
+            weak_hashes: Vec::new(),
+            offsets: Vec::new(),
+        }
+    }
+
+    fn entry(data: &[u8]) -> FileEntry {
+        FileEntry {
+            modified: UNIX_EPOCH,
+            permissions: 0o644,
+            blocks: blocks(data),
+            symlink: None,
+            xattrs: Default::default(),
+            moved_from: None,
+            deleted: false,
+            modified_by: "device".to_string(),
+            version: VersionVector::default(),
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn keeps_entries_in_the_order_they_changed() {
+        let directory = tempdir().unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        assert_eq!(folder.insert("a", entry(b"1234")).unwrap(), 1);
+        assert_eq!(folder.insert("dir/b", entry(b"5678")).unwrap(), 2);
+        assert_eq!(folder.insert("a", entry(b"4321")).unwrap(), 3);
+        assert_eq!(folder.get("a").unwrap().unwrap().blocks, blocks(b"4321"));
+        let since: Vec<String> = folder.since(1).map(|item| item.unwrap().0).collect();
+        assert_eq!(since, vec!["dir/b", "a"]);
+        assert!(index.folder("other").unwrap().is_empty());
+
+        assert!(folder.remove("dir/b").unwrap().is_some());
+        assert!(folder.remove("dir/b").unwrap().is_none());
+        drop((folder, index));
+
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        assert_eq!(folder.len(), 1);
+        assert_eq!(folder.sequence().unwrap(), 3);
+        assert_eq!(folder.insert("c", entry(b"")).unwrap(), 4);
+        index.remove_folder("folder").unwrap();
+        assert_eq!(index.folder("folder").unwrap().sequence().unwrap(), 0);
+    }
+
+    #[test]
+    fn takes_newer_entries_from_peers() {
+        let directory = tempdir().unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        let versioned = |data: &[u8], devices: &[&str]| {
+            let mut entry = entry(data);
+            devices.iter().for_each(|device| entry.version.increment(device));
+            entry
+        };
+        folder.insert("a", versioned(b"1", &["here"])).unwrap();
+
+        assert_eq!(folder.receive("a", versioned(b"2", &["here", "peer"])).unwrap(), Comparison::Newer);
+        assert_eq!(folder.get("a").unwrap().unwrap().blocks, blocks(b"2"));
+        assert_eq!(folder.receive("a", versioned(b"1", &["here"])).unwrap(), Comparison::Older);
+        assert_eq!(folder.receive("a", versioned(b"3", &["here", "other"])).unwrap(), Comparison::Concurrent);
+        assert_eq!(folder.get("a").unwrap().unwrap().blocks, blocks(b"2"));
+        assert_eq!(folder.receive("b", versioned(b"4", &["peer"])).unwrap(), Comparison::Newer);
+        let ignore = IgnorePatterns::new(&["*.tmp"]);
+        assert_eq!(folder.receive_synced("c.tmp", versioned(b"5", &["peer"]), &ignore).unwrap(), None);
+        assert_eq!(folder.receive_synced(".ss-partial.c", versioned(b"5", &["peer"]), &ignore).unwrap(), None);
+        assert!(folder.get("c.tmp").unwrap().is_none());
+
+        // The local version of a conflict is made after both, so the peers take it.
+        let mut remote = versioned(b"3", &["here", "other"]);
+        remote.modified_by = "other".to_string();
+        let conflict = folder.resolve_conflict("a", &remote, "here", UNIX_EPOCH).unwrap();
+        assert_eq!(conflict.copy, conflict_path("a", UNIX_EPOCH, "other"));
+        let local = folder.get("a").unwrap().unwrap();
+        assert_eq!((local.blocks, local.version.compare(&remote.version)), (blocks(b"2"), Comparison::Newer));
+        assert_eq!(folder.conflicts().map(Result::unwrap).collect::<Vec<_>>(), vec![conflict]);
+    }
+
+    #[test]
+    fn keeps_files_peers_deleted_as_they_are() {
+        let directory = tempdir().unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let (here, there) = (index.folder("here").unwrap(), index.folder("there").unwrap());
+        let mut kept = entry(b"1");
+        kept.version.increment("peer");
+        here.insert("a", kept.clone()).unwrap();
+        there.insert("a", kept.clone()).unwrap();
+        let deleted = there.delete("a", "peer", UNIX_EPOCH).unwrap().unwrap();
+
+        assert!(here.keeps("a", &deleted).unwrap());
+        assert!(!here.keeps("a", &kept).unwrap());
+        assert!(!here.keeps("b", &deleted).unwrap());
+        // Left older than the deletion, which the peer doesn't take it back for.
+        assert_eq!(there.receive("a", here.get("a").unwrap().unwrap()).unwrap(), Comparison::Older);
+        // Once it is taken note of there is nothing left to keep.
+        here.receive("a", deleted.clone()).unwrap();
+        assert!(!here.keeps("a", &deleted).unwrap());
+    }
+}
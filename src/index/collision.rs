@@ -0,0 +1,137 @@
+use std::fs::{remove_file, File};
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use log::warn;
+
+use crate::index::{conflict_path, Comparison, Conflict, FileEntry, FolderIndex, IndexError};
+use crate::transfer::PARTIAL_PREFIX;
+
+/// Name of the file that tells whether a file system tells apart names that only differ in case.
+const PROBE_NAME: &str = "case-Probe";
+
+/// `path` as a file system that doesn't tell case apart sees it, the same for every path that
+/// would be the same file there.
+pub fn fold_case(path: &str) -> String {
+    path.to_lowercase()
+}
+
+/// The key of `path` among the folded paths of a folder, which starts with its folded path so
+/// that every path folding the same is found together.
+pub(super) fn folded_key(path: &str) -> Vec<u8> {
+    let mut key = fold_case(path).into_bytes();
+    key.push(0);
+    key.extend_from_slice(path.as_bytes());
+    key
+}
+
+impl FolderIndex {
+    /// Another file in the folder whose path only differs from `path` in case, which a file
+    /// system that doesn't tell case apart would take for the same file. Deleted ones are left
+    /// out.
+    pub fn collision(&self, path: &str) -> Result<Option<String>, IndexError> {
+        let mut prefix = fold_case(path).into_bytes();
+        prefix.push(0);
+        for key in self.folded.scan_prefix(&prefix).keys() {
+            let other = String::from_utf8_lossy(&key?[prefix.len()..]).to_string();
+            if other != path && self.get(&other)?.is_some_and(|entry| !entry.deleted) {
+                return Ok(Some(other));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like [`FolderIndex::receive`] on a file system that doesn't tell case apart, for `remote`
+    /// at a `path` that only differs in case from `other` here. Writing it would overwrite
+    /// `other`, so it is kept in a copy instead, which is recorded as a conflict and returned to
+    /// pull `remote` to. Its entry stays in the index though the file isn't there, so that it
+    /// isn't taken for deleted.
+    pub fn receive_colliding(&self, path: &str, other: &str, remote: FileEntry, time: SystemTime)
+        -> Result<Option<Conflict>, IndexError> {
+        let device = remote.modified_by.clone();
+        if self.receive(path, remote)? != Comparison::Newer {
+            return Ok(None);
+        }
+        let conflict = Conflict {
+            path: path.to_string(),
+            copy: conflict_path(path, time, &device),
+            device,
+            time,
+        };
+        warn!("{} only differs in case from {}, keeping it as {}", path, other, conflict.copy);
+        self.record_conflict(&conflict)?;
+        Ok(Some(conflict))
+    }
+}
+
+/// Whether the file system the folder at `root` is on takes names that only differ in case for
+/// the same name.
+pub fn is_case_insensitive(root: &Path) -> io::Result<bool> {
+    let probe = root.join(format!("{}{}", PARTIAL_PREFIX, PROBE_NAME));
+    File::create(&probe)?;
+    let insensitive = root.join(format!("{}{}", PARTIAL_PREFIX, PROBE_NAME.to_uppercase())).exists();
+    if let Err(e) = remove_file(&probe) {
+        warn!("Unable to remove {}: {}", probe.display(), e);
+    }
+    Ok(insensitive)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use tempfile::tempdir;
+
+    use crate::index::Index;
+
+    use super::*;
+
+    #[test]
+    fn keeps_a_file_that_only_differs_in_case_in_a_copy() {
+        let directory = tempdir().unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        let mut entry = FileEntry {
+            modified: UNIX_EPOCH,
+            permissions: 0o644,
+            blocks: crate::transfer::FileBlocks {
+                size: 0,
+                hash: Default::default(),
+                block_size: 4,
+                hashes: Vec::new(),
+                weak_hashes: Vec::new(),
+                offsets: Vec::new(),
+            },
+            symlink: None,
+            xattrs: Default::default(),
+            moved_from: None,
+            deleted: false,
+            modified_by: "peer".to_string(),
+            version: Default::default(),
+            sequence: 0,
+        };
+        entry.version.increment("peer");
+        folder.insert("Dir/README.md", entry.clone()).unwrap();
+        assert_eq!(folder.collision("dir/readme.md").unwrap(), Some("Dir/README.md".to_string()));
+        assert_eq!(folder.collision("Dir/README.md").unwrap(), None);
+        assert_eq!(folder.collision("dir/other.md").unwrap(), None);
+
+        let conflict = folder.receive_colliding("dir/readme.md", "Dir/README.md", entry.clone(), UNIX_EPOCH).unwrap();
+        assert_eq!(conflict.unwrap().copy, conflict_path("dir/readme.md", UNIX_EPOCH, "peer"));
+        assert!(folder.get("dir/readme.md").unwrap().is_some());
+        // The same version again is already kept.
+        assert!(folder.receive_colliding("dir/readme.md", "Dir/README.md", entry, UNIX_EPOCH).unwrap().is_none());
+        assert_eq!(folder.conflicts().count(), 1);
+
+        folder.remove("Dir/README.md").unwrap();
+        assert_eq!(folder.collision("dir/readme.md").unwrap(), None);
+    }
+
+    #[test]
+    fn probes_the_file_system_without_leaving_anything() {
+        let directory = tempdir().unwrap();
+        is_case_insensitive(directory.path()).unwrap();
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+}
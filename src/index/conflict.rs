@@ -0,0 +1,259 @@
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConflictPolicy;
+use crate::index::{FileEntry, FolderIndex, IndexError};
+
+/// Marks the name of a copy of a file that conflicted, between its stem and the time and device
+/// of the conflict.
+pub const CONFLICT_MARKER: &str = ".sync-conflict-";
+/// Characters of the device id a conflict copy is named after, enough to tell devices apart.
+const DEVICE_ID_LENGTH: usize = 8;
+
+/// A file changed on two devices apart, the local version kept where it was and the other one
+/// written next to it under another name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflict {
+    pub path: String,
+    /// Where the version of the other device goes.
+    pub copy: String,
+    /// The device that made the other version.
+    pub device: String,
+    pub time: SystemTime,
+}
+
+/// What became of a conflict once resolved with the policy of the folder.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    /// The local version stays, as the version the other device takes too.
+    Local,
+    /// The version of the other device replaces the local one, to be received with this entry,
+    /// made after both, once pulled.
+    Remote(FileEntry),
+    /// Both are kept, the version of the other device to be pulled to the copy.
+    Both(Conflict),
+}
+
+impl FolderIndex {
+    /// Resolves the conflict between the version of the file at `path` here and `remote` with
+    /// `policy`, the same way on both devices so that they end up with the same file.
+    pub fn resolve(&self, path: &str, remote: &FileEntry, own_id: &str, policy: &ConflictPolicy, time: SystemTime)
+        -> Result<Resolution, IndexError> {
+        let mut local = match self.get(path)? {
+            Some(local) => local,
+            None => return Ok(Resolution::Remote(remote.clone())),
+        };
+        match local_wins(policy, &local, remote) {
+            Some(true) => {
+                local.version.merge(&remote.version);
+                self.insert(path, local)?;
+                Ok(Resolution::Local)
+            }
+            Some(false) => {
+                let mut version = remote.version.clone();
+                version.merge(&local.version);
+                Ok(Resolution::Remote(FileEntry { version, ..remote.clone() }))
+            }
+            None => Ok(Resolution::Both(self.resolve_conflict(path, remote, own_id, time)?)),
+        }
+    }
+
+    /// Resolves the conflict between the version of the file at `path` here and `remote` by
+    /// keeping the local one, which becomes the version `own_id` made after both so that it
+    /// replaces `remote` on the other devices too. The conflict is recorded, and `remote` is then
+    /// to be pulled to the copy it returns.
+    pub fn resolve_conflict(&self, path: &str, remote: &FileEntry, own_id: &str, time: SystemTime)
+        -> Result<Conflict, IndexError> {
+        if let Some(mut local) = self.get(path)? {
+            local.version.merge(&remote.version);
+            local.version.increment(own_id);
+            local.modified_by = own_id.to_string();
+            self.insert(path, local)?;
+        }
+        let conflict = Conflict {
+            path: path.to_string(),
+            copy: conflict_path(path, time, &remote.modified_by),
+            device: remote.modified_by.clone(),
+            time,
+        };
+        warn!("{} changed on device {} too, keeping its version as {}", path, conflict.device, conflict.copy);
+        self.record_conflict(&conflict)?;
+        Ok(conflict)
+    }
+
+    pub(super) fn record_conflict(&self, conflict: &Conflict) -> Result<(), IndexError> {
+        self.conflicts.insert(&conflict.copy, bincode::serialize(conflict)?)?;
+        Ok(())
+    }
+
+    /// The conflicts in the folder, by the path of their copy.
+    pub fn conflicts(&self) -> impl Iterator<Item = Result<Conflict, IndexError>> {
+        self.conflicts.iter().map(|item| Ok(bincode::deserialize(&item?.1)?))
+    }
+}
+
+/// Whether `local` wins over `remote` with `policy`, `None` to keep both. Ties go to the version
+/// made by the device with the greater id, so that every device picks the same one. A file
+/// changed on one device wins over its deletion on the other whatever the policy, as nothing is
+/// lost that way.
+fn local_wins(policy: &ConflictPolicy, local: &FileEntry, remote: &FileEntry) -> Option<bool> {
+    let tie = || local.modified_by > remote.modified_by;
+    match (local.deleted, remote.deleted) {
+        (true, true) => return Some(tie()),
+        (true, false) => return Some(false),
+        (false, true) => return Some(true),
+        (false, false) => {}
+    }
+    match policy {
+        ConflictPolicy::KeepBoth => None,
+        ConflictPolicy::NewestWins if local.modified == remote.modified => Some(tie()),
+        ConflictPolicy::NewestWins => Some(local.modified > remote.modified),
+        ConflictPolicy::LargestWins if local.blocks.size == remote.blocks.size => Some(tie()),
+        ConflictPolicy::LargestWins => Some(local.blocks.size > remote.blocks.size),
+        ConflictPolicy::PreferDevice(id) if local.modified_by == *id => Some(true),
+        ConflictPolicy::PreferDevice(id) if remote.modified_by == *id => Some(false),
+        ConflictPolicy::PreferDevice(_) => None,
+    }
+}
+
+/// Where the version of the file at `path` that `device` made goes when it conflicts with the
+/// local one, such as `notes.sync-conflict-20210314-152653-1a2b3c4d.txt` for `notes.txt`.
+pub fn conflict_path(path: &str, time: SystemTime, device: &str) -> String {
+    let (directory, name) = match path.rfind('/') {
+        Some(slash) => path.split_at(slash + 1),
+        None => ("", path),
+    };
+    let name_path = Path::new(name);
+    let stem = name_path.file_stem().map_or(name.into(), |stem| stem.to_string_lossy());
+    let extension = name_path.extension().map(|extension| format!(".{}", extension.to_string_lossy()));
+    let device: String = device.chars().filter(|c| *c != '-').take(DEVICE_ID_LENGTH).collect();
+    format!("{}{}{}{}-{}{}", directory, stem, CONFLICT_MARKER, timestamp(time), device,
+        extension.unwrap_or_default())
+}
+
+/// `time` in UTC as `YYYYMMDD-HHMMSS`.
+pub fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, of_day) = (seconds / 86_400, seconds % 86_400);
+    // Howard Hinnant's days to civil date, over 400 year eras starting on the 1st of March.
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, of_day / 3600, of_day / 60 % 60, of_day % 60)
+}
+
+/// The time a [`timestamp`] was made at, `None` if `text` isn't one.
+pub fn parse_timestamp(text: &str) -> Option<SystemTime> {
+    let field = |range: Range<usize>| text.get(range)?.parse::<i64>().ok();
+    if text.len() != 15 || text.as_bytes()[8] != b'-' || !text.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+        return None;
+    }
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(9..11)?, field(11..13)?, field(13..15)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    // The inverse of the days to civil date in `timestamp`.
+    let year = year - i64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let seconds = u64::try_from(days * 86_400 + hour * 3600 + minute * 60 + second).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::index::{Comparison, Index};
+    use crate::transfer::FileBlocks;
+
+    use super::*;
+
+    fn entry(device: &str, size: u64, modified: u64) -> FileEntry {
+        let mut entry = FileEntry {
+            modified: UNIX_EPOCH + Duration::from_secs(modified),
+            permissions: 0o644,
+            blocks: FileBlocks {
+                size,
+                hash: Default::default(),
+                block_size: 4,
+                hashes: Vec::new(),
+                weak_hashes: Vec::new(),
+                offsets: Vec::new(),
+            },
+            symlink: None,
+            xattrs: Default::default(),
+            moved_from: None,
+            deleted: false,
+            modified_by: device.to_string(),
+            version: Default::default(),
+            sequence: 0,
+        };
+        entry.version.increment(device);
+        entry
+    }
+
+    #[test]
+    fn names_conflict_copies_after_the_time_and_device() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_615_735_613);
+        let device = "1a2b3c4d-5e6f-7a8b-9c0d-1e2f3a4b5c6d";
+        let marked = |name: &str| format!("{}.sync-conflict-20210314-152653-1a2b3c4d", name);
+        assert_eq!(conflict_path("dir/notes.txt", time, device), marked("dir/notes") + ".txt");
+        assert_eq!(conflict_path("Makefile", time, device), marked("Makefile"));
+        assert_eq!(conflict_path("a.b/.profile", time, device), marked("a.b/.profile"));
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)), "20000229-000000");
+        assert_eq!(parse_timestamp("20210314-152653"), Some(time));
+        assert_eq!(parse_timestamp("20000229-000000"), Some(UNIX_EPOCH + Duration::from_secs(951_782_400)));
+        assert_eq!(parse_timestamp("20211314-152653"), None);
+        assert_eq!(parse_timestamp("20210314_152653"), None);
+    }
+
+    #[test]
+    fn resolves_conflicts_with_the_policy_of_the_folder() {
+        let (a, b) = (entry("a", 10, 200), entry("b", 20, 100));
+        assert_eq!(local_wins(&ConflictPolicy::KeepBoth, &a, &b), None);
+        assert_eq!(local_wins(&ConflictPolicy::NewestWins, &a, &b), Some(true));
+        assert_eq!(local_wins(&ConflictPolicy::LargestWins, &a, &b), Some(false));
+        assert_eq!(local_wins(&ConflictPolicy::PreferDevice("b".to_string()), &a, &b), Some(false));
+        assert_eq!(local_wins(&ConflictPolicy::PreferDevice("c".to_string()), &a, &b), None);
+        // Both devices break a tie the same way.
+        let tied = entry("b", 10, 200);
+        assert_eq!(local_wins(&ConflictPolicy::NewestWins, &a, &tied), Some(false));
+        assert_eq!(local_wins(&ConflictPolicy::NewestWins, &tied, &a), Some(true));
+        let deleted = FileEntry { deleted: true, ..b.clone() };
+        assert_eq!(local_wins(&ConflictPolicy::KeepBoth, &a, &deleted), Some(true));
+        assert_eq!(local_wins(&ConflictPolicy::PreferDevice("b".to_string()), &deleted, &a), Some(false));
+
+        let directory = tempdir().unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        folder.insert("file", a.clone()).unwrap();
+        let time = UNIX_EPOCH;
+        assert_eq!(folder.resolve("file", &b, "a", &ConflictPolicy::NewestWins, time).unwrap(), Resolution::Local);
+        let local = folder.get("file").unwrap().unwrap();
+        assert_eq!(b.version.compare(&local.version), Comparison::Older);
+
+        folder.insert("other", a.clone()).unwrap();
+        match folder.resolve("other", &b, "a", &ConflictPolicy::LargestWins, time).unwrap() {
+            Resolution::Remote(remote) => assert_eq!(folder.receive("other", remote).unwrap(), Comparison::Newer),
+            other => panic!("the larger version should win, not {:?}", other),
+        }
+        assert_eq!(folder.get("other").unwrap().unwrap().blocks.size, 20);
+        assert!(folder.conflicts().next().is_none());
+    }
+}
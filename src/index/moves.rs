@@ -0,0 +1,108 @@
+use crate::index::{Comparison, FileEntry, FolderIndex, IndexError};
+
+impl FolderIndex {
+    /// Takes the file at `path` for the one at `from` moved there, as a scan finds when one is
+    /// gone and the other came with the same contents. The file at `from` is deleted, and the one
+    /// at `path` becomes a version made after it that says where it came from, returning it.
+    pub fn record_move(&self, from: &str, path: &str) -> Result<Option<FileEntry>, IndexError> {
+        let (old, mut entry) = match (self.get(from)?, self.get(path)?) {
+            (Some(old), Some(entry)) if !old.deleted => (old, entry),
+            _ => return Ok(None),
+        };
+        entry.version.merge(&old.version);
+        entry.version.increment(&entry.modified_by);
+        entry.moved_from = Some(from.to_string());
+        self.delete(from, &entry.modified_by, entry.modified)?;
+        let sequence = self.insert(path, entry.clone())?;
+        Ok(Some(FileEntry { sequence, ..entry }))
+    }
+
+    /// Where the file `remote` is for at `path` is here already, for a peer that moved it, so
+    /// that it is moved here too rather than pulled. That is only where this device has the
+    /// version it was moved from, and nothing newer at `path`.
+    pub fn moved(&self, path: &str, remote: &FileEntry) -> Result<Option<String>, IndexError> {
+        let from = match &remote.moved_from {
+            Some(from) if from != path => from,
+            _ => return Ok(None),
+        };
+        match self.get(from)? {
+            Some(old) if !old.deleted && old.same_contents(remote)
+                && remote.version.compare(&old.version) == Comparison::Newer => {}
+            _ => return Ok(None),
+        }
+        match self.get(path)? {
+            Some(local) if remote.version.compare(&local.version) != Comparison::Newer => Ok(None),
+            _ => Ok(Some(from.clone())),
+        }
+    }
+
+    /// Like [`FolderIndex::receive`] for `remote` that was moved from `from`, once the file is
+    /// moved, which deletes the file at `from` as the peer that moved it did.
+    pub fn receive_moved(&self, from: &str, path: &str, remote: FileEntry) -> Result<Comparison, IndexError> {
+        self.delete(from, &remote.modified_by, remote.modified)?;
+        self.receive(path, remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use tempfile::tempdir;
+
+    use crate::index::Index;
+    use crate::transfer::FileBlocks;
+
+    use super::*;
+
+    fn entry(device: &str, size: u64) -> FileEntry {
+        let mut entry = FileEntry {
+            modified: UNIX_EPOCH,
+            permissions: 0o644,
+            blocks: FileBlocks {
+                size,
+                hash: [size as u8; 32],
+                block_size: 4,
+                hashes: Vec::new(),
+                weak_hashes: Vec::new(),
+                offsets: Vec::new(),
+            },
+            symlink: None,
+            xattrs: Default::default(),
+            moved_from: None,
+            deleted: false,
+            modified_by: device.to_string(),
+            version: Default::default(),
+            sequence: 0,
+        };
+        entry.version.increment(device);
+        entry
+    }
+
+    #[test]
+    fn moves_files_a_peer_moved() {
+        let directory = tempdir().unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let (here, there) = (index.folder("here").unwrap(), index.folder("there").unwrap());
+        let old = entry("a", 10);
+        here.insert("old", old.clone()).unwrap();
+        there.insert("old", old).unwrap();
+
+        there.insert("dir/new", entry("b", 10)).unwrap();
+        let moved = there.record_move("old", "dir/new").unwrap().unwrap();
+        assert_eq!(moved.moved_from.as_deref(), Some("old"));
+        assert!(there.get("old").unwrap().unwrap().deleted);
+
+        assert_eq!(here.moved("dir/new", &moved).unwrap().as_deref(), Some("old"));
+        // Not when the contents here aren't the ones moved.
+        here.insert("old", entry("a", 20)).unwrap();
+        assert_eq!(here.moved("dir/new", &moved).unwrap(), None);
+        here.insert("old", entry("a", 10)).unwrap();
+
+        assert_eq!(here.receive_moved("old", "dir/new", moved.clone()).unwrap(), Comparison::Newer);
+        assert_eq!(here.moved("dir/new", &moved).unwrap(), None);
+        // Both took the file at the old path for deleted the same way.
+        let deleted = there.get("old").unwrap().unwrap();
+        assert_eq!(here.receive("old", deleted).unwrap(), Comparison::Equal);
+    }
+}
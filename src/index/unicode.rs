@@ -0,0 +1,20 @@
+use std::borrow::Cow;
+
+use icu_normalizer::ComposingNormalizerBorrowed;
+
+/// `path` in NFC, the Unicode form the index keeps paths in. macOS writes names decomposed, in
+/// NFD, and Linux writes them as they come, so the same name can be two different ones on disk.
+pub fn normalize(path: &str) -> Cow<'_, str> {
+    ComposingNormalizerBorrowed::new_nfc().normalize(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composes_decomposed_names() {
+        assert_eq!(normalize("Cafe\u{301}/re\u{301}sume\u{301}.txt"), "Caf\u{e9}/r\u{e9}sum\u{e9}.txt");
+        assert!(matches!(normalize("Caf\u{e9}.txt"), Cow::Borrowed(_)));
+    }
+}
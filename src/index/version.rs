@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+/// How the versions of a file on two devices relate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    /// Made after seeing the other version, which it replaces.
+    Newer,
+    Older,
+    /// Made apart from the other version, on devices that each changed the file without seeing
+    /// the change of the other, so the two conflict.
+    Concurrent,
+}
+
+/// How many times each device changed a file, which carries which changes a version was made
+/// after whichever way the changes went between devices.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector {
+    /// The counter of every device that changed the file, by device id.
+    counters: Vec<(String, u64)>,
+}
+
+impl VersionVector {
+    pub fn counter(&self, device: &str) -> u64 {
+        self.counters.iter().find(|(id, _)| id == device).map_or(0, |(_, counter)| *counter)
+    }
+
+    /// Counts a change `device` made to the file.
+    pub fn increment(&mut self, device: &str) {
+        match self.counters.binary_search_by(|(id, _)| id.as_str().cmp(device)) {
+            Ok(index) => self.counters[index].1 += 1,
+            Err(index) => self.counters.insert(index, (device.to_string(), 1)),
+        }
+    }
+
+    /// Takes in the changes `other` counts, as when a version made after both is.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (device, counter) in &other.counters {
+            match self.counters.binary_search_by(|(id, _)| id.cmp(device)) {
+                Ok(index) => self.counters[index].1 = self.counters[index].1.max(*counter),
+                Err(index) => self.counters.insert(index, (device.clone(), *counter)),
+            }
+        }
+    }
+
+    /// How this version relates to `other`.
+    pub fn compare(&self, other: &VersionVector) -> Comparison {
+        let devices = self.counters.iter().chain(&other.counters).map(|(device, _)| device);
+        let (mut ahead, mut behind) = (false, false);
+        for device in devices {
+            let (own, theirs) = (self.counter(device), other.counter(device));
+            ahead |= own > theirs;
+            behind |= own < theirs;
+        }
+        match (ahead, behind) {
+            (false, false) => Comparison::Equal,
+            (true, false) => Comparison::Newer,
+            (false, true) => Comparison::Older,
+            (true, true) => Comparison::Concurrent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_newer_older_and_concurrent_versions_apart() {
+        let mut base = VersionVector::default();
+        base.increment("a");
+        let mut on_a = base.clone();
+        on_a.increment("a");
+        let mut on_b = base.clone();
+        on_b.increment("b");
+
+        assert_eq!(base.compare(&base.clone()), Comparison::Equal);
+        assert_eq!(on_a.compare(&base), Comparison::Newer);
+        assert_eq!(base.compare(&on_b), Comparison::Older);
+        assert_eq!(on_a.compare(&on_b), Comparison::Concurrent);
+
+        // The version that resolves the conflict comes after both.
+        let mut resolved = on_a.clone();
+        resolved.merge(&on_b);
+        resolved.increment("c");
+        assert_eq!((resolved.counter("a"), resolved.counter("b"), resolved.counter("c")), (2, 1, 1));
+        assert_eq!(resolved.compare(&on_a), Comparison::Newer);
+        assert_eq!(on_b.compare(&resolved), Comparison::Older);
+    }
+}
@@ -1,7 +1,358 @@
+use std::collections::HashSet;
+use std::process::exit;
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::Duration;
+
+use clap::ArgMatches;
+use log::{debug, info, warn};
+use structopt::StructOpt;
+
+use crate::broadcast::{
+    get_ip_addrs, spawn_announcer, spawn_dht, spawn_global_discovery, spawn_interface_monitor, spawn_listener, spawn_mdns,
+    spawn_peer_cache, spawn_peer_expiry, spawn_port_mapping, PeerTable,
+};
+use crate::config::{
+    dump_config, first_run_setup, generate_config, index_path, load_program_data, program_data, Command, ConfigHandle,
+    Device, Options, RunCommand, Source,
+};
+use crate::index::{Conflict, Index};
+use crate::scan::{lower_priority, scan, ScanSettings, ScanThrottle};
+use crate::sync::{shares, spawn_sync};
+use crate::transfer::local_path;
+use crate::transport::{spawn_connection_manager, spawn_status_log, spawn_status_server, ConnectionManager};
+use crate::versions::spawn_version_cleaner;
+use crate::watcher::{spawn_watcher, Change};
+
 mod config;
 mod broadcast;
+mod hash;
+mod ignore;
+mod index;
+mod protocol;
+mod scan;
+mod sync;
+mod transfer;
+mod transport;
+mod versions;
+mod watcher;
+mod xattr;
 
 const PROJECT_NAME: &str = "simple-simple-sync";
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+/// Status `run --once` exits with when a folder couldn't be brought up to date.
+const EXIT_FAILED: i32 = 1;
+/// Status `run --once` exits with when a folder has conflicts left to resolve.
+const EXIT_CONFLICTS: i32 = 2;
+/// How long `run --once` waits for a device to be connected to before it gives up on it.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() {
-}
\ No newline at end of file
+    env_logger::init();
+
+    let matches = Options::clap().get_matches();
+    let (options, sources) = load(&matches);
+
+    if options.dump_config() {
+        print!("{}", dump_config(&options, &sources));
+        return;
+    }
+    if options.generate_config() {
+        match generate_config(&options) {
+            Ok(path) => println!("Wrote {}", path.display()),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    // Only the commands that talk to peers need the identity of this device.
+    if matches!(options.command(), None | Some(Command::Run(_)) | Some(Command::Discover(_))) {
+        if let Err(e) = load_program_data() {
+            eprintln!("error: {}", e);
+            exit(1);
+        }
+    }
+
+    let result = match options.command() {
+        Some(Command::Config(command)) => command.run(&options),
+        Some(Command::Folder(command)) => command.run(&options),
+        Some(Command::Secret(command)) => command.run(),
+        Some(Command::Discover(command)) => {
+            command.run(ConfigHandle::new(load(&matches).0, matches.clone()));
+            return;
+        }
+        Some(Command::DiscoveryServer(command)) => {
+            if let Err(e) = command.run() {
+                eprintln!("error: {}", e);
+                exit(1);
+            }
+            return;
+        }
+        Some(Command::Relay(command)) => {
+            if let Err(e) = command.run() {
+                eprintln!("error: {}", e);
+                exit(1);
+            }
+            return;
+        }
+        Some(Command::Status(command)) => {
+            if let Err(e) = command.run(&options) {
+                eprintln!("error: {}", e);
+                exit(1);
+            }
+            return;
+        }
+        Some(Command::Run(RunCommand { once: true })) => {
+            exit(run_once(ConfigHandle::new(load(&matches).0, matches.clone())))
+        }
+        None | Some(Command::Run(_)) => match first_run_setup(&options) {
+            Ok(true) => return run(load(&matches).0, matches),
+            Ok(false) => return run(options, matches),
+            Err(e) => Err(e),
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
+
+/// Brings the index of the folder with id `id` up to date with the files in it, returning
+/// whether it could be, which a paused one needn't be.
+fn scan_folder(options: &Options, index: &Index, id: &str, throttle: &Arc<ScanThrottle>) -> bool {
+    let folder = match options.folders().iter().find(|folder| folder.id == id) {
+        Some(folder) if folder.paused => {
+            debug!("Not scanning folder {}, it is paused", folder.label());
+            return true;
+        }
+        Some(folder) => folder,
+        None => return true,
+    };
+    let settings = ScanSettings::of(folder, options, throttle);
+    let scanned = index.folder(id).and_then(|files| scan(&files, &folder.path, &settings));
+    match scanned {
+        Ok(scanned) => {
+            info!("Scanned folder {}, {} files of which {} changed and {} removed", folder.label(), scanned.files,
+                scanned.changed, scanned.removed);
+            true
+        }
+        Err(e) => {
+            warn!("Unable to scan folder {}: {}", folder.label(), e);
+            false
+        }
+    }
+}
+
+/// Brings every folder up to date once for `run --once`, with the files in it and with every
+/// device it is shared with that can be connected to, returning the status to exit with, 0 where
+/// they all are and have no conflicts left.
+fn run_once(config: ConfigHandle) -> i32 {
+    let options = config.current();
+    let index = match index_path().map(|path| Index::open(&path).map_err(|e| (path, e))) {
+        Some(Ok(index)) => index,
+        Some(Err((path, e))) => {
+            eprintln!("error: unable to open the index at {}: {}", path.display(), e);
+            return EXIT_FAILED;
+        }
+        None => {
+            eprintln!("error: no directory to keep the index in");
+            return EXIT_FAILED;
+        }
+    };
+    let throttle = Arc::new(ScanThrottle::of(&options));
+    let (mut failed, mut conflicts) = (false, false);
+    for folder in options.folders() {
+        if !scan_folder(&options, &index, &folder.id, &throttle) {
+            eprintln!("error: unable to scan folder {}", folder.label());
+            failed = true;
+        }
+    }
+
+    let devices: Vec<&Device> = options.devices().iter()
+        .filter(|device| options.folders().iter().any(|folder| shares(folder, device)))
+        .collect();
+    if !devices.is_empty() {
+        let (_, manager) = spawn_connections(&config);
+        let sync = spawn_sync(config.clone(), index.clone(), manager);
+        let ids: Vec<String> = devices.iter().map(|device| device.id.to_string()).collect();
+        let synced = sync.wait_synced(&ids, CONNECT_TIMEOUT);
+        for device in devices.iter().filter(|device| !synced.contains(&device.id.to_string())) {
+            eprintln!("Unable to sync with device {}", device.name());
+        }
+        if synced.is_empty() {
+            eprintln!("error: unable to sync with any device");
+            failed = true;
+        }
+        for (folder, path) in sync.failed() {
+            eprintln!("error: unable to pull {} in folder {}", path, folder);
+            failed = true;
+        }
+    }
+
+    for folder in options.folders() {
+        // A conflict is resolved by dealing with its copy, which is then gone.
+        let left = |conflict: &Conflict| local_path(&folder.path, &conflict.copy).exists();
+        let count = index.folder(&folder.id).and_then(|files| files.conflicts().collect::<Result<Vec<_>, _>>())
+            .map(|conflicts| conflicts.iter().filter(|conflict| left(conflict)).count());
+        match count {
+            Ok(0) => {}
+            Ok(count) => {
+                eprintln!("Folder {} has {} conflicts left to resolve", folder.label(), count);
+                conflicts = true;
+            }
+            Err(e) => {
+                eprintln!("error: folder {}: {}", folder.label(), e);
+                failed = true;
+            }
+        }
+    }
+    if let Err(e) = index.flush() {
+        eprintln!("error: unable to write the index: {}", e);
+        failed = true;
+    }
+    match (failed, conflicts) {
+        (true, _) => EXIT_FAILED,
+        (_, true) => EXIT_CONFLICTS,
+        _ => 0,
+    }
+}
+
+/// Starts finding the devices on the network and connecting to them, returning the peers found
+/// and the connections to them.
+fn spawn_connections(config: &ConfigHandle) -> (PeerTable, ConnectionManager) {
+    let peers = PeerTable::new();
+    let interfaces = spawn_interface_monitor(config.clone(), peers.clone());
+    spawn_announcer(config.clone(), interfaces.clone());
+    spawn_listener(config.clone(), peers.clone(), interfaces);
+    spawn_mdns(config.clone(), peers.clone());
+    spawn_global_discovery(config.clone(), peers.clone());
+    spawn_dht(config.clone(), peers.clone());
+    let manager = spawn_connection_manager(config.clone(), peers.clone());
+    spawn_status_log(manager.clone());
+    (peers, manager)
+}
+
+fn load(matches: &ArgMatches) -> (Options, Vec<(String, Source)>) {
+    match Options::load(matches) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn run(options: Options, matches: ArgMatches<'static>) {
+    let data = program_data();
+    info!("Device {} with key {}, first seen at {}", options.device_id(),
+        hex::encode(data.verifying_key().as_bytes()), data.first_seen());
+    if options.device_id() != data.device_id() {
+        warn!("Device id overridden, it isn't {} derived from the device key, so peers refuse to connect",
+            data.device_id());
+    }
+
+    if let Some(bind) = options.bind_interface() {
+        let (ipv4, ipv6) = get_ip_addrs(&options);
+        if ipv4.is_empty() && ipv6.is_empty() {
+            warn!("No addresses found for interface {}", bind);
+        } else {
+            info!("Bound to interface {} with addresses {:?} {:?}", bind, ipv4, ipv6);
+        }
+    }
+
+    let index = index_path().map(|path| Index::open(&path).map_err(|e| (path, e)));
+    let index = match index {
+        Some(Ok(index)) => Some(index),
+        Some(Err((path, e))) => {
+            warn!("Unable to open the index at {}, folders are scanned in full: {}", path.display(), e);
+            None
+        }
+        None => None,
+    };
+    for folder in options.folders() {
+        info!("Sharing folder {} ({}) at {}", folder.label(), folder.mode, folder.path.display());
+        if folder.paused {
+            info!("Folder {} is paused", folder.label());
+        }
+        debug!("Folder {} settings: {:?}", folder.id, folder.settings(&options));
+        debug!("Folder {} ignore patterns: {:?}", folder.id, folder.ignore_patterns(&options));
+        match index.as_ref().map(|index| index.folder(&folder.id)) {
+            Some(Ok(files)) if files.is_empty() => debug!("Folder {} has no files in the index yet", folder.id),
+            Some(Ok(files)) => debug!("Folder {} has {} files in the index", folder.id, files.len()),
+            Some(Err(e)) => warn!("Folder {}: {}", folder.id, e),
+            None => {}
+        }
+        if folder.password.as_ref().is_some_and(|password| password.value().is_none()) {
+            warn!("Folder {} password could not be resolved", folder.id);
+        }
+    }
+
+    for peer in data.cached_peers() {
+        info!("Last saw device {} ({}) at {:?} port {}", peer.device_name, peer.device_id, peer.addresses, peer.port);
+    }
+
+    for device in options.devices() {
+        let limits = options.bandwidth_limits(device.id);
+        info!("Known device {} ({}), send limit {} kbps, receive limit {} kbps", device.name(), device.id,
+            limits.send_kbps, limits.recv_kbps);
+        if device.untrusted {
+            info!("Device {} is untrusted, it only gets folders encrypted", device.name());
+            for folder in options.folders().iter().filter(|folder| folder.password.is_none()) {
+                warn!("Folder {} has no password, so it can't be shared with device {}", folder.id, device.name());
+            }
+        }
+    }
+
+    let config = ConfigHandle::new(options, matches);
+    let reloads = config.subscribe();
+    config.watch(CONFIG_WATCH_INTERVAL);
+    spawn_port_mapping(config.clone());
+    let (peers, manager) = spawn_connections(&config);
+    spawn_status_server(&config.current(), manager.clone());
+    match &index {
+        Some(index) => {
+            spawn_sync(config.clone(), index.clone(), manager);
+        }
+        None => warn!("Not syncing with any device without an index"),
+    }
+    spawn_peer_cache(peers.clone());
+    spawn_peer_expiry(peers);
+    spawn_version_cleaner(config.clone());
+    let changes = spawn_watcher(config.clone());
+    let forgetting = index.clone();
+    let scanning = config.clone();
+    let throttle = Arc::new(ScanThrottle::of(&config.current()));
+    let scan_throttle = throttle.clone();
+    spawn(move || {
+        if scanning.current().low_priority_scan() {
+            if let Err(e) = lower_priority() {
+                debug!("Scanning at the usual priority: {}", e);
+            }
+        }
+        for change in changes {
+            info!("{}", change);
+            if let (Change::Rescan, Some(index)) = (&change.change, &index) {
+                scan_folder(&scanning.current(), index, &change.folder, &scan_throttle);
+            }
+        }
+    });
+
+    let mut shared: HashSet<String> = config.current().folders().iter().map(|folder| folder.id.clone()).collect();
+    for options in reloads {
+        info!("Running with {:?}", options);
+        throttle.update(&options);
+        let folders: HashSet<String> = options.folders().iter().map(|folder| folder.id.clone()).collect();
+        if let Some(index) = &forgetting {
+            for id in shared.difference(&folders) {
+                info!("Forgetting folder {}, it is no longer shared", id);
+                if let Err(e) = index.remove_folder(id) {
+                    warn!("Unable to forget folder {}: {}", id, e);
+                }
+            }
+        }
+        shared = folders;
+    }
+}
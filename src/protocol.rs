@@ -0,0 +1,170 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind, Read, Write};
+
+pub use self::codec::{encode, CodecError, Decoder, Frame, MAX_FRAME_LENGTH};
+pub use self::compress::{compress_frame, decompress_frame, Compression};
+pub use self::hello::{Hello, MIN_SYNC_PROTOCOL_VERSION, PING_PROTOCOL_VERSION, SYNC_PROTOCOL_VERSION};
+pub use self::mux::{Keepalive, Mux, MuxStream, Traffic};
+
+mod codec;
+mod compress;
+mod hello;
+mod mux;
+
+// Tags stay below `COMPRESSED_TAG`, which marks a compressed message.
+
+/// Tag of the [`Hello`] each side starts a connection with.
+pub const HELLO: u8 = 0;
+/// Tags of the [`Mux`] frames that follow it, each starting with the id of its stream.
+pub const OPEN: u8 = 1;
+pub const DATA: u8 = 2;
+/// More window for a stream, as four big endian bytes after the id.
+pub const WINDOW: u8 = 3;
+pub const CLOSE: u8 = 4;
+/// A ping on an idle connection, answered with a pong that echoes its eight bytes. Neither has a
+/// stream id.
+pub const PING: u8 = 5;
+pub const PONG: u8 = 6;
+
+/// Largest hello that is read, far more than any device needs to say.
+const MAX_HELLO_LENGTH: usize = 64 * 1024;
+
+/// What a [`MuxStream`] carries, told by the byte the side opening it writes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    /// The changes to the index of the side that opened it.
+    Index,
+    /// Requests for blocks, which the side that accepted it answers.
+    Blocks,
+}
+
+impl StreamKind {
+    fn id(self) -> u8 {
+        match self {
+            StreamKind::Index => 0,
+            StreamKind::Blocks => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    Codec(CodecError),
+    Encode(String),
+    Decode(String),
+    /// A message with this tag where another was expected.
+    Unexpected(u8),
+    /// The other side speaks protocol versions `min_version` to `version`, none of which this
+    /// release does.
+    IncompatibleVersion { version: u32, min_version: u32 },
+    /// The other side said hello as another device than its certificate is for.
+    WrongDevice { expected: String, found: String },
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "{}", e),
+            ProtocolError::Codec(e) => write!(f, "{}", e),
+            ProtocolError::Encode(e) => write!(f, "unable to encode message: {}", e),
+            ProtocolError::Decode(e) => write!(f, "invalid message: {}", e),
+            ProtocolError::Unexpected(tag) => write!(f, "unexpected message with tag {}", tag),
+            ProtocolError::IncompatibleVersion { version, min_version } if version == min_version =>
+                write!(f, "peer runs incompatible protocol version {}, this release supports versions {} to {}",
+                    version, MIN_SYNC_PROTOCOL_VERSION, SYNC_PROTOCOL_VERSION),
+            ProtocolError::IncompatibleVersion { version, min_version } =>
+                write!(f, "peer runs incompatible protocol versions {} to {}, this release supports versions {} to {}",
+                    min_version, version, MIN_SYNC_PROTOCOL_VERSION, SYNC_PROTOCOL_VERSION),
+            ProtocolError::WrongDevice { expected, found } =>
+                write!(f, "device {} said hello as device {}", expected, found),
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+impl From<CodecError> for ProtocolError {
+    fn from(e: CodecError) -> Self {
+        ProtocolError::Codec(e)
+    }
+}
+
+pub fn write_frame(writer: &mut (impl Write + ?Sized), frame: &Frame) -> Result<(), ProtocolError> {
+    let mut bytes = Vec::new();
+    encode(frame, &mut bytes)?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads from `reader` until `decoder` has a whole frame, `None` if the stream ended between
+/// frames. Bytes read past the frame stay in the decoder for the next one.
+pub fn read_frame(reader: &mut (impl Read + ?Sized), decoder: &mut Decoder) -> Result<Option<Frame>, ProtocolError> {
+    let mut buffer = [0; 4096];
+    loop {
+        if let Some(frame) = decoder.decode()? {
+            return Ok(Some(frame));
+        }
+        match reader.read(&mut buffer) {
+            Ok(0) => return decoder.finish().map(|()| None).map_err(ProtocolError::from),
+            Ok(read) => decoder.feed(&buffer[..read]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Reads exactly one frame from `reader` and nothing past it, for messages after which the stream
+/// carries something else. Frames longer than `max_length` are refused from their length.
+pub fn read_one_frame(reader: &mut (impl Read + ?Sized), max_length: usize) -> Result<Frame, ProtocolError> {
+    let mut decoder = Decoder::with_max_length(max_length);
+    let mut prefix = [0; 4];
+    reader.read_exact(&mut prefix)?;
+    decoder.feed(&prefix);
+    if let Some(frame) = decoder.decode()? {
+        return Ok(frame);
+    }
+    let mut rest = vec![0; u32::from_be_bytes(prefix) as usize];
+    reader.read_exact(&mut rest)?;
+    decoder.feed(&rest);
+    Ok(decoder.decode()?.expect("frame is complete"))
+}
+
+/// Opens a stream on `mux` for what `kind` says it carries.
+pub fn open_stream(mux: &Mux, kind: StreamKind) -> io::Result<MuxStream> {
+    let mut stream = mux.open()?;
+    stream.write_all(&[kind.id()])?;
+    Ok(stream)
+}
+
+/// Reads what a stream the other side opened with [`open_stream`] carries.
+pub fn accept_stream(stream: &mut MuxStream) -> Result<StreamKind, ProtocolError> {
+    let mut id = [0];
+    stream.read_exact(&mut id)?;
+    match id[0] {
+        0 => Ok(StreamKind::Index),
+        1 => Ok(StreamKind::Blocks),
+        id => Err(ProtocolError::Unexpected(id)),
+    }
+}
+
+/// Sends `own` hello over a new connection to the device `device_id` and reads its hello back,
+/// returning it with the protocol version both sides settled on. Both sides send first, so a side
+/// that gives up on the other still tells it why.
+pub fn exchange_hello<S: Read + Write + ?Sized>(stream: &mut S, own: &Hello, device_id: &str)
+    -> Result<(Hello, u32), ProtocolError> {
+    write_frame(stream, &own.to_frame()?)?;
+    // The peer may start on its streams as soon as it has this side's hello.
+    let frame = read_one_frame(stream, MAX_HELLO_LENGTH)?;
+    let peer = Hello::from_frame(&frame)?;
+    if peer.device_id != device_id {
+        return Err(ProtocolError::WrongDevice { expected: device_id.to_string(), found: peer.device_id });
+    }
+    let version = own.negotiate(&peer)?;
+    Ok((peer, version))
+}
@@ -0,0 +1,177 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+
+/// Largest frame, tag included, that is decoded. Anything longer is refused from its length,
+/// before any of it is buffered.
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+/// Bytes of the length every frame starts with.
+const LENGTH_BYTES: usize = 4;
+
+/// One message on a connection: a tag telling what kind of message it is, and its encoded body. On
+/// the wire it is the length of the tag and payload as four big endian bytes, then the tag byte,
+/// then the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub tag: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(tag: u8, payload: Vec<u8>) -> Frame {
+        Frame { tag, payload }
+    }
+
+    /// Bytes the frame takes encoded.
+    pub fn encoded_length(&self) -> usize {
+        LENGTH_BYTES + 1 + self.payload.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// A frame of this many bytes, more than the decoder takes.
+    TooLarge(usize),
+    /// A frame without even a tag.
+    Empty,
+    /// The stream ended this many bytes into a frame.
+    Truncated(usize),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::TooLarge(length) => write!(f, "frame of {} bytes is too large", length),
+            CodecError::Empty => write!(f, "frame without a message tag"),
+            CodecError::Truncated(length) => write!(f, "connection closed {} bytes into a frame", length),
+        }
+    }
+}
+
+/// Appends `frame` to `out` as it is sent.
+pub fn encode(frame: &Frame, out: &mut Vec<u8>) -> Result<(), CodecError> {
+    let length = 1 + frame.payload.len();
+    if length > MAX_FRAME_LENGTH {
+        return Err(CodecError::TooLarge(length));
+    }
+    out.reserve(LENGTH_BYTES + length);
+    out.extend_from_slice(&(length as u32).to_be_bytes());
+    out.push(frame.tag);
+    out.extend_from_slice(&frame.payload);
+    Ok(())
+}
+
+/// Turns bytes from a connection back into frames without doing any I/O itself: bytes are fed in
+/// as they arrive, in pieces of any size, and frames are taken out once they are complete.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    max_length: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::with_max_length(MAX_FRAME_LENGTH)
+    }
+
+    /// A decoder that refuses frames longer than `max_length`, for connections that only ever
+    /// carry small messages.
+    pub fn with_max_length(max_length: usize) -> Decoder {
+        Decoder { buffer: Vec::new(), max_length: max_length.min(MAX_FRAME_LENGTH) }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete frame, or `None` until more bytes are fed. After an error the stream
+    /// can't be decoded any further.
+    pub fn decode(&mut self) -> Result<Option<Frame>, CodecError> {
+        if self.buffer.len() < LENGTH_BYTES {
+            return Ok(None);
+        }
+        let prefix = <[u8; LENGTH_BYTES]>::try_from(&self.buffer[..LENGTH_BYTES]).expect("prefix is 4 bytes");
+        let length = u32::from_be_bytes(prefix) as usize;
+        if length == 0 {
+            return Err(CodecError::Empty);
+        }
+        if length > self.max_length {
+            return Err(CodecError::TooLarge(length));
+        }
+        if self.buffer.len() < LENGTH_BYTES + length {
+            return Ok(None);
+        }
+        let tag = self.buffer[LENGTH_BYTES];
+        let payload = self.buffer[LENGTH_BYTES + 1..LENGTH_BYTES + length].to_vec();
+        self.buffer.drain(..LENGTH_BYTES + length);
+        Ok(Some(Frame { tag, payload }))
+    }
+
+    /// Checks that the stream ended between frames.
+    pub fn finish(&self) -> Result<(), CodecError> {
+        match self.buffer.len() {
+            0 => Ok(()),
+            length => Err(CodecError::Truncated(length)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(frames: &[Frame]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for frame in frames {
+            encode(frame, &mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = vec![Frame::new(1, b"hello".to_vec()), Frame::new(2, Vec::new()), Frame::new(255, vec![0; 1000])];
+        let mut decoder = Decoder::new();
+        decoder.feed(&encoded(&frames));
+        for frame in &frames {
+            assert_eq!(decoder.decode().unwrap().as_ref(), Some(frame));
+        }
+        assert_eq!(decoder.decode().unwrap(), None);
+        assert_eq!(decoder.finish(), Ok(()));
+    }
+
+    #[test]
+    fn decodes_bytes_fed_one_at_a_time() {
+        let frame = Frame::new(7, b"split up".to_vec());
+        let mut decoder = Decoder::new();
+        let bytes = encoded(std::slice::from_ref(&frame));
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.feed(&[*byte]);
+            assert_eq!(decoder.decode().unwrap(), None);
+        }
+        assert_eq!(decoder.finish(), Err(CodecError::Truncated(bytes.len() - 1)));
+        decoder.feed(&bytes[bytes.len() - 1..]);
+        assert_eq!(decoder.decode().unwrap(), Some(frame));
+    }
+
+    #[test]
+    fn refuses_oversized_frames_from_their_length() {
+        let mut decoder = Decoder::with_max_length(10);
+        decoder.feed(&11u32.to_be_bytes());
+        assert_eq!(decoder.decode(), Err(CodecError::TooLarge(11)));
+        let oversized = Frame::new(0, vec![0; MAX_FRAME_LENGTH]);
+        assert_eq!(encode(&oversized, &mut Vec::new()), Err(CodecError::TooLarge(MAX_FRAME_LENGTH + 1)));
+    }
+
+    #[test]
+    fn refuses_frames_without_a_tag() {
+        let mut decoder = Decoder::new();
+        decoder.feed(&0u32.to_be_bytes());
+        assert_eq!(decoder.decode(), Err(CodecError::Empty));
+    }
+}
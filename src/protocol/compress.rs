@@ -0,0 +1,227 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Frame, ProtocolError, MAX_FRAME_LENGTH};
+
+/// Set in the tag of a frame whose payload is a compressed block.
+pub const COMPRESSED_TAG: u8 = 0x80;
+/// Blocks shorter than this are sent as they are, compressing them saves next to nothing.
+const MIN_COMPRESS_LENGTH: usize = 128;
+/// How much of a block is looked at to guess whether it compresses.
+const SAMPLE_LENGTH: usize = 4096;
+/// Bits of entropy per byte above which a sample is taken to be compressed or encrypted already.
+const MAX_ENTROPY: f64 = 7.5;
+/// Level zstd compresses at, fast enough to keep up with a network.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Starts of file formats that are compressed already.
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"\x1f\x8b",             // gzip
+    b"PK\x03\x04",           // zip, and the formats built on it
+    b"\x28\xb5\x2f\xfd",     // zstd
+    b"\xfd7zXZ\x00",         // xz
+    b"BZh",                  // bzip2
+    b"7z\xbc\xaf\x27\x1c",   // 7z
+    b"\x04\x22\x4d\x18",     // lz4
+    b"\x89PNG",
+    b"\xff\xd8\xff",         // jpeg
+    b"GIF8",
+    b"OggS",
+    b"fLaC",
+    b"ID3",                  // mp3
+];
+
+/// How transfers are compressed, the first that both devices support out of the ones this device
+/// prefers being used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    Lz4,
+    #[default]
+    Zstd,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression `{}`, use zstd, lz4 or none", s)),
+        }
+    }
+}
+
+impl Compression {
+    /// The compressions a device preferring this one accepts, most preferred first. A device that
+    /// prefers none accepts none, so nothing it receives needs decompressing.
+    pub fn accepted(self) -> Vec<Compression> {
+        match self {
+            Compression::None => Vec::new(),
+            Compression::Lz4 => vec![Compression::Lz4, Compression::Zstd],
+            Compression::Zstd => vec![Compression::Zstd, Compression::Lz4],
+        }
+    }
+
+    /// The compression to send with, the first of `own` that the other side accepts too.
+    pub fn negotiate(own: &[String], peer: &[String]) -> Compression {
+        own.iter().filter(|compression| peer.contains(compression))
+            .find_map(|compression| compression.parse().ok())
+            .unwrap_or(Compression::None)
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+}
+
+/// Compresses a block of a file or message, which starts with a byte telling how it was
+/// compressed. Blocks that look compressed already, or that don't get smaller, are stored as they
+/// are.
+pub fn compress_block(compression: Compression, data: &[u8]) -> Vec<u8> {
+    let compressed = match compression {
+        _ if data.len() < MIN_COMPRESS_LENGTH || looks_compressed(data) => None,
+        Compression::None => None,
+        Compression::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+        Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+    };
+    let mut block = Vec::with_capacity(1 + data.len());
+    match compressed.filter(|compressed| compressed.len() < data.len()) {
+        Some(compressed) => {
+            block.push(compression.id());
+            block.extend_from_slice(&compressed);
+        }
+        None => {
+            block.push(Compression::None.id());
+            block.extend_from_slice(data);
+        }
+    }
+    block
+}
+
+/// Reverses [`compress_block`], refusing blocks that would decompress to more than
+/// [`MAX_FRAME_LENGTH`].
+pub fn decompress_block(block: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let invalid = |e: String| ProtocolError::Decode(format!("invalid compressed block: {}", e));
+    let (&id, data) = block.split_first().ok_or_else(|| invalid("empty block".to_string()))?;
+    match id {
+        0 => Ok(data.to_vec()),
+        1 => {
+            let (length, _) = lz4_flex::block::uncompressed_size(data).map_err(|e| invalid(e.to_string()))?;
+            if length > MAX_FRAME_LENGTH {
+                return Err(invalid(format!("block of {} bytes is too large", length)));
+            }
+            lz4_flex::decompress_size_prepended(data).map_err(|e| invalid(e.to_string()))
+        }
+        2 => zstd::bulk::decompress(data, MAX_FRAME_LENGTH).map_err(|e| invalid(e.to_string())),
+        id => Err(invalid(format!("unknown compression {}", id))),
+    }
+}
+
+/// Compresses the payload of a message, marking it in the tag.
+pub fn compress_frame(compression: Compression, frame: &Frame) -> Frame {
+    if compression == Compression::None {
+        return frame.clone();
+    }
+    Frame::new(frame.tag | COMPRESSED_TAG, compress_block(compression, &frame.payload))
+}
+
+/// Reverses [`compress_frame`], leaving frames that were not compressed as they are.
+pub fn decompress_frame(frame: Frame) -> Result<Frame, ProtocolError> {
+    if frame.tag & COMPRESSED_TAG == 0 {
+        return Ok(frame);
+    }
+    Ok(Frame::new(frame.tag & !COMPRESSED_TAG, decompress_block(&frame.payload)?))
+}
+
+/// Whether `data` is probably compressed or encrypted already, from the file format it starts
+/// with or from how random its first bytes are.
+pub fn looks_compressed(data: &[u8]) -> bool {
+    if COMPRESSED_MAGIC.iter().any(|magic| data.starts_with(magic)) {
+        return true;
+    }
+    let sample = &data[..data.len().min(SAMPLE_LENGTH)];
+    if sample.len() < 256 {
+        return false;
+    }
+    let mut counts = [0usize; 256];
+    for byte in sample {
+        counts[usize::from(*byte)] += 1;
+    }
+    let length = sample.len() as f64;
+    let entropy: f64 = counts.iter().filter(|count| **count > 0).map(|count| {
+        let p = *count as f64 / length;
+        -p * p.log2()
+    }).sum();
+    entropy > MAX_ENTROPY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text() -> Vec<u8> {
+        "the quick brown fox jumps over the lazy dog\n".repeat(200).into_bytes()
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        for compression in &[Compression::None, Compression::Lz4, Compression::Zstd] {
+            let block = compress_block(*compression, &text());
+            if *compression != Compression::None {
+                assert!(block.len() < text().len() / 4);
+            }
+            assert_eq!(decompress_block(&block).unwrap(), text());
+        }
+    }
+
+    #[test]
+    fn stores_compressed_data_as_it_is() {
+        let random: Vec<u8> = (0..10_000).map(|_| rand::random()).collect();
+        assert!(looks_compressed(&random));
+        assert!(!looks_compressed(&text()));
+        assert_eq!(compress_block(Compression::Zstd, &random)[0], Compression::None.id());
+        let gzip = [b"\x1f\x8b".to_vec(), text()].concat();
+        assert_eq!(compress_block(Compression::Zstd, &gzip)[1..], gzip[..]);
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = Frame::new(5, text());
+        let compressed = compress_frame(Compression::Lz4, &frame);
+        assert_eq!(compressed.tag, 5 | COMPRESSED_TAG);
+        assert_eq!(decompress_frame(compressed).unwrap(), frame);
+        assert_eq!(decompress_frame(frame.clone()).unwrap(), frame);
+    }
+
+    #[test]
+    fn negotiates_the_first_shared_compression() {
+        let names = |compressions: Vec<Compression>| -> Vec<String> {
+            compressions.iter().map(ToString::to_string).collect()
+        };
+        let (zstd, lz4) = (names(Compression::Zstd.accepted()), names(Compression::Lz4.accepted()));
+        assert_eq!(Compression::negotiate(&zstd, &lz4), Compression::Zstd);
+        assert_eq!(Compression::negotiate(&lz4, &zstd), Compression::Lz4);
+        assert_eq!(Compression::negotiate(&zstd, &[]), Compression::None);
+        assert_eq!(Compression::negotiate(&zstd, &["brotli".to_string()]), Compression::None);
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+
+use crate::broadcast::capabilities;
+use crate::config::Options;
+use crate::protocol::{Frame, ProtocolError, HELLO};
+
+/// Version of the messages exchanged over a connection, raised when they change in a way older
+/// releases can't follow.
+pub const SYNC_PROTOCOL_VERSION: u32 = 2;
+/// Oldest version this release still speaks, to devices that don't run the latest one.
+pub const MIN_SYNC_PROTOCOL_VERSION: u32 = 1;
+/// First version that pings idle connections. Older releases drop a connection that sends them
+/// a ping.
+pub const PING_PROTOCOL_VERSION: u32 = 2;
+
+/// The first message each side sends on a new connection, saying who it is and what it can do.
+/// Fields added later must have a default, so older messages still decode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    pub min_version: u32,
+    pub device_id: String,
+    pub device_name: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Compressions the device can decompress, the one it prefers first.
+    #[serde(default)]
+    pub compression: Vec<String>,
+}
+
+impl Hello {
+    /// The hello of this device with the current options.
+    pub fn new(options: &Options) -> Hello {
+        Hello {
+            version: SYNC_PROTOCOL_VERSION,
+            min_version: MIN_SYNC_PROTOCOL_VERSION,
+            device_id: options.device_id().to_string(),
+            device_name: options.device_name(),
+            capabilities: capabilities(options),
+            compression: options.compression().accepted().iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    pub fn to_frame(&self) -> Result<Frame, ProtocolError> {
+        let mut payload = Vec::new();
+        ciborium::into_writer(self, &mut payload).map_err(|e| ProtocolError::Encode(e.to_string()))?;
+        Ok(Frame::new(HELLO, payload))
+    }
+
+    pub fn from_frame(frame: &Frame) -> Result<Hello, ProtocolError> {
+        if frame.tag != HELLO {
+            return Err(ProtocolError::Unexpected(frame.tag));
+        }
+        ciborium::from_reader(frame.payload.as_slice()).map_err(|e| ProtocolError::Decode(e.to_string()))
+    }
+
+    /// The version both sides speak, the newest one they share, once `peer` said hello.
+    pub fn negotiate(&self, peer: &Hello) -> Result<u32, ProtocolError> {
+        let version = self.version.min(peer.version);
+        if version < self.min_version.max(peer.min_version) {
+            return Err(ProtocolError::IncompatibleVersion {
+                version: peer.version,
+                min_version: peer.min_version,
+            });
+        }
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(version: u32, min_version: u32) -> Hello {
+        Hello {
+            version,
+            min_version,
+            device_id: "device".to_string(),
+            device_name: "name".to_string(),
+            capabilities: Vec::new(),
+            compression: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn hello_round_trips() {
+        let hello = Hello::new(&Options::default());
+        assert_eq!(Hello::from_frame(&hello.to_frame().unwrap()).unwrap(), hello);
+        assert!(matches!(Hello::from_frame(&Frame::new(HELLO + 1, Vec::new())), Err(ProtocolError::Unexpected(_))));
+    }
+
+    #[test]
+    fn negotiates_the_newest_shared_version() {
+        assert_eq!(hello(3, 1).negotiate(&hello(2, 2)).unwrap(), 2);
+        assert_eq!(hello(2, 2).negotiate(&hello(3, 1)).unwrap(), 2);
+        assert!(matches!(hello(3, 3).negotiate(&hello(2, 1)),
+            Err(ProtocolError::IncompatibleVersion { version: 2, min_version: 1 })));
+        assert!(matches!(hello(2, 1).negotiate(&hello(5, 4)),
+            Err(ProtocolError::IncompatibleVersion { version: 5, min_version: 4 })));
+    }
+}
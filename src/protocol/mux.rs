@@ -0,0 +1,704 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{read_frame, write_frame, Decoder, Frame, ProtocolError, CLOSE, DATA, OPEN, PING, PONG, WINDOW};
+use crate::transport::Stream;
+
+/// How many bytes of a stream may be on their way before the other side reads them. A stream
+/// whose reader falls behind stops there, and leaves the connection to the others.
+pub const STREAM_WINDOW: u32 = 256 * 1024;
+/// Largest piece of a stream sent at once, so that streams with data to send take turns in small
+/// steps.
+const CHUNK_LENGTH: usize = 16 * 1024;
+/// How long the rates of a connection are measured over.
+const RATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// When a connection is pinged, and when it is given up on. A connection nothing came over for
+/// `interval` is pinged, again every `interval` until something comes, and closed once nothing did
+/// for `timeout`, long before TCP would notice the other side is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive { interval: Duration::from_secs(10), timeout: Duration::from_secs(45) }
+    }
+}
+
+/// What went over a connection, in frames and the bytes they took before encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Traffic {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// Bytes sent a second over the last few seconds.
+    pub send_rate: u64,
+    /// Bytes received a second over the last few seconds.
+    pub receive_rate: u64,
+}
+
+/// Many streams over one connection, such as one for control messages, one for exchanging an
+/// index and one for every file being transferred. Each stream has a window of its own, and
+/// streams with data ready take turns, so one large transfer doesn't hold up the rest.
+///
+/// Streams the dialing side opens have odd ids and those the other side opens even ones, so both
+/// can open streams at once. Dropping a stream closes it for both sides.
+#[derive(Clone)]
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    streams: HashMap<u32, Channel>,
+    next_id: u32,
+    /// Streams the other side opened that were not accepted yet.
+    incoming: VecDeque<u32>,
+    /// Frames sent ahead of any data, which are opens and window updates.
+    control: VecDeque<Frame>,
+    /// Streams with data to send, in the order they take turns.
+    ready: VecDeque<u32>,
+    closed: bool,
+    /// What the connection failed with, if it did not close cleanly.
+    error: Option<String>,
+    /// When the last frame came from the other side.
+    last_received: Option<Instant>,
+    pings_sent: u64,
+    /// The ping waiting for its pong, and when it was sent.
+    ping: Option<(u64, Instant)>,
+    /// Round trip time of the last ping answered.
+    rtt: Option<Duration>,
+    sent: Meter,
+    received: Meter,
+}
+
+/// Counts the frames going one way, and how fast their bytes went over the last
+/// [`RATE_INTERVAL`].
+#[derive(Default)]
+struct Meter {
+    bytes: u64,
+    frames: u64,
+    /// When the bytes of the current interval started to be counted, and how many came since.
+    interval: Option<(Instant, u64)>,
+    /// Bytes a second over the last interval.
+    rate: u64,
+}
+
+impl Meter {
+    fn add(&mut self, frame: &Frame) {
+        let (now, length) = (Instant::now(), frame.encoded_length() as u64);
+        self.bytes += length;
+        self.frames += 1;
+        let (started, bytes) = self.interval.get_or_insert((now, 0));
+        *bytes += length;
+        let elapsed = now.duration_since(*started);
+        if elapsed >= RATE_INTERVAL {
+            self.rate = (*bytes as f64 / elapsed.as_secs_f64()) as u64;
+            self.interval = Some((now, 0));
+        }
+    }
+
+    /// The rate over the last interval, or over the current one once it ran longer, so a
+    /// connection that went quiet drops to nothing.
+    fn rate(&self) -> u64 {
+        match self.interval {
+            Some((started, bytes)) if started.elapsed() >= RATE_INTERVAL =>
+                (bytes as f64 / started.elapsed().as_secs_f64()) as u64,
+            _ => self.rate,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Channel {
+    received: VecDeque<u8>,
+    /// Most bytes the other side may have on their way, [`STREAM_WINDOW`] unless raised.
+    window: u32,
+    /// Bytes read since the other side was last given more window.
+    unacknowledged: u32,
+    send_window: u32,
+    /// Pieces to send, with `None` for the close that follows them.
+    outgoing: VecDeque<Option<Vec<u8>>>,
+    /// The stream was dropped on this side.
+    local_closed: bool,
+    remote_closed: bool,
+}
+
+impl Mux {
+    /// Multiplexes `stream`, with `dialer` telling whether this device dialed the connection.
+    pub fn new(stream: Stream, dialer: bool) -> Mux {
+        Mux::start(stream, dialer, None)
+    }
+
+    /// Multiplexes `stream` like [`Mux::new`], and pings it while idle, closing it once the other
+    /// side stops answering. The other side must speak a version that knows pings.
+    pub fn with_keepalive(stream: Stream, dialer: bool, keepalive: Keepalive) -> Mux {
+        Mux::start(stream, dialer, Some(keepalive))
+    }
+
+    fn start(stream: Stream, dialer: bool, keepalive: Option<Keepalive>) -> Mux {
+        let state = State { next_id: if dialer { 1 } else { 2 }, last_received: Some(Instant::now()), ..State::default() };
+        let shared = Arc::new(Shared { state: Mutex::new(state), changed: Condvar::new() });
+        let (reader, writer) = stream.split();
+        let (receiving, sending) = (shared.clone(), shared.clone());
+        spawn(move || receiving.receive_all(reader));
+        spawn(move || sending.send_all(writer));
+        if let Some(keepalive) = keepalive {
+            let pinging = shared.clone();
+            spawn(move || pinging.keep_alive(keepalive));
+        }
+        Mux { shared }
+    }
+
+    pub fn open(&self) -> io::Result<MuxStream> {
+        let mut state = self.shared.lock();
+        state.check_open()?;
+        let id = state.next_id;
+        state.next_id += 2;
+        state.streams.insert(id, Channel::new());
+        state.control.push_back(Frame::new(OPEN, id.to_be_bytes().to_vec()));
+        self.shared.changed.notify_all();
+        Ok(MuxStream { id, shared: self.shared.clone() })
+    }
+
+    /// Waits for the other side to open a stream, `None` once the connection closed.
+    pub fn accept(&self) -> Option<MuxStream> {
+        let mut state = self.shared.lock();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(id) = state.incoming.pop_front() {
+                return Some(MuxStream { id, shared: self.shared.clone() });
+            }
+            state = self.shared.wait(state);
+        }
+    }
+
+    /// Waits for the connection to close, returning the error it failed with, if any.
+    pub fn wait_closed(&self) -> Option<String> {
+        let mut state = self.shared.lock();
+        while !state.closed {
+            state = self.shared.wait(state);
+        }
+        state.error.clone()
+    }
+
+    /// Waits up to `timeout` for every stream to close, returning whether they did, or the
+    /// connection closed.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        while !state.closed && !state.streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.shared.changed.wait_timeout(state, deadline - now).expect("multiplexer lock poisoned").0;
+        }
+        true
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+
+    /// Round trip time to the other side as of the last ping it answered, `None` before any was.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.shared.lock().rtt
+    }
+
+    /// What went over the connection so far.
+    pub fn traffic(&self) -> Traffic {
+        let state = self.shared.lock();
+        Traffic {
+            bytes_sent: state.sent.bytes,
+            bytes_received: state.received.bytes,
+            frames_sent: state.sent.frames,
+            frames_received: state.received.frames,
+            send_rate: state.sent.rate(),
+            receive_rate: state.received.rate(),
+        }
+    }
+}
+
+/// The same connection, rather than two that look alike.
+impl PartialEq for Mux {
+    fn eq(&self, other: &Mux) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+}
+
+impl Debug for Mux {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let state = self.shared.lock();
+        f.debug_struct("Mux").field("streams", &state.streams.len()).field("closed", &state.closed).finish()
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("multiplexer lock poisoned")
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.changed.wait(state).expect("multiplexer lock poisoned")
+    }
+
+    fn close(&self, error: Option<String>) {
+        let mut state = self.lock();
+        if !state.closed {
+            state.closed = true;
+            state.error = error;
+        }
+        self.changed.notify_all();
+    }
+
+    fn receive_all(&self, mut reader: Box<dyn Read + Send>) {
+        let mut decoder = Decoder::new();
+        loop {
+            let received = read_frame(&mut reader, &mut decoder).and_then(|frame| match frame {
+                Some(frame) => self.receive(frame).map(|()| true),
+                None => Ok(false),
+            });
+            match received {
+                Ok(true) => {}
+                Ok(false) => return self.close(None),
+                Err(e) => return self.close(Some(e.to_string())),
+            }
+            if self.lock().closed {
+                return;
+            }
+        }
+    }
+
+    fn receive(&self, frame: Frame) -> Result<(), ProtocolError> {
+        let invalid = |reason: &str| ProtocolError::Decode(reason.to_string());
+        let mut state = self.lock();
+        state.last_received = Some(Instant::now());
+        state.received.add(&frame);
+        if let PING | PONG = frame.tag {
+            let nonce = <[u8; 8]>::try_from(frame.payload.as_slice()).map_err(|_| invalid("ping without a nonce"))?;
+            if frame.tag == PING {
+                state.control.push_back(Frame::new(PONG, nonce.to_vec()));
+            } else if let Some((sent, at)) = state.ping {
+                if sent == u64::from_be_bytes(nonce) {
+                    state.rtt = Some(at.elapsed());
+                    state.ping = None;
+                }
+            }
+            self.changed.notify_all();
+            return Ok(());
+        }
+        if frame.payload.len() < 4 {
+            return Err(invalid("frame without a stream id"));
+        }
+        let (id, body) = frame.payload.split_at(4);
+        let id = u32::from_be_bytes(<[u8; 4]>::try_from(id).expect("id is 4 bytes"));
+        match frame.tag {
+            OPEN => {
+                if id % 2 == state.next_id % 2 || state.streams.contains_key(&id) {
+                    return Err(invalid("stream opened with an id that is taken"));
+                }
+                state.streams.insert(id, Channel::new());
+                state.incoming.push_back(id);
+            }
+            DATA => {
+                if let Some(channel) = state.streams.get_mut(&id) {
+                    if channel.received.len() + body.len() > channel.window as usize {
+                        return Err(invalid("stream sent more than its window"));
+                    }
+                    if !channel.local_closed {
+                        channel.received.extend(body);
+                    }
+                }
+            }
+            WINDOW => {
+                let credit = <[u8; 4]>::try_from(body).map_err(|_| invalid("window update without a size"))?;
+                if let Some(channel) = state.streams.get_mut(&id) {
+                    channel.send_window = channel.send_window.saturating_add(u32::from_be_bytes(credit));
+                }
+            }
+            CLOSE => {
+                if let Some(channel) = state.streams.get_mut(&id) {
+                    channel.remote_closed = true;
+                }
+                state.forget(id);
+            }
+            tag => return Err(ProtocolError::Unexpected(tag)),
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    fn send_all(&self, mut writer: Box<dyn Write + Send>) {
+        loop {
+            let frame = {
+                let mut state = self.lock();
+                loop {
+                    if state.closed {
+                        return;
+                    }
+                    if let Some(frame) = state.control.pop_front().or_else(|| state.next_piece()) {
+                        break frame;
+                    }
+                    state = self.wait(state);
+                }
+            };
+            if let Err(e) = write_frame(&mut writer, &frame) {
+                return self.close(Some(e.to_string()));
+            }
+            self.lock().sent.add(&frame);
+        }
+    }
+
+    fn keep_alive(&self, keepalive: Keepalive) {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return;
+            }
+            let now = Instant::now();
+            let idle = now.duration_since(state.last_received.unwrap_or(now));
+            if idle >= keepalive.timeout {
+                drop(state);
+                return self.close(Some(format!("no answer to pings for {} seconds", idle.as_secs())));
+            }
+            let due = state.ping.is_none_or(|(_, sent)| now.duration_since(sent) >= keepalive.interval);
+            if idle >= keepalive.interval && due {
+                state.pings_sent += 1;
+                let nonce = state.pings_sent;
+                state.ping = Some((nonce, now));
+                state.control.push_back(Frame::new(PING, nonce.to_be_bytes().to_vec()));
+                self.changed.notify_all();
+            }
+            let wait = (keepalive.interval / 4).min(keepalive.timeout - idle);
+            state = self.changed.wait_timeout(state, wait).expect("multiplexer lock poisoned").0;
+        }
+    }
+}
+
+impl State {
+    fn check_open(&self) -> io::Result<()> {
+        match (&self.closed, &self.error) {
+            (false, _) => Ok(()),
+            (true, Some(error)) => Err(io::Error::new(ErrorKind::ConnectionAborted, error.clone())),
+            (true, None) => Err(ErrorKind::ConnectionAborted.into()),
+        }
+    }
+
+    /// The next piece of the stream whose turn it is.
+    fn next_piece(&mut self) -> Option<Frame> {
+        while let Some(id) = self.ready.pop_front() {
+            let channel = match self.streams.get_mut(&id) {
+                Some(channel) => channel,
+                None => continue,
+            };
+            let piece = match channel.outgoing.pop_front() {
+                Some(piece) => piece,
+                None => continue,
+            };
+            if !channel.outgoing.is_empty() {
+                self.ready.push_back(id);
+            }
+            let mut payload = id.to_be_bytes().to_vec();
+            return Some(match piece {
+                Some(data) => {
+                    payload.extend_from_slice(&data);
+                    Frame::new(DATA, payload)
+                }
+                None => {
+                    self.forget(id);
+                    Frame::new(CLOSE, payload)
+                }
+            });
+        }
+        None
+    }
+
+    /// Drops a stream once both sides closed it and the close was sent.
+    fn forget(&mut self, id: u32) {
+        if let Some(channel) = self.streams.get(&id) {
+            if channel.local_closed && channel.remote_closed && channel.outgoing.is_empty() {
+                self.streams.remove(&id);
+            }
+        }
+    }
+}
+
+/// One stream of a [`Mux`].
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel { window: STREAM_WINDOW, send_window: STREAM_WINDOW, ..Channel::default() }
+    }
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Lets the other side have up to `window` bytes on their way rather than [`STREAM_WINDOW`],
+    /// for a stream that has to keep a fast or distant link busy. A window is never made smaller.
+    pub fn set_receive_window(&self, window: u32) {
+        let mut state = self.shared.lock();
+        if let Some(channel) = state.streams.get_mut(&self.id) {
+            if window > channel.window {
+                let mut payload = self.id.to_be_bytes().to_vec();
+                payload.extend_from_slice(&(window - channel.window).to_be_bytes());
+                channel.window = window;
+                state.control.push_back(Frame::new(WINDOW, payload));
+                self.shared.changed.notify_all();
+            }
+        }
+    }
+}
+
+impl Read for MuxStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.shared.lock();
+        loop {
+            let channel = state.streams.get_mut(&self.id).expect("open stream is known");
+            if !channel.received.is_empty() {
+                let length = buf.len().min(channel.received.len());
+                for (byte, received) in buf.iter_mut().zip(channel.received.drain(..length)) {
+                    *byte = received;
+                }
+                channel.unacknowledged += length as u32;
+                if channel.unacknowledged >= channel.window / 2 && !channel.remote_closed {
+                    let mut payload = self.id.to_be_bytes().to_vec();
+                    payload.extend_from_slice(&channel.unacknowledged.to_be_bytes());
+                    channel.unacknowledged = 0;
+                    state.control.push_back(Frame::new(WINDOW, payload));
+                    self.shared.changed.notify_all();
+                }
+                return Ok(length);
+            }
+            if channel.remote_closed {
+                return Ok(0);
+            }
+            state.check_open()?;
+            state = self.shared.wait(state);
+        }
+    }
+}
+
+impl Write for MuxStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.shared.lock();
+        loop {
+            state.check_open()?;
+            let channel = state.streams.get_mut(&self.id).expect("open stream is known");
+            if channel.remote_closed {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            if channel.send_window > 0 {
+                let length = buf.len().min(CHUNK_LENGTH).min(channel.send_window as usize);
+                channel.send_window -= length as u32;
+                channel.outgoing.push_back(Some(buf[..length].to_vec()));
+                if channel.outgoing.len() == 1 {
+                    state.ready.push_back(self.id);
+                }
+                self.shared.changed.notify_all();
+                return Ok(length);
+            }
+            state = self.shared.wait(state);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        if let Some(channel) = state.streams.get_mut(&self.id) {
+            channel.local_closed = true;
+            channel.received.clear();
+            channel.outgoing.push_back(None);
+            if channel.outgoing.len() == 1 {
+                state.ready.push_back(self.id);
+            }
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn sockets() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dialed = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (dialed, listener.accept().unwrap().0)
+    }
+
+    fn stream(socket: TcpStream) -> Stream {
+        Stream::new(socket.try_clone().unwrap(), socket)
+    }
+
+    fn pair() -> (Mux, Mux) {
+        let (dialed, accepted) = sockets();
+        (Mux::new(stream(dialed), true), Mux::new(stream(accepted), false))
+    }
+
+    const KEEPALIVE: Keepalive = Keepalive { interval: Duration::from_millis(20), timeout: Duration::from_millis(200) };
+
+    #[test]
+    fn streams_carry_data_both_ways() {
+        let (dialer, listener) = pair();
+        let sent: Vec<u8> = (0..1_000_000).map(|i| i as u8).collect();
+        let mut stream = dialer.open().unwrap();
+        let writer = {
+            let sent = sent.clone();
+            spawn(move || stream.write_all(&sent).map(|()| stream))
+        };
+        let mut accepted = listener.accept().unwrap();
+        let mut received = vec![0; sent.len()];
+        accepted.read_exact(&mut received).unwrap();
+        assert_eq!(received, sent);
+        let mut stream = writer.join().unwrap().unwrap();
+
+        accepted.write_all(b"thanks").unwrap();
+        drop(accepted);
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"thanks");
+        assert!(stream.write_all(b"more").is_err());
+    }
+
+    #[test]
+    fn a_stalled_stream_leaves_the_others_going() {
+        let (dialer, listener) = pair();
+        let mut large = dialer.open().unwrap();
+        let mut small = dialer.open().unwrap();
+        let writer = spawn(move || large.write_all(&vec![0; 4 * STREAM_WINDOW as usize]).map(|()| large));
+
+        // Nothing reads the large stream, so its writer runs out of window.
+        let _large = listener.accept().unwrap();
+        let mut accepted = listener.accept().unwrap();
+        small.write_all(b"metadata").unwrap();
+        let mut received = [0; 8];
+        accepted.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"metadata");
+        assert!(!writer.is_finished());
+    }
+
+    #[test]
+    fn counts_the_traffic_both_ways() {
+        let (dialer, listener) = pair();
+        let mut stream = dialer.open().unwrap();
+        stream.write_all(&[0; 1000]).unwrap();
+        let mut accepted = listener.accept().unwrap();
+        accepted.read_exact(&mut [0; 1000]).unwrap();
+
+        let start = Instant::now();
+        let settled = || dialer.traffic().bytes_sent == listener.traffic().bytes_received
+            && listener.traffic().bytes_sent == dialer.traffic().bytes_received;
+        while !settled() {
+            assert!(start.elapsed() < Duration::from_secs(5), "frames still on their way");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let (sent, received) = (dialer.traffic(), listener.traffic());
+        // An open frame and the data in one frame.
+        assert!(sent.bytes_sent > 1000 && sent.frames_sent >= 2);
+        assert_eq!((received.frames_received, received.bytes_received), (sent.frames_sent, sent.bytes_sent));
+        assert_eq!(sent.frames_received, received.frames_sent);
+    }
+
+    #[test]
+    fn pings_measure_the_round_trip() {
+        let (dialed, accepted) = sockets();
+        let dialer = Mux::with_keepalive(stream(dialed), true, KEEPALIVE);
+        let listener = Mux::with_keepalive(stream(accepted), false, KEEPALIVE);
+        let start = Instant::now();
+        while dialer.rtt().is_none() || listener.rtt().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5), "no pong came back");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(KEEPALIVE.timeout);
+        assert!(!dialer.is_closed() && !listener.is_closed());
+    }
+
+    #[test]
+    fn closes_a_connection_pings_go_unanswered_on() {
+        // The other side is connected but never answers, as when its network went away.
+        let (dialed, _accepted) = sockets();
+        let dialer = Mux::with_keepalive(stream(dialed), true, KEEPALIVE);
+        let error = dialer.wait_closed().unwrap();
+        assert!(error.starts_with("no answer to pings"), "{}", error);
+        assert!(dialer.open().is_err());
+    }
+
+    #[test]
+    fn waits_for_streams_to_close() {
+        let (dialer, listener) = pair();
+        let stream = dialer.open().unwrap();
+        let accepted = listener.accept().unwrap();
+        assert!(!dialer.wait_idle(Duration::from_millis(50)));
+        let closing = spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(accepted);
+            drop(stream);
+        });
+        assert!(dialer.wait_idle(Duration::from_secs(5)));
+        closing.join().unwrap();
+    }
+
+    #[test]
+    fn raised_windows_let_more_through() {
+        let (dialer, listener) = pair();
+        let mut stream = dialer.open().unwrap();
+        let accepted = listener.accept().unwrap();
+        accepted.set_receive_window(4 * STREAM_WINDOW);
+        // Nothing is read, so all of it fits in the window.
+        let writer = spawn(move || stream.write_all(&vec![0; 3 * STREAM_WINDOW as usize]));
+        let start = std::time::Instant::now();
+        while !writer.is_finished() {
+            assert!(start.elapsed() < Duration::from_secs(5), "the writer ran out of window");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        writer.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn both_sides_open_streams() {
+        let (dialer, listener) = pair();
+        let ids = (dialer.open().unwrap().id(), listener.open().unwrap().id());
+        assert_eq!(ids, (1, 2));
+        assert_eq!(listener.accept().unwrap().id(), 1);
+        assert_eq!(dialer.accept().unwrap().id(), 2);
+    }
+}
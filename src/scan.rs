@@ -0,0 +1,628 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{read_dir, rename, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{available_parallelism, scope};
+use std::time::SystemTime;
+
+use log::{debug, warn};
+
+use crate::config::{Folder, Options, SymlinkPolicy};
+use crate::hash::{index_file, index_link};
+use crate::ignore::{IgnorePatterns, Selection};
+use crate::hash::Hash;
+use crate::index::{fold_case, normalize, FileEntry, FolderIndex, IndexError};
+use crate::transfer::{index_name, local_path, Chunking};
+use crate::transport::TokenBucket;
+
+use self::walk::Directories;
+
+mod walk;
+
+/// What a scan of a folder found.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Scanned {
+    /// Files synced in the folder.
+    pub files: usize,
+    /// Files that changed since the last scan, or are new.
+    pub changed: usize,
+    /// Files the index had that are gone or are no longer synced.
+    pub removed: usize,
+    /// Files that are gone where one with the same contents came, taken for moved there.
+    pub moved: usize,
+}
+
+/// How a folder is scanned.
+#[derive(Debug, Clone)]
+pub struct ScanSettings {
+    pub ignore: IgnorePatterns,
+    /// The files that are pulled, the others only known from peers so they stay in the index when
+    /// they aren't there.
+    pub selection: Selection,
+    pub chunking: Chunking,
+    pub symlinks: SymlinkPolicy,
+    pub ignore_permissions: bool,
+    pub xattrs: bool,
+    pub auto_normalize: bool,
+    /// The device the files that changed are changed by, this one.
+    pub device: String,
+    pub throttle: Arc<ScanThrottle>,
+    /// Workers that read directories and hash files at once.
+    pub threads: usize,
+}
+
+impl ScanSettings {
+    pub fn of(folder: &Folder, options: &Options, throttle: &Arc<ScanThrottle>) -> ScanSettings {
+        let settings = folder.settings(options);
+        ScanSettings {
+            ignore: folder.ignore_patterns(options),
+            selection: folder.selection(),
+            chunking: settings.chunking,
+            symlinks: settings.symlinks,
+            ignore_permissions: settings.ignore_permissions,
+            xattrs: settings.xattrs,
+            auto_normalize: settings.auto_normalize,
+            device: options.device_id().to_string(),
+            throttle: throttle.clone(),
+            threads: match options.scan_threads() {
+                0 => available_parallelism().map_or(1, usize::from),
+                threads => threads as usize,
+            },
+        }
+    }
+}
+
+/// How fast and how many files at once scans of every folder hash, so that they leave the disk
+/// and processors to other programs too.
+#[derive(Debug)]
+pub struct ScanThrottle {
+    bucket: TokenBucket,
+    /// Files hashed at once at most.
+    hashers: AtomicUsize,
+    hashing: Mutex<usize>,
+    done: Condvar,
+}
+
+impl ScanThrottle {
+    pub fn of(options: &Options) -> ScanThrottle {
+        let throttle = ScanThrottle::default();
+        throttle.update(options);
+        throttle
+    }
+
+    /// Takes up changed limits, for scans going on as well.
+    pub fn update(&self, options: &Options) {
+        self.bucket.set_rate(u64::from(options.max_scan_mbps()) * 1_000_000);
+        let hashers = match options.scan_hashers() {
+            0 => available_parallelism().map_or(1, usize::from),
+            hashers => hashers as usize,
+        };
+        self.hashers.store(hashers, Ordering::Relaxed);
+        self.done.notify_all();
+    }
+
+    /// Waits for a file to be hashed with as few others at once as the limit allows, until the
+    /// [`Hashing`] returned goes.
+    pub fn hashing(&self) -> Hashing<'_> {
+        let mut hashing = self.hashing.lock().expect("scan throttle lock poisoned");
+        while *hashing >= self.hashers.load(Ordering::Relaxed) {
+            hashing = self.done.wait(hashing).expect("scan throttle lock poisoned");
+        }
+        *hashing += 1;
+        Hashing { throttle: self }
+    }
+
+    /// `reader` read no faster than the limit allows.
+    pub fn reader<R: Read>(&self, reader: R) -> Throttled<'_, R> {
+        Throttled { reader, bucket: &self.bucket }
+    }
+}
+
+impl Default for ScanThrottle {
+    /// No limits at all.
+    fn default() -> Self {
+        ScanThrottle { bucket: TokenBucket::new(0), hashers: AtomicUsize::new(usize::MAX), hashing: Mutex::new(0),
+            done: Condvar::new() }
+    }
+}
+
+/// A file being hashed, until it is dropped.
+#[derive(Debug)]
+pub struct Hashing<'a> {
+    throttle: &'a ScanThrottle,
+}
+
+impl Drop for Hashing<'_> {
+    fn drop(&mut self) {
+        *self.throttle.hashing.lock().expect("scan throttle lock poisoned") -= 1;
+        self.throttle.done.notify_one();
+    }
+}
+
+#[derive(Debug)]
+pub struct Throttled<'a, R> {
+    reader: R,
+    bucket: &'a TokenBucket,
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let allowed = self.bucket.take(buffer.len());
+        self.reader.read(&mut buffer[..allowed])
+    }
+}
+
+/// Lowers the processor and disk priority of the calling thread, for one that only scans.
+#[cfg(target_os = "linux")]
+pub fn lower_priority() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    // Both only apply to the calling thread when given its id, or 0 for it.
+    let idle = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, idle) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, 10) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lower_priority() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "scans can't have a lower priority on this system"))
+}
+
+/// Indexes the files in the folder at `root` that are synced, and takes out of `index` the ones
+/// that aren't there anymore. Ignored directories aren't read at all, and neither are linked ones
+/// outside the folder when following links. As many directories are read at once as the settings
+/// have threads.
+pub fn scan(index: &FolderIndex, root: &Path, settings: &ScanSettings) -> Result<Scanned, IndexError> {
+    let walk = Walk {
+        index,
+        root,
+        canonical_root: root.canonicalize()?,
+        settings,
+        directories: Directories::new(settings.threads, PathBuf::new()),
+        read: Mutex::new(HashSet::new()),
+    };
+    walk.read.lock().expect("scan lock poisoned").insert(walk.canonical_root.clone());
+    let found: Vec<Result<Found, IndexError>> = scope(|scope| {
+        let walk = &walk;
+        let workers: Vec<_> = (0..walk.directories.workers()).map(|worker| scope.spawn(move || walk.run(worker)))
+            .collect();
+        workers.into_iter().map(|worker| worker.join().expect("scan worker panicked")).collect()
+    });
+    let mut scanned = Scanned::default();
+    let mut seen = HashSet::new();
+    // Files that weren't in the index, which may have been moved from files that are gone.
+    let mut created = Vec::new();
+    for found in found {
+        let found = found?;
+        scanned.files += found.files;
+        scanned.changed += found.changed;
+        seen.extend(found.seen);
+        created.extend(found.created);
+    }
+    // In the same order however the directories were split between workers.
+    created.sort_by(|(a, _), (b, _)| a.cmp(b));
+    // A file received as a copy because it only differs in case from one here isn't there, but
+    // isn't gone either.
+    let folded: HashSet<String> = seen.iter().map(|path| fold_case(path)).collect();
+    let collided: HashSet<String> = index.conflicts().map(|conflict| conflict.map(|conflict| conflict.path))
+        .collect::<Result<_, _>>()?;
+    let kept = |path: &str| {
+        seen.contains(path) || !settings.selection.is_selected(Path::new(path))
+            || (collided.contains(path) && folded.contains(&fold_case(path)))
+    };
+    let gone: Vec<(String, FileEntry)> = index.entries().filter(|item| match item {
+        Ok((path, entry)) => !entry.deleted && !kept(path),
+        Err(_) => true,
+    }).collect::<Result<_, _>>()?;
+    // Empty files all have the same contents, so which one moved where can't be told.
+    let mut moved_from: HashMap<_, Vec<&str>> = HashMap::new();
+    for (path, entry) in gone.iter().filter(|(_, entry)| entry.size() > 0) {
+        moved_from.entry(contents(entry)).or_default().push(path);
+    }
+    let mut moved = HashSet::new();
+    for (path, entry) in &created {
+        if let Some(from) = moved_from.get_mut(&contents(entry)).and_then(Vec::pop) {
+            debug!("{} was moved to {}", from, path);
+            index.record_move(from, path)?;
+            moved.insert(from);
+            scanned.moved += 1;
+        }
+    }
+    for (path, _) in gone.iter().filter(|(path, _)| !moved.contains(path.as_str())) {
+        // A file still there is only no longer synced, which peers needn't hear of, and one that
+        // isn't was deleted, which they delete too.
+        match local_path(root, path).symlink_metadata() {
+            Ok(_) => {
+                debug!("{} is no longer synced", path);
+                index.remove(path)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("{} was deleted", path);
+                index.delete(path, &settings.device, SystemTime::now())?;
+            }
+            Err(e) => {
+                warn!("Unable to scan {}: {}", path, e);
+                continue;
+            }
+        }
+        scanned.removed += 1;
+    }
+    Ok(scanned)
+}
+
+/// What a worker of a scan found in the directories it read.
+#[derive(Debug, Default)]
+struct Found {
+    files: usize,
+    changed: usize,
+    seen: HashSet<String>,
+    created: Vec<(String, FileEntry)>,
+}
+
+/// A scan of a folder going on, shared by its workers.
+struct Walk<'a> {
+    index: &'a FolderIndex,
+    root: &'a Path,
+    canonical_root: PathBuf,
+    settings: &'a ScanSettings,
+    directories: Directories,
+    /// Where the directories read are, so that a link to one of them isn't followed round again.
+    read: Mutex<HashSet<PathBuf>>,
+}
+
+impl Walk<'_> {
+    /// Reads directories as `worker` until every one is read, stopping the others if one fails.
+    fn run(&self, worker: usize) -> Result<Found, IndexError> {
+        let mut found = Found::default();
+        while let Some(directory) = self.directories.next(worker) {
+            let result = self.read_directory(worker, &directory, &mut found);
+            self.directories.done();
+            if let Err(e) = result {
+                self.directories.stop();
+                return Err(e);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Indexes the files in `directory` into `found` and queues the directories in it.
+    fn read_directory(&self, worker: usize, directory: &Path, found: &mut Found) -> Result<(), IndexError> {
+        let (index, root, settings) = (self.index, self.root, self.settings);
+        let entries = match read_dir(root.join(directory)) {
+            Ok(entries) => entries,
+            // The folder itself has to be there, anything in it may have just gone.
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !directory.as_os_str().is_empty() => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let relative = match self.normalized(directory, entry.file_name()) {
+                Some(name) => directory.join(name),
+                None => continue,
+            };
+            let mut file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    warn!("Unable to scan {}: {}", relative.display(), e);
+                    continue;
+                }
+            };
+            let mut link = false;
+            if file_type.is_symlink() {
+                match settings.symlinks {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Link => link = true,
+                    SymlinkPolicy::Follow => match follow(&self.canonical_root, &root.join(&relative)) {
+                        Some((target, metadata)) => {
+                            if metadata.is_dir() && !self.read.lock().expect("scan lock poisoned").insert(target) {
+                                debug!("Not following {} to a directory that was read", relative.display());
+                                continue;
+                            }
+                            file_type = metadata.file_type();
+                        }
+                        None => {
+                            warn!("Not following {} to outside of the folder", relative.display());
+                            continue;
+                        }
+                    },
+                }
+            }
+            if !settings.ignore.is_synced(&relative, file_type.is_dir()) {
+                continue;
+            }
+            if file_type.is_dir() {
+                self.directories.push(worker, relative);
+            } else if file_type.is_file() || link {
+                let path = index_path(&relative);
+                found.files += 1;
+                let known = index.get(&path)?.is_some_and(|entry| !entry.deleted);
+                let indexed = match link {
+                    true => index_link(index, root, &path, &settings.device),
+                    false => index_file(index, root, &path, settings),
+                };
+                match indexed {
+                    Ok(Some(entry)) => {
+                        found.changed += 1;
+                        if !known {
+                            found.created.push((path.clone(), entry));
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(IndexError::Read(e)) => warn!("Unable to scan {}: {}", relative.display(), e),
+                    Err(e) => return Err(e),
+                }
+                found.seen.insert(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// `name` in `directory` in the form the index keeps it in, renaming the file to it where it
+    /// differs and the settings allow. `None` where it is left out instead.
+    fn normalized(&self, directory: &Path, name: OsString) -> Option<OsString> {
+        let normal = match name.to_str().map(normalize) {
+            Some(Cow::Owned(normal)) => normal,
+            _ => return Some(name),
+        };
+        let (from, to) = (self.root.join(directory).join(&name), self.root.join(directory).join(&normal));
+        match (from.symlink_metadata(), to.symlink_metadata()) {
+            // The file system takes either form for the same name, as the ones of macOS do.
+            (Ok(from), Ok(to)) if same_file(&from, &to) => Some(normal.into()),
+            (_, Ok(_)) => {
+                warn!("Not scanning {}, a file with the same name in another Unicode form is there too",
+                    from.display());
+                None
+            }
+            (_, Err(e)) if e.kind() == io::ErrorKind::NotFound && self.settings.auto_normalize => {
+                match rename(&from, &to) {
+                    Ok(()) => {
+                        debug!("Renamed {} to the NFC form of its name", from.display());
+                        Some(normal.into())
+                    }
+                    Err(e) => {
+                        warn!("Unable to rename {} to the NFC form of its name: {}", from.display(), e);
+                        None
+                    }
+                }
+            }
+            (_, Err(e)) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("Not scanning {}, its name isn't in NFC", from.display());
+                None
+            }
+            (_, Err(e)) => {
+                warn!("Unable to scan {}: {}", from.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Whether `a` and `b` are of the same file.
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Metadata, _b: &Metadata) -> bool {
+    false
+}
+
+/// What tells the contents of a file from others, the same for a file that was moved.
+fn contents(entry: &FileEntry) -> (Hash, u64, Option<&str>) {
+    (entry.blocks.hash, entry.size(), entry.symlink.as_deref())
+}
+
+/// Where the link at `path` leads in the end and what is there, `None` if it is outside the
+/// folder at `root` or nowhere.
+fn follow(root: &Path, path: &Path) -> Option<(PathBuf, Metadata)> {
+    let target = path.canonicalize().ok()?;
+    if !target.starts_with(root) {
+        return None;
+    }
+    let metadata = target.metadata().ok()?;
+    Some((target, metadata))
+}
+
+/// `relative` as the index names it, with `/` between its components on every system.
+fn index_path(relative: &Path) -> String {
+    relative.iter().map(|component| index_name(&component.to_string_lossy()).into_owned()).collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_file, write};
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use tempfile::tempdir;
+
+    use crate::index::{Comparison, Index};
+
+    use super::*;
+
+    fn settings(ignore: &[&str]) -> ScanSettings {
+        ScanSettings {
+            ignore: IgnorePatterns::new(ignore),
+            selection: Selection::default(),
+            chunking: Chunking::Fixed,
+            symlinks: SymlinkPolicy::Skip,
+            ignore_permissions: false,
+            xattrs: false,
+            auto_normalize: true,
+            device: "device".to_string(),
+            throttle: Default::default(),
+            threads: 4,
+        }
+    }
+
+    #[test]
+    fn indexes_the_files_that_are_synced() {
+        let directory = tempdir().unwrap();
+        let root = directory.path().join("folder");
+        create_dir_all(root.join("dir/build")).unwrap();
+        create_dir_all(root.join(".sync-versions")).unwrap();
+        for path in ["a.txt", "dir/b.txt", "dir/c.tmp", "dir/build/out", ".sync-versions/a~20210314-152653.txt",
+            "dir/.ss-partial.d.txt"] {
+            write(root.join(path), path).unwrap();
+        }
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        let mut settings = settings(&["*.tmp", "build/"]);
+
+        let scanned = scan(&folder, &root, &settings).unwrap();
+        assert_eq!(scanned, Scanned { files: 2, changed: 2, removed: 0, moved: 0 });
+        let known = folder.get("dir/b.txt").unwrap().unwrap();
+        assert_eq!(scan(&folder, &root, &settings).unwrap().changed, 0);
+
+        // A file that is deleted is kept as deleted, and one ignored from then on leaves the index.
+        remove_file(root.join("a.txt")).unwrap();
+        write(root.join("dir/b.txt"), "changed").unwrap();
+        settings.ignore = IgnorePatterns::new(&["*.tmp", "build/", "dir/"]);
+        let scanned = scan(&folder, &root, &settings).unwrap();
+        assert_eq!(scanned, Scanned { files: 0, changed: 0, removed: 2, moved: 0 });
+        assert!(folder.get("a.txt").unwrap().unwrap().deleted);
+        assert_eq!(folder.len(), 1);
+
+        // Files that aren't pulled stay known while they aren't there.
+        folder.insert("unselected/e.txt", known).unwrap();
+        settings.selection = Selection::new(&["dir/"]);
+        assert_eq!(scan(&folder, &root, &settings).unwrap().removed, 0);
+        assert_eq!(folder.len(), 2);
+
+        // Nor do files kept in a copy for only differing in case from one that is there.
+        write(root.join("dir/D.txt"), "d").unwrap();
+        settings.ignore = IgnorePatterns::new(&["*.tmp", "build/"]);
+        scan(&folder, &root, &settings).unwrap();
+        let entry = folder.get("dir/D.txt").unwrap().unwrap();
+        folder.receive_colliding("dir/d.txt", "dir/D.txt", entry, UNIX_EPOCH).unwrap().unwrap();
+        assert_eq!(scan(&folder, &root, &settings).unwrap().removed, 0);
+        remove_file(root.join("dir/D.txt")).unwrap();
+        assert_eq!(scan(&folder, &root, &settings).unwrap().removed, 2);
+
+        // A file deleted and made again is a version made after the deletion.
+        let deleted = folder.get("a.txt").unwrap().unwrap();
+        write(root.join("a.txt"), "a.txt").unwrap();
+        assert_eq!(scan(&folder, &root, &settings).unwrap().changed, 1);
+        let again = folder.get("a.txt").unwrap().unwrap();
+        assert!(!again.deleted && again.version.compare(&deleted.version) == Comparison::Newer);
+    }
+
+    #[test]
+    fn takes_files_gone_where_the_same_came_for_moved() {
+        let directory = tempdir().unwrap();
+        let root = directory.path().join("folder");
+        create_dir_all(root.join("photos")).unwrap();
+        for path in ["photos/a.jpg", "photos/b.jpg", "photos/empty"] {
+            write(root.join(path), if path.ends_with("empty") { "" } else { path }).unwrap();
+        }
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        let settings = settings(&[]);
+        scan(&folder, &root, &settings).unwrap();
+
+        std::fs::rename(root.join("photos"), root.join("pictures")).unwrap();
+        let scanned = scan(&folder, &root, &settings).unwrap();
+        assert_eq!((scanned.moved, scanned.removed), (2, 1));
+        let moved = folder.get("pictures/a.jpg").unwrap().unwrap();
+        assert_eq!(moved.moved_from.as_deref(), Some("photos/a.jpg"));
+        assert_eq!(folder.get("pictures/empty").unwrap().unwrap().moved_from, None);
+        assert!(folder.get("photos/a.jpg").unwrap().unwrap().deleted);
+        assert_eq!(folder.len(), 6);
+    }
+
+    #[test]
+    fn renames_names_to_the_form_the_index_keeps() {
+        let directory = tempdir().unwrap();
+        let root = directory.path().join("folder");
+        let (decomposed, composed) = ("Cafe\u{301}", "Caf\u{e9}");
+        create_dir_all(root.join(decomposed)).unwrap();
+        write(root.join(decomposed).join("menu"), "menu").unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        let mut settings = settings(&[]);
+        settings.auto_normalize = false;
+        assert_eq!(scan(&folder, &root, &settings).unwrap().files, 0);
+
+        settings.auto_normalize = true;
+        assert_eq!(scan(&folder, &root, &settings).unwrap().files, 1);
+        assert!(folder.get(&format!("{}/menu", composed)).unwrap().is_some());
+        assert!(root.join(composed).join("menu").exists());
+
+        // Both forms side by side, which is only where the file system tells them apart.
+        write(root.join(format!("{}.txt", decomposed)), "").unwrap();
+        write(root.join(format!("{}.txt", composed)), "").unwrap();
+        if read_dir(&root).unwrap().count() == 3 {
+            assert_eq!(scan(&folder, &root, &settings).unwrap().files, 2);
+            assert!(root.join(format!("{}.txt", decomposed)).exists());
+        }
+    }
+
+    #[test]
+    fn throttles_reading_and_hashing() {
+        let throttle = Arc::new(ScanThrottle::default());
+        throttle.bucket.set_rate(1_000_000);
+        let start = Instant::now();
+        let mut read = Vec::new();
+        throttle.reader([0u8; 500_000].as_slice()).read_to_end(&mut read).unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(read.len(), 500_000);
+        assert!(elapsed >= Duration::from_millis(200), "took {:?}", elapsed);
+
+        throttle.hashers.store(1, Ordering::Relaxed);
+        let hashing = throttle.hashing();
+        let waiting = throttle.clone();
+        let other = spawn(move || {
+            let _hashing = waiting.hashing();
+            Instant::now()
+        });
+        sleep(Duration::from_millis(100));
+        let released = Instant::now();
+        drop(hashing);
+        assert!(other.join().unwrap() >= released);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_follows_or_keeps_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let directory = tempdir().unwrap();
+        let root = directory.path().join("folder");
+        create_dir_all(root.join("dir")).unwrap();
+        write(root.join("dir/file"), "contents").unwrap();
+        write(directory.path().join("outside"), "secret").unwrap();
+        symlink("dir", root.join("linked")).unwrap();
+        symlink(directory.path().join("outside"), root.join("escape")).unwrap();
+        // A link back up would be read forever if followed each time.
+        symlink("..", root.join("dir/up")).unwrap();
+        let index = Index::open(&directory.path().join("index")).unwrap();
+        let folder = index.folder("folder").unwrap();
+        let mut settings = settings(&[]);
+
+        assert_eq!(scan(&folder, &root, &settings).unwrap().files, 1);
+        settings.symlinks = SymlinkPolicy::Follow;
+        assert_eq!(scan(&folder, &root, &settings).unwrap().files, 2);
+        assert!(folder.get("linked/file").unwrap().is_some() && folder.get("escape").unwrap().is_none());
+
+        settings.symlinks = SymlinkPolicy::Link;
+        let scanned = scan(&folder, &root, &settings).unwrap();
+        assert_eq!((scanned.files, scanned.removed), (4, 1));
+        assert_eq!(folder.get("linked").unwrap().unwrap().symlink.as_deref(), Some("dir"));
+        assert_eq!(folder.get("dir/up").unwrap().unwrap().symlink.as_deref(), Some(".."));
+        assert_eq!(scan(&folder, &root, &settings).unwrap().changed, 0);
+    }
+}
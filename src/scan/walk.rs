@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// How long a worker with nothing to read waits before looking again.
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+/// The directories left to read in a scan, split between workers. Each reads the ones it found
+/// itself depth first, and takes the ones another found first when it runs out, so that they all
+/// keep reading until the whole folder is.
+#[derive(Debug)]
+pub(super) struct Directories {
+    queues: Vec<Mutex<VecDeque<PathBuf>>>,
+    /// Directories queued or being read, none once every one is read.
+    pending: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+impl Directories {
+    /// Directories for `workers` workers to read, starting with `root`.
+    pub fn new(workers: usize, root: PathBuf) -> Directories {
+        let directories = Directories {
+            queues: (0..workers.max(1)).map(|_| Mutex::new(VecDeque::new())).collect(),
+            pending: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+        };
+        directories.push(0, root);
+        directories
+    }
+
+    pub fn workers(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Queues `directory`, which `worker` found, to read.
+    pub fn push(&self, worker: usize, directory: PathBuf) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.queue(worker).push_back(directory);
+    }
+
+    /// The next directory for `worker` to read, waiting for others to find more while they read
+    /// theirs. `None` once every one is read, or the scan is stopped.
+    pub fn next(&self, worker: usize) -> Option<PathBuf> {
+        loop {
+            if self.stopped.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Some(directory) = self.queue(worker).pop_back() {
+                return Some(directory);
+            }
+            let others = (1..self.workers()).map(|offset| (worker + offset) % self.workers());
+            if let Some(directory) = others.filter_map(|other| self.queue(other).pop_front()).next() {
+                return Some(directory);
+            }
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            sleep(IDLE_WAIT);
+        }
+    }
+
+    /// Takes a directory [`Directories::next`] gave for read, after the ones in it are queued.
+    pub fn done(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Stops every worker at the next directory, as when one fails.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn queue(&self, worker: usize) -> MutexGuard<'_, VecDeque<PathBuf>> {
+        self.queues[worker].lock().expect("scan queue lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::scope;
+
+    use super::*;
+
+    #[test]
+    fn reads_every_directory_once() {
+        let directories = Directories::new(4, PathBuf::new());
+        let read: Vec<Vec<PathBuf>> = scope(|scope| {
+            let directories = &directories;
+            let workers: Vec<_> = (0..directories.workers()).map(|worker| scope.spawn(move || {
+                let mut read = Vec::new();
+                while let Some(directory) = directories.next(worker) {
+                    if directory.components().count() < 4 {
+                        for name in ["a", "b", "c"] {
+                            directories.push(worker, directory.join(name));
+                        }
+                    }
+                    read.push(directory);
+                    directories.done();
+                }
+                read
+            })).collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+        let mut read: Vec<PathBuf> = read.into_iter().flatten().collect();
+        read.sort();
+        let count = read.len();
+        read.dedup();
+        assert_eq!(read.len(), count);
+        assert_eq!(count, 1 + 3 + 9 + 27 + 81);
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ConfigHandle, Device, Folder};
+use crate::index::{is_case_insensitive, FileEntry, Index, IndexError};
+use crate::protocol::{
+    accept_stream, compress_frame, decompress_frame, read_frame, write_frame, Compression, Decoder, Frame, Mux,
+    MuxStream, ProtocolError, StreamKind,
+};
+use crate::transfer::{local_path, serve, FolderKey, Hash, LocalBlocks, Served, TransferError};
+use crate::transport::{ConnectionManager, NewConnection};
+
+mod receive;
+mod send;
+
+/// How often the index of every folder is looked at for changes to tell the peers about.
+const SEND_INTERVAL: Duration = Duration::from_secs(1);
+/// Most files told about in one update, so that a large folder goes over in pieces.
+const UPDATE_LENGTH: usize = 100;
+/// How long the exchange with a peer stays where it is before it is taken to be over, long enough
+/// for a change either side made to be sent.
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+// Tag of the frames on an index stream.
+
+/// An [`IndexMessage`], encoded as CBOR.
+const MESSAGE: u8 = 0;
+
+/// What goes over an index stream, from the side that opened it unless said otherwise.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum IndexMessage {
+    /// Files of `folder` that changed, in the order they did, by path.
+    Update { folder: String, files: Vec<(String, FileEntry)> },
+    /// Every change made before it was sent, as round `round` of them.
+    CaughtUp { round: u64 },
+    /// Every change in the round `round` the other side sent is taken in here. Goes the other way,
+    /// over this side's own index stream.
+    Synced { round: u64 },
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    Io(io::Error),
+    Index(IndexError),
+    Protocol(ProtocolError),
+    Transfer(TransferError),
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::Io(e) => write!(f, "{}", e),
+            SyncError::Index(e) => write!(f, "{}", e),
+            SyncError::Protocol(e) => write!(f, "{}", e),
+            SyncError::Transfer(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for SyncError {
+    fn from(e: io::Error) -> Self {
+        SyncError::Io(e)
+    }
+}
+
+impl From<IndexError> for SyncError {
+    fn from(e: IndexError) -> Self {
+        SyncError::Index(e)
+    }
+}
+
+impl From<ProtocolError> for SyncError {
+    fn from(e: ProtocolError) -> Self {
+        SyncError::Protocol(e)
+    }
+}
+
+impl From<TransferError> for SyncError {
+    fn from(e: TransferError) -> Self {
+        SyncError::Transfer(e)
+    }
+}
+
+/// Syncs the folders with every peer the connection manager keeps a connection to. Each side
+/// tells the other of every change to its index over a stream of its own, and pulls the files the
+/// other changed over streams of blocks it opens, from every device that has the same version.
+#[derive(Clone)]
+pub struct Syncer {
+    config: ConfigHandle,
+    index: Index,
+    manager: ConnectionManager,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    peers: HashMap<String, Rounds>,
+    /// The files being pulled, by folder and path, which another pull of the same file waits for.
+    pulling: HashSet<(String, String)>,
+    /// The hash of the version each device last told of, by folder and path.
+    announced: HashMap<(String, String), HashMap<String, Hash>>,
+    /// The files whose last pull failed, by folder and path.
+    failed: HashSet<(String, String)>,
+    /// The key of each folder, with the password it was derived from.
+    keys: HashMap<String, (String, Arc<FolderKey>)>,
+    /// Whether the file system of each folder, by its root, takes names that only differ in case
+    /// for the same name.
+    case_insensitive: HashMap<PathBuf, bool>,
+    /// Where the blocks of the files of each folder are, for the files pulled to take the ones
+    /// they share from there.
+    local_blocks: HashMap<String, Arc<RwLock<LocalBlocks>>>,
+}
+
+/// How far the index exchange with a peer went, in rounds of changes each side sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Rounds {
+    /// The last round sent to the peer, and the last it took in.
+    sent: u64,
+    synced: u64,
+    /// The last round the peer sent that was taken in here, and the last the peer was told of.
+    received: u64,
+    answered: u64,
+}
+
+impl Rounds {
+    /// Whether each side took in every change the other sent and was told so.
+    fn up_to_date(&self) -> bool {
+        self.sent > 0 && self.synced == self.sent && self.received > 0 && self.answered == self.received
+    }
+}
+
+impl Syncer {
+    /// Waits until each of `devices` and this one took in every change the other had, with nothing
+    /// left to pull either way, returning the devices that got there. A device that isn't
+    /// connected to is given up on once `connect_timeout` passed.
+    pub fn wait_synced(&self, devices: &[String], connect_timeout: Duration) -> Vec<String> {
+        let start = Instant::now();
+        // When the exchange with each device was last seen up to date, and how far it went.
+        let mut up_to_date: HashMap<&str, (Rounds, Instant)> = HashMap::new();
+        let mut synced = Vec::new();
+        let mut state = self.lock();
+        loop {
+            let now = Instant::now();
+            let mut waiting = false;
+            for device in devices {
+                if synced.contains(device) {
+                    continue;
+                }
+                let connected = self.manager.mux(device).is_some();
+                let rounds = state.peers.get(device.as_str()).copied()
+                    .filter(|rounds| connected && rounds.up_to_date() && state.pulling.is_empty());
+                match (rounds, up_to_date.get(device.as_str())) {
+                    (Some(rounds), Some((seen, since))) if *seen == rounds => {
+                        if now - *since >= SETTLE_TIME {
+                            synced.push(device.clone());
+                        } else {
+                            waiting = true;
+                        }
+                    }
+                    (Some(rounds), _) => {
+                        up_to_date.insert(device, (rounds, now));
+                        waiting = true;
+                    }
+                    (None, _) => {
+                        up_to_date.remove(device.as_str());
+                        waiting |= connected || now - start < connect_timeout;
+                    }
+                }
+            }
+            if !waiting {
+                return synced;
+            }
+            state = self.shared.changed.wait_timeout(state, SEND_INTERVAL).expect("sync lock poisoned").0;
+        }
+    }
+
+    /// The files whose last pull failed, by folder and path.
+    pub fn failed(&self) -> Vec<(String, String)> {
+        self.lock().failed.iter().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().expect("sync lock poisoned")
+    }
+
+    /// Starts syncing over a connection the connection manager took, which goes on until the
+    /// connection closes or another takes over from it.
+    fn connected(&self, connection: NewConnection) {
+        let NewConnection { device_id, mux, compression } = connection;
+        debug!("Syncing with device {}, {:?}", device_id, self.manager.status(&device_id));
+        // Rounds are counted anew on every connection.
+        self.lock().peers.remove(&device_id);
+        let (sending, sending_mux, sending_id) = (self.clone(), mux.clone(), device_id.clone());
+        spawn(move || {
+            if let Err(e) = sending.send_index(&sending_id, &sending_mux, compression) {
+                debug!("Stopped telling device {} of changes: {}", sending_id, e);
+            }
+        });
+        let accepting = self.clone();
+        spawn(move || accepting.accept_all(&device_id, &mux, compression));
+    }
+
+    /// Handles every stream the peer opens on `mux`, each on a thread of its own, sending with
+    /// `compression`.
+    fn accept_all(&self, device_id: &str, mux: &Mux, compression: Compression) {
+        while let Some(mut stream) = mux.accept() {
+            let (sync, device_id) = (self.clone(), device_id.to_string());
+            spawn(move || {
+                let id = stream.id();
+                let result = match accept_stream(&mut stream) {
+                    Ok(StreamKind::Index) => sync.receive_index(&device_id, stream),
+                    Ok(StreamKind::Blocks) => sync.serve_blocks(&device_id, stream, compression),
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    debug!("Stream {} from device {} failed: {}", id, device_id, e);
+                }
+            });
+        }
+    }
+
+    /// Serves the blocks of the folders shared with the peer, other than of the files in them that
+    /// aren't synced.
+    fn serve_blocks(&self, device_id: &str, stream: MuxStream, compression: Compression) -> Result<(), SyncError> {
+        let options = self.config.current();
+        let device = match options.device(device_id) {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let folders: Vec<Served> = options.folders().iter().filter(|folder| shares(folder, device))
+            .map(|folder| Served { folder: folder.clone(), ignore: folder.ignore_patterns(&options) })
+            .collect();
+        Ok(serve(stream, &folders, device.untrusted, compression)?)
+    }
+
+    /// The key `folder` is encrypted with for untrusted devices, `None` for a folder without a
+    /// password. Keys are only derived again once the password changes, for the time it takes.
+    fn folder_key(&self, folder: &Folder) -> Option<Arc<FolderKey>> {
+        let password = folder.password.as_ref().and_then(|password| password.value())?;
+        match self.lock().keys.get(&folder.id) {
+            Some((derived_from, key)) if derived_from == password => return Some(key.clone()),
+            _ => {}
+        }
+        let key = Arc::new(FolderKey::derive(&folder.id, password));
+        self.lock().keys.insert(folder.id.clone(), (password.to_string(), key.clone()));
+        Some(key)
+    }
+
+    /// Where the blocks of the files in `folder` are, as the index has them when first asked for
+    /// and as the files pulled since changed them.
+    fn local_blocks(&self, folder: &Folder) -> Result<Arc<RwLock<LocalBlocks>>, IndexError> {
+        if let Some(local) = self.lock().local_blocks.get(&folder.id) {
+            return Ok(local.clone());
+        }
+        let mut local = LocalBlocks::default();
+        for item in self.index.folder(&folder.id)?.since(0) {
+            let (path, entry) = item?;
+            if !entry.deleted && entry.symlink.is_none() {
+                local.add(&local_path(&folder.path, &path), &entry.blocks);
+            }
+        }
+        debug!("Found {} blocks in the files of folder {}", local.len(), folder.label());
+        let local = Arc::new(RwLock::new(local));
+        Ok(self.lock().local_blocks.entry(folder.id.clone()).or_insert(local).clone())
+    }
+
+    /// Whether the file system the folder at `root` is on takes names that only differ in case for
+    /// the same name, found out once.
+    fn is_case_insensitive(&self, root: &Path) -> bool {
+        if let Some(insensitive) = self.lock().case_insensitive.get(root) {
+            return *insensitive;
+        }
+        let insensitive = is_case_insensitive(root).unwrap_or_else(|e| {
+            warn!("Unable to tell whether {} tells case apart, taking it that it does: {}", root.display(), e);
+            false
+        });
+        self.lock().case_insensitive.insert(root.to_path_buf(), insensitive);
+        insensitive
+    }
+
+    /// Takes note that the peer sent round `round` of its changes, which are all taken in.
+    fn caught_up(&self, device_id: &str, round: u64) {
+        self.lock().peers.entry(device_id.to_string()).or_default().received = round;
+        self.shared.changed.notify_all();
+    }
+
+    /// Takes note that the peer took in round `round` of the changes sent to it.
+    fn synced(&self, device_id: &str, round: u64) {
+        self.lock().peers.entry(device_id.to_string()).or_default().synced = round;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Whether `folder` is synced with `device`. A paused folder isn't synced with any, and an
+/// untrusted device only gets the folders it can be given encrypted.
+pub fn shares(folder: &Folder, device: &Device) -> bool {
+    !folder.paused && (!device.untrusted || folder.password.is_some())
+}
+
+fn write_message(stream: &mut MuxStream, compression: Compression, message: &IndexMessage)
+    -> Result<(), ProtocolError> {
+    let mut payload = Vec::new();
+    ciborium::into_writer(message, &mut payload).map_err(|e| ProtocolError::Encode(e.to_string()))?;
+    write_frame(stream, &compress_frame(compression, &Frame::new(MESSAGE, payload)))
+}
+
+fn read_message(stream: &mut MuxStream, decoder: &mut Decoder) -> Result<Option<IndexMessage>, ProtocolError> {
+    let frame = match read_frame(stream, decoder)? {
+        Some(frame) => decompress_frame(frame)?,
+        None => return Ok(None),
+    };
+    if frame.tag != MESSAGE {
+        return Err(ProtocolError::Unexpected(frame.tag));
+    }
+    ciborium::from_reader(frame.payload.as_slice()).map(Some).map_err(|e| ProtocolError::Decode(e.to_string()))
+}
+
+/// Spawns the thread that syncs the folders in `index` over every connection `manager` keeps.
+pub fn spawn_sync(config: ConfigHandle, index: Index, manager: ConnectionManager) -> Syncer {
+    let connections = manager.subscribe();
+    let sync = Syncer { config, index, manager, shared: Arc::default() };
+    let syncing = sync.clone();
+    spawn(move || {
+        for connection in connections {
+            syncing.connected(connection);
+        }
+    });
+    sync
+}
@@ -0,0 +1,513 @@
+use std::fs::create_dir_all;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+
+use crate::config::{Folder, FolderMode, FolderSettings, Options, SymlinkPolicy, Versioning};
+use crate::ignore::{IgnorePatterns, Selection};
+use crate::index::{Comparison, Conflict, FileEntry, FolderIndex, Resolution};
+use crate::protocol::{Decoder, Mux, MuxStream};
+use crate::sync::{read_message, shares, IndexMessage, SyncError, Syncer};
+use crate::transfer::{
+    local_path, place_link, place_moved, pull_from, resolve, skipped, Source, Target,
+};
+use crate::versions::Versions;
+
+impl Syncer {
+    /// Takes in the changes the peer tells of over `stream`, pulling the files it changed, until
+    /// it closes the stream.
+    pub(super) fn receive_index(&self, device_id: &str, mut stream: MuxStream) -> Result<(), SyncError> {
+        let mut decoder = Decoder::new();
+        while let Some(message) = read_message(&mut stream, &mut decoder)? {
+            match message {
+                IndexMessage::Update { folder, files } => self.receive_update(device_id, &folder, files)?,
+                IndexMessage::CaughtUp { round } => self.caught_up(device_id, round),
+                IndexMessage::Synced { round } => self.synced(device_id, round),
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_update(&self, device_id: &str, folder_id: &str, files: Vec<(String, FileEntry)>)
+        -> Result<(), SyncError> {
+        let options = self.config.current();
+        let device = match options.device(device_id) {
+            Some(device) if device.untrusted => {
+                debug!("Not taking changes from untrusted device {}, it only keeps the folders encrypted",
+                    device.name());
+                return Ok(());
+            }
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let folder = match options.folders().iter().find(|folder| folder.id == folder_id) {
+            Some(folder) if shares(folder, device) => folder,
+            Some(folder) => {
+                debug!("Not taking changes to folder {} from device {}, it isn't shared with it", folder.label(),
+                    device.name());
+                return Ok(());
+            }
+            None => {
+                debug!("Not taking changes to unknown folder {} from device {}", folder_id, device.name());
+                return Ok(());
+            }
+        };
+        if folder.mode == FolderMode::SendOnly {
+            debug!("Not taking changes to folder {} from device {}, it only sends", folder.label(), device.name());
+            return Ok(());
+        }
+        let mut receiving = Receiving {
+            sync: self,
+            options: &options,
+            own_id: options.device_id().to_string(),
+            device_id,
+            folder,
+            settings: folder.settings(&options),
+            ignore: folder.ignore_patterns(&options),
+            selection: folder.selection(),
+            versions: Versions::of(folder, &options),
+            files: self.index.folder(&folder.id)?,
+        };
+        for (path, remote) in files {
+            self.announce(&folder.id, &path, device_id, &remote);
+            let key = (folder.id.clone(), path.clone());
+            match receiving.receive(&path, remote) {
+                Ok(()) => self.lock().failed.remove(&key),
+                Err(e) => {
+                    warn!("Unable to sync {} in folder {} with device {}: {}", path, folder.label(), device.name(),
+                        e);
+                    self.lock().failed.insert(key)
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Takes note of the version of a file a peer has, for pulling that version from it.
+    fn announce(&self, folder: &str, path: &str, device_id: &str, entry: &FileEntry) {
+        let mut state = self.lock();
+        let devices = state.announced.entry((folder.to_string(), path.to_string())).or_default();
+        match entry.deleted {
+            true => devices.remove(device_id),
+            false => devices.insert(device_id.to_string(), entry.blocks.hash),
+        };
+    }
+
+    /// The connections to the devices that told of the same version as `entry` of the file at
+    /// `path`, the one at `device_id` that told of it last first.
+    fn sources(&self, folder: &str, path: &str, device_id: &str, entry: &FileEntry) -> Vec<Mux> {
+        let mut devices = vec![device_id.to_string()];
+        if let Some(announced) = self.lock().announced.get(&(folder.to_string(), path.to_string())) {
+            devices.extend(announced.iter().filter(|(device, hash)| *device != device_id && **hash == entry.blocks.hash)
+                .map(|(device, _)| device.clone()));
+        }
+        devices.iter().filter_map(|device| self.manager.mux(device)).collect()
+    }
+
+    /// The connections to the untrusted devices `folder` is shared with, which keep every file in
+    /// it encrypted, if perhaps not the same version.
+    fn untrusted_sources(&self, options: &Options, folder: &Folder) -> Vec<Mux> {
+        options.devices().iter().filter(|device| device.untrusted && shares(folder, device))
+            .filter_map(|device| self.manager.mux(&device.id.to_string())).collect()
+    }
+
+    /// Waits for any other pull of the file at `path` to be over, and marks it as being pulled
+    /// until the guard returned is dropped.
+    fn start_pull(&self, folder: &str, path: &str) -> Pulling<'_> {
+        let key = (folder.to_string(), path.to_string());
+        let mut state = self.lock();
+        while state.pulling.contains(&key) {
+            state = self.shared.changed.wait(state).expect("sync lock poisoned");
+        }
+        state.pulling.insert(key.clone());
+        Pulling { sync: self, key }
+    }
+}
+
+/// A file being pulled, which other pulls of wait for until this is dropped.
+struct Pulling<'a> {
+    sync: &'a Syncer,
+    key: (String, String),
+}
+
+impl Drop for Pulling<'_> {
+    fn drop(&mut self) {
+        self.sync.lock().pulling.remove(&self.key);
+        self.sync.shared.changed.notify_all();
+    }
+}
+
+/// The changes a peer made to a folder being taken in.
+struct Receiving<'a> {
+    sync: &'a Syncer,
+    options: &'a Options,
+    own_id: String,
+    device_id: &'a str,
+    folder: &'a Folder,
+    settings: FolderSettings,
+    ignore: IgnorePatterns,
+    selection: Selection,
+    /// Where the versions here go as remote changes replace or delete them.
+    versions: Versions,
+    files: FolderIndex,
+}
+
+impl Receiving<'_> {
+    /// Brings the file at `path` up to the version `remote` of the peer where it is newer than the
+    /// one here, and takes note of it once the file is as it says.
+    fn receive(&mut self, path: &str, remote: FileEntry) -> Result<(), SyncError> {
+        if resolve(&self.folder.path, path).is_none() {
+            warn!("Not syncing {} in folder {}, it leads out of the folder", path, self.folder.label());
+            return Ok(());
+        }
+        if !self.ignore.is_synced(Path::new(path), false) {
+            debug!("Not syncing {} in folder {}, it is ignored", path, self.folder.label());
+            return Ok(());
+        }
+        // Files that aren't selected are only known of.
+        if !self.selection.is_selected(Path::new(path)) {
+            self.files.receive_synced(path, remote, &self.ignore)?;
+            return Ok(());
+        }
+        if skipped(path, self.settings.windows_names) {
+            debug!("Not syncing {}, Windows doesn't allow its name", path);
+            return Ok(());
+        }
+        let local = self.files.get(path)?;
+        let remote = match local.as_ref().map(|local| remote.version.compare(&local.version)) {
+            None | Some(Comparison::Newer) => remote,
+            Some(Comparison::Equal) | Some(Comparison::Older) => return Ok(()),
+            Some(Comparison::Concurrent) => {
+                let policy = &self.settings.conflict_policy;
+                match self.files.resolve(path, &remote, &self.own_id, policy, SystemTime::now())? {
+                    Resolution::Local => {
+                        info!("{} in folder {} changed on device {} too, keeping the version here", path,
+                            self.folder.label(), self.device_id);
+                        return Ok(());
+                    }
+                    Resolution::Remote(entry) => entry,
+                    Resolution::Both(conflict) => return self.keep_both(&conflict, &remote),
+                }
+            }
+        };
+        if self.sync.is_case_insensitive(&self.folder.path) {
+            if let Some(other) = self.files.collision(path)? {
+                return self.receive_colliding(path, &other, remote);
+            }
+        }
+        let target = local_path(&self.folder.path, path);
+        // A file changed here since it was last scanned conflicts with `remote` whatever it
+        // replaces, the change not being known to the index yet.
+        let changed = match target.symlink_metadata() {
+            Ok(metadata) => metadata.is_file() && !self.files.is_hashed(path, &metadata)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        if changed && remote.deleted {
+            debug!("Not deleting {} in folder {}, it changed since it was scanned", path, self.folder.label());
+            return Ok(());
+        }
+        if changed {
+            let conflict = self.files.resolve_conflict(path, &remote, &self.own_id, SystemTime::now())?;
+            return self.keep_both(&conflict, &remote);
+        }
+        if remote.deleted {
+            let removed = match self.versions.remove_deleted(path) {
+                Ok(removed) => removed,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+                Err(e) => return Err(e.into()),
+            };
+            if !removed && self.folder.mode.keeps_deleted() && self.files.keeps(path, &remote)? {
+                debug!("Keeping {} in folder {}, which keeps the files peers delete", path, self.folder.label());
+                return Ok(());
+            }
+            self.sync.local_blocks(self.folder)?.write().expect("local blocks lock poisoned").remove(&target);
+            return self.take(path, remote);
+        }
+        if remote.symlink.is_some() {
+            return self.receive_link(path, &target, remote);
+        }
+        if let Some(from) = self.files.moved(path, &remote)? {
+            if self.receive_moved(&from, path, &target, &remote)? {
+                return Ok(());
+            }
+        }
+        let unchanged = local.as_ref().is_some_and(|local| !local.deleted && local.same_contents(&remote));
+        if unchanged && target.is_file() {
+            Target::of(&target, &remote, &self.settings).update(self.window())?;
+        } else {
+            self.pull(path, path, &remote)?;
+        }
+        self.take(path, remote)
+    }
+
+    /// Moves the file at `from` to `target`, the file at `path`, for `remote` that the peer moved
+    /// there, rather than pulling it again. Returns whether it was, which is only where the file
+    /// is still as the index has it.
+    fn receive_moved(&mut self, from: &str, path: &str, target: &Path, remote: &FileEntry) -> Result<bool, SyncError> {
+        let source = local_path(&self.folder.path, from);
+        match source.symlink_metadata() {
+            Ok(metadata) if metadata.is_file() && self.files.is_hashed(from, &metadata)? => {}
+            _ => return Ok(false),
+        }
+        let _pulling = self.sync.start_pull(&self.folder.id, path);
+        debug!("Moving {} to {} in folder {} as device {} did", from, path, self.folder.label(), self.device_id);
+        self.versions.replace(path, || place_moved(&source, target))?;
+        let local = self.sync.local_blocks(self.folder)?;
+        let mut local = local.write().expect("local blocks lock poisoned");
+        local.remove(&source);
+        local.remove(target);
+        local.add(target, &remote.blocks);
+        drop(local);
+        Target::of(target, remote, &self.settings).update(self.window())?;
+        self.files.receive_moved(from, path, remote.clone())?;
+        self.files.record_hashed(path, &target.metadata()?)?;
+        Ok(true)
+    }
+
+    /// Puts the link `remote` is at `target`, the file at `path`, for a folder that syncs links
+    /// as they are.
+    fn receive_link(&mut self, path: &str, target: &Path, remote: FileEntry) -> Result<(), SyncError> {
+        let link = match &remote.symlink {
+            Some(link) if self.settings.symlinks == SymlinkPolicy::Link => link,
+            _ => {
+                debug!("Not syncing the link {}", path);
+                return Ok(());
+            }
+        };
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        self.versions.replace(path, || place_link(target, link))?;
+        self.files.receive(path, remote)?;
+        Ok(())
+    }
+
+    /// Takes in `remote` for the file at `path` that only differs in case from `other` here, a file
+    /// this file system takes for the same one. It is pulled to a copy rather than over `other`,
+    /// and a deletion leaves `other` where it is.
+    fn receive_colliding(&mut self, path: &str, other: &str, remote: FileEntry) -> Result<(), SyncError> {
+        if remote.deleted {
+            self.files.receive(path, remote)?;
+            return Ok(());
+        }
+        match self.files.receive_colliding(path, other, remote.clone(), SystemTime::now())? {
+            Some(conflict) => self.keep_both(&conflict, &remote),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps both versions of a file after `conflict`, pulling `remote` to the copy next to the
+    /// version here, which the next scan takes note of.
+    fn keep_both(&mut self, conflict: &Conflict, remote: &FileEntry) -> Result<(), SyncError> {
+        if remote.symlink.is_some() || remote.deleted {
+            return Ok(());
+        }
+        self.pull(&conflict.path, &conflict.copy, remote)
+    }
+
+    /// Pulls the version `remote` of the file at `path` to `to`, from every device that has it.
+    fn pull(&mut self, path: &str, to: &str, remote: &FileEntry) -> Result<(), SyncError> {
+        let target = local_path(&self.folder.path, to);
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        let muxes = self.sync.sources(&self.folder.id, path, self.device_id, remote);
+        let untrusted = match self.sync.folder_key(self.folder) {
+            Some(key) => self.sync.untrusted_sources(self.options, self.folder).into_iter()
+                .map(|mux| (mux, key.clone())).collect(),
+            None => Vec::new(),
+        };
+        let sources: Vec<Source> = muxes.iter().map(Source::trusted)
+            .chain(untrusted.iter().map(|(mux, key)| Source::untrusted(mux, key))).collect();
+        if sources.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::NotConnected).into());
+        }
+        let _pulling = self.sync.start_pull(&self.folder.id, to);
+        debug!("Pulling {} in folder {} from {} devices", path, self.folder.label(), sources.len());
+        let into = Target::of(&target, remote, &self.settings);
+        let local = self.sync.local_blocks(self.folder)?;
+        self.versions.replace(to, || {
+            let local = local.read().expect("local blocks lock poisoned");
+            pull_from(&sources, &self.folder.id, path, &remote.blocks, into, &local, self.settings.pull_requests)
+        })?;
+        let mut local = local.write().expect("local blocks lock poisoned");
+        local.remove(&target);
+        local.add(&target, &remote.blocks);
+        drop(local);
+        if self.versions.versioning != Versioning::None {
+            debug!("{} in folder {} has {} old copies", to, self.folder.label(), self.versions.versions(to)?.len());
+        }
+        Ok(())
+    }
+
+    /// Takes note of `remote` for the file at `path` once it is as it says, as just hashed so that
+    /// the next scan doesn't read it again.
+    fn take(&self, path: &str, remote: FileEntry) -> Result<(), SyncError> {
+        let deleted = remote.deleted;
+        self.files.receive(path, remote)?;
+        if !deleted {
+            let metadata = local_path(&self.folder.path, path).metadata()?;
+            self.files.record_hashed(path, &metadata)?;
+        }
+        Ok(())
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.settings.modified_window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_dir, write};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use structopt::StructOpt;
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::config::ConfigHandle;
+    use crate::index::Index;
+    use crate::sync::{Rounds, SETTLE_TIME};
+    use crate::transfer::{Chunking, FileBlocks};
+    use crate::transport::ConnectionManager;
+    use crate::versions::VERSIONS_DIRECTORY;
+
+    const PEER: &str = "6d0b8c7e-8a4e-4d55-9a43-8f4bd5e33f1a";
+
+    /// Syncs the folder `folder` at `root`, whose table in the config file has `settings`, with
+    /// the device `PEER`, and no connection to it. The directory returned holds the config file
+    /// and the index.
+    fn syncing(root: &Path, settings: &str) -> (Syncer, TempDir) {
+        let directory = tempdir().unwrap();
+        let config = directory.path().join("config.toml");
+        write(&config, format!("[[device]]\nid = \"{}\"\n[[folder]]\nid = \"folder\"\npath = {:?}\n{}", PEER,
+            root, settings)).unwrap();
+        let matches = Options::clap().get_matches_from(vec![
+            "simple-sync", "--config-file", config.to_str().unwrap(),
+        ]);
+        let (options, _) = Options::load_from(&matches, &|_| None).unwrap();
+        let sync = Syncer {
+            config: ConfigHandle::new(options, matches),
+            index: Index::open(&directory.path().join("index")).unwrap(),
+            manager: ConnectionManager::default(),
+            shared: Arc::default(),
+        };
+        (sync, directory)
+    }
+
+    /// Takes note of the file at `path` as changed here, the way a scan does.
+    fn scanned(sync: &Syncer, root: &Path, path: &str) -> FileEntry {
+        let files = sync.index.folder("folder").unwrap();
+        let metadata = root.join(path).metadata().unwrap();
+        let blocks = FileBlocks::of(&root.join(path), Chunking::Fixed).unwrap();
+        let own_id = sync.config.current().device_id().to_string();
+        let entry = FileEntry::new(&metadata, blocks, &own_id, None);
+        files.insert(path, entry.clone()).unwrap();
+        files.record_hashed(path, &metadata).unwrap();
+        entry
+    }
+
+    /// `entry` deleted by `PEER`.
+    fn deleted(entry: &FileEntry) -> FileEntry {
+        let mut version = entry.version.clone();
+        version.increment(PEER);
+        FileEntry { deleted: true, modified_by: PEER.to_string(), version, ..entry.clone() }
+    }
+
+    #[test]
+    fn keeps_the_files_peers_delete_as_old_copies() {
+        let root = tempdir().unwrap();
+        write(root.path().join("a.txt"), "a").unwrap();
+        let (sync, _directory) = syncing(root.path(), "versioning = \"trash\"");
+        let entry = scanned(&sync, root.path(), "a.txt");
+
+        sync.receive_update(PEER, "folder", vec![("a.txt".to_string(), deleted(&entry))]).unwrap();
+        assert!(!root.path().join("a.txt").exists());
+        assert_eq!(read_dir(root.path().join(VERSIONS_DIRECTORY)).unwrap().count(), 1);
+        assert!(sync.index.folder("folder").unwrap().get("a.txt").unwrap().unwrap().deleted);
+    }
+
+    #[test]
+    fn leaves_a_file_that_only_differs_in_case_where_it_is() {
+        let root = tempdir().unwrap();
+        write(root.path().join("README.md"), "here").unwrap();
+        let (sync, _directory) = syncing(root.path(), "");
+        sync.lock().case_insensitive.insert(root.path().to_path_buf(), true);
+        let entry = scanned(&sync, root.path(), "README.md");
+
+        sync.receive_update(PEER, "folder", vec![("readme.md".to_string(), deleted(&entry))]).unwrap();
+        let mut remote = FileEntry { deleted: false, ..deleted(&entry) };
+        remote.version.increment(PEER);
+        // With no connection to the peer, the copy can't be pulled.
+        sync.receive_update(PEER, "folder", vec![("readme.md".to_string(), remote)]).unwrap();
+        assert_eq!(std::fs::read_to_string(root.path().join("README.md")).unwrap(), "here");
+        let files = sync.index.folder("folder").unwrap();
+        assert_eq!(files.conflicts().count(), 1);
+        assert!(sync.lock().failed.contains(&("folder".to_string(), "readme.md".to_string())));
+    }
+
+    #[test]
+    fn moves_files_peers_moved_rather_than_pulling_them() {
+        let root = tempdir().unwrap();
+        write(root.path().join("old"), "contents").unwrap();
+        let (sync, _directory) = syncing(root.path(), "");
+        let entry = scanned(&sync, root.path(), "old");
+
+        let mut moved = FileEntry { moved_from: Some("old".to_string()), modified_by: PEER.to_string(), ..entry };
+        moved.version.increment(PEER);
+        // With no connection to the peer, the file could only be moved.
+        sync.receive_update(PEER, "folder", vec![("dir/new".to_string(), moved)]).unwrap();
+        assert!(!root.path().join("old").exists());
+        assert_eq!(std::fs::read_to_string(root.path().join("dir/new")).unwrap(), "contents");
+        let files = sync.index.folder("folder").unwrap();
+        assert!(files.get("old").unwrap().unwrap().deleted);
+        assert!(!files.get("dir/new").unwrap().unwrap().deleted);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn puts_links_as_they_are_where_the_folder_syncs_them() {
+        let root = tempdir().unwrap();
+        let (sync, _directory) = syncing(root.path(), "symlinks = \"link\"");
+        write(root.path().join("file"), "contents").unwrap();
+        let mut link = FileEntry { symlink: Some("file".to_string()), ..scanned(&sync, root.path(), "file") };
+        link.version = Default::default();
+        link.version.increment(PEER);
+
+        sync.receive_update(PEER, "folder", vec![("link".to_string(), link)]).unwrap();
+        assert_eq!(std::fs::read_link(root.path().join("link")).unwrap(), Path::new("file"));
+        let files = sync.index.folder("folder").unwrap();
+        assert_eq!(files.get("link").unwrap().unwrap().symlink.as_deref(), Some("file"));
+    }
+
+    #[test]
+    fn backup_folders_keep_the_files_peers_delete_in_place() {
+        let root = tempdir().unwrap();
+        write(root.path().join("a.txt"), "a").unwrap();
+        let (sync, _directory) = syncing(root.path(), "mode = \"backup\"");
+        let entry = scanned(&sync, root.path(), "a.txt");
+
+        sync.receive_update(PEER, "folder", vec![("a.txt".to_string(), deleted(&entry))]).unwrap();
+        assert_eq!(std::fs::read_to_string(root.path().join("a.txt")).unwrap(), "a");
+        assert!(!root.path().join(VERSIONS_DIRECTORY).exists());
+        let kept = sync.index.folder("folder").unwrap().get("a.txt").unwrap().unwrap();
+        assert!(!kept.deleted);
+        assert_eq!(kept.version, entry.version);
+    }
+
+    #[test]
+    fn gives_up_on_devices_it_is_not_connected_to() {
+        let root = tempdir().unwrap();
+        let (sync, _directory) = syncing(root.path(), "");
+        let rounds = Rounds { sent: 1, synced: 1, received: 1, answered: 1 };
+        sync.lock().peers.insert(PEER.to_string(), rounds);
+        let start = Instant::now();
+        assert!(sync.wait_synced(&[PEER.to_string()], Duration::from_millis(100)).is_empty());
+        assert!(start.elapsed() < SETTLE_TIME);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use log::debug;
+
+use crate::hash::hash_blocks;
+use crate::index::FileEntry;
+use crate::protocol::{open_stream, Compression, Mux, MuxStream, StreamKind};
+use crate::sync::{shares, write_message, IndexMessage, SyncError, Syncer, SEND_INTERVAL, UPDATE_LENGTH};
+use crate::transfer::{local_path, Chunking, FolderKey};
+use crate::xattr::Xattrs;
+
+impl Syncer {
+    /// Tells the peer over a stream of its own of every file in the folders it shares, and then
+    /// of every change as it comes, until `mux` is no longer the connection to it. Every round of
+    /// changes is followed by a [`IndexMessage::CaughtUp`], and the rounds the peer sent are
+    /// answered as they are taken in. Messages are compressed with `compression`.
+    ///
+    /// An untrusted peer is only told of the folders encrypted, as [`encrypt_entry`] has them.
+    pub(super) fn send_index(&self, device_id: &str, mux: &Mux, compression: Compression) -> Result<(), SyncError> {
+        let mut stream = open_stream(mux, StreamKind::Index)?;
+        // The last change of each folder sent.
+        let mut sent: HashMap<String, u64> = HashMap::new();
+        while !mux.is_closed() && self.manager.mux(device_id).as_ref() == Some(mux) {
+            let options = self.config.current();
+            let device = match options.device(device_id) {
+                Some(device) => device,
+                None => return Ok(()),
+            };
+            let mut changed = false;
+            for folder in options.folders().iter().filter(|folder| shares(folder, device)) {
+                let key = match device.untrusted {
+                    true => match self.folder_key(folder) {
+                        Some(key) => Some(key),
+                        None => {
+                            debug!("Not telling device {} of folder {}, its password isn't available", device.name(),
+                                folder.label());
+                            continue;
+                        }
+                    },
+                    false => None,
+                };
+                let files = self.index.folder(&folder.id)?;
+                let last = sent.entry(folder.id.clone()).or_default();
+                if files.sequence()? == *last {
+                    continue;
+                }
+                let ignore = folder.ignore_patterns(&options);
+                let mut update = Vec::new();
+                for item in files.since(*last) {
+                    let (path, entry) = item?;
+                    *last = (*last).max(entry.sequence);
+                    if !ignore.is_synced(Path::new(&path), false) {
+                        continue;
+                    }
+                    let told = match &key {
+                        Some(key) => encrypt_entry(key, &folder.path, path, entry),
+                        None => Some((path, entry)),
+                    };
+                    update.extend(told);
+                    if update.len() == UPDATE_LENGTH {
+                        send_update(&mut stream, compression, &folder.id, std::mem::take(&mut update))?;
+                    }
+                }
+                send_update(&mut stream, compression, &folder.id, update)?;
+                changed = true;
+            }
+            let (round, answer) = self.next_rounds(device_id, changed);
+            if let Some(round) = round {
+                write_message(&mut stream, compression, &IndexMessage::CaughtUp { round })?;
+            }
+            if let Some(round) = answer {
+                write_message(&mut stream, compression, &IndexMessage::Synced { round })?;
+            }
+            let state = self.lock();
+            drop(self.shared.changed.wait_timeout(state, SEND_INTERVAL).expect("sync lock poisoned"));
+        }
+        Ok(())
+    }
+
+    /// The round of changes to tell the peer is over, for the first round or where something
+    /// `changed`, and the round the peer sent to tell it is taken in, if not told yet.
+    fn next_rounds(&self, device_id: &str, changed: bool) -> (Option<u64>, Option<u64>) {
+        let mut state = self.lock();
+        let rounds = state.peers.entry(device_id.to_string()).or_default();
+        let round = (changed || rounds.sent == 0).then(|| {
+            rounds.sent += 1;
+            rounds.sent
+        });
+        let answer = (rounds.received > rounds.answered).then(|| {
+            rounds.answered = rounds.received;
+            rounds.answered
+        });
+        (round, answer)
+    }
+}
+
+/// The entry an untrusted device is told of for the file at `path` in the folder at `root`, under
+/// its encrypted path and with the blocks of the file encrypted, and nothing of what the file held
+/// once it is deleted. `None` for a link, which it isn't told of, and for a file that changed since
+/// it was hashed, which it is told of once it is hashed again.
+fn encrypt_entry(key: &FolderKey, root: &Path, path: String, entry: FileEntry) -> Option<(String, FileEntry)> {
+    if entry.symlink.is_some() {
+        return None;
+    }
+    let blocks = match entry.deleted {
+        true => hash_blocks(&mut io::empty(), Chunking::Fixed).expect("nothing is read"),
+        false => match key.encrypt_blocks(&local_path(root, &path), &entry.blocks) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                debug!("Not telling an untrusted device of {} yet: {}", path, e);
+                return None;
+            }
+        },
+    };
+    let moved_from = entry.moved_from.as_ref().map(|from| key.encrypt_path(from));
+    Some((key.encrypt_path(&path), FileEntry { blocks, moved_from, xattrs: Xattrs::new(), ..entry }))
+}
+
+fn send_update(stream: &mut MuxStream, compression: Compression, folder: &str, files: Vec<(String, FileEntry)>)
+    -> Result<(), SyncError> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    Ok(write_message(stream, compression, &IndexMessage::Update { folder: folder.to_string(), files })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::transfer::{FileBlocks, OVERHEAD};
+
+    #[test]
+    fn untrusted_devices_are_told_of_files_encrypted() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("file");
+        write(&path, "contents".repeat(1000)).unwrap();
+        let blocks = FileBlocks::of(&path, Chunking::Fixed).unwrap();
+        let mut entry = FileEntry::new(&path.metadata().unwrap(), blocks.clone(), "device", None);
+        entry.xattrs.insert("user.comment".to_string(), b"secret".to_vec());
+        entry.moved_from = Some("before".to_string());
+        let key = FolderKey::derive("folder", "password");
+
+        let (encrypted_path, encrypted) = encrypt_entry(&key, directory.path(), "file".to_string(), entry.clone())
+            .unwrap();
+        assert_eq!(key.decrypt_path(&encrypted_path).unwrap(), "file");
+        assert_eq!(key.decrypt_path(encrypted.moved_from.as_ref().unwrap()).unwrap(), "before");
+        assert_eq!(encrypted.blocks.size, blocks.size + u64::from(OVERHEAD));
+        assert_ne!(encrypted.blocks.hash, blocks.hash);
+        assert!(encrypted.xattrs.is_empty());
+        assert_eq!(encrypted.version, entry.version);
+
+        let deleted = FileEntry { deleted: true, ..entry.clone() };
+        let (_, deleted) = encrypt_entry(&key, directory.path(), "file".to_string(), deleted).unwrap();
+        assert_eq!(deleted.blocks.size, 0);
+        let link = FileEntry { symlink: Some("target".to_string()), ..entry };
+        assert!(encrypt_entry(&key, directory.path(), "file".to_string(), link).is_none());
+    }
+}
@@ -0,0 +1,851 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Folder, FolderSettings};
+use crate::hash::read_full;
+use crate::ignore::IgnorePatterns;
+use crate::index::{self, same_time, set_permissions, FileEntry};
+use crate::xattr::{self, Xattrs};
+use crate::protocol::{
+    compress_frame, decompress_frame, open_stream, read_frame, write_frame, Compression, Decoder, Frame, Mux, MuxStream,
+    ProtocolError, StreamKind,
+};
+
+pub use crate::hash::Hash;
+pub use self::chunk::{Chunking, FastCdc};
+pub use self::dedup::LocalBlocks;
+pub use self::delta::{reuse, Rolling};
+pub use self::encrypt::{encrypted_block, FolderKey, OVERHEAD};
+pub use self::names::{index_name, local_path, skipped};
+pub use self::partial::{place_link, place_moved, Partial, PARTIAL_PREFIX};
+
+mod chunk;
+mod dedup;
+mod delta;
+mod encrypt;
+mod names;
+mod partial;
+mod sparse;
+
+/// Size of the blocks files are split into to be transferred, the last block of a file being
+/// shorter.
+pub const BLOCK_SIZE: u32 = 128 * 1024;
+/// Longest block that is sent, far more than any block size.
+const MAX_BLOCK_LENGTH: u32 = 8 * 1024 * 1024;
+/// Largest request that is read.
+const MAX_REQUEST_LENGTH: usize = 64 * 1024;
+
+// Tags of the frames on a stream blocks are pulled over.
+
+/// A request for a block, encoded as CBOR.
+const REQUEST: u8 = 0;
+/// The bytes of the block requested.
+const BLOCK: u8 = 1;
+/// Why the block requested can't be sent, as UTF-8.
+const REFUSED: u8 = 2;
+
+/// Times a block that doesn't match its hash, or a file that doesn't, is pulled before giving up.
+const MAX_ATTEMPTS: u32 = 3;
+
+
+/// The blocks of one version of a file, which tell it from any other version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileBlocks {
+    pub size: u64,
+    /// BLAKE3 hash of the whole file, checked once every block came.
+    pub hash: Hash,
+    /// Length of every block but the last, or their average length where they vary.
+    pub block_size: u32,
+    /// BLAKE3 hash of every block, in order.
+    pub hashes: Vec<Hash>,
+    /// The [`Rolling`] checksum of every block, in order, with which a device finds the blocks it
+    /// already holds wherever they moved to in its files. Blocks that vary in length have none.
+    #[serde(default)]
+    pub weak_hashes: Vec<u32>,
+    /// Where every block starts, for blocks that vary in length.
+    #[serde(default)]
+    pub offsets: Vec<u64>,
+}
+
+impl FileBlocks {
+    /// Reads the file at `path` to split it into blocks with `chunking` and hash them.
+    #[cfg(test)]
+    pub fn of(path: &Path, chunking: Chunking) -> io::Result<FileBlocks> {
+        crate::hash::hash_blocks(&mut File::open(path)?, chunking)
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Whether the blocks were cut where the content says, rather than every `block_size` bytes.
+    pub fn content_defined(&self) -> bool {
+        !self.offsets.is_empty()
+    }
+
+    /// Offset and length of block `index`.
+    pub fn block(&self, index: usize) -> (u64, u32) {
+        if self.content_defined() {
+            let offset = self.offsets[index];
+            let end = self.offsets.get(index + 1).copied().unwrap_or(self.size);
+            return (offset, (end - offset) as u32);
+        }
+        let offset = index as u64 * u64::from(self.block_size);
+        (offset, (self.size - offset).min(u64::from(self.block_size)) as u32)
+    }
+
+    /// A digest of the blocks, which differs between versions of a file.
+    pub fn digest(&self) -> Hash {
+        let mut digest = blake3::Hasher::new();
+        digest.update(&self.size.to_be_bytes());
+        digest.update(&self.block_size.to_be_bytes());
+        for hash in &self.hashes {
+            digest.update(hash);
+        }
+        for offset in &self.offsets {
+            digest.update(&offset.to_be_bytes());
+        }
+        digest.finalize().into()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BlockRequest {
+    folder: String,
+    /// Path of the file from the folder root, with `/` between components.
+    path: String,
+    offset: u64,
+    length: u32,
+    /// Which block of the file it is.
+    #[serde(default)]
+    index: usize,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    Io(io::Error),
+    Protocol(ProtocolError),
+    /// The other side can't send a block, for the reason given.
+    Refused(String),
+    /// What came doesn't match its hash however many times it is pulled.
+    Corrupt(String),
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Io(e) => write!(f, "{}", e),
+            TransferError::Protocol(e) => write!(f, "{}", e),
+            TransferError::Refused(reason) => write!(f, "block refused: {}", reason),
+            TransferError::Corrupt(what) => write!(f, "{} doesn't match its hash", what),
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(e)
+    }
+}
+
+impl From<ProtocolError> for TransferError {
+    fn from(e: ProtocolError) -> Self {
+        TransferError::Protocol(e)
+    }
+}
+
+/// A folder blocks are served from, and the patterns of the files in it that aren't synced, which
+/// none are served of.
+#[derive(Debug, Clone)]
+pub struct Served {
+    pub folder: Folder,
+    pub ignore: IgnorePatterns,
+}
+
+/// Answers the block requests that come over `stream`, a [`StreamKind::Blocks`] stream the other
+/// side opened, from the files in `folders`, until the other side closes it.
+///
+/// When the other side is `untrusted`, it names files by their encrypted paths and blocks by where
+/// they are in the encrypted files it keeps, and gets them encrypted with the [`FolderKey`] of the
+/// folder's password, so it never learns what they hold.
+///
+/// Blocks are sent compressed with `compression`, the one negotiated with the other side.
+pub fn serve(mut stream: MuxStream, folders: &[Served], untrusted: bool, compression: Compression)
+    -> Result<(), TransferError> {
+    let mut keys = HashMap::new();
+    let mut decoder = Decoder::with_max_length(MAX_REQUEST_LENGTH);
+    while let Some(frame) = read_frame(&mut stream, &mut decoder)? {
+        let frame = decompress_frame(frame)?;
+        if frame.tag != REQUEST {
+            return Err(ProtocolError::Unexpected(frame.tag).into());
+        }
+        let request: BlockRequest = ciborium::from_reader(frame.payload.as_slice())
+            .map_err(|e| ProtocolError::Decode(e.to_string()))?;
+        let block = match untrusted {
+            true => read_encrypted_block(folders, &mut keys, &request),
+            false => find_folder(folders, &request.folder)
+                .and_then(|folder| read_block(folder, &request.path, request.offset, request.length)),
+        };
+        let reply = match block {
+            Ok(data) => Frame::new(BLOCK, data),
+            Err(reason) => {
+                debug!("Refusing block at {} of {} in folder {}: {}", request.offset, request.path, request.folder,
+                    reason);
+                Frame::new(REFUSED, reason.into_bytes())
+            }
+        };
+        write_frame(&mut stream, &compress_frame(compression, &reply))?;
+    }
+    Ok(())
+}
+
+fn find_folder<'a>(folders: &'a [Served], id: &str) -> Result<&'a Served, String> {
+    match folders.iter().find(|served| served.folder.id == id) {
+        Some(served) if served.folder.paused => Err(format!("folder {} is paused", id)),
+        Some(served) => Ok(served),
+        None => Err(format!("unknown folder {}", id)),
+    }
+}
+
+/// The keys of the folders are only derived once for every stream, for the time it takes.
+fn read_encrypted_block(folders: &[Served], keys: &mut HashMap<String, FolderKey>, request: &BlockRequest)
+    -> Result<Vec<u8>, String> {
+    let served = find_folder(folders, &request.folder)?;
+    let folder = &served.folder;
+    let password = folder.password.as_ref().and_then(|password| password.value())
+        .ok_or_else(|| format!("folder {} has no password to encrypt it with", folder.id))?;
+    let key = keys.entry(folder.id.clone()).or_insert_with(|| FolderKey::derive(&folder.id, password));
+    let path = key.decrypt_path(&request.path).ok_or_else(|| format!("invalid path {}", request.path))?;
+    let offset = request.offset.checked_sub(request.index as u64 * u64::from(OVERHEAD));
+    let length = request.length.checked_sub(OVERHEAD);
+    match offset.zip(length) {
+        Some((offset, length)) => Ok(key.encrypt_block(&read_block(served, &path, offset, length)?)),
+        None => Err(format!("block {} can't be at {}", request.index, request.offset)),
+    }
+}
+
+fn read_block(served: &Served, path: &str, offset: u64, length: u32) -> Result<Vec<u8>, String> {
+    if !served.ignore.is_synced(Path::new(path), false) {
+        return Err(format!("{} isn't synced", path));
+    }
+    let folder = &served.folder;
+    let path = resolve(&folder.path, path).ok_or_else(|| format!("invalid path {}", path))?;
+    // Links are followed to read files, but never out of the folder.
+    let inside = folder.path.canonicalize().and_then(|root| Ok(path.canonicalize()?.starts_with(root)));
+    if !inside.map_err(|e| e.to_string())? {
+        return Err(format!("{} is outside the folder", path.display()));
+    }
+    if length > MAX_BLOCK_LENGTH {
+        return Err(format!("block of {} bytes is too long", length));
+    }
+    let read = || -> io::Result<Vec<u8>> {
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; length as usize];
+        let read = read_full(&mut file, &mut data)?;
+        data.truncate(read);
+        Ok(data)
+    };
+    read().map_err(|e| e.to_string())
+}
+
+/// The file at `path` from a request within `root`, `None` for a path that would lead out of it.
+pub fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let normal = path.split('/').all(|name| {
+        let name = names::local_name(name);
+        let mut components = Path::new(&*name).components();
+        matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+    });
+    normal.then(|| local_path(root, path))
+}
+
+/// Where a file being pulled goes, and what it is given once there.
+#[derive(Debug, Clone, Copy)]
+pub struct Target<'a> {
+    pub path: &'a Path,
+    /// When the version pulled was modified, otherwise it is as modified when it came.
+    pub modified: Option<SystemTime>,
+    /// The permission bits of the version pulled, otherwise it keeps the ones of the file it
+    /// replaces.
+    pub permissions: Option<u32>,
+    /// The extended attributes of the version pulled, otherwise it has none.
+    pub xattrs: Option<&'a Xattrs>,
+}
+
+impl<'a> Target<'a> {
+    /// Where the version `entry` of a file goes, given the time it has and as much of its
+    /// permissions and extended attributes as the folder syncs.
+    pub fn of(path: &'a Path, entry: &'a FileEntry, settings: &FolderSettings) -> Self {
+        Target {
+            path,
+            modified: Some(entry.modified),
+            permissions: (!settings.ignore_permissions).then_some(entry.permissions),
+            xattrs: settings.xattrs.then_some(&entry.xattrs),
+        }
+    }
+
+    /// Gives the file already at the path the time, permissions and extended attributes it is to
+    /// have, for a version it has the contents of. A time at most `window` from the one it has is
+    /// left, so that clocks and file systems that disagree slightly don't change it back and
+    /// forth.
+    pub fn update(&self, window: Duration) -> io::Result<()> {
+        let file = File::open(self.path)?;
+        let metadata = file.metadata()?;
+        match self.permissions {
+            Some(permissions) if permissions != index::permissions(&metadata) => set_permissions(&file, permissions)?,
+            _ => {}
+        }
+        if let Some(xattrs) = self.xattrs {
+            if xattr::read(&file)? != *xattrs {
+                xattr::write(&file, xattrs)?;
+            }
+        }
+        match self.modified {
+            Some(modified) if !same_time(metadata.modified()?, modified, window) => file.set_modified(modified),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a> From<&'a Path> for Target<'a> {
+    fn from(path: &'a Path) -> Self {
+        Target { path, modified: None, permissions: None, xattrs: None }
+    }
+}
+
+impl<'a> From<&'a PathBuf> for Target<'a> {
+    fn from(path: &'a PathBuf) -> Self {
+        Target::from(path.as_path())
+    }
+}
+
+/// A device a file is pulled from.
+#[derive(Debug, Clone, Copy)]
+pub struct Source<'a> {
+    pub mux: &'a Mux,
+    /// The key of the folder, for an untrusted device that only keeps it encrypted.
+    pub key: Option<&'a FolderKey>,
+}
+
+impl<'a> Source<'a> {
+    pub fn trusted(mux: &'a Mux) -> Source<'a> {
+        Source { mux, key: None }
+    }
+
+    pub fn untrusted(mux: &'a Mux, key: &'a FolderKey) -> Source<'a> {
+        Source { mux, key: Some(key) }
+    }
+}
+
+/// Pulls the file at `path` in `folder`, of which `blocks` is the version wanted, to `target`
+/// from every device in `sources` that has that version at once, untrusted ones sending it
+/// encrypted. A pull of the same version that was cut short, even before a restart, picks up
+/// where it stopped. Every block is checked against its hash as it comes and the whole file once
+/// they all did, and what doesn't match is pulled again, so the file is only moved into place
+/// holding what was asked for. It is written next to `target` and renamed over it, so anything
+/// reading the file there sees either the old version or the new one, whole.
+///
+/// The blocks the file at `target` already holds, wherever they are in it, are taken from there,
+/// and so are those found in `local`, the other files on this device, so when a file changed only
+/// the blocks that did are pulled. Up to `requests` blocks are requested ahead of the ones that
+/// came from each source, so the link is kept busy rather than idle for a round trip between
+/// blocks. Once that many are on their way, more are only requested as they come.
+///
+/// The sources all take the blocks still to be requested from one queue, so each comes back for more as
+/// fast as its blocks come and a faster one ends up sending more of the file. When one fails, the
+/// blocks it was asked for go back to the others, and the pull only fails once they all did.
+pub fn pull_from<'a>(sources: &[Source], folder: &str, path: &str, blocks: &FileBlocks,
+    target: impl Into<Target<'a>>, local: &LocalBlocks, requests: u32) -> Result<(), TransferError> {
+    let Target { path: target, modified, permissions, xattrs } = target.into();
+    let mut partial = Partial::open(target, blocks)?;
+    if partial.received() > 0 {
+        debug!("Resuming the pull of {} at {} of {} blocks", path, partial.received(), blocks.block_count());
+    }
+    if target.exists() {
+        match reuse(target, blocks, &mut partial) {
+            Ok(reused) => debug!("Reusing {} of {} blocks of {} from the previous version", reused,
+                blocks.block_count(), path),
+            Err(e) => warn!("Unable to read the previous version of {}: {}", path, e),
+        }
+    }
+    if !local.is_empty() {
+        let copied = local.copy(blocks, &mut partial)?;
+        if copied > 0 {
+            debug!("Copying {} of {} blocks of {} from other files", copied, blocks.block_count(), path);
+        }
+    }
+    let mut attempts = 0;
+    loop {
+        if let Err(e) = fetch(sources, folder, path, blocks, &mut partial, requests.max(1) as usize) {
+            // Keep what came for the next pull.
+            if let Err(e) = partial.save() {
+                warn!("Unable to save the state of the pull of {}: {}", path, e);
+            }
+            return Err(e);
+        }
+        let corrupt = partial.verify()?;
+        if corrupt.is_empty() {
+            return Ok(partial.finish(modified, permissions, xattrs)?);
+        }
+        attempts += 1;
+        warn!("{} of {} blocks of {} changed on disk after they came", corrupt.len(), blocks.block_count(), path);
+        if attempts >= MAX_ATTEMPTS {
+            return Err(TransferError::Corrupt(path.to_string()));
+        }
+    }
+}
+
+/// What the sources of a pull share.
+struct Swarm<'a> {
+    folder: &'a str,
+    path: &'a str,
+    blocks: &'a FileBlocks,
+    /// Blocks no source has been asked for yet.
+    missing: Mutex<VecDeque<usize>>,
+    partial: Mutex<&'a mut Partial>,
+}
+
+fn fetch(sources: &[Source], folder: &str, path: &str, blocks: &FileBlocks, partial: &mut Partial, requests: usize)
+    -> Result<(), TransferError> {
+    let missing = partial.missing().into();
+    let swarm = Swarm { folder, path, blocks, missing: Mutex::new(missing), partial: Mutex::new(partial) };
+    let mut sources = sources.to_vec();
+    let swarm = &swarm;
+    loop {
+        let results: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = sources.iter().copied()
+                .map(|source| scope.spawn(move || fetch_from(source, swarm, requests)))
+                .collect();
+            workers.into_iter().map(|worker| worker.join().expect("pull thread panicked")).collect()
+        });
+        let mut error = None;
+        let mut remaining = Vec::new();
+        for (source, result) in sources.into_iter().zip(results) {
+            match result {
+                Ok(()) => remaining.push(source),
+                Err(e) => {
+                    debug!("Dropping a source of {}: {}", path, e);
+                    error = Some(e);
+                }
+            }
+        }
+        // Blocks a source failed to send after the others were done are still to be pulled.
+        if swarm.missing.lock().expect("pull lock poisoned").is_empty() {
+            return Ok(());
+        }
+        match error {
+            Some(e) if remaining.is_empty() => return Err(e),
+            None if remaining.is_empty() => return Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+            _ => sources = remaining,
+        }
+    }
+}
+
+/// Pulls blocks from `source` until none are left to request, putting back those it was asked for
+/// but didn't send when it fails.
+fn fetch_from(source: Source, swarm: &Swarm, requests: usize) -> Result<(), TransferError> {
+    let mut requested = VecDeque::new();
+    let started = Instant::now();
+    let mut received = 0u64;
+    let result = fetch_blocks(source, swarm, requests, &mut requested, &mut received);
+    if result.is_err() {
+        let mut missing = swarm.missing.lock().expect("pull lock poisoned");
+        for index in requested.into_iter().rev() {
+            missing.push_front(index);
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64().max(0.001);
+    debug!("Pulled {} bytes of {} at {:.0} KiB/s", received, swarm.path, received as f64 / 1024.0 / elapsed);
+    result
+}
+
+fn fetch_blocks(source: Source, swarm: &Swarm, requests: usize, requested: &mut VecDeque<usize>, received: &mut u64)
+    -> Result<(), TransferError> {
+    let (blocks, path) = (swarm.blocks, swarm.path);
+    // An untrusted device keeps the file under another name, and its blocks further along.
+    let (remote_path, range): (_, &dyn Fn(usize) -> (u64, u32)) = match source.key {
+        Some(key) => (key.encrypt_path(path), &|index| encrypted_block(blocks, index)),
+        None => (path.to_string(), &|index| blocks.block(index)),
+    };
+    let mut stream = open_stream(source.mux, StreamKind::Blocks)?;
+    // Room for every block on its way, on top of the frames around them.
+    let longest = (0..blocks.block_count()).map(|index| range(index).1).max().unwrap_or_default();
+    stream.set_receive_window((requests as u32).saturating_mul(longest.saturating_add(64)));
+    let mut decoder = Decoder::new();
+    let mut attempts: HashMap<usize, u32> = HashMap::new();
+    loop {
+        while requested.len() < requests {
+            let index = match swarm.missing.lock().expect("pull lock poisoned").pop_front() {
+                Some(index) => index,
+                None => break,
+            };
+            // Put back should the request not go out.
+            requested.push_back(index);
+            let (offset, length) = range(index);
+            let request =
+                BlockRequest { folder: swarm.folder.to_string(), path: remote_path.clone(), offset, length, index };
+            let mut payload = Vec::new();
+            ciborium::into_writer(&request, &mut payload).map_err(|e| ProtocolError::Encode(e.to_string()))?;
+            write_frame(&mut stream, &Frame::new(REQUEST, payload))?;
+        }
+        // Blocks come in the order they were requested.
+        let index = match requested.front() {
+            Some(index) => *index,
+            None => return Ok(()),
+        };
+        let (offset, length) = range(index);
+        let frame = read_frame(&mut stream, &mut decoder)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        // The length is only known once the block is decompressed.
+        let frame = decompress_frame(frame)?;
+        match frame.tag {
+            BLOCK if frame.payload.len() == length as usize => {
+                let data = match source.key {
+                    Some(key) => key.decrypt_block(frame.payload),
+                    None => Some(frame.payload),
+                };
+                let written = match data {
+                    Some(data) => swarm.partial.lock().expect("pull lock poisoned").write(index, &data),
+                    None => Err(io::Error::new(io::ErrorKind::InvalidData, "block doesn't decrypt with the folder key")),
+                };
+                match written {
+                    Ok(()) => *received += u64::from(length),
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        let attempts = attempts.entry(index).or_default();
+                        *attempts += 1;
+                        if *attempts >= MAX_ATTEMPTS {
+                            return Err(TransferError::Corrupt(format!("block at {} of {}", offset, path)));
+                        }
+                        debug!("Pulling the block at {} of {} again: {}", offset, path, e);
+                        swarm.missing.lock().expect("pull lock poisoned").push_front(index);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                requested.pop_front();
+            }
+            BLOCK => return Err(TransferError::Refused(format!("block at {} is {} bytes instead of {}", offset,
+                frame.payload.len(), length))),
+            REFUSED => return Err(TransferError::Refused(String::from_utf8_lossy(&frame.payload).to_string())),
+            tag => return Err(ProtocolError::Unexpected(tag).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::spawn;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::protocol::accept_stream;
+    use crate::transport::Stream;
+
+    fn pull<'a>(mux: &Mux, folder: &str, path: &str, blocks: &FileBlocks, target: impl Into<Target<'a>>,
+        local: &LocalBlocks, requests: u32) -> Result<(), TransferError> {
+        pull_from(&[Source::trusted(mux)], folder, path, blocks, target, local, requests)
+    }
+
+    fn folder(path: &Path) -> Folder {
+        Folder {
+            id: "folder".to_string(),
+            path: path.to_path_buf(),
+            label: None,
+            mode: Default::default(),
+            password: None,
+            select: Vec::new(),
+            paused: false,
+            overrides: Default::default(),
+        }
+    }
+
+    /// A connection to a device serving `folders`, every file in them.
+    fn serving(folders: Vec<Folder>) -> Mux {
+        serving_to(folders.into_iter().map(everything).collect(), false, Compression::None)
+    }
+
+    fn everything(folder: Folder) -> Served {
+        Served { folder, ignore: IgnorePatterns::default() }
+    }
+
+    /// A connection to a device serving `folders` to a device that is `untrusted`, compressing
+    /// blocks with `compression`.
+    fn serving_to(folders: Vec<Served>, untrusted: bool, compression: Compression) -> Mux {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dialed = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let stream = |socket: TcpStream| Stream::new(socket.try_clone().unwrap(), socket);
+        let server = Mux::new(stream(accepted), false);
+        spawn(move || {
+            while let Some(mut stream) = server.accept() {
+                let folders = folders.clone();
+                spawn(move || match accept_stream(&mut stream) {
+                    Ok(StreamKind::Blocks) => serve(stream, &folders, untrusted, compression),
+                    kind => panic!("unexpected stream {:?}", kind),
+                });
+            }
+        });
+        Mux::new(stream(dialed), true)
+    }
+
+    fn contents() -> Vec<u8> {
+        (0..BLOCK_SIZE as usize * 5 + 1000).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn pulls_a_file() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        write(source.path().join("file"), contents()).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fixed).unwrap();
+        assert_eq!(blocks.block_count(), 6);
+
+        let mux = serving(vec![folder(source.path())]);
+        let target = destination.path().join("file");
+        pull(&mux, "folder", "file", &blocks, &target, &LocalBlocks::default(), 4).unwrap();
+        assert_eq!(read(&target).unwrap(), contents());
+        assert!(matches!(pull(&mux, "folder", "other", &blocks, &target, &LocalBlocks::default(), 4), Err(TransferError::Refused(_))));
+
+        let mux = serving(vec![Folder { paused: true, ..folder(source.path()) }]);
+        let pulled = pull(&mux, "folder", "file", &blocks, &target, &LocalBlocks::default(), 4);
+        assert!(matches!(pulled, Err(TransferError::Refused(_))), "{:?}", pulled);
+
+        let ignoring = Served { folder: folder(source.path()), ignore: IgnorePatterns::new(&["file"]) };
+        let mux = serving_to(vec![ignoring], false, Compression::None);
+        let pulled = pull(&mux, "folder", "file", &blocks, &target, &LocalBlocks::default(), 4);
+        assert!(matches!(pulled, Err(TransferError::Refused(_))), "{:?}", pulled);
+    }
+
+    #[test]
+    fn resumes_a_pull_that_was_cut_short() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        write(source.path().join("file"), contents()).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fixed).unwrap();
+        let target = destination.path().join("file");
+        {
+            let mut partial = Partial::open(&target, &blocks).unwrap();
+            let (offset, length) = blocks.block(0);
+            partial.write(0, &contents()[offset as usize..][..length as usize]).unwrap();
+            partial.save().unwrap();
+        }
+
+        // What the first block now holds is never fetched, since it already came.
+        let mut changed = contents();
+        changed[..10].copy_from_slice(&[0; 10]);
+        write(source.path().join("file"), &changed).unwrap();
+        pull(&serving(vec![folder(source.path())]), "folder", "file", &blocks, &target, &LocalBlocks::default(), 1).unwrap();
+        assert_eq!(read(&target).unwrap(), contents());
+    }
+
+    #[test]
+    fn only_pulls_the_blocks_that_changed() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        let target = destination.path().join("file");
+        write(&target, contents()).unwrap();
+        // Bytes inserted near the start move every block after them.
+        let mut changed = contents();
+        changed.splice(1000..1000, b"inserted".iter().copied());
+        changed[3 * BLOCK_SIZE as usize + 10] ^= 0xff;
+        write(source.path().join("file"), &changed).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fixed).unwrap();
+
+        let mut partial = Partial::open(&target, &blocks).unwrap();
+        assert_eq!(reuse(&target, &blocks, &mut partial).unwrap(), 3);
+        assert_eq!(partial.missing(), vec![0, 3, 5]);
+        drop(partial);
+        pull(&serving(vec![folder(source.path())]), "folder", "file", &blocks, &target, &LocalBlocks::default(), 4).unwrap();
+        assert_eq!(read(&target).unwrap(), changed);
+    }
+
+    #[test]
+    fn pulls_content_defined_blocks() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        let target = destination.path().join("file");
+        let mut state = 1u64;
+        let random: Vec<u8> = (0..BLOCK_SIZE as usize * 12).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect();
+        write(&target, &random).unwrap();
+        let mut changed = random.clone();
+        changed.splice(BLOCK_SIZE as usize * 6..BLOCK_SIZE as usize * 6, b"inserted".iter().copied());
+        write(source.path().join("file"), &changed).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fastcdc).unwrap();
+        assert!(blocks.content_defined());
+
+        let mut partial = Partial::open(&target, &blocks).unwrap();
+        assert!(reuse(&target, &blocks, &mut partial).unwrap() >= blocks.block_count() - 2);
+        drop(partial);
+        pull(&serving(vec![folder(source.path())]), "folder", "file", &blocks, &target, &LocalBlocks::default(), 4).unwrap();
+        assert_eq!(read(&target).unwrap(), changed);
+    }
+
+    #[test]
+    fn copies_blocks_found_in_other_files() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        write(source.path().join("file"), contents()).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fixed).unwrap();
+        // The same file under another name, as after a rename on the other device.
+        let copy = destination.path().join("renamed");
+        write(&copy, contents()).unwrap();
+        let mut local = LocalBlocks::default();
+        local.add(&copy, &FileBlocks::of(&copy, Chunking::Fixed).unwrap());
+
+        let target = destination.path().join("file");
+        let mut partial = Partial::open(&target, &blocks).unwrap();
+        assert_eq!(local.copy(&blocks, &mut partial).unwrap(), blocks.block_count());
+        drop(partial);
+        // Nothing is served, so the blocks must come from the copy.
+        pull(&serving(Vec::new()), "folder", "file", &blocks, &target, &local, 4).unwrap();
+        assert_eq!(read(&target).unwrap(), contents());
+    }
+
+    #[test]
+    fn gives_up_on_blocks_that_dont_match() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        write(source.path().join("file"), contents()).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fixed).unwrap();
+        // The file served no longer holds what the blocks say in one place.
+        let mut changed = contents();
+        changed[2 * BLOCK_SIZE as usize] ^= 0xff;
+        write(source.path().join("file"), &changed).unwrap();
+
+        let target = destination.path().join("file");
+        let local = LocalBlocks::default();
+        let result = pull(&serving(vec![folder(source.path())]), "folder", "file", &blocks, &target, &local, 4);
+        assert!(matches!(result, Err(TransferError::Corrupt(_))));
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn pulls_compressed_blocks() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        // Blocks that look random, as the ones of `contents` do, are sent as they are.
+        let text = "the quick brown fox jumps over the lazy dog\n".repeat(20_000).into_bytes();
+        write(source.path().join("file"), &text).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fixed).unwrap();
+        for compression in &[Compression::Lz4, Compression::Zstd] {
+            let mux = serving_to(vec![everything(folder(source.path()))], false, *compression);
+            let target = destination.path().join(compression.to_string());
+            pull(&mux, "folder", "file", &blocks, &target, &LocalBlocks::default(), 4).unwrap();
+            assert_eq!(read(&target).unwrap(), text);
+            assert!(mux.traffic().bytes_received < blocks.size / 4, "{:?}", mux.traffic());
+        }
+    }
+
+    #[test]
+    fn pulls_from_several_sources() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        write(source.path().join("file"), contents()).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fixed).unwrap();
+        let (first, second) = (serving(vec![folder(source.path())]), serving(vec![folder(source.path())]));
+        // One that doesn't have the folder, whose blocks the others then send.
+        let missing = serving(Vec::new());
+
+        let target = destination.path().join("file");
+        let local = LocalBlocks::default();
+        let sources = [Source::trusted(&first), Source::trusted(&missing), Source::trusted(&second)];
+        pull_from(&sources, "folder", "file", &blocks, &target, &local, 2).unwrap();
+        assert_eq!(read(&target).unwrap(), contents());
+        let other = destination.path().join("other");
+        let result = pull_from(&[Source::trusted(&missing)], "folder", "file", &blocks, &other, &local, 2);
+        assert!(matches!(result, Err(TransferError::Refused(_))));
+    }
+
+    #[test]
+    fn untrusted_devices_only_keep_the_file_encrypted() {
+        let (trusted, untrusted, other) = (tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap());
+        write(trusted.path().join("file"), contents()).unwrap();
+        let blocks = FileBlocks::of(&trusted.path().join("file"), Chunking::Fixed).unwrap();
+        let mut shared = folder(trusted.path());
+        shared.password = Some(toml::Value::from("password").try_into().unwrap());
+        let key = FolderKey::derive("folder", "password");
+
+        // What the trusted device tells the untrusted one about the file.
+        let encrypted_path = key.encrypt_path("file");
+        let encrypted_blocks = key.encrypt_blocks(&trusted.path().join("file"), &blocks).unwrap();
+        let kept = untrusted.path().join(&encrypted_path);
+        std::fs::create_dir_all(kept.parent().unwrap()).unwrap();
+        let local = LocalBlocks::default();
+        let to_untrusted = serving_to(vec![everything(shared.clone())], true, Compression::None);
+        pull(&to_untrusted, "folder", &encrypted_path, &encrypted_blocks, &kept, &local, 4).unwrap();
+        let encrypted = read(&kept).unwrap();
+        assert_eq!(encrypted.len() as u64, blocks.size + blocks.block_count() as u64 * u64::from(OVERHEAD));
+        let plain = &contents()[1000..1064];
+        assert!(!encrypted.windows(plain.len()).any(|window| window == plain));
+        // Plain paths mean nothing to the device serving an untrusted one.
+        let result = pull(&to_untrusted, "folder", "file", &blocks, &other.path().join("file"), &local, 4);
+        assert!(matches!(result, Err(TransferError::Refused(_))));
+
+        // A new trusted device syncs the file from the untrusted one alone.
+        let from_untrusted = serving(vec![folder(untrusted.path())]);
+        let target = other.path().join("file");
+        pull_from(&[Source::untrusted(&from_untrusted, &key)], "folder", "file", &blocks, &target, &local, 4)
+            .unwrap();
+        assert_eq!(read(&target).unwrap(), contents());
+    }
+
+    #[test]
+    fn refuses_paths_out_of_the_folder() {
+        let root = Path::new("/folder");
+        assert_eq!(resolve(root, "a/b"), Some(PathBuf::from("/folder/a/b")));
+        assert_eq!(resolve(root, "../secret"), None);
+        assert_eq!(resolve(root, "/etc/passwd"), None);
+        assert_eq!(resolve(root, ""), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_links_out_of_the_folder() {
+        let (source, outside, target) = (tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap());
+        std::fs::write(outside.path().join("secret"), contents()).unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), source.path().join("escape")).unwrap();
+        let blocks = FileBlocks::of(&outside.path().join("secret"), Chunking::Fixed).unwrap();
+        let mux = serving(vec![folder(source.path())]);
+        let result = pull(&mux, "folder", "escape", &blocks, &target.path().join("file"), &LocalBlocks::default(), 4);
+        assert!(matches!(result, Err(TransferError::Refused(_))));
+    }
+
+    #[test]
+    fn gives_a_pulled_file_its_extended_attributes() {
+        let (source, destination) = (tempdir().unwrap(), tempdir().unwrap());
+        write(source.path().join("file"), contents()).unwrap();
+        let blocks = FileBlocks::of(&source.path().join("file"), Chunking::Fixed).unwrap();
+        let xattrs = Xattrs::from([("user.tag".to_string(), b"red".to_vec())]);
+        let path = destination.path().join("file");
+        let target = Target { path: &path, modified: None, permissions: None, xattrs: Some(&xattrs) };
+        pull(&serving(vec![folder(source.path())]), "folder", "file", &blocks, target, &LocalBlocks::default(), 4)
+            .unwrap();
+        // Not every file system temporary files are on has them.
+        let placed = xattr::read(&File::open(&path).unwrap()).unwrap();
+        assert!(placed == xattrs || placed.is_empty(), "{:?}", placed);
+    }
+
+    #[test]
+    fn updates_the_time_of_a_file_unless_it_is_close() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("file");
+        std::fs::write(&path, contents()).unwrap();
+        let modified = path.metadata().unwrap().modified().unwrap();
+        let window = Duration::from_secs(2);
+        let target = |modified| Target { path: &path, modified: Some(modified), permissions: None, xattrs: None };
+
+        target(modified + Duration::from_millis(1500)).update(window).unwrap();
+        assert_eq!(path.metadata().unwrap().modified().unwrap(), modified);
+        let later = modified + Duration::from_secs(60);
+        target(later).update(window).unwrap();
+        assert_eq!(path.metadata().unwrap().modified().unwrap(), later);
+    }
+}
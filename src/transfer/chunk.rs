@@ -0,0 +1,158 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Numbers mixed into the FastCDC hash for each byte value, which every device must agree on.
+const GEAR: [u64; 256] = gear();
+
+/// How files are split into blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Chunking {
+    /// Blocks of [`BLOCK_SIZE`](crate::transfer::BLOCK_SIZE), so bytes inserted or removed in a
+    /// file change every block after them, though they are still found in the old version.
+    #[default]
+    Fixed,
+    /// Blocks around that size cut where the content says, so bytes inserted or removed only
+    /// change the blocks around them.
+    Fastcdc,
+}
+
+impl Display for Chunking {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Chunking::Fixed => write!(f, "fixed"),
+            Chunking::Fastcdc => write!(f, "fastcdc"),
+        }
+    }
+}
+
+impl FromStr for Chunking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Chunking::Fixed),
+            "fastcdc" => Ok(Chunking::Fastcdc),
+            _ => Err(format!("unknown chunking `{}`, use fixed or fastcdc", s)),
+        }
+    }
+}
+
+/// FastCDC, which cuts a block where a hash of the 64 bytes before has enough zero bits. Blocks
+/// are a quarter to four times `average` long, and cuts are made harder before the average length
+/// and easier after it, so most blocks are close to it.
+#[derive(Debug, Clone, Copy)]
+pub struct FastCdc {
+    min: usize,
+    average: usize,
+    max: usize,
+    /// Bits that must be zero for a cut before the average length.
+    hard: u64,
+    /// Bits that must be zero for a cut after it.
+    easy: u64,
+}
+
+impl FastCdc {
+    pub fn new(average: u32) -> FastCdc {
+        let bits = 31 - average.max(64).leading_zeros();
+        let top = |count: u32| !0u64 << (64 - count);
+        let average = average as usize;
+        FastCdc { min: average / 4, average, max: average * 4, hard: top(bits + 1), easy: top(bits - 1) }
+    }
+
+    /// The length of the block `data` starts with. Unless it is the end of a file, `data` must
+    /// hold as many bytes as the longest block can, so that where blocks are cut doesn't depend on how the file
+    /// was read.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+        let end = data.len().min(self.max);
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(end).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[usize::from(*byte)]);
+            let mask = if i < self.average { self.hard } else { self.easy };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+
+    /// Splits everything `reader` reads into blocks, handing each to `block` in turn.
+    pub fn split(&self, reader: &mut impl Read, mut block: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+        let mut buffer = Vec::with_capacity(2 * self.max);
+        let mut chunk = vec![0; self.max];
+        let mut ended = false;
+        loop {
+            while !ended && buffer.len() < self.max {
+                match reader.read(&mut chunk) {
+                    Ok(0) => ended = true,
+                    Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if buffer.is_empty() {
+                return Ok(());
+            }
+            let length = self.cut(&buffer);
+            block(&buffer[..length])?;
+            buffer.drain(..length);
+        }
+    }
+}
+
+/// SplitMix64 from a fixed seed.
+const fn gear() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x5eed_0f51_49c5_c5c5;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lengths(chunker: &FastCdc, data: &[u8]) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        chunker.split(&mut &data[..], |block| {
+            lengths.push(block.len());
+            Ok(())
+        }).unwrap();
+        lengths
+    }
+
+    #[test]
+    fn cuts_stay_put_around_an_insertion() {
+        let chunker = FastCdc::new(4096);
+        let mut state = 1u64;
+        let data: Vec<u8> = (0..200_000).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect();
+        let before = lengths(&chunker, &data);
+        assert_eq!(before.iter().sum::<usize>(), data.len());
+        assert!(before.iter().all(|length| *length <= chunker.max));
+        let average = data.len() / before.len();
+        assert!((2048..8192).contains(&average), "blocks average {} bytes", average);
+
+        let mut inserted = data.clone();
+        inserted.splice(50_000..50_000, vec![7; 100]);
+        let after = lengths(&chunker, &inserted);
+        // Only the blocks around the insertion differ.
+        assert_eq!(before[before.len() - 30..], after[after.len() - 30..]);
+    }
+}
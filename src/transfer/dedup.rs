@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::hash::{hash, read_full};
+use crate::transfer::{FileBlocks, Hash, Partial};
+
+/// Where blocks are found in the files on this device, so that a file pulled takes the blocks it
+/// shares with them from there rather than over the network, such as all of a file that was
+/// renamed or copied on another device.
+#[derive(Debug, Clone, Default)]
+pub struct LocalBlocks {
+    blocks: HashMap<Hash, Location>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Location {
+    path: PathBuf,
+    offset: u64,
+    length: u32,
+}
+
+impl LocalBlocks {
+    /// Takes note of the blocks of the file at `path`.
+    pub fn add(&mut self, path: &Path, blocks: &FileBlocks) {
+        for (index, hash) in blocks.hashes.iter().enumerate() {
+            let (offset, length) = blocks.block(index);
+            self.blocks.insert(*hash, Location { path: path.to_path_buf(), offset, length });
+        }
+    }
+
+    /// Forgets the blocks of the file at `path`, once it changed or is gone.
+    pub fn remove(&mut self, path: &Path) {
+        self.blocks.retain(|_, location| location.path != path);
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Copies the blocks still missing from `partial` that are found in local files, returning
+    /// how many. A block that no longer holds what it did is left to be pulled.
+    pub fn copy(&self, blocks: &FileBlocks, partial: &mut Partial) -> io::Result<usize> {
+        let mut copied = 0;
+        let mut open: Option<(&Path, File)> = None;
+        for index in partial.missing() {
+            let location = match self.blocks.get(&blocks.hashes[index]) {
+                Some(location) if location.length == blocks.block(index).1 => location,
+                _ => continue,
+            };
+            if open.as_ref().is_none_or(|(path, _)| *path != location.path) {
+                match File::open(&location.path) {
+                    Ok(file) => open = Some((&location.path, file)),
+                    Err(e) => {
+                        debug!("Unable to copy a block from {}: {}", location.path.display(), e);
+                        continue;
+                    }
+                }
+            }
+            let file = &mut open.as_mut().expect("file was opened").1;
+            match read_at(file, location.offset, location.length) {
+                Ok(data) if hash(&data) == blocks.hashes[index] => {
+                    partial.write(index, &data)?;
+                    copied += 1;
+                }
+                Ok(_) => {}
+                Err(e) => debug!("Unable to copy a block from {}: {}", location.path.display(), e),
+            }
+        }
+        Ok(copied)
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, length: u32) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![0; length as usize];
+    let read = read_full(file, &mut data)?;
+    data.truncate(read);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::transfer::{Chunking, BLOCK_SIZE};
+
+    fn contents() -> Vec<u8> {
+        (0..BLOCK_SIZE as usize * 3).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn takes_note_of_the_blocks_of_files_and_forgets_them() {
+        let directory = tempdir().unwrap();
+        let (first, second) = (directory.path().join("first"), directory.path().join("second"));
+        write(&first, contents()).unwrap();
+        write(&second, [contents(), vec![1; 10]].concat()).unwrap();
+        let mut local = LocalBlocks::default();
+        assert!(local.is_empty());
+
+        local.add(&first, &FileBlocks::of(&first, Chunking::Fixed).unwrap());
+        assert_eq!(local.len(), 3);
+        // The blocks both have are taken from the one noted last.
+        local.add(&second, &FileBlocks::of(&second, Chunking::Fixed).unwrap());
+        assert_eq!(local.len(), 4);
+        local.remove(&second);
+        assert_eq!(local.len(), 0);
+        local.add(&first, &FileBlocks::of(&first, Chunking::Fixed).unwrap());
+        local.remove(&second);
+        assert_eq!(local.len(), 3);
+    }
+
+    #[test]
+    fn copies_the_blocks_that_still_hold_what_they_did() {
+        let directory = tempdir().unwrap();
+        let (copy, wanted) = (directory.path().join("copy"), directory.path().join("wanted"));
+        write(&copy, contents()).unwrap();
+        write(&wanted, contents()).unwrap();
+        let mut local = LocalBlocks::default();
+        local.add(&copy, &FileBlocks::of(&copy, Chunking::Fixed).unwrap());
+        let blocks = FileBlocks::of(&wanted, Chunking::Fixed).unwrap();
+        // The copy changed in its second block after it was taken note of.
+        let mut changed = contents();
+        changed[BLOCK_SIZE as usize + 1] ^= 0xff;
+        write(&copy, &changed).unwrap();
+
+        let target = directory.path().join("target");
+        let mut partial = Partial::open(&target, &blocks).unwrap();
+        assert_eq!(local.copy(&blocks, &mut partial).unwrap(), 2);
+        assert_eq!(partial.missing(), vec![1]);
+        assert_eq!(local.copy(&blocks, &mut partial).unwrap(), 0);
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::hash::{hash, Hash};
+use crate::transfer::{FastCdc, FileBlocks, Partial};
+
+/// The weak checksum of rsync over a window of bytes, which is cheap to move along by one byte so
+/// that a block can be looked for at every offset of a file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rolling {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl Rolling {
+    pub fn new(window: &[u8]) -> Rolling {
+        let mut rolling = Rolling { length: window.len() as u32, ..Rolling::default() };
+        for (i, byte) in window.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(u32::from(*byte));
+            rolling.b = rolling.b.wrapping_add((window.len() - i) as u32 * u32::from(*byte));
+        }
+        rolling
+    }
+
+    /// Moves the window on by one byte, `out` leaving it at the start and `into` coming in at the
+    /// end.
+    pub fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(u32::from(out)).wrapping_add(u32::from(into));
+        self.b = self.b.wrapping_sub(self.length.wrapping_mul(u32::from(out))).wrapping_add(self.a);
+    }
+
+    pub fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Copies the blocks still missing from `partial` that `basis`, an older version of the file or
+/// any other one, holds at any offset, returning how many. Only the blocks it doesn't hold then
+/// have to be pulled, so a file that changed in places, or had bytes inserted, costs little more
+/// than what changed.
+pub fn reuse(basis: &Path, blocks: &FileBlocks, partial: &mut Partial) -> io::Result<usize> {
+    if blocks.content_defined() {
+        return reuse_chunks(basis, blocks, partial);
+    }
+    let block_size = blocks.block_size as usize;
+    let mut wanted: HashMap<u32, Vec<usize>> = HashMap::new();
+    for index in partial.missing() {
+        // A shorter last block can't be told from its weak checksum over a whole block.
+        match (blocks.block(index), blocks.weak_hashes.get(index)) {
+            ((_, length), Some(weak)) if length as usize == block_size => wanted.entry(*weak).or_default().push(index),
+            _ => {}
+        }
+    }
+    if wanted.is_empty() {
+        return Ok(0);
+    }
+
+    let mut file = File::open(basis)?;
+    let mut window = Window { buffer: Vec::with_capacity(4 * block_size), start: 0, ended: false };
+    let mut reused = 0;
+    if !window.fill(&mut file, block_size)? {
+        return Ok(0);
+    }
+    let mut rolling = Rolling::new(window.bytes(block_size));
+    loop {
+        let found = match wanted.get(&rolling.digest()) {
+            Some(indices) => {
+                let strong = hash(window.bytes(block_size));
+                let found: Vec<usize> = indices.iter().copied().filter(|index| blocks.hashes[*index] == strong).collect();
+                for index in &found {
+                    partial.write(*index, window.bytes(block_size))?;
+                }
+                found
+            }
+            None => Vec::new(),
+        };
+        if !found.is_empty() {
+            reused += found.len();
+            wanted.retain(|_, indices| {
+                indices.retain(|index| !found.contains(index));
+                !indices.is_empty()
+            });
+            if wanted.is_empty() {
+                return Ok(reused);
+            }
+            // The next block of the basis likely follows on from this one.
+            window.start += block_size;
+            if !window.fill(&mut file, block_size)? {
+                return Ok(reused);
+            }
+            rolling = Rolling::new(window.bytes(block_size));
+            continue;
+        }
+        if !window.fill(&mut file, block_size + 1)? {
+            return Ok(reused);
+        }
+        let bytes = window.bytes(block_size + 1);
+        rolling.roll(bytes[0], bytes[block_size]);
+        window.start += 1;
+    }
+}
+
+/// Blocks cut where the content says are cut in the same places in the basis wherever they moved
+/// to, so splitting it the same way finds them without rolling over every offset.
+fn reuse_chunks(basis: &Path, blocks: &FileBlocks, partial: &mut Partial) -> io::Result<usize> {
+    let mut wanted: HashMap<Hash, Vec<usize>> = HashMap::new();
+    for index in partial.missing() {
+        wanted.entry(blocks.hashes[index]).or_default().push(index);
+    }
+    let mut reused = 0;
+    FastCdc::new(blocks.block_size).split(&mut File::open(basis)?, |block| {
+        let strong = hash(block);
+        if let Some(indices) = wanted.remove(&strong) {
+            for index in &indices {
+                partial.write(*index, block)?;
+            }
+            reused += indices.len();
+        }
+        Ok(())
+    })?;
+    Ok(reused)
+}
+
+/// The part of a file being looked through, from `start` in `buffer`.
+struct Window {
+    buffer: Vec<u8>,
+    start: usize,
+    ended: bool,
+}
+
+impl Window {
+    /// Reads on until `length` bytes from the start are in the buffer, returning whether they are.
+    fn fill(&mut self, file: &mut File, length: usize) -> io::Result<bool> {
+        if self.buffer.len() - self.start >= length {
+            return Ok(true);
+        }
+        self.buffer.drain(..self.start);
+        self.start = 0;
+        let mut chunk = vec![0; (4 * length).max(64 * 1024)];
+        while !self.ended && self.buffer.len() < length {
+            match file.read(&mut chunk) {
+                Ok(0) => self.ended = true,
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.buffer.len() >= length)
+    }
+
+    fn bytes(&self, length: usize) -> &[u8] {
+        &self.buffer[self.start..self.start + length]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolls_to_the_checksum_of_the_next_window() {
+        let data: Vec<u8> = (0..200).map(|i| (i * 7 % 256) as u8).collect();
+        let mut rolling = Rolling::new(&data[..64]);
+        for start in 1..=136 {
+            rolling.roll(data[start - 1], data[start + 63]);
+            assert_eq!(rolling.digest(), Rolling::new(&data[start..start + 64]).digest());
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt::{self, Debug, Formatter};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::{hmac, pbkdf2};
+
+use crate::hash::{hash, read_full, BlockHasher};
+use crate::transfer::FileBlocks;
+
+/// Bytes an encrypted block is longer than the block, for its nonce and tag.
+pub const OVERHEAD: u32 = (NONCE_LEN + 16) as u32;
+/// Rounds of PBKDF2 the folder password goes through, to slow down guessing it.
+const PBKDF2_ROUNDS: u32 = 100_000;
+/// Longest component of an encrypted path, well under what file systems allow.
+const MAX_COMPONENT_LENGTH: usize = 200;
+
+/// The keys a folder is encrypted with for untrusted devices, which only ever hold the folder
+/// encrypted and never its password.
+///
+/// Encryption is deterministic, the nonce of a name or block being a keyed hash of it, so every
+/// trusted device encrypts the same file the same way and an untrusted device can tell which
+/// blocks and files it already holds, though not what they hold.
+pub struct FolderKey {
+    names: LessSafeKey,
+    name_nonces: hmac::Key,
+    blocks: LessSafeKey,
+    block_nonces: hmac::Key,
+}
+
+impl FolderKey {
+    /// The keys of the folder `folder_id` from its password, the same on every device.
+    pub fn derive(folder_id: &str, password: &str) -> FolderKey {
+        let mut master = [0; 32];
+        let rounds = NonZeroU32::new(PBKDF2_ROUNDS).expect("rounds are not zero");
+        let salt = format!("simple-sync folder {}", folder_id);
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, rounds, salt.as_bytes(), password.as_bytes(), &mut master);
+        let master = hmac::Key::new(hmac::HMAC_SHA256, &master);
+        let subkey = |label: &str| hmac::sign(&master, label.as_bytes());
+        let aead = |label: &str| {
+            let key = UnboundKey::new(&CHACHA20_POLY1305, subkey(label).as_ref()).expect("subkeys are 32 bytes");
+            LessSafeKey::new(key)
+        };
+        FolderKey {
+            names: aead("names"),
+            name_nonces: hmac::Key::new(hmac::HMAC_SHA256, subkey("name nonces").as_ref()),
+            blocks: aead("blocks"),
+            block_nonces: hmac::Key::new(hmac::HMAC_SHA256, subkey("block nonces").as_ref()),
+        }
+    }
+
+    /// The path an untrusted device keeps the file at `path` under, hex split into directories so
+    /// that no component grows too long.
+    pub fn encrypt_path(&self, path: &str) -> String {
+        let hex = hex::encode(seal(&self.names, &self.name_nonces, path.as_bytes()));
+        let (first, rest) = hex.split_at(2);
+        let mut encrypted = first.to_string();
+        for piece in rest.as_bytes().chunks(MAX_COMPONENT_LENGTH) {
+            encrypted.push('/');
+            encrypted.push_str(std::str::from_utf8(piece).expect("hex is ASCII"));
+        }
+        encrypted
+    }
+
+    /// The path `encrypted` stands for, `None` for one that wasn't encrypted with this key.
+    pub fn decrypt_path(&self, encrypted: &str) -> Option<String> {
+        let sealed = hex::decode(encrypted.replace('/', "")).ok()?;
+        String::from_utf8(open(&self.names, sealed)?).ok()
+    }
+
+    pub fn encrypt_block(&self, data: &[u8]) -> Vec<u8> {
+        seal(&self.blocks, &self.block_nonces, data)
+    }
+
+    /// What `encrypted` holds, `None` for a block that wasn't encrypted with this key or was
+    /// changed since.
+    pub fn decrypt_block(&self, encrypted: Vec<u8>) -> Option<Vec<u8>> {
+        open(&self.blocks, encrypted)
+    }
+
+    /// The blocks of the file at `path`, whose blocks are `blocks`, as an untrusted device keeps
+    /// it, each block encrypted in turn.
+    pub fn encrypt_blocks(&self, path: &Path, blocks: &FileBlocks) -> io::Result<FileBlocks> {
+        let mut file = File::open(path)?;
+        let mut encrypted = BlockHasher::new(blocks.block_size + OVERHEAD, true);
+        for index in 0..blocks.block_count() {
+            let (offset, length) = blocks.block(index);
+            let mut data = vec![0; length as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            if read_full(&mut file, &mut data)? < data.len() || hash(&data) != blocks.hashes[index] {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "the file changed since its blocks were read"));
+            }
+            encrypted.add(&self.encrypt_block(&data));
+        }
+        let encrypted = encrypted.finish();
+        Ok(encrypted)
+    }
+}
+
+impl Debug for FolderKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "FolderKey(<redacted>)")
+    }
+}
+
+/// Where block `index` of `blocks` is in the file an untrusted device keeps.
+pub fn encrypted_block(blocks: &FileBlocks, index: usize) -> (u64, u32) {
+    let (offset, length) = blocks.block(index);
+    (offset + index as u64 * u64::from(OVERHEAD), length + OVERHEAD)
+}
+
+/// The nonce, then `data` encrypted, then the tag.
+fn seal(key: &LessSafeKey, nonces: &hmac::Key, data: &[u8]) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&hmac::sign(nonces, data).as_ref()[..NONCE_LEN]);
+    let mut contents = data.to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut contents)
+        .expect("blocks are far shorter than ChaCha20-Poly1305 allows");
+    let mut sealed = nonce.to_vec();
+    sealed.append(&mut contents);
+    sealed
+}
+
+fn open(key: &LessSafeKey, mut sealed: Vec<u8>) -> Option<Vec<u8>> {
+    if sealed.len() < OVERHEAD as usize {
+        return None;
+    }
+    let mut contents = sealed.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&sealed).ok()?;
+    let length = key.open_in_place(nonce, Aad::empty(), &mut contents).ok()?.len();
+    contents.truncate(length);
+    Some(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypts_names_and_blocks_the_same_way_every_time() {
+        let key = FolderKey::derive("folder", "password");
+        let path = "photos/2021/a very long name ".repeat(10);
+        let encrypted = key.encrypt_path(&path);
+        assert_eq!(encrypted, FolderKey::derive("folder", "password").encrypt_path(&path));
+        assert!(encrypted.split('/').all(|component| component.len() <= MAX_COMPONENT_LENGTH));
+        assert!(!encrypted.contains("photos"));
+        assert_eq!(key.decrypt_path(&encrypted), Some(path.clone()));
+        assert_eq!(FolderKey::derive("folder", "guess").decrypt_path(&encrypted), None);
+        assert_eq!(FolderKey::derive("other", "password").decrypt_path(&encrypted), None);
+
+        let block = key.encrypt_block(b"contents");
+        assert_eq!(block.len(), 8 + OVERHEAD as usize);
+        assert_eq!(key.decrypt_block(block.clone()), Some(b"contents".to_vec()));
+        let mut changed = block;
+        changed[NONCE_LEN] ^= 1;
+        assert_eq!(key.decrypt_block(changed), None);
+    }
+}
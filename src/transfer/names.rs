@@ -0,0 +1,154 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+use crate::config::WindowsNames;
+
+/// Where the characters Windows doesn't allow in names are written when escaped, each at its own
+/// code point in the private use area past this, as Cygwin and the SMB servers of macOS write them.
+const ESCAPE_BASE: u32 = 0xf000;
+
+/// Characters Windows doesn't allow in names, besides control characters.
+const RESERVED_CHARACTERS: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+/// Names of devices, which Windows doesn't allow for a file whatever its extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1",
+    "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Longest path Windows takes without the `\\?\` prefix of long paths.
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// Whether Windows allows `name` for a file.
+pub fn allowed(name: &str) -> bool {
+    !name.chars().any(reserved_character) && !name.ends_with(['.', ' ']) && !reserved_name(name)
+}
+
+/// `name` as Windows allows it, with the characters it doesn't written as look-alikes from the
+/// private use area. Those are the reserved ones, dots and spaces at the end, and the last one
+/// before the extension of the name of a device.
+pub fn escape(name: &str) -> Cow<'_, str> {
+    if allowed(name) {
+        return Cow::Borrowed(name);
+    }
+    let stem = &name[..name.find('.').unwrap_or(name.len())];
+    let last_of_device = stem.char_indices().last().filter(|_| reserved_name(name)).map(|(at, _)| at);
+    let trailing = name.trim_end_matches(['.', ' ']).len();
+    let escaped = name.char_indices().map(|(at, c)| match c {
+        _ if reserved_character(c) || at >= trailing || Some(at) == last_of_device => {
+            char::from_u32(ESCAPE_BASE + c as u32).expect("only ASCII is escaped")
+        }
+        c => c,
+    });
+    Cow::Owned(escaped.collect())
+}
+
+/// `name` as it was before [`escape`]. Look-alikes that `escape` wouldn't have written are kept.
+pub fn unescape(name: &str) -> Cow<'_, str> {
+    let original = |c: char| (c as u32).checked_sub(ESCAPE_BASE).filter(|&c| c < 0x80).and_then(char::from_u32);
+    if !name.chars().any(|c| original(c).is_some()) {
+        return Cow::Borrowed(name);
+    }
+    let unescaped: String = name.chars().map(|c| original(c).unwrap_or(c)).collect();
+    match escape(&unescaped) == name {
+        true => Cow::Owned(unescaped),
+        false => Cow::Borrowed(name),
+    }
+}
+
+/// The name in an index path `name` is kept as here, escaped on Windows.
+pub(super) fn local_name(name: &str) -> Cow<'_, str> {
+    match cfg!(windows) {
+        true => escape(name),
+        false => Cow::Borrowed(name),
+    }
+}
+
+/// The name a file kept on disk as `name` has in index paths, the other way round from
+/// [`local_name`].
+pub fn index_name(name: &str) -> Cow<'_, str> {
+    match cfg!(windows) {
+        true => unescape(name),
+        false => Cow::Borrowed(name),
+    }
+}
+
+/// Where the file at the index path `path` is kept in the folder at `root`. Its names are joined
+/// one by one, since a root with the `\\?\` prefix, as [`Path::canonicalize`] gives on Windows,
+/// doesn't take `/` between them, and one too long for Windows gets that prefix.
+pub fn local_path(root: &Path, path: &str) -> PathBuf {
+    let mut local = root.to_path_buf();
+    for name in path.split('/') {
+        local.push(&*local_name(name));
+    }
+    long_path(local)
+}
+
+/// Whether the file at the index path `path` is left out here, where Windows doesn't allow its
+/// name and the folder doesn't escape them.
+pub fn skipped(path: &str, names: WindowsNames) -> bool {
+    cfg!(windows) && names == WindowsNames::Skip && !path.split('/').all(allowed)
+}
+
+fn reserved_character(c: char) -> bool {
+    c < ' ' || RESERVED_CHARACTERS.contains(&c)
+}
+
+/// Whether `name` is of a device, which Windows takes whatever follows the first dot and spaces
+/// before it for.
+fn reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end_matches(' ');
+    RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved))
+}
+
+#[cfg(windows)]
+fn long_path(path: PathBuf) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    let long = match path.to_str() {
+        Some(text) if text.len() >= MAX_PATH => text.replace('/', "\\"),
+        _ => return path,
+    };
+    // Any other prefix is verbatim already, or of a device.
+    let (disk, unc) = match path.components().next() {
+        Some(Component::Prefix(prefix)) => {
+            (matches!(prefix.kind(), Prefix::Disk(_)), matches!(prefix.kind(), Prefix::UNC(..)))
+        }
+        _ => (false, false),
+    };
+    match (disk, unc) {
+        (true, _) => PathBuf::from(format!(r"\\?\{}", long)),
+        (_, true) => PathBuf::from(format!(r"\\?\UNC{}", &long[1..])),
+        _ => path,
+    }
+}
+
+#[cfg(not(windows))]
+fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_names_windows_does_not_allow() {
+        for (name, escaped) in [
+            ("notes.txt", "notes.txt"),
+            ("a:b?", "a\u{f03a}b\u{f03f}"),
+            ("tab\there", "tab\u{f009}here"),
+            ("ends in. ", "ends in\u{f02e}\u{f020}"),
+            ("CON", "CO\u{f04e}"),
+            ("nul.tar.gz", "nu\u{f06c}.tar.gz"),
+            ("console", "console"),
+        ] {
+            assert_eq!(escape(name), escaped);
+            assert_eq!(unescape(escaped), name);
+            assert!(allowed(&escape(name)), "{}", name);
+        }
+        // Not written by escaping, so kept as it is.
+        assert_eq!(unescape("\u{f041}"), "\u{f041}");
+    }
+}
@@ -0,0 +1,380 @@
+use std::ffi::OsString;
+use std::fs::{create_dir_all, read_to_string, remove_file, rename, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::{hash, verify};
+use crate::index::set_permissions;
+use crate::transfer::{sparse, FileBlocks};
+use crate::xattr::{self, Xattrs};
+
+/// Prefix of the name of a file being pulled, which is kept next to the file it becomes.
+pub const PARTIAL_PREFIX: &str = ".ss-partial.";
+/// Bytes written between two saves of which blocks came, the most a crash makes a pull fetch
+/// again.
+const SAVE_INTERVAL: u64 = 16 * 1024 * 1024;
+
+/// A file being pulled, which is written next to where it goes along with which of its blocks
+/// came. Opening it again for the same version of the file, after a restart too, keeps the blocks
+/// that came, and for any other version starts over.
+#[derive(Debug)]
+pub struct Partial {
+    target: PathBuf,
+    path: PathBuf,
+    file: File,
+    blocks: FileBlocks,
+    received: Vec<bool>,
+    /// Where the file had holes when opened, which blocks of zeros are left as so that a sparse
+    /// file stays so.
+    holes: Vec<Range<u64>>,
+    /// Bytes written since which blocks came was last saved.
+    unsaved: u64,
+}
+
+/// Which blocks of a partial file came, stored next to it.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct State {
+    /// Hex encoded [`FileBlocks::digest`] of the version being pulled.
+    version: String,
+    /// Hex encoded bit set of the blocks that came, the lowest bit of the first byte for the first
+    /// block.
+    received: String,
+}
+
+impl Partial {
+    /// Opens the partial file for pulling `blocks` to `target`, keeping what came of the same
+    /// version before.
+    pub fn open(target: &Path, blocks: &FileBlocks) -> io::Result<Partial> {
+        let path = partial_path(target);
+        let version = hex::encode(blocks.digest());
+        let received = match read_to_string(state_path(&path)).ok().and_then(|state| toml::from_str::<State>(&state).ok()) {
+            Some(state) if state.version == version && path.exists() => {
+                let bits = hex::decode(&state.received).unwrap_or_default();
+                (0..blocks.block_count()).map(|i| bits.get(i / 8).is_some_and(|byte| byte & (1 << (i % 8)) != 0))
+                    .collect()
+            }
+            _ => vec![false; blocks.block_count()],
+        };
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        if !received.contains(&true) {
+            // What came of another version is of no use, and must not be taken for this one's.
+            file.set_len(0)?;
+            if let Err(e) = remove_file(state_path(&path)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+        file.set_len(blocks.size)?;
+        let holes = sparse::holes(&file).unwrap_or_else(|e| {
+            debug!("Unable to find the holes in {}: {}", path.display(), e);
+            Vec::new()
+        });
+        let mut partial = Partial { target: target.to_path_buf(), path, file, blocks: blocks.clone(), received, holes,
+            unsaved: 0 };
+        for index in sparse::zero_blocks(blocks) {
+            let (offset, length) = blocks.block(index);
+            if sparse::in_hole(&partial.holes, offset..offset + u64::from(length)) {
+                partial.received[index] = true;
+            }
+        }
+        Ok(partial)
+    }
+
+    /// How many blocks came.
+    pub fn received(&self) -> usize {
+        self.received.iter().filter(|received| **received).count()
+    }
+
+    /// The blocks still to come, in order.
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.received.len()).filter(|index| !self.received[*index]).collect()
+    }
+
+    /// Writes block `index`, and now and then saves which blocks came. A block that doesn't match
+    /// its hash is refused with [`io::ErrorKind::InvalidData`], and one of zeros where the file
+    /// has a hole is left as that.
+    pub fn write(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        if hash(data) != self.blocks.hashes[index] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("block {} doesn't match its hash", index)));
+        }
+        let (offset, _) = self.blocks.block(index);
+        let zeros = data.iter().all(|byte| *byte == 0);
+        if !zeros || !sparse::in_hole(&self.holes, offset..offset + data.len() as u64) {
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(data)?;
+        }
+        self.received[index] = true;
+        self.unsaved += data.len() as u64;
+        if self.unsaved >= SAVE_INTERVAL {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Saves which blocks came, once what they hold is on disk.
+    pub fn save(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        let mut bits = vec![0u8; self.received.len().div_ceil(8)];
+        for (index, _) in self.received.iter().enumerate().filter(|(_, received)| **received) {
+            bits[index / 8] |= 1 << (index % 8);
+        }
+        let state = State { version: hex::encode(self.blocks.digest()), received: hex::encode(bits) };
+        let contents = toml::to_string(&state).map_err(|e| io::Error::other(e.to_string()))?;
+        let (state, temp) = (state_path(&self.path), with_suffix(&self.path, "state.tmp"));
+        {
+            let mut file = File::create(&temp)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+        }
+        rename(temp, state)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Reads the file back to check it against the hash of the whole file, returning the blocks
+    /// that no longer match theirs, which are then missing again.
+    pub fn verify(&mut self) -> io::Result<Vec<usize>> {
+        self.file.sync_data()?;
+        self.file.seek(SeekFrom::Start(0))?;
+        let corrupt = verify(&mut self.file, &self.blocks)?;
+        for index in &corrupt {
+            self.received[*index] = false;
+        }
+        Ok(corrupt)
+    }
+
+    /// Moves the file into place once every block came, as `modified` then and with
+    /// `permissions` and `xattrs` where given, otherwise with the permissions of the file it
+    /// replaces. It is renamed over that file once on disk, and the rename is put on disk too, so
+    /// the file there is always a whole version of it, the old or the new one, even after a
+    /// crash.
+    pub fn finish(self, modified: Option<SystemTime>, permissions: Option<u32>, xattrs: Option<&Xattrs>)
+        -> io::Result<()> {
+        if self.received.contains(&false) {
+            return Err(io::Error::other(format!("{} blocks are missing", self.missing().len())));
+        }
+        match permissions {
+            Some(permissions) => set_permissions(&self.file, permissions)?,
+            None => match self.target.metadata() {
+                Ok(replaced) => self.file.set_permissions(replaced.permissions())?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            },
+        }
+        if let Some(xattrs) = xattrs {
+            xattr::write(&self.file, xattrs)?;
+        }
+        if let Some(modified) = modified {
+            self.file.set_modified(modified)?;
+        }
+        self.file.sync_all()?;
+        rename(&self.path, &self.target)?;
+        if let Err(e) = sync_directory(&self.target) {
+            warn!("Unable to save the move of {} into place: {}", self.target.display(), e);
+        }
+        if let Err(e) = remove_file(state_path(&self.path)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Unable to remove {}: {}", state_path(&self.path).display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Puts a symbolic link to `link` at `target`, replacing what is there at once as a pulled file
+/// does.
+#[cfg(unix)]
+pub fn place_link(target: &Path, link: &str) -> io::Result<()> {
+    let partial = partial_path(target);
+    match remove_file(&partial) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    std::os::unix::fs::symlink(link, &partial)?;
+    rename(&partial, target)?;
+    sync_directory(target)
+}
+
+/// Moves the file at `from` to `target`, for a file a peer moved that is already here, replacing
+/// what is there at once as a pulled file does.
+pub fn place_moved(from: &Path, target: &Path) -> io::Result<()> {
+    if let Some(parent) = target.parent() {
+        create_dir_all(parent)?;
+    }
+    rename(from, target)?;
+    sync_directory(target)?;
+    sync_directory(from)
+}
+
+#[cfg(not(unix))]
+pub fn place_link(_target: &Path, _link: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symbolic links aren't synced on this system"))
+}
+
+/// Puts the entries of the directory `path` is in on disk, such as the file renamed to it.
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories can't be opened to be put on disk here, renames are on their own.
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn partial_path(target: &Path) -> PathBuf {
+    let mut name = OsString::from(PARTIAL_PREFIX);
+    name.push(target.file_name().unwrap_or_default());
+    target.with_file_name(name)
+}
+
+fn state_path(path: &Path) -> PathBuf {
+    with_suffix(path, "state")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn blocks(data: &[u8]) -> FileBlocks {
+        FileBlocks {
+            size: data.len() as u64,
+            hash: hash(data),
+            block_size: 4,
+            hashes: data.chunks(4).map(hash).collect(),
+            weak_hashes: Vec::new(),
+            offsets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_blocks_of_the_same_version() {
+        let directory = tempdir().unwrap();
+        let target = directory.path().join("file");
+        let mut partial = Partial::open(&target, &blocks(b"1234567890")).unwrap();
+        assert_eq!(partial.missing(), vec![0, 1, 2]);
+        partial.write(1, b"5678").unwrap();
+        partial.save().unwrap();
+        drop(partial);
+
+        let mut partial = Partial::open(&target, &blocks(b"1234567890")).unwrap();
+        assert_eq!(partial.missing(), vec![0, 2]);
+        partial.write(0, b"1234").unwrap();
+        partial.write(2, b"90").unwrap();
+        partial.finish(None, None, None).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"1234567890");
+        assert!(!state_path(&partial_path(&target)).exists());
+    }
+
+    #[test]
+    fn refuses_blocks_that_dont_match() {
+        let directory = tempdir().unwrap();
+        let target = directory.path().join("file");
+        let mut partial = Partial::open(&target, &blocks(b"1234567890")).unwrap();
+        let error = partial.write(0, b"1235").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(partial.missing(), vec![0, 1, 2]);
+
+        partial.write(0, b"1234").unwrap();
+        partial.write(1, b"5678").unwrap();
+        partial.write(2, b"90").unwrap();
+        // A block that changed on disk after it came is found before the file goes into place.
+        std::fs::write(partial_path(&target), b"1234567899").unwrap();
+        assert_eq!(partial.verify().unwrap(), vec![2]);
+        assert_eq!(partial.missing(), vec![2]);
+        partial.write(2, b"90").unwrap();
+        assert!(partial.verify().unwrap().is_empty());
+        partial.finish(None, None, None).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"1234567890");
+    }
+
+    #[test]
+    fn replaces_the_file_whole_with_its_time_and_permissions() {
+        let directory = tempdir().unwrap();
+        let target = directory.path().join("file");
+        std::fs::write(&target, b"old").unwrap();
+        let read_only = std::fs::File::open(&target).unwrap();
+        set_permissions(&read_only, 0o444).unwrap();
+
+        let mut partial = Partial::open(&target, &blocks(b"new")).unwrap();
+        partial.write(0, b"new").unwrap();
+        // Until it is done the old file is there as it was.
+        assert_eq!(std::fs::read(&target).unwrap(), b"old");
+        partial.finish(None, None, None).unwrap();
+        assert!(target.metadata().unwrap().permissions().readonly());
+
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_615_735_613);
+        let mut partial = Partial::open(&target, &blocks(b"newer")).unwrap();
+        partial.write(0, b"newe").unwrap();
+        partial.write(1, b"r").unwrap();
+        partial.finish(Some(modified), Some(0o644), None).unwrap();
+        let metadata = target.metadata().unwrap();
+        assert_eq!((metadata.modified().unwrap(), metadata.permissions().readonly()), (modified, false));
+        assert_eq!(std::fs::read(&target).unwrap(), b"newer");
+    }
+
+    #[test]
+    fn leaves_blocks_of_zeros_as_holes() {
+        let directory = tempdir().unwrap();
+        let target = directory.path().join("image");
+        let block = crate::transfer::BLOCK_SIZE as usize;
+        let mut data = vec![1; 3 * block];
+        data[block..2 * block].fill(0);
+        let blocks = crate::hash::hash_blocks(&mut data.as_slice(), crate::transfer::Chunking::Fixed).unwrap();
+        let mut partial = Partial::open(&target, &blocks).unwrap();
+        // Not every file system temporary files are on has holes.
+        if partial.holes.is_empty() {
+            return;
+        }
+        assert_eq!(partial.missing(), vec![0, 2]);
+        partial.write(0, &data[..block]).unwrap();
+        partial.write(2, &data[2 * block..]).unwrap();
+        assert!(partial.verify().unwrap().is_empty());
+        partial.finish(None, None, None).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+        let holes = sparse::holes(&File::open(&target).unwrap()).unwrap();
+        assert!(sparse::in_hole(&holes, block as u64..2 * block as u64), "{:?}", holes);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn places_links() {
+        let directory = tempdir().unwrap();
+        let target = directory.path().join("link");
+        std::fs::write(&target, b"a file before").unwrap();
+        place_link(&target, "elsewhere").unwrap();
+        place_link(&target, "../other").unwrap();
+        assert_eq!(std::fs::read_link(&target).unwrap(), Path::new("../other"));
+    }
+
+    #[test]
+    fn starts_over_for_another_version() {
+        let directory = tempdir().unwrap();
+        let target = directory.path().join("file");
+        let mut partial = Partial::open(&target, &blocks(b"1234567890")).unwrap();
+        partial.write(1, b"5678").unwrap();
+        partial.save().unwrap();
+        assert_eq!(Partial::open(&target, &blocks(b"0987654321")).unwrap().missing(), vec![0, 1, 2]);
+        assert!(Partial::open(&target, &blocks(b"1234567890")).unwrap().finish(None, None, None).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+
+use crate::hash::{hash, Hash};
+use crate::transfer::FileBlocks;
+
+/// Where `file` has holes, ranges it takes no space on disk for and reads as zeros, as the file
+/// system tells. None where it can't tell, as if the file had none.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn holes(file: &File) -> io::Result<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let length = file.metadata()?.len();
+    let seek = |offset: u64, whence| match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+        -1 => Err(io::Error::last_os_error()),
+        found => Ok(found as u64),
+    };
+    let mut holes = Vec::new();
+    let mut offset = 0;
+    while offset < length {
+        let hole = match seek(offset, libc::SEEK_HOLE) {
+            Ok(hole) => hole,
+            // The file system doesn't know of holes.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => break,
+            Err(e) => return Err(e),
+        };
+        if hole >= length {
+            break;
+        }
+        let data = match seek(hole, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // Nothing but the hole up to the end.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => length,
+            Err(e) => return Err(e),
+        };
+        holes.push(hole..data);
+        offset = data;
+    }
+    Ok(holes)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn holes(_file: &File) -> io::Result<Vec<Range<u64>>> {
+    Ok(Vec::new())
+}
+
+/// Whether `range` is all within one of `holes`.
+pub fn in_hole(holes: &[Range<u64>], range: Range<u64>) -> bool {
+    holes.iter().any(|hole| hole.start <= range.start && range.end <= hole.end)
+}
+
+/// The blocks of `blocks` that are nothing but zeros, which a file can leave as a hole rather than
+/// have them written or sent.
+pub fn zero_blocks(blocks: &FileBlocks) -> Vec<usize> {
+    let mut zeros: HashMap<u32, Hash> = HashMap::new();
+    (0..blocks.block_count()).filter(|index| {
+        let (_, length) = blocks.block(*index);
+        let zero = zeros.entry(length).or_insert_with(|| hash(&vec![0; length as usize]));
+        blocks.hashes[*index] == *zero
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::hash::hash_blocks;
+    use crate::transfer::{Chunking, BLOCK_SIZE};
+
+    use super::*;
+
+    #[test]
+    fn finds_holes_and_blocks_of_zeros() {
+        let directory = tempdir().unwrap();
+        let file = File::create(directory.path().join("file")).unwrap();
+        file.set_len(1 << 20).unwrap();
+        let holes = holes(&file).unwrap();
+        // Not every file system temporary files are on has holes.
+        assert!(holes.is_empty() || in_hole(&holes, 4096..8192), "{:?}", holes);
+
+        let block = BLOCK_SIZE as usize;
+        let mut data = vec![1; 3 * block];
+        data[block..2 * block].fill(0);
+        let blocks = hash_blocks(&mut data.as_slice(), Chunking::Fixed).unwrap();
+        assert_eq!(zero_blocks(&blocks), vec![1]);
+    }
+}
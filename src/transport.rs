@@ -0,0 +1,483 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::SigningKey;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::{IpVersions, Options};
+use crate::protocol::{exchange_hello, Compression, Hello, ProtocolError};
+
+pub use self::limit::{BandwidthGovernor, TokenBucket};
+pub use self::manager::{spawn_connection_manager, spawn_status_log, ConnectionManager, NewConnection};
+pub use self::noise::Noise;
+pub use self::path::{local_networks, Path};
+pub use self::quic::{Incoming, Quic};
+pub use self::relay::RelayCommand;
+pub use self::socks::{DeviceProxy, SocksProxy};
+pub use self::status::{spawn_status_server, StatusCommand};
+pub use self::tls::{PeerCertificate, Tls};
+
+mod limit;
+mod manager;
+mod noise;
+mod path;
+mod punch;
+mod quic;
+mod relay;
+mod socks;
+mod status;
+mod tls;
+
+/// How long to wait for an address to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the other side of a connection has to finish the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The port connections are accepted on after the configured one was taken, 0 while it wasn't.
+static FALLBACK_PORT: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    Tls(rustls::Error),
+    Noise(snow::Error),
+    /// The other side did not follow the sync protocol, or speaks no version of it this release
+    /// does.
+    Protocol(ProtocolError),
+    /// This device's certificate could not be made, or the other side's is not a device
+    /// certificate.
+    Certificate(String),
+    /// The other side does not speak the sync protocol.
+    BadProtocol,
+    /// The other side is not the device that was dialed.
+    WrongDevice { expected: String, found: String },
+    /// The other side's key is not the one its device id is derived from.
+    WrongKey { device_id: String },
+    /// The peer encrypts connections another way than this device.
+    OtherEncryption { device_id: String },
+    /// The peer announced no address to dial.
+    NoAddresses,
+    /// The SOCKS proxy could not be reached, or could not connect.
+    Proxy(String),
+}
+
+impl Display for TransportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(e) => write!(f, "{}", e),
+            TransportError::Tls(e) => write!(f, "TLS failed: {}", e),
+            TransportError::Noise(e) => write!(f, "Noise handshake failed: {}", e),
+            TransportError::Protocol(e) => write!(f, "{}", e),
+            TransportError::Certificate(e) => write!(f, "bad certificate: {}", e),
+            TransportError::BadProtocol => write!(f, "the other side does not speak the sync protocol"),
+            TransportError::WrongDevice { expected, found } =>
+                write!(f, "expected device {} but reached device {}", expected, found),
+            TransportError::WrongKey { device_id } =>
+                write!(f, "the key of device {} is not the one its id is derived from", device_id),
+            TransportError::OtherEncryption { device_id } =>
+                write!(f, "device {} encrypts connections another way than this device", device_id),
+            TransportError::NoAddresses => write!(f, "no addresses to dial"),
+            TransportError::Proxy(e) => write!(f, "SOCKS proxy {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<rustls::Error> for TransportError {
+    fn from(e: rustls::Error) -> Self {
+        TransportError::Tls(e)
+    }
+}
+
+impl From<ProtocolError> for TransportError {
+    fn from(e: ProtocolError) -> Self {
+        TransportError::Protocol(e)
+    }
+}
+
+impl From<snow::Error> for TransportError {
+    fn from(e: snow::Error) -> Self {
+        TransportError::Noise(e)
+    }
+}
+
+/// How connections over TCP are encrypted. With Noise no certificates are involved, QUIC is left
+/// out as it needs TLS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encryption {
+    #[default]
+    Tls,
+    Noise,
+}
+
+impl Display for Encryption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Encryption::Tls => write!(f, "tls"),
+            Encryption::Noise => write!(f, "noise"),
+        }
+    }
+}
+
+impl FromStr for Encryption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tls" => Ok(Encryption::Tls),
+            "noise" => Ok(Encryption::Noise),
+            _ => Err(format!("unknown encryption `{}`, use tls or noise", s)),
+        }
+    }
+}
+
+/// The handshake TCP connections are set up with, one of the [`Encryption`] options.
+#[derive(Clone)]
+pub enum Security {
+    Tls(Tls),
+    Noise(Noise),
+}
+
+impl Security {
+    pub fn new(encryption: Encryption, device_id: &str, key: &SigningKey) -> Result<Security, TransportError> {
+        match encryption {
+            Encryption::Tls => Ok(Security::Tls(Tls::new(device_id, key)?)),
+            Encryption::Noise => Ok(Security::Noise(Noise::new(device_id, key))),
+        }
+    }
+
+    /// The TLS setup, which QUIC connections need.
+    pub fn tls(&self) -> Option<&Tls> {
+        match self {
+            Security::Tls(tls) => Some(tls),
+            Security::Noise(_) => None,
+        }
+    }
+
+    fn connect(&self, socket: TcpStream) -> Result<(PeerCertificate, Stream), TransportError> {
+        match self {
+            Security::Tls(tls) => tls.connect(socket),
+            Security::Noise(noise) => noise.connect(socket),
+        }
+    }
+
+    fn accept(&self, socket: TcpStream) -> Result<(PeerCertificate, Stream), TransportError> {
+        match self {
+            Security::Tls(tls) => tls.accept(socket),
+            Security::Noise(noise) => noise.accept(socket),
+        }
+    }
+}
+
+/// Both directions of an encrypted connection, which can be split so that one thread reads while
+/// another writes.
+pub struct Stream {
+    reader: Box<dyn Read + Send>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl Stream {
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Stream {
+        Stream { reader: Box::new(reader), writer: Box::new(writer) }
+    }
+
+    pub fn split(self) -> (Box<dyn Read + Send>, Box<dyn Write + Send>) {
+        (self.reader, self.writer)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// An authenticated connection to a peer. The stream carrying its data is handed out separately
+/// when it is set up.
+#[derive(Debug)]
+pub struct Connection {
+    pub device_id: String,
+    /// Hex encoded key of the peer's certificate.
+    pub public_key: String,
+    pub address: SocketAddr,
+    /// One of the [`TRANSPORTS`](crate::broadcast::TRANSPORTS), or `relay` through a relay at
+    /// `address`.
+    pub transport: &'static str,
+    /// Whether this device dialed the peer, rather than the peer dialing this device.
+    pub outbound: bool,
+    pub established: Instant,
+    /// Protocol version both sides settled on when they said hello.
+    pub version: u32,
+    /// What the peer said it supports when it said hello.
+    pub capabilities: Vec<String>,
+    /// How data sent to the peer is compressed, settled on when saying hello.
+    pub compression: Compression,
+    closer: Closer,
+}
+
+/// What closes a connection from another thread than the one using its stream.
+#[derive(Debug)]
+enum Closer {
+    Tcp(TcpStream),
+    Quic(quinn::Connection),
+    /// A TCP connection to a relay, which leads on to the peer.
+    Relay(TcpStream),
+}
+
+impl Connection {
+    /// Connects to `address`, through `proxy` if one is given, expecting to reach the device
+    /// `expected`.
+    pub fn dial(security: &Security, proxy: Option<&SocksProxy>, address: SocketAddr, expected: &str)
+        -> Result<(Connection, Stream), TransportError> {
+        let socket = connect_tcp(proxy, address)?;
+        let (peer, stream) = security.connect(socket.try_clone()?)?;
+        let dialed = Connection::new(peer, stream, Closer::Tcp(socket), address, true);
+        Connection::expecting(dialed, expected)
+    }
+
+    /// Connects to `address` over QUIC, expecting to reach the device `expected`.
+    pub fn dial_quic(quic: &Quic, address: SocketAddr, expected: &str) -> Result<(Connection, Stream), TransportError> {
+        let (peer, stream, connection) = quic.connect(address)?;
+        let dialed = Connection::new(peer, stream, Closer::Quic(connection), address, true);
+        Connection::expecting(dialed, expected)
+    }
+
+    /// Connects through the relay at `relay`, reached through `proxy` if one is given, to the
+    /// device `expected`, which waits on the relay.
+    pub fn dial_relay(security: &Security, proxy: Option<&SocksProxy>, relay: SocketAddr, own_id: &str, expected: &str)
+        -> Result<(Connection, Stream), TransportError> {
+        let socket = relay::connect(proxy, relay, own_id, expected)?;
+        let (peer, stream) = security.connect(socket.try_clone()?)?;
+        let dialed = Connection::new(peer, stream, Closer::Relay(socket), relay, true);
+        Connection::expecting(dialed, expected)
+    }
+
+    /// Opens a direct path to the device `expected`, which waits on the relay at `relay`, and
+    /// connects over QUIC along it, through the gateways in front of both.
+    pub fn dial_punched(tls: &Tls, relay: SocketAddr, own_id: &str, expected: &str)
+        -> Result<(Connection, Stream), TransportError> {
+        let socket = quic::bind_socket(0)?;
+        let observed = relay::observe(&socket, relay)?;
+        let address = relay::request_punch(relay, own_id, expected, observed)?;
+        punch::punch(&socket, address)?;
+        let quic = Quic::with_socket(tls, socket)?;
+        let (peer, stream, connection) = quic.connect(address)?;
+        let dialed = Connection::new(peer, stream, Closer::Quic(connection), address, true);
+        Connection::expecting(dialed, expected)
+    }
+
+    /// Sets up a connection that a peer made to this device. Whether its key is the device's is
+    /// up to the caller.
+    pub fn accept(security: &Security, socket: TcpStream, address: SocketAddr)
+        -> Result<(Connection, Stream), TransportError> {
+        socket.set_nonblocking(false)?;
+        let (peer, stream) = security.accept(socket.try_clone()?)?;
+        Ok(Connection::new(peer, stream, Closer::Tcp(socket), address, false))
+    }
+
+    /// Sets up a connection that a peer made through the relay at `relay`, reached through `proxy`
+    /// if one is given, after inviting this device to `session`. Whether its key is the device's
+    /// is up to the caller.
+    pub fn accept_relayed(security: &Security, proxy: Option<&SocksProxy>, relay: SocketAddr, session: u64)
+        -> Result<(Connection, Stream), TransportError> {
+        let socket = relay::join(proxy, relay, session)?;
+        let (peer, stream) = security.accept(socket.try_clone()?)?;
+        Ok(Connection::new(peer, stream, Closer::Relay(socket), relay, false))
+    }
+
+    /// Opens a direct path to a peer at `address` that asked for one through the relay at `relay`
+    /// in `session`, and waits for it to connect over QUIC along it. Whether its key is the
+    /// device's is up to the caller.
+    pub fn accept_punched(tls: &Tls, relay: SocketAddr, session: u64, address: SocketAddr)
+        -> Result<(Connection, Stream), TransportError> {
+        let socket = quic::bind_socket(0)?;
+        let observed = relay::observe(&socket, relay)?;
+        relay::answer_punch(relay, session, observed)?;
+        punch::punch(&socket, address)?;
+        let quic = Quic::with_socket(tls, socket)?;
+        let incoming = quic.incoming(CONNECT_TIMEOUT).ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))?;
+        Connection::accept_quic(incoming)
+    }
+
+    /// Sets up a connection that a peer started over QUIC. Whether its key is the device's is up
+    /// to the caller.
+    pub fn accept_quic(incoming: Incoming) -> Result<(Connection, Stream), TransportError> {
+        // The endpoint is dual-stack, so IPv4 peers show up with IPv4-mapped addresses.
+        let address = incoming.remote_address();
+        let address = SocketAddr::new(address.ip().to_canonical(), address.port());
+        let (peer, stream, connection) = incoming.accept()?;
+        Ok(Connection::new(peer, stream, Closer::Quic(connection), address, false))
+    }
+
+    fn new(peer: PeerCertificate, stream: Stream, closer: Closer, address: SocketAddr, outbound: bool)
+        -> (Connection, Stream) {
+        let transport = match closer {
+            Closer::Tcp(_) => "tcp",
+            Closer::Quic(_) => "quic",
+            Closer::Relay(_) => "relay",
+        };
+        let connection = Connection {
+            device_id: peer.device_id,
+            public_key: peer.public_key,
+            address,
+            transport,
+            outbound,
+            established: Instant::now(),
+            version: 0,
+            capabilities: Vec::new(),
+            compression: Compression::None,
+            closer,
+        };
+        (connection, stream)
+    }
+
+    /// Keeps a dialed connection that reached the device `expected`. The handshake has already
+    /// checked that the device holds the key its id is derived from.
+    fn expecting((connection, stream): (Connection, Stream), expected: &str)
+        -> Result<(Connection, Stream), TransportError> {
+        if connection.device_id != expected {
+            connection.close();
+            return Err(TransportError::WrongDevice { expected: expected.to_string(), found: connection.device_id });
+        }
+        Ok((connection, stream))
+    }
+
+    /// Says hello over the new connection and settles on a protocol version, closing it if the
+    /// devices share none. Over TCP the peer has [`HANDSHAKE_TIMEOUT`] to answer, a QUIC
+    /// connection closes by itself when the peer goes away.
+    pub fn greet(&mut self, stream: &mut Stream, own: &Hello) -> Result<(), TransportError> {
+        let greeted = self.set_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(TransportError::from)
+            .and_then(|()| exchange_hello(stream, own, &self.device_id).map_err(TransportError::from))
+            .and_then(|greeted| self.set_timeout(None).map(|()| greeted).map_err(TransportError::from));
+        match greeted {
+            Ok((peer, version)) => {
+                self.version = version;
+                self.compression = Compression::negotiate(&own.compression, &peer.compression);
+                self.capabilities = peer.capabilities;
+                Ok(())
+            }
+            Err(e) => {
+                self.close();
+                Err(e)
+            }
+        }
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.closer {
+            Closer::Tcp(socket) | Closer::Relay(socket) => {
+                socket.set_read_timeout(timeout)?;
+                socket.set_write_timeout(timeout)
+            }
+            Closer::Quic(_) => Ok(()),
+        }
+    }
+
+    /// The device that dialed. When two devices dial each other at once, both keep the connection
+    /// dialed by the device with the lower id.
+    pub fn dialer<'a>(&'a self, own_id: &'a str) -> &'a str {
+        if self.outbound { own_id } else { &self.device_id }
+    }
+
+    pub fn close(&self) {
+        match &self.closer {
+            Closer::Tcp(socket) | Closer::Relay(socket) => {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            Closer::Quic(connection) => connection.close(0u32.into(), b""),
+        }
+    }
+}
+
+/// Opens a TCP connection to `address`, through `proxy` if one is given.
+pub fn connect_tcp(proxy: Option<&SocksProxy>, address: SocketAddr) -> Result<TcpStream, TransportError> {
+    match proxy {
+        Some(proxy) => socks::connect(proxy, address),
+        None => Ok(TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?),
+    }
+}
+
+/// The port this device accepts connections on, the configured one unless it was taken and
+/// [`Options::port_fallback`] picked another.
+pub fn listening_port(options: &Options) -> u16 {
+    match FALLBACK_PORT.load(Ordering::Relaxed) {
+        0 => options.port(),
+        port => port,
+    }
+}
+
+fn set_fallback_port(port: Option<u16>) {
+    FALLBACK_PORT.store(port.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Listens on the configured port, or on one the system picks if it is taken and
+/// [`Options::port_fallback`] is set, then on each of [`Options::listen_addresses`] of an allowed IP
+/// version. An explicit address that can't be bound is left out.
+pub fn bind_listeners(options: &Options) -> io::Result<Vec<TcpListener>> {
+    let (port, versions) = (options.port(), options.ip_versions());
+    let listener = match bind_listener(port, versions) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && options.port_fallback() => {
+            let listener = bind_listener(0, versions)?;
+            let fallback = listener.local_addr()?.port();
+            warn!("Port {} is taken, listening for connections on port {} instead", port, fallback);
+            set_fallback_port(Some(fallback));
+            listener
+        }
+        bound => {
+            set_fallback_port(None);
+            bound?
+        }
+    };
+    let mut listeners = vec![listener];
+    for address in options.listen_addresses().iter().filter(|address| versions.allows(address.ip())) {
+        match TcpListener::bind(address) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => warn!("Unable to listen for connections on {}: {}", address, e),
+        }
+    }
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    Ok(listeners)
+}
+
+/// Listens on `port` on every address of the IP versions in `versions`, or every IPv4 address
+/// where IPv6 is not available.
+pub fn bind_listener(port: u16, versions: IpVersions) -> io::Result<TcpListener> {
+    let ipv6 = |only_v6: bool| -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(only_v6)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+        socket.listen(128)?;
+        Ok(socket.into())
+    };
+    let ipv4 = || TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port));
+    match (versions.ipv4, versions.ipv6) {
+        (true, false) => ipv4(),
+        (false, true) => ipv6(true),
+        _ => ipv6(false).or_else(|_| ipv4()),
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::Options;
+use crate::transport::Stream;
+
+/// Fewest bytes a bucket holds when full, so that slow rates still move whole pieces at once.
+const MIN_BURST: u64 = 4096;
+/// Longest a reader or writer sleeps before looking at the rate again, so that raising a limit
+/// takes effect straight away.
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+/// A token bucket that lets through `rate` bytes per second on average, 0 meaning unlimited. It
+/// fills up to a quarter of a second's worth, so short bursts go through at once.
+#[derive(Debug)]
+pub struct TokenBucket {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn capacity(&self) -> f64 {
+        (self.rate / 4).max(MIN_BURST) as f64
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity());
+        self.updated = now;
+    }
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        let mut bucket = Bucket { rate, tokens: 0.0, updated: Instant::now() };
+        bucket.tokens = bucket.capacity();
+        TokenBucket { bucket: Mutex::new(bucket) }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.lock().rate
+    }
+
+    /// Changes the rate, which readers and writers waiting on the bucket pick up.
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.lock();
+        bucket.refill();
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(bucket.capacity());
+    }
+
+    /// Waits until some of `wanted` bytes may go through, returning how many.
+    pub fn take(&self, wanted: usize) -> usize {
+        loop {
+            let wait = {
+                let mut bucket = self.lock();
+                if bucket.rate == 0 || wanted == 0 {
+                    return wanted;
+                }
+                bucket.refill();
+                let needed = (wanted as f64).min(bucket.capacity());
+                if bucket.tokens >= needed {
+                    bucket.tokens -= needed;
+                    return needed as usize;
+                }
+                Duration::from_secs_f64((needed - bucket.tokens) / bucket.rate as f64)
+            };
+            sleep(wait.min(MAX_SLEEP));
+        }
+    }
+
+    /// Puts back bytes that were taken but not used.
+    fn refund(&self, unused: usize) {
+        let mut bucket = self.lock();
+        bucket.tokens = (bucket.tokens + unused as f64).min(bucket.capacity());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.bucket.lock().expect("token bucket lock poisoned")
+    }
+}
+
+/// The buckets transfers go through. Transfers with every device share one bucket in each
+/// direction, except in a direction a device has a limit of its own for, where it gets a bucket of
+/// its own.
+#[derive(Debug, Default)]
+pub struct BandwidthGovernor {
+    send: Arc<TokenBucket>,
+    recv: Arc<TokenBucket>,
+    devices: Mutex<HashMap<String, DeviceBuckets>>,
+}
+
+#[derive(Debug)]
+struct DeviceBuckets {
+    send: Option<Arc<TokenBucket>>,
+    recv: Option<Arc<TokenBucket>>,
+}
+
+impl BandwidthGovernor {
+    /// Takes up changed limits, for the transfers already going as well as new ones. Transfers with
+    /// a device that no longer has a limit of its own go on at the global rate of the time until
+    /// it reconnects.
+    pub fn update(&self, options: &Options) {
+        let (send_rate, recv_rate) = (bytes_per_second(options.max_send_kbps()), bytes_per_second(options.max_recv_kbps()));
+        self.send.set_rate(send_rate);
+        self.recv.set_rate(recv_rate);
+        let mut devices = self.devices.lock().expect("bandwidth governor lock poisoned");
+        for buckets in devices.values() {
+            buckets.send.iter().for_each(|bucket| bucket.set_rate(send_rate));
+            buckets.recv.iter().for_each(|bucket| bucket.set_rate(recv_rate));
+        }
+        for device in options.devices() {
+            let buckets = devices.entry(device.id.to_string()).or_insert(DeviceBuckets { send: None, recv: None });
+            own_bucket(&mut buckets.send, device.max_send_kbps);
+            own_bucket(&mut buckets.recv, device.max_recv_kbps);
+        }
+    }
+
+    /// The rates in bytes per second transfers with `device_id` are limited to, sending and
+    /// receiving, 0 meaning unlimited.
+    pub fn rates(&self, device_id: &str) -> (u64, u64) {
+        let devices = self.devices.lock().expect("bandwidth governor lock poisoned");
+        let buckets = devices.get(device_id);
+        let rate = |own: Option<&Arc<TokenBucket>>, global: &TokenBucket| own.map_or(global.rate(), |own| own.rate());
+        (rate(buckets.and_then(|buckets| buckets.send.as_ref()), &self.send),
+            rate(buckets.and_then(|buckets| buckets.recv.as_ref()), &self.recv))
+    }
+
+    /// Limits the transfers over `stream` to `device_id`.
+    pub fn limit(&self, device_id: &str, stream: Stream) -> Stream {
+        let (send, recv) = match self.devices.lock().expect("bandwidth governor lock poisoned").get(device_id) {
+            Some(buckets) => (buckets.send.clone(), buckets.recv.clone()),
+            None => (None, None),
+        };
+        let (reader, writer) = stream.split();
+        Stream::new(
+            Limited { inner: reader, bucket: recv.unwrap_or_else(|| self.recv.clone()) },
+            Limited { inner: writer, bucket: send.unwrap_or_else(|| self.send.clone()) },
+        )
+    }
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        TokenBucket::new(0)
+    }
+}
+
+/// Sets the rate of a device's own bucket, making one the first time the device has a limit and
+/// letting go of it once the device has none.
+fn own_bucket(bucket: &mut Option<Arc<TokenBucket>>, kbps: Option<u32>) {
+    match (bucket.as_ref(), kbps) {
+        (Some(own), Some(kbps)) => own.set_rate(bytes_per_second(kbps)),
+        (None, Some(kbps)) => *bucket = Some(Arc::new(TokenBucket::new(bytes_per_second(kbps)))),
+        (_, None) => *bucket = None,
+    }
+}
+
+fn bytes_per_second(kbps: u32) -> u64 {
+    u64::from(kbps) * 1000
+}
+
+/// A reader or writer that goes through a bucket.
+struct Limited<T> {
+    inner: T,
+    bucket: Arc<TokenBucket>,
+}
+
+impl<T: Read> Read for Limited<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let allowed = self.bucket.take(buf.len());
+        let read = self.inner.read(&mut buf[..allowed]);
+        self.bucket.refund(allowed - *read.as_ref().unwrap_or(&0));
+        read
+    }
+}
+
+impl<T: Write> Write for Limited<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let allowed = self.bucket.take(buf.len());
+        let written = self.inner.write(&buf[..allowed]);
+        self.bucket.refund(allowed - *written.as_ref().unwrap_or(&0));
+        written
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take_all(bucket: &TokenBucket, mut bytes: usize) {
+        while bytes > 0 {
+            bytes -= bucket.take(bytes);
+        }
+    }
+
+    #[test]
+    fn holds_transfers_to_the_rate() {
+        let bucket = TokenBucket::new(100_000);
+        let start = Instant::now();
+        // A quarter of a second's worth goes through at once, the rest at the rate.
+        take_all(&bucket, 50_000);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn unlimited_buckets_do_not_wait() {
+        let bucket = TokenBucket::new(0);
+        assert_eq!(bucket.take(10_000_000), 10_000_000);
+        bucket.set_rate(1000);
+        assert_eq!(bucket.rate(), 1000);
+        assert_eq!(bucket.take(10_000_000), MIN_BURST as usize);
+    }
+}
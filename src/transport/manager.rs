@@ -0,0 +1,780 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use rand::Rng;
+
+use crate::broadcast::{
+    announced_encryption, capabilities, capability_value, listen_addresses, mutual_transports, Peer, PeerEvent,
+    PeerTable, RELAY_SERVER_CAPABILITY,
+};
+use crate::config::{device_id_of, program_data, ConfigHandle, Options};
+use crate::protocol::{Compression, Hello, Keepalive, Mux, ProtocolError, Traffic, PING_PROTOCOL_VERSION};
+use crate::transport::relay::{self, Invitation};
+use crate::transport::{
+    bind_listeners, local_networks, BandwidthGovernor, Connection, Path, Quic, Security, Stream, Tls, TransportError,
+};
+
+/// How long to wait before dialing a peer again after the first failed attempt, doubling with
+/// every further one up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Waits are up to this fraction shorter or longer, so peers that failed together don't retry
+/// together.
+const BACKOFF_JITTER: f64 = 0.2;
+/// How often the peers are checked for being due to be dialed.
+const DIAL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often the listener checks for connections and for the port changing.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(200);
+/// How long to wait before listening again after the port could not be bound.
+const LISTEN_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the QUIC and relay listeners check whether they were enabled while they are not.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// How often a peer connected over a WAN or through a relay is dialed over the better paths, in
+/// case one opened up.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a connection a better one took over from is kept for the streams still open on it.
+const RETIRE_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often where the connection to every peer stands is logged.
+const STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where the connection to a peer stands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Not connected, dialed again at `until`. `failures` counts the attempts that failed in a
+    /// row, the last one with `last_error`.
+    Waiting { until: Instant, failures: u32, last_error: Option<String> },
+    Dialing { failures: u32 },
+    /// Connected since `since` over `path`, with `rtt` the round trip time of the last ping the
+    /// peer answered and `traffic` what went over the connection.
+    Connected {
+        address: SocketAddr,
+        transport: &'static str,
+        path: Path,
+        outbound: bool,
+        since: Instant,
+        rtt: Option<Duration>,
+        traffic: Traffic,
+    },
+}
+
+#[derive(Debug)]
+enum State {
+    Waiting { until: Instant, failures: u32, last_error: Option<String> },
+    Dialing { failures: u32 },
+    /// Connected with the connection numbered `id`, which tells it from one that replaced it.
+    /// Better paths than `path` were last tried at `probed`.
+    Connected { id: u64, connection: Connection, mux: Mux, path: Path, probed: Instant },
+}
+
+/// A connection the manager took to a peer, for syncing over.
+#[derive(Debug, Clone)]
+pub struct NewConnection {
+    pub device_id: String,
+    pub mux: Mux,
+    /// How data sent to the peer is compressed.
+    pub compression: Compression,
+}
+
+#[derive(Debug)]
+struct Entry {
+    peer: Peer,
+    state: State,
+}
+
+/// Keeps one connection to every peer in the peer table, dialing the addresses it announced and
+/// accepting the connections it makes. Connections are encrypted with TLS or Noise, and only kept
+/// when the peer proves it holds the key it signs its announcements with.
+///
+/// Peers are dialed over the most preferred transport both devices enable, falling back to the
+/// others. QUIC keeps a connection open when a laptop roams to another network, as the dialing
+/// side moves the connection to its new address. Addresses on a network this device is on are
+/// dialed before others, and the relay last; a peer connected any other way than over a LAN keeps
+/// being dialed over the better paths, and the first that connects takes over.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionManager {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    next_id: Arc<AtomicU64>,
+    /// The QUIC endpoint while QUIC is enabled.
+    quic: Arc<Mutex<Option<Quic>>>,
+    bandwidth: Arc<BandwidthGovernor>,
+    subscribers: Arc<Mutex<Vec<Sender<NewConnection>>>>,
+}
+
+impl ConnectionManager {
+    pub fn status(&self, device_id: &str) -> Option<ConnectionStatus> {
+        self.entries.lock().expect("connection manager lock poisoned").get(device_id).map(Entry::status)
+    }
+
+    /// The status of the connection to every known peer.
+    pub fn statuses(&self) -> Vec<(Peer, ConnectionStatus)> {
+        self.entries.lock().expect("connection manager lock poisoned").values()
+            .map(|entry| (entry.peer.clone(), entry.status())).collect()
+    }
+
+    /// The streams of the connection to a peer, while it is connected.
+    pub fn mux(&self, device_id: &str) -> Option<Mux> {
+        match &self.entries.lock().expect("connection manager lock poisoned").get(device_id)?.state {
+            State::Connected { mux, .. } => Some(mux.clone()),
+            _ => None,
+        }
+    }
+
+    /// The bandwidth limits every connection goes through, which follow the config as it is
+    /// reloaded.
+    pub fn bandwidth(&self) -> &BandwidthGovernor {
+        &self.bandwidth
+    }
+
+    /// Returns a receiver that gets every connection kept from now on, after the ones connected
+    /// already.
+    pub fn subscribe(&self) -> Receiver<NewConnection> {
+        let (sender, receiver) = channel();
+        let entries = self.entries.lock().expect("connection manager lock poisoned");
+        for (device_id, entry) in entries.iter() {
+            if let State::Connected { connection, mux, .. } = &entry.state {
+                let connected = NewConnection { device_id: device_id.clone(), mux: mux.clone(),
+                    compression: connection.compression };
+                sender.send(connected).expect("receiver is kept");
+            }
+        }
+        self.subscribers.lock().expect("connection manager lock poisoned").push(sender);
+        receiver
+    }
+
+    fn quic(&self) -> Option<Quic> {
+        self.quic.lock().expect("connection manager lock poisoned").clone()
+    }
+
+    /// Starts connecting to a newly found peer, or takes the latest addresses of a known one. A
+    /// peer waiting to be dialed again is dialed straight away if its addresses changed. Peers
+    /// that aren't configured devices are never dialed.
+    fn add_peer(&self, options: &Options, peer: Peer) {
+        if options.device(&peer.device_id).is_none() {
+            debug!("Not connecting to device {} ({}), it isn't configured", peer.device_name, peer.device_id);
+            return;
+        }
+        let mut entries = self.entries.lock().expect("connection manager lock poisoned");
+        match entries.get_mut(&peer.device_id) {
+            Some(entry) => {
+                let moved = (entry.peer.port, &entry.peer.addresses) != (peer.port, &peer.addresses);
+                if let State::Waiting { until, .. } = &mut entry.state {
+                    if moved {
+                        *until = Instant::now();
+                    }
+                }
+                entry.peer = peer;
+            }
+            None => {
+                let state = State::Waiting { until: Instant::now(), failures: 0, last_error: None };
+                entries.insert(peer.device_id.clone(), Entry { peer, state });
+            }
+        }
+    }
+
+    /// Forgets a lost peer, closing the connection to it.
+    fn remove_peer(&self, device_id: &str) {
+        let entry = self.entries.lock().expect("connection manager lock poisoned").remove(device_id);
+        if let Some(Entry { peer, state: State::Connected { connection, .. } }) = entry {
+            info!("Disconnecting from device {} ({}), it was lost", peer.device_name, peer.device_id);
+            connection.close();
+        }
+    }
+
+    /// The peers whose wait is over, which are marked as being dialed.
+    fn due(&self) -> Vec<Peer> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("connection manager lock poisoned");
+        entries.values_mut().filter_map(|entry| match entry.state {
+            State::Waiting { until, failures, .. } if until <= now => {
+                entry.state = State::Dialing { failures };
+                Some(entry.peer.clone())
+            }
+            _ => None,
+        }).collect()
+    }
+
+    /// The peers connected over a path that could be better and due to have the better ones
+    /// tried, with the path they are connected over.
+    fn due_for_probing(&self) -> Vec<(Peer, Path)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("connection manager lock poisoned");
+        entries.values_mut().filter_map(|entry| match &mut entry.state {
+            State::Connected { path, probed, .. } if *path > Path::Lan && now.duration_since(*probed) >= PROBE_INTERVAL => {
+                *probed = now;
+                Some((entry.peer.clone(), *path))
+            }
+            _ => None,
+        }).collect()
+    }
+
+    fn dial_failed(&self, device_id: &str, error: TransportError) {
+        let mut entries = self.entries.lock().expect("connection manager lock poisoned");
+        if let Some(entry) = entries.get_mut(device_id) {
+            // The peer may have connected to this device in the meantime.
+            if let State::Dialing { failures } = entry.state {
+                let failures = failures.saturating_add(1);
+                let wait = backoff(failures);
+                debug!("Unable to connect to device {} ({}), {} failed attempts, trying again in {:?}: {}",
+                    entry.peer.device_name, device_id, failures, wait, error);
+                entry.state = State::Waiting { until: Instant::now() + wait, failures, last_error: Some(error.to_string()) };
+            }
+        }
+    }
+
+    /// Keeps a new connection to a known peer, unless one is kept in its place, and watches it
+    /// until it closes. A connection with a certificate for a device that isn't configured, or with
+    /// another key than the one the device id is derived from, is refused.
+    fn register(&self, options: &Options, connection: Connection, stream: Stream) {
+        let key = hex::decode(&connection.public_key).unwrap_or_default();
+        if device_id_of(&key).to_string() != connection.device_id {
+            warn!("Refusing connection from {}, {}", connection.address,
+                TransportError::WrongKey { device_id: connection.device_id.clone() });
+            connection.close();
+            return;
+        }
+        if options.device(&connection.device_id).is_none() {
+            warn!("Refusing connection from device {} at {}, it isn't configured", connection.device_id,
+                connection.address);
+            connection.close();
+            return;
+        }
+        let own_id = options.device_id().to_string();
+        let mut entries = self.entries.lock().expect("connection manager lock poisoned");
+        let entry = match entries.get_mut(&connection.device_id) {
+            Some(entry) => entry,
+            None => {
+                debug!("Refusing connection from unknown device {} at {}", connection.device_id, connection.address);
+                connection.close();
+                return;
+            }
+        };
+        let path = Path::of(connection.transport, connection.address, &local_networks());
+        if let State::Connected { connection: existing, path: existing_path, .. } = &entry.state {
+            // A connection over a better path takes over, and otherwise a newer connection dialed by
+            // the same side replaces one that may be dead.
+            if (path, connection.dialer(&own_id)) > (*existing_path, existing.dialer(&own_id)) {
+                debug!("Already connected to device {}, closing the connection at {}", connection.device_id,
+                    connection.address);
+                connection.close();
+                return;
+            }
+        }
+
+        info!("Connected to device {} ({}) at {} over {} ({})", entry.peer.device_name, connection.device_id,
+            connection.address, connection.transport, path);
+        debug!("Speaking protocol version {} with device {}, compressing with {}", connection.version,
+            connection.device_id, connection.compression);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let device_id = connection.device_id.clone();
+        let stream = self.bandwidth.limit(&device_id, stream);
+        let mux = if connection.version >= PING_PROTOCOL_VERSION {
+            Mux::with_keepalive(stream, connection.outbound, Keepalive::default())
+        } else {
+            Mux::new(stream, connection.outbound)
+        };
+        let event = NewConnection { device_id: device_id.clone(), mux: mux.clone(),
+            compression: connection.compression };
+        let connected = State::Connected { id, connection, mux: mux.clone(), path, probed: Instant::now() };
+        match std::mem::replace(&mut entry.state, connected) {
+            // Streams already open on the connection taken over from finish on it.
+            State::Connected { connection: existing, mux: existing_mux, path: existing_path, .. }
+                if path < existing_path => retire(existing, existing_mux),
+            State::Connected { connection: existing, .. } => existing.close(),
+            _ => {}
+        }
+        // Sent before the entries are let go of, so a new subscriber gets every connection once.
+        self.subscribers.lock().expect("connection manager lock poisoned")
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        drop(entries);
+
+        let manager = self.clone();
+        spawn(move || {
+            let error = mux.wait_closed();
+            manager.disconnected(&device_id, id, error);
+        });
+    }
+
+    /// Dials the peer again soon after connection `id` to it closed.
+    fn disconnected(&self, device_id: &str, id: u64, error: Option<String>) {
+        let mut entries = self.entries.lock().expect("connection manager lock poisoned");
+        if let Some(entry) = entries.get_mut(device_id) {
+            if matches!(entry.state, State::Connected { id: current, .. } if current == id) {
+                match &error {
+                    Some(e) => info!("Connection to device {} ({}) failed: {}", entry.peer.device_name, device_id, e),
+                    None => info!("Connection to device {} ({}) closed", entry.peer.device_name, device_id),
+                }
+                let waiting = State::Waiting { until: Instant::now() + backoff(1), failures: 0, last_error: error };
+                // A connection given up on for not answering pings may still be open underneath.
+                if let State::Connected { connection, mux, .. } = std::mem::replace(&mut entry.state, waiting) {
+                    let traffic = mux.traffic();
+                    debug!("Sent {} bytes in {} frames to device {} and received {} bytes in {} frames",
+                        traffic.bytes_sent, traffic.frames_sent, device_id, traffic.bytes_received,
+                        traffic.frames_received);
+                    connection.close();
+                }
+            }
+        }
+    }
+}
+
+impl Entry {
+    fn status(&self) -> ConnectionStatus {
+        match &self.state {
+            State::Waiting { until, failures, last_error } =>
+                ConnectionStatus::Waiting { until: *until, failures: *failures, last_error: last_error.clone() },
+            State::Dialing { failures } => ConnectionStatus::Dialing { failures: *failures },
+            State::Connected { connection, mux, path, .. } => ConnectionStatus::Connected {
+                address: connection.address,
+                transport: connection.transport,
+                path: *path,
+                outbound: connection.outbound,
+                since: connection.established,
+                rtt: mux.rtt(),
+                traffic: mux.traffic(),
+            },
+        }
+    }
+}
+
+/// Logs where the connection to every peer stands every [`STATUS_INTERVAL`], and the rates
+/// transfers with it are limited to.
+pub fn spawn_status_log(manager: ConnectionManager) {
+    spawn(move || loop {
+        sleep(STATUS_INTERVAL);
+        for (peer, status) in manager.statuses() {
+            let name = &peer.device_name;
+            match status {
+                ConnectionStatus::Connected { address, transport, path, since, rtt, traffic, .. } => {
+                    let (send_limit, receive_limit) = manager.bandwidth().rates(&peer.device_id);
+                    info!("Connected to device {} at {} over {} ({}) for {:?}, round trip {:?}, sent {} bytes at \
+                        {} B/s (limit {}), received {} bytes at {} B/s (limit {})", name, address, transport, path,
+                        since.elapsed(), rtt, traffic.bytes_sent, traffic.send_rate, send_limit, traffic.bytes_received,
+                        traffic.receive_rate, receive_limit);
+                }
+                ConnectionStatus::Dialing { failures } => debug!("Dialing device {} after {} failures", name, failures),
+                ConnectionStatus::Waiting { until, failures, last_error } => debug!(
+                    "Dialing device {} again in {:?} after {} failures, the last with {}", name,
+                    until.saturating_duration_since(Instant::now()), failures,
+                    last_error.as_deref().unwrap_or("no error")),
+            }
+        }
+    });
+}
+
+/// Closes a connection once the streams open on it closed, or after [`RETIRE_TIMEOUT`].
+fn retire(connection: Connection, mux: Mux) {
+    spawn(move || {
+        mux.wait_idle(RETIRE_TIMEOUT);
+        connection.close();
+    });
+}
+
+/// Spawns the threads that connect to every peer in `peers`, dialing each again with exponential
+/// backoff while it can't be reached, and accept connections from them on the sync port, the QUIC
+/// port and the relay this device waits on. A connection to a lost peer is closed. Changing the encryption takes a restart.
+pub fn spawn_connection_manager(config: ConfigHandle, peers: PeerTable) -> ConnectionManager {
+    let manager = ConnectionManager::default();
+    manager.bandwidth.update(&config.current());
+    let own_id = config.current().device_id().to_string();
+    let encryption = config.current().encryption();
+    let security = match Security::new(encryption, &own_id, &program_data().signing_key()) {
+        Ok(security) => security,
+        Err(e) => {
+            warn!("Unable to set up {} encryption, not connecting to any device: {}", encryption, e);
+            return manager;
+        }
+    };
+    let events = peers.subscribe();
+    for peer in peers.peers() {
+        manager.add_peer(&config.current(), peer);
+    }
+
+    let dialer = manager.clone();
+    let (dialer_config, dialer_id, dialer_security) = (config.clone(), own_id.clone(), security.clone());
+    spawn(move || loop {
+        match events.recv_timeout(DIAL_INTERVAL) {
+            Ok(PeerEvent::Found(peer)) | Ok(PeerEvent::Updated(peer)) => dialer.add_peer(&dialer_config.current(), peer),
+            Ok(PeerEvent::Lost(peer)) => dialer.remove_peer(&peer.device_id),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        dialer.bandwidth.update(&dialer_config.current());
+        for peer in dialer.due() {
+            let (manager, own_id, security) = (dialer.clone(), dialer_id.clone(), dialer_security.clone());
+            let options = dialer_config.current();
+            spawn(move || dial(&manager, &security, &options, &own_id, &peer));
+        }
+        for (peer, path) in dialer.due_for_probing() {
+            let (manager, own_id, security) = (dialer.clone(), dialer_id.clone(), dialer_security.clone());
+            let options = dialer_config.current();
+            spawn(move || probe(&manager, &security, &options, &own_id, &peer, path));
+        }
+    });
+
+    let listener = manager.clone();
+    if let Some(tls) = security.tls() {
+        let (config, manager, tls) = (config.clone(), manager.clone(), tls.clone());
+        spawn(move || listen_quic(&config, &manager, &tls));
+    }
+    let (relay_config, relay_manager, relay_security, relay_id) =
+        (config.clone(), manager.clone(), security.clone(), own_id.clone());
+    spawn(move || listen_relay(&relay_config, &relay_manager, &relay_security, &relay_id));
+    spawn(move || listen(&config, &listener, &security));
+    manager
+}
+
+/// Tries each way to the peer in turn until one connects: each address over each transport both
+/// devices enable, and then with the help of the relay the peer waits on.
+fn dial(manager: &ConnectionManager, security: &Security, options: &Options, own_id: &str, peer: &Peer) {
+    if let Err(e) = connect(manager, security, options, own_id, peer, None) {
+        manager.dial_failed(&peer.device_id, e);
+    }
+}
+
+/// Tries the ways to a connected peer over a better path than `path`, which take over from the
+/// connection once one connects.
+fn probe(manager: &ConnectionManager, security: &Security, options: &Options, own_id: &str, peer: &Peer, path: Path) {
+    if let Err(e) = connect(manager, security, options, own_id, peer, Some(path)) {
+        debug!("No path to device {} better than over {}: {}", peer.device_id, path, e);
+    }
+}
+
+/// Connects to the peer the most direct way that works of those better than `better_than`, LAN
+/// addresses first, then WAN ones, then the relay, and in the order of the transports for each.
+fn connect(manager: &ConnectionManager, security: &Security, options: &Options, own_id: &str, peer: &Peer,
+    better_than: Option<Path>) -> Result<(), TransportError> {
+    if announced_encryption(&peer.capabilities) != Some(options.encryption()) {
+        return Err(TransportError::OtherEncryption { device_id: peer.device_id.clone() });
+    }
+    let mut transports = mutual_transports(&capabilities(options), &peer.capabilities);
+    // Releases before capabilities were announced only sync over TCP.
+    if peer.capabilities.is_empty() {
+        transports.push("tcp");
+    }
+    // A SOCKS proxy only carries TCP.
+    let proxy = options.proxy_for(&peer.device_id);
+    if proxy.is_some() {
+        transports.retain(|transport| *transport == "tcp");
+    }
+    // Then a direct path through the gateways in front of both devices, which the relay the peer
+    // waits on helps open, and the last resort is relaying everything through it.
+    let relay = capability_value(&peer.capabilities, RELAY_SERVER_CAPABILITY).filter(|relay| !relay.is_empty());
+    if relay.is_some() {
+        if security.tls().is_some() && proxy.is_none() {
+            transports.push("punch");
+        }
+        transports.push("relay");
+    }
+
+    let networks = local_networks();
+    let versions = options.ip_versions();
+    let mut candidates = Vec::new();
+    for transport in transports {
+        let quic = match transport {
+            "quic" => match (manager.quic(), capability_value(&peer.capabilities, "transport:quic")
+                .and_then(|port| port.parse::<u16>().ok())) {
+                (Some(quic), Some(port)) => Some((quic, port)),
+                _ => continue,
+            },
+            _ => None,
+        };
+        let addresses = match transport {
+            "punch" | "relay" => relay.map(relay::resolve).unwrap_or_default(),
+            _ => dial_addresses(peer),
+        };
+        for address in addresses.into_iter().filter(|address| versions.allows(address.ip())) {
+            // A punched path leads to the peer's gateway, wherever the relay is.
+            let path = match transport {
+                "punch" => Path::Wan,
+                _ => Path::of(transport, address, &networks),
+            };
+            if better_than.is_none_or(|current| path < current) {
+                candidates.push((path, transport, address, quic.clone()));
+            }
+        }
+    }
+    candidates.sort_by_key(|(path, ..)| *path);
+
+    let hello = Hello::new(options);
+    let mut error = TransportError::NoAddresses;
+    for (_, transport, mut address, quic) in candidates {
+        let dialed = match (transport, &quic) {
+            ("punch", _) => match security.tls() {
+                Some(tls) => Connection::dial_punched(tls, address, own_id, &peer.device_id),
+                None => continue,
+            },
+            ("relay", _) => Connection::dial_relay(security, proxy, address, own_id, &peer.device_id),
+            // The gateway only forwards the sync port, and only over TCP.
+            (_, Some(_)) if Some(address) == peer.external_address => continue,
+            (_, Some((quic, port))) => {
+                address.set_port(*port);
+                Connection::dial_quic(quic, address, &peer.device_id)
+            }
+            (_, None) => Connection::dial(security, proxy, address, &peer.device_id),
+        };
+        let greeted = dialed.and_then(|(mut connection, mut stream)| {
+            connection.greet(&mut stream, &hello).map(|()| (connection, stream))
+        });
+        match greeted {
+            Ok((connection, stream)) => {
+                manager.register(options, connection, stream);
+                return Ok(());
+            }
+            Err(e @ TransportError::Protocol(ProtocolError::IncompatibleVersion { .. })) => {
+                warn!("Unable to connect to device {} ({}), {}", peer.device_name, peer.device_id, e);
+                return Err(e);
+            }
+            Err(e) => {
+                debug!("Unable to connect to device {} at {} over {}: {}", peer.device_id, address, transport, e);
+                error = e;
+            }
+        }
+    }
+    Err(error)
+}
+
+/// The addresses to dial a peer at, in the order it announced them, then the one its gateway
+/// forwards and the ones its announcements came from.
+fn dial_addresses(peer: &Peer) -> Vec<SocketAddr> {
+    let mut addresses: Vec<SocketAddr> = Vec::new();
+    // A device whose port was taken announces the one it fell back to.
+    let port = capability_value(&peer.capabilities, "transport:tcp").and_then(|port| port.parse::<u16>().ok())
+        .unwrap_or(peer.port);
+    let announced = peer.addresses.iter().filter_map(|ip| {
+        let mut address = SocketAddr::new(*ip, port);
+        if let SocketAddr::V6(v6) = &mut address {
+            // Link-local addresses only work on the interface an announcement came in on.
+            if v6.ip().is_unicast_link_local() {
+                let source = peer.sources.iter().find(|(source, _)| source.ip() == *ip)?;
+                match source.0 {
+                    SocketAddr::V6(source) => v6.set_scope_id(source.scope_id()),
+                    SocketAddr::V4(_) => return None,
+                }
+            }
+        }
+        Some(address)
+    });
+    let sources = peer.sources.iter().map(|(source, _)| SocketAddr::new(source.ip(), port));
+    for address in announced.chain(listen_addresses(&peer.capabilities)).chain(peer.external_address).chain(sources) {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+/// Accepts connections on the sync port and the listen addresses, binding them again whenever they
+/// change.
+fn listen(config: &ConfigHandle, manager: &ConnectionManager, security: &Security) {
+    let wanted = |options: &Options| {
+        (options.port(), options.ip_versions(), options.port_fallback(), options.listen_addresses().to_vec())
+    };
+    loop {
+        let current = wanted(&config.current());
+        let listeners = match bind_listeners(&config.current()) {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                warn!("Unable to listen for connections on port {}: {}", current.0, e);
+                sleep(LISTEN_RETRY_INTERVAL);
+                continue;
+            }
+        };
+        for listener in &listeners {
+            if let Ok(address) = listener.local_addr() {
+                debug!("Listening for connections on {}", address);
+            }
+        }
+
+        while wanted(&config.current()) == current {
+            let mut accepted = false;
+            for listener in &listeners {
+                match listener.accept() {
+                    Ok((stream, address)) => {
+                        accepted = true;
+                        let (manager, security, options) = (manager.clone(), security.clone(), config.current());
+                        spawn(move || {
+                            let accepted = Connection::accept(&security, stream, address);
+                            accepted_from(&manager, &options, address, accepted);
+                        });
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => debug!("Unable to accept a connection: {}", e),
+                }
+            }
+            if !accepted {
+                sleep(ACCEPT_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Accepts connections on the QUIC port while QUIC is enabled, binding it again whenever it
+/// changes. The endpoint is shared with the dialer, so both directions use the one socket.
+fn listen_quic(config: &ConfigHandle, manager: &ConnectionManager, tls: &Tls) {
+    loop {
+        let options = config.current();
+        let wanted = if options.quic() { Some(options.quic_port()) } else { None };
+        if manager.quic().map(|quic| quic.port()) != wanted {
+            *manager.quic.lock().expect("connection manager lock poisoned") = None;
+            if let Some(port) = wanted {
+                match Quic::bind(tls, port) {
+                    Ok(quic) => {
+                        debug!("Listening for QUIC connections on port {}", port);
+                        *manager.quic.lock().expect("connection manager lock poisoned") = Some(quic);
+                    }
+                    Err(e) => {
+                        warn!("Unable to listen for QUIC connections on port {}: {}", port, e);
+                        sleep(LISTEN_RETRY_INTERVAL);
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let quic = match manager.quic() {
+            Some(quic) => quic,
+            None => {
+                sleep(RELOAD_INTERVAL);
+                continue;
+            }
+        };
+        if let Some(incoming) = quic.incoming(ACCEPT_INTERVAL) {
+            let (manager, address) = (manager.clone(), incoming.remote_address());
+            spawn(move || {
+                let accepted = Connection::accept_quic(incoming);
+                accepted_from(&manager, &options, address, accepted);
+            });
+        }
+    }
+}
+
+/// Waits on the configured relay for devices that can't dial this one, joining every connection
+/// they make through it. The relay is waited on again when it goes away or is changed.
+fn listen_relay(config: &ConfigHandle, manager: &ConnectionManager, security: &Security, own_id: &str) {
+    let wanted = |options: &Options| options.relay_server().map(|relay| {
+        (relay.to_string(), options.socks_proxy().cloned(), options.relay_password().map(str::to_string))
+    });
+    loop {
+        let current = match wanted(&config.current()) {
+            Some(wanted) => wanted,
+            None => {
+                sleep(RELOAD_INTERVAL);
+                continue;
+            }
+        };
+        let (relay, proxy, password) = (&current.0, &current.1, current.2.as_deref());
+        let waited = relay::resolve(relay).into_iter().find_map(|address| match relay::wait(proxy.as_ref(), address, own_id, password) {
+            Ok(waiting) => Some((address, waiting)),
+            Err(e) => {
+                debug!("Unable to wait on relay {} at {}: {}", relay, address, e);
+                None
+            }
+        });
+        let (address, mut waiting) = match waited {
+            Some(waited) => waited,
+            None => {
+                warn!("Unable to reach relay {}, devices that can't dial this one won't reach it", relay);
+                sleep(LISTEN_RETRY_INTERVAL);
+                continue;
+            }
+        };
+        debug!("Waiting on relay {} at {}", relay, address);
+
+        while wanted(&config.current()).as_ref() == Some(&current) {
+            match waiting.invitation(RELOAD_INTERVAL) {
+                Ok(Some(Invitation::Relay { session, from })) => {
+                    debug!("Device {} connects through relay {}", from, relay);
+                    let (manager, security, options) = (manager.clone(), security.clone(), config.current());
+                    let proxy = proxy.clone();
+                    spawn(move || {
+                        let accepted = Connection::accept_relayed(&security, proxy.as_ref(), address, session);
+                        accepted_from(&manager, &options, address, accepted);
+                    });
+                }
+                Ok(Some(Invitation::Punch { from, .. })) if proxy.is_some() =>
+                    debug!("Device {} wants to punch through, which this device doesn't behind a proxy", from),
+                Ok(Some(Invitation::Punch { session, from, address: peer })) => match security.tls() {
+                    Some(tls) => {
+                        debug!("Device {} at {} punches through to this device", from, peer);
+                        let (manager, tls, options) = (manager.clone(), tls.clone(), config.current());
+                        spawn(move || {
+                            let accepted = Connection::accept_punched(&tls, address, session, peer);
+                            accepted_from(&manager, &options, peer, accepted);
+                        });
+                    }
+                    None => debug!("Device {} wants to punch through, which takes TLS", from),
+                },
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Stopped waiting on relay {}: {}", relay, e);
+                    sleep(LISTEN_RETRY_INTERVAL);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Says hello over a connection a peer made and keeps it.
+fn accepted_from(manager: &ConnectionManager, options: &Options, address: SocketAddr,
+    accepted: Result<(Connection, Stream), TransportError>) {
+    let hello = Hello::new(options);
+    let greeted = accepted.and_then(|(mut connection, mut stream)| {
+        connection.greet(&mut stream, &hello).map(|()| (connection, stream))
+    });
+    match greeted {
+        Ok((connection, stream)) => manager.register(options, connection, stream),
+        Err(e @ TransportError::Protocol(ProtocolError::IncompatibleVersion { .. })) =>
+            warn!("Refusing connection from {}, {}", address, e),
+        Err(e) => debug!("Refusing connection from {}: {}", address, e),
+    }
+}
+
+/// How long to wait after `failures` attempts in a row failed.
+fn backoff(failures: u32) -> Duration {
+    let wait = MIN_BACKOFF.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(MAX_BACKOFF);
+    wait.mul_f64(1.0 + rand::thread_rng().gen_range(-BACKOFF_JITTER..=BACKOFF_JITTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let within = |wait: Duration, expected: Duration| {
+            wait >= expected.mul_f64(1.0 - BACKOFF_JITTER) && wait <= expected.mul_f64(1.0 + BACKOFF_JITTER)
+        };
+        assert!(within(backoff(1), MIN_BACKOFF));
+        assert!(within(backoff(4), MIN_BACKOFF * 8));
+        assert!(within(backoff(100), MAX_BACKOFF));
+    }
+
+    #[test]
+    fn dials_the_announced_port_and_listen_addresses() {
+        let now = SystemTime::now();
+        let peer = Peer {
+            device_id: "peer".to_string(),
+            device_name: "peer".to_string(),
+            port: 11529,
+            addresses: vec!["192.168.1.2".parse().unwrap()],
+            external_address: None,
+            public_key: None,
+            capabilities: vec!["transport:tcp=40000".to_string(), "listen=10.8.0.2:11530".to_string()],
+            sources: vec![("192.168.1.2:11529".parse().unwrap(), now)],
+            last_seen: now,
+            expires: now,
+        };
+        let expected: Vec<SocketAddr> = vec!["192.168.1.2:40000".parse().unwrap(), "10.8.0.2:11530".parse().unwrap()];
+        assert_eq!(dial_addresses(&peer), expected);
+    }
+}
@@ -0,0 +1,247 @@
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use snow::{Builder, HandshakeState, TransportState};
+
+use crate::transport::tls::ALPN_PROTOCOL;
+use crate::transport::{PeerCertificate, Stream, TransportError, HANDSHAKE_TIMEOUT};
+
+/// XX sends both static keys during the handshake, so neither side has to know the other's first.
+const NOISE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Largest Noise message, set by the length prefix of a frame.
+const MAX_MESSAGE: usize = u16::MAX as usize;
+/// Space the authentication tag takes up in every encrypted message.
+const TAG_LENGTH: usize = 16;
+
+/// The Noise setup of this device. Its static key is the X25519 form of the device key, so a peer
+/// that knows the key the device signs its announcements with knows its Noise key too, without any
+/// certificates. Each side sends its device id and Ed25519 key in the handshake, which the other
+/// checks against the static key it proved it holds.
+#[derive(Clone)]
+pub struct Noise {
+    private_key: [u8; 32],
+    identity: Vec<u8>,
+}
+
+impl Noise {
+    pub fn new(device_id: &str, key: &SigningKey) -> Noise {
+        let mut identity = key.verifying_key().to_bytes().to_vec();
+        identity.extend_from_slice(device_id.as_bytes());
+        Noise { private_key: key.to_scalar_bytes(), identity }
+    }
+
+    /// Runs the handshake as the side that dialed, returning who answered.
+    pub fn connect(&self, socket: TcpStream) -> Result<(PeerCertificate, Stream), TransportError> {
+        let mut socket = socket;
+        let mut handshake = self.builder().build_initiator()?;
+        with_timeout(&mut socket, |socket| {
+            write_handshake(&mut handshake, socket, &[])?;
+            let peer = read_handshake(&mut handshake, socket)?;
+            write_handshake(&mut handshake, socket, &self.identity)?;
+            Ok(peer)
+        }).and_then(|peer| self.finish(peer, handshake, socket))
+    }
+
+    /// Runs the handshake as the side that was dialed, returning who dialed.
+    pub fn accept(&self, socket: TcpStream) -> Result<(PeerCertificate, Stream), TransportError> {
+        let mut socket = socket;
+        let mut handshake = self.builder().build_responder()?;
+        with_timeout(&mut socket, |socket| {
+            read_handshake(&mut handshake, socket)?;
+            write_handshake(&mut handshake, socket, &self.identity)?;
+            read_handshake(&mut handshake, socket)
+        }).and_then(|peer| self.finish(peer, handshake, socket))
+    }
+
+    fn builder(&self) -> Builder<'_> {
+        Builder::new(NOISE_PATTERN.parse().expect("invalid Noise pattern"))
+            .local_private_key(&self.private_key)
+            .prologue(ALPN_PROTOCOL)
+    }
+
+    /// Checks that the identity the other side sent is its static key, and starts the session.
+    fn finish(&self, identity: Vec<u8>, handshake: HandshakeState, socket: TcpStream)
+        -> Result<(PeerCertificate, Stream), TransportError> {
+        let invalid = |reason: &str| TransportError::Certificate(reason.to_string());
+        if identity.len() < 32 {
+            return Err(invalid("the other side sent no device key"));
+        }
+        let (key, device_id) = identity.split_at(32);
+        let key = <[u8; 32]>::try_from(key).expect("key is 32 bytes");
+        let verifying_key = VerifyingKey::from_bytes(&key).map_err(|_| invalid("the device key is not an Ed25519 key"))?;
+        if handshake.get_remote_static() != Some(&verifying_key.to_montgomery().to_bytes()[..]) {
+            return Err(invalid("the other side does not hold the device key it sent"));
+        }
+        let device_id = String::from_utf8(device_id.to_vec()).map_err(|_| invalid("the device id is not UTF-8"))?;
+        let peer = PeerCertificate::new(&device_id, &key).map_err(TransportError::Certificate)?;
+        let session = Arc::new(Mutex::new(handshake.into_transport_mode()?));
+        let writer = NoiseWriter { socket: socket.try_clone()?, session: session.clone() };
+        let reader = NoiseReader { socket, session, buffer: Vec::new(), position: 0 };
+        Ok((peer, Stream::new(reader, writer)))
+    }
+}
+
+/// Runs `handshake` with the socket timing out after [`HANDSHAKE_TIMEOUT`], like TLS does.
+fn with_timeout<T>(socket: &mut TcpStream, handshake: impl FnOnce(&mut TcpStream) -> Result<T, TransportError>)
+    -> Result<T, TransportError> {
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let result = handshake(socket)?;
+    socket.set_read_timeout(None)?;
+    socket.set_write_timeout(None)?;
+    Ok(result)
+}
+
+fn write_handshake(handshake: &mut HandshakeState, socket: &mut TcpStream, payload: &[u8]) -> Result<(), TransportError> {
+    let mut message = vec![0; MAX_MESSAGE];
+    let length = handshake.write_message(payload, &mut message)?;
+    write_frame(socket, &message[..length])?;
+    Ok(())
+}
+
+fn read_handshake(handshake: &mut HandshakeState, socket: &mut TcpStream) -> Result<Vec<u8>, TransportError> {
+    let message = read_frame(socket)?.ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+    let mut payload = vec![0; MAX_MESSAGE];
+    let length = handshake.read_message(&message, &mut payload)?;
+    payload.truncate(length);
+    Ok(payload)
+}
+
+/// Writes a message prefixed with its length, as two big endian bytes.
+fn write_frame(socket: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(message.len()).map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(message);
+    socket.write_all(&frame)
+}
+
+/// Reads a message written by [`write_frame`], `None` if the stream ended before one started.
+fn read_frame(socket: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 2];
+    match socket.read(&mut length[..1])? {
+        0 => return Ok(None),
+        _ => socket.read_exact(&mut length[1..])?,
+    }
+    let mut message = vec![0; usize::from(u16::from_be_bytes(length))];
+    socket.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+/// A TCP stream encrypted with the keys of a finished Noise handshake. Each direction has keys of
+/// its own, so the session is only locked to encrypt or decrypt a message.
+struct NoiseReader {
+    socket: TcpStream,
+    session: Arc<Mutex<TransportState>>,
+    /// Decrypted data not read yet, from `position` on.
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+struct NoiseWriter {
+    socket: TcpStream,
+    session: Arc<Mutex<TransportState>>,
+}
+
+impl Read for NoiseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            let message = match read_frame(&mut self.socket)? {
+                Some(message) => message,
+                None => return Ok(0),
+            };
+            self.buffer.resize(message.len(), 0);
+            let length = self.session.lock().expect("Noise session lock poisoned").read_message(&message, &mut self.buffer)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            self.buffer.truncate(length);
+            self.position = 0;
+        }
+        let length = buf.len().min(self.buffer.len() - self.position);
+        buf[..length].copy_from_slice(&self.buffer[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+impl Write for NoiseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(MAX_MESSAGE - TAG_LENGTH);
+        let mut message = vec![0; length + TAG_LENGTH];
+        let written = self.session.lock().expect("Noise session lock poisoned").write_message(&buf[..length], &mut message)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        write_frame(&mut self.socket, &message[..written])?;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread::spawn;
+
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::config::device_id_of;
+
+    fn id_of(key: &SigningKey) -> String {
+        device_id_of(key.verifying_key().as_bytes()).to_string()
+    }
+
+    #[test]
+    fn sessions_are_keyed_on_the_device_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (server_key, client_key) = (SigningKey::generate(&mut OsRng), SigningKey::generate(&mut OsRng));
+        let server = Noise::new(&id_of(&server_key), &server_key);
+        let accepted = spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let (peer, mut stream) = server.accept(socket).unwrap();
+            let mut message = vec![0; 100_000];
+            stream.read_exact(&mut message).unwrap();
+            (peer, message)
+        });
+
+        let client = Noise::new(&id_of(&client_key), &client_key);
+        let (peer, mut stream) = client.connect(TcpStream::connect(address).unwrap()).unwrap();
+        // Longer than one Noise message.
+        let sent: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        stream.write_all(&sent).unwrap();
+        assert_eq!(peer, PeerCertificate {
+            device_id: id_of(&server_key),
+            public_key: hex::encode(server_key.verifying_key().as_bytes()),
+        });
+        let (peer, message) = accepted.join().unwrap();
+        assert_eq!(peer, PeerCertificate {
+            device_id: id_of(&client_key),
+            public_key: hex::encode(client_key.verifying_key().as_bytes()),
+        });
+        assert_eq!(message, sent);
+    }
+
+    #[test]
+    fn refuses_a_key_the_other_side_does_not_hold() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_key = SigningKey::generate(&mut OsRng);
+        let server = Noise::new(&id_of(&server_key), &server_key);
+        let accepted = spawn(move || server.accept(listener.accept().unwrap().0).map(|(peer, _)| peer));
+
+        let client_key = SigningKey::generate(&mut OsRng);
+        let mut client = Noise::new(&id_of(&client_key), &client_key);
+        // The id matches the key sent, but the handshake is run with another.
+        let other = SigningKey::generate(&mut OsRng);
+        let mut identity = other.verifying_key().to_bytes().to_vec();
+        identity.extend_from_slice(id_of(&other).as_bytes());
+        client.identity = identity;
+        let _ = client.connect(TcpStream::connect(address).unwrap());
+        assert!(matches!(accepted.join().unwrap(), Err(TransportError::Certificate(_))));
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+
+use pnet::datalink::interfaces;
+use pnet::ipnetwork::IpNetwork;
+
+/// How directly a connection reaches a peer, the most preferred first: over a network this device
+/// is on, across the internet, or through a relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Path {
+    Lan,
+    Wan,
+    Relay,
+}
+
+impl Path {
+    /// The path to `address` over `transport`, a LAN one for an address in one of `networks`.
+    pub fn of(transport: &str, address: SocketAddr, networks: &[IpNetwork]) -> Path {
+        let ip = address.ip().to_canonical();
+        let link_local = match ip {
+            IpAddr::V4(v4) => v4.is_link_local(),
+            IpAddr::V6(v6) => v6.is_unicast_link_local(),
+        };
+        if transport == "relay" {
+            Path::Relay
+        } else if ip.is_loopback() || link_local || networks.iter().any(|network| network.contains(ip)) {
+            Path::Lan
+        } else {
+            Path::Wan
+        }
+    }
+}
+
+impl Display for Path {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Path::Lan => write!(f, "LAN"),
+            Path::Wan => write!(f, "WAN"),
+            Path::Relay => write!(f, "relay"),
+        }
+    }
+}
+
+/// The networks of the interfaces that are up, which hold the addresses reached over a LAN.
+pub fn local_networks() -> Vec<IpNetwork> {
+    interfaces().into_iter().filter(|interface| interface.is_up()).flat_map(|interface| interface.ips).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_lan_from_wan_and_relay() {
+        let networks = ["192.168.1.0/24".parse().unwrap(), "fd00::/64".parse().unwrap()];
+        let path = |transport, address: &str| Path::of(transport, address.parse().unwrap(), &networks);
+        assert_eq!(path("tcp", "192.168.1.20:11449"), Path::Lan);
+        assert_eq!(path("quic", "[::ffff:192.168.1.20]:11449"), Path::Lan);
+        assert_eq!(path("tcp", "[fe80::1]:11449"), Path::Lan);
+        assert_eq!(path("tcp", "192.168.2.20:11449"), Path::Wan);
+        assert_eq!(path("quic", "203.0.113.5:11449"), Path::Wan);
+        assert_eq!(path("relay", "192.168.1.1:11532"), Path::Relay);
+        assert!(Path::Lan < Path::Wan && Path::Wan < Path::Relay);
+    }
+}
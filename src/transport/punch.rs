@@ -0,0 +1,71 @@
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::transport::quic::mapped;
+
+/// What every datagram sent to open a hole in a gateway holds. It is not a QUIC packet, so an
+/// endpoint it reaches drops it.
+pub const PUNCH: &[u8] = b"simple-sync punch";
+/// How long both sides send datagrams at each other before giving up on hearing the other.
+const PUNCH_TIME: Duration = Duration::from_secs(3);
+/// Time between two datagrams sent to open a hole.
+const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+/// Datagrams sent after hearing the other side, so that it hears this side too.
+const PUNCHES_AFTER: u32 = 3;
+
+/// Opens a path through the gateways in front of both devices to `peer` from `socket`, which the
+/// other device does at the same time the other way round.
+///
+/// A gateway lets datagrams from an address in once one went out to it, so after both devices sent
+/// a few the datagrams of each get through. Returns whether a datagram from `peer` came through;
+/// even if none did, the path may have opened for the other direction.
+pub fn punch(socket: &UdpSocket, peer: SocketAddr) -> io::Result<bool> {
+    let peer = mapped(peer);
+    let start = Instant::now();
+    let mut buffer = [0; 64];
+    let mut after = None;
+    socket.set_read_timeout(Some(PUNCH_INTERVAL))?;
+    let punched = loop {
+        socket.send_to(PUNCH, peer)?;
+        match after {
+            Some(0) => break true,
+            Some(count) => after = Some(count - 1),
+            None if start.elapsed() >= PUNCH_TIME => break false,
+            None => {}
+        }
+        match socket.recv_from(&mut buffer) {
+            Ok((read, from)) if from == peer && &buffer[..read] == PUNCH => {
+                after = after.or(Some(PUNCHES_AFTER));
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            // An ICMP error for a datagram the other gateway refused before its side sent any.
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {}
+            Err(e) => return Err(e),
+        }
+    };
+    socket.set_read_timeout(None)?;
+    Ok(punched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::spawn;
+
+    use crate::transport::quic::bind_socket;
+
+    fn local(socket: &UdpSocket) -> SocketAddr {
+        SocketAddr::new("::1".parse().unwrap(), socket.local_addr().unwrap().port())
+    }
+
+    #[test]
+    fn both_sides_hear_each_other() {
+        let (a, b) = (bind_socket(0).unwrap(), bind_socket(0).unwrap());
+        let (to_a, to_b) = (local(&a), local(&b));
+        let punching = spawn(move || punch(&b, to_a).unwrap());
+        assert!(punch(&a, to_b).unwrap());
+        assert!(punching.join().unwrap());
+    }
+}
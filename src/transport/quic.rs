@@ -0,0 +1,222 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use quinn::crypto::rustls::{HandshakeData, QuicClientConfig, QuicServerConfig};
+use quinn::{ClientConfig, Connection, Endpoint, EndpointConfig, RecvStream, SendStream, ServerConfig, TokioRuntime,
+    TransportConfig};
+use rustls::pki_types::CertificateDer;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::timeout;
+
+use crate::transport::tls::{ALPN_PROTOCOL, SERVER_NAME};
+use crate::transport::{PeerCertificate, Stream, Tls, TransportError, CONNECT_TIMEOUT, HANDSHAKE_TIMEOUT};
+
+/// How often an idle connection is pinged, well within the idle timeout, so it stays open.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Byte the dialing side opens the stream with, the other side only learns of a stream once
+/// something is sent on it.
+const STREAM_OPENER: u8 = 0;
+
+/// A QUIC endpoint on one UDP socket, which dials and accepts connections with the same TLS setup
+/// as TCP connections. It is driven by a runtime of its own, so everything else keeps to blocking
+/// calls.
+#[derive(Debug, Clone)]
+pub struct Quic {
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+    client: ClientConfig,
+    port: u16,
+}
+
+impl Quic {
+    /// Binds an endpoint to `port` on every IPv4 and IPv6 address.
+    pub fn bind(tls: &Tls, port: u16) -> Result<Quic, TransportError> {
+        Quic::with_socket(tls, bind_socket(port)?)
+    }
+
+    /// An endpoint on a socket bound with [`bind_socket`], which may have been used for something
+    /// else before.
+    pub fn with_socket(tls: &Tls, socket: UdpSocket) -> Result<Quic, TransportError> {
+        let port = socket.local_addr()?.port();
+        let mut transport = TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+        let transport = Arc::new(transport);
+
+        let crypto = QuicServerConfig::try_from(tls.server_config())
+            .map_err(|e| TransportError::Certificate(e.to_string()))?;
+        let mut server = ServerConfig::with_crypto(Arc::new(crypto));
+        server.transport_config(transport.clone());
+        let crypto = QuicClientConfig::try_from(tls.client_config())
+            .map_err(|e| TransportError::Certificate(e.to_string()))?;
+        let mut client = ClientConfig::new(Arc::new(crypto));
+        client.transport_config(transport);
+
+        let runtime = Builder::new_multi_thread().worker_threads(1).thread_name("quic").enable_all().build()?;
+        let endpoint = {
+            let _runtime = runtime.enter();
+            Endpoint::new(EndpointConfig::default(), Some(server), socket, Arc::new(TokioRuntime))?
+        };
+        Ok(Quic { runtime: Arc::new(runtime), endpoint, client, port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Connects to `address`, returning who answered.
+    pub fn connect(&self, address: SocketAddr) -> Result<(PeerCertificate, Stream, Connection), TransportError> {
+        let address = mapped(address);
+        self.runtime.block_on(async {
+            let connecting = self.endpoint.connect_with(self.client.clone(), address, SERVER_NAME)
+                .map_err(|e| TransportError::Io(io::Error::other(e)))?;
+            let connection = timeout(CONNECT_TIMEOUT + HANDSHAKE_TIMEOUT, connecting).await.map_err(timed_out)?
+                .map_err(io::Error::from)?;
+            let peer = peer_certificate(&connection)?;
+            let (mut send, recv) = connection.open_bi().await.map_err(io::Error::from)?;
+            send.write_all(&[STREAM_OPENER]).await.map_err(io::Error::from)?;
+            Ok((peer, self.stream(send, recv), connection))
+        })
+    }
+
+    /// Waits up to `wait` for a device to start connecting, `None` meaning none did.
+    pub fn incoming(&self, wait: Duration) -> Option<Incoming> {
+        let incoming = self.runtime.block_on(async { timeout(wait, self.endpoint.accept()).await.ok().flatten() })?;
+        Some(Incoming { quic: self.clone(), incoming })
+    }
+
+    fn stream(&self, send: SendStream, recv: RecvStream) -> Stream {
+        Stream::new(QuicReader { runtime: self.runtime.clone(), recv }, QuicWriter { runtime: self.runtime.clone(), send })
+    }
+}
+
+/// A UDP socket on `port` of every IPv4 and IPv6 address, 0 for any free port.
+pub fn bind_socket(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+    Ok(socket.into())
+}
+
+/// `address` as sockets from [`bind_socket`] send to it, IPv4 addresses being mapped to IPv6.
+pub fn mapped(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V4(v4) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        address => address,
+    }
+}
+
+/// A connection a device started, which the handshake is still to be run for.
+pub struct Incoming {
+    quic: Quic,
+    incoming: quinn::Incoming,
+}
+
+impl Incoming {
+    pub fn remote_address(&self) -> SocketAddr {
+        self.incoming.remote_address()
+    }
+
+    /// Runs the handshake as the side that was dialed, returning who dialed.
+    pub fn accept(self) -> Result<(PeerCertificate, Stream, Connection), TransportError> {
+        let Incoming { quic, incoming } = self;
+        quic.runtime.block_on(async {
+            let connection = timeout(HANDSHAKE_TIMEOUT, incoming).await.map_err(timed_out)?
+                .map_err(io::Error::from)?;
+            let peer = peer_certificate(&connection)?;
+            let (send, mut recv) = timeout(HANDSHAKE_TIMEOUT, connection.accept_bi()).await.map_err(timed_out)?
+                .map_err(io::Error::from)?;
+            let mut opener = [0];
+            recv.read_exact(&mut opener).await.map_err(|e| io::Error::new(io::ErrorKind::UnexpectedEof, e))?;
+            Ok((peer, quic.stream(send, recv), connection))
+        })
+    }
+}
+
+fn timed_out(_: tokio::time::error::Elapsed) -> TransportError {
+    TransportError::Io(io::ErrorKind::TimedOut.into())
+}
+
+/// Who the other side is, checking that it speaks the sync protocol.
+fn peer_certificate(connection: &Connection) -> Result<PeerCertificate, TransportError> {
+    let protocol = connection.handshake_data().and_then(|data| data.downcast::<HandshakeData>().ok())
+        .and_then(|data| data.protocol);
+    if protocol.as_deref() != Some(ALPN_PROTOCOL) {
+        return Err(TransportError::BadProtocol);
+    }
+    let chain = connection.peer_identity().and_then(|identity| identity.downcast::<Vec<CertificateDer>>().ok())
+        .ok_or_else(|| TransportError::Certificate("the other side sent no certificate".to_string()))?;
+    let certificate = chain.first()
+        .ok_or_else(|| TransportError::Certificate("the other side sent no certificate".to_string()))?;
+    PeerCertificate::parse(certificate).map_err(TransportError::Certificate)
+}
+
+/// The stream a QUIC connection carries, read and written with blocking calls.
+struct QuicReader {
+    runtime: Arc<Runtime>,
+    recv: RecvStream,
+}
+
+struct QuicWriter {
+    runtime: Arc<Runtime>,
+    send: SendStream,
+}
+
+impl Read for QuicReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let recv = &mut self.recv;
+        Ok(self.runtime.block_on(recv.read(buf))?.unwrap_or(0))
+    }
+}
+
+impl Write for QuicWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let send = &mut self.send;
+        Ok(self.runtime.block_on(send.write(buf))?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::spawn;
+
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::config::device_id_of;
+
+    fn tls() -> (String, Tls) {
+        let key = SigningKey::generate(&mut OsRng);
+        let device_id = device_id_of(key.verifying_key().as_bytes()).to_string();
+        let tls = Tls::new(&device_id, &key).unwrap();
+        (device_id, tls)
+    }
+
+    #[test]
+    fn connects_with_device_certificates() {
+        let ((server_id, server_tls), (client_id, client_tls)) = (tls(), tls());
+        let server = Quic::bind(&server_tls, 0).unwrap();
+        let port = server.endpoint.local_addr().unwrap().port();
+        let accepted = spawn(move || {
+            let (peer, mut stream, _connection) = server.incoming(HANDSHAKE_TIMEOUT).unwrap().accept().unwrap();
+            let mut message = [0; 5];
+            stream.read_exact(&mut message).unwrap();
+            (peer.device_id, message)
+        });
+
+        let client = Quic::bind(&client_tls, 0).unwrap();
+        let (peer, mut stream, _connection) = client.connect(SocketAddr::new("127.0.0.1".parse().unwrap(), port))
+            .unwrap();
+        stream.write_all(b"hello").unwrap();
+        assert_eq!(peer.device_id, server_id);
+        assert_eq!(accepted.join().unwrap(), (client_id, *b"hello"));
+    }
+}
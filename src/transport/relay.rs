@@ -0,0 +1,636 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use structopt::StructOpt;
+use subtle::ConstantTimeEq;
+
+use crate::config::Secret;
+use crate::protocol::{read_frame, read_one_frame, write_frame, Decoder, Frame, ProtocolError};
+use crate::transport::quic::mapped;
+use crate::transport::{connect_tcp, SocksProxy, TransportError, HANDSHAKE_TIMEOUT};
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:11532";
+/// Tag of every message to and from a relay, which only ever speaks one kind.
+const RELAY_MESSAGE: u8 = 0;
+/// How long a waiting device's connection stays idle before it is probed.
+const KEEPALIVE_TIME: Duration = Duration::from_secs(60);
+/// How long to wait for the relay to say at what address it sees a UDP socket, and how often to
+/// ask it.
+const OBSERVE_TIMEOUT: Duration = Duration::from_millis(500);
+const OBSERVE_ATTEMPTS: u32 = 4;
+/// Largest message that is read, far more than any of them needs.
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// What devices and a relay say to each other before the relay joins two connections up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RelayMessage {
+    /// From a device that waits on the relay for others to reach it, with the password the relay
+    /// requires, if it does.
+    Listen {
+        device_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    /// From a device that wants to reach a device waiting on the relay.
+    Connect { from: String, to: String },
+    /// To a waiting device: another one wants to reach it, over a new connection joining
+    /// `session`.
+    Invitation { session: u64, from: String },
+    /// From a waiting device, on the new connection.
+    Join { session: u64 },
+    /// To both devices: they are joined up, whatever follows comes from the other one.
+    Ready,
+    Refused { reason: String },
+    /// From a device that wants to open a direct path to a device waiting on the relay, with the
+    /// address its UDP socket is seen at.
+    Punch { from: String, to: String, address: SocketAddr },
+    /// To a waiting device: another one wants to open a direct path from `address`, to be
+    /// answered over a new connection.
+    PunchInvitation { session: u64, from: String, address: SocketAddr },
+    /// From a waiting device, on the new connection, with the address its UDP socket is seen at.
+    PunchReply { session: u64, address: SocketAddr },
+    /// To the device that wants to open the path: the other device is sending from `address`.
+    PunchReady { address: SocketAddr },
+    /// A datagram asking the relay at what address it sees the socket that sent it.
+    Observe,
+    /// The relay's answer to [`RelayMessage::Observe`].
+    Observed { address: SocketAddr },
+}
+
+impl RelayMessage {
+    fn send(&self, socket: &mut TcpStream) -> Result<(), ProtocolError> {
+        write_frame(socket, &Frame::new(RELAY_MESSAGE, self.to_datagram()?))
+    }
+
+    /// Reads the next message and nothing past it.
+    fn receive(socket: &mut TcpStream) -> Result<RelayMessage, ProtocolError> {
+        RelayMessage::from_frame(read_one_frame(socket, MAX_MESSAGE_LENGTH)?)
+    }
+
+    fn to_datagram(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut datagram = Vec::new();
+        ciborium::into_writer(self, &mut datagram).map_err(|e| ProtocolError::Encode(e.to_string()))?;
+        Ok(datagram)
+    }
+
+    fn from_datagram(datagram: &[u8]) -> Result<RelayMessage, ProtocolError> {
+        ciborium::from_reader(datagram).map_err(|e| ProtocolError::Decode(e.to_string()))
+    }
+
+    fn from_frame(frame: Frame) -> Result<RelayMessage, ProtocolError> {
+        if frame.tag != RELAY_MESSAGE {
+            return Err(ProtocolError::Unexpected(frame.tag));
+        }
+        ciborium::from_reader(frame.payload.as_slice()).map_err(|e| ProtocolError::Decode(e.to_string()))
+    }
+}
+
+/// The addresses of a relay given as `host:port`.
+pub fn resolve(relay: &str) -> Vec<SocketAddr> {
+    match relay.to_socket_addrs() {
+        Ok(addresses) => addresses.collect(),
+        Err(e) => {
+            debug!("Unable to resolve relay {}: {}", relay, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Asks the relay at `address` to join this device up with `to`, which waits on it. The socket
+/// returned leads to that device, with the relay in between.
+pub fn connect(proxy: Option<&SocksProxy>, address: SocketAddr, from: &str, to: &str)
+    -> Result<TcpStream, TransportError> {
+    let mut socket = open(proxy, address)?;
+    RelayMessage::Connect { from: from.to_string(), to: to.to_string() }.send(&mut socket)?;
+    ready(socket)
+}
+
+/// Waits on the relay at `address` for devices that can't dial this one, returning the connection
+/// invitations arrive over. A relay that requires a password refuses it over that connection.
+pub fn wait(proxy: Option<&SocksProxy>, address: SocketAddr, device_id: &str, password: Option<&str>)
+    -> Result<Waiting, TransportError> {
+    let mut socket = open(proxy, address)?;
+    keep_alive(&socket)?;
+    let password = password.map(str::to_string);
+    RelayMessage::Listen { device_id: device_id.to_string(), password }.send(&mut socket)?;
+    Ok(Waiting { socket, decoder: Decoder::with_max_length(MAX_MESSAGE_LENGTH) })
+}
+
+/// A device that wants to reach one waiting on a relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invitation {
+    /// Through the relay, joining the connection with `session`.
+    Relay { session: u64, from: String },
+    /// Directly, answering `session` with the address a UDP socket is seen at and then opening a
+    /// path from it to `address`.
+    Punch { session: u64, from: String, address: SocketAddr },
+}
+
+/// A device waiting on a relay.
+#[derive(Debug)]
+pub struct Waiting {
+    socket: TcpStream,
+    decoder: Decoder,
+}
+
+impl Waiting {
+    /// The next device that wants to reach this one and the session to join it with, waiting no
+    /// longer than `timeout`. `None` after the timeout, an error once the relay went away.
+    pub fn invitation(&mut self, timeout: Duration) -> Result<Option<Invitation>, TransportError> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        match read_frame(&mut self.socket, &mut self.decoder) {
+            Ok(Some(frame)) => match RelayMessage::from_frame(frame)? {
+                RelayMessage::Invitation { session, from } => Ok(Some(Invitation::Relay { session, from })),
+                RelayMessage::PunchInvitation { session, from, address } =>
+                    Ok(Some(Invitation::Punch { session, from, address })),
+                RelayMessage::Refused { reason } => Err(refused(reason)),
+                _ => Err(ProtocolError::Decode("unexpected relay message".to_string()).into()),
+            },
+            Ok(None) => Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Err(ProtocolError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Joins `session` on the relay at `address`, once invited to it. The socket returned leads to
+/// the device that wants to reach this one.
+pub fn join(proxy: Option<&SocksProxy>, address: SocketAddr, session: u64) -> Result<TcpStream, TransportError> {
+    let mut socket = open(proxy, address)?;
+    RelayMessage::Join { session }.send(&mut socket)?;
+    ready(socket)
+}
+
+/// The address the relay at `relay` sees `socket` at, which is the one the gateway in front of
+/// this device maps it to.
+pub fn observe(socket: &UdpSocket, relay: SocketAddr) -> Result<SocketAddr, TransportError> {
+    let relay = mapped(relay);
+    let mut buffer = [0; 256];
+    socket.set_read_timeout(Some(OBSERVE_TIMEOUT))?;
+    let mut observed = Err(io::Error::from(ErrorKind::TimedOut).into());
+    for _ in 0..OBSERVE_ATTEMPTS {
+        socket.send_to(&RelayMessage::Observe.to_datagram()?, relay)?;
+        match socket.recv_from(&mut buffer) {
+            Ok((read, from)) if from == relay => {
+                if let Ok(RelayMessage::Observed { address }) = RelayMessage::from_datagram(&buffer[..read]) {
+                    observed = Ok(address);
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => {
+                observed = Err(e.into());
+                break;
+            }
+        }
+    }
+    socket.set_read_timeout(None)?;
+    observed
+}
+
+/// Asks the relay at `relay` to have `to`, which waits on it, open a direct path to this device's
+/// UDP socket at `address`. Returns the address the other device sends from.
+pub fn request_punch(relay: SocketAddr, from: &str, to: &str, address: SocketAddr)
+    -> Result<SocketAddr, TransportError> {
+    let mut socket = open(None, relay)?;
+    RelayMessage::Punch { from: from.to_string(), to: to.to_string(), address }.send(&mut socket)?;
+    match RelayMessage::receive(&mut socket)? {
+        RelayMessage::PunchReady { address } => Ok(address),
+        RelayMessage::Refused { reason } => Err(refused(reason)),
+        _ => Err(ProtocolError::Decode("unexpected relay message".to_string()).into()),
+    }
+}
+
+/// Answers the invitation to punch `session` with the address this device's UDP socket is seen at.
+pub fn answer_punch(relay: SocketAddr, session: u64, address: SocketAddr) -> Result<(), TransportError> {
+    let mut socket = open(None, relay)?;
+    RelayMessage::PunchReply { session, address }.send(&mut socket)?;
+    Ok(())
+}
+
+fn open(proxy: Option<&SocksProxy>, address: SocketAddr) -> Result<TcpStream, TransportError> {
+    let socket = connect_tcp(proxy, address)?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    Ok(socket)
+}
+
+/// Waits for the relay to join the connection up with the other device.
+fn ready(mut socket: TcpStream) -> Result<TcpStream, TransportError> {
+    match RelayMessage::receive(&mut socket)? {
+        RelayMessage::Ready => {
+            socket.set_read_timeout(None)?;
+            socket.set_write_timeout(None)?;
+            Ok(socket)
+        }
+        RelayMessage::Refused { reason } => Err(refused(reason)),
+        _ => Err(ProtocolError::Decode("unexpected relay message".to_string()).into()),
+    }
+}
+
+/// Probes a connection that stays idle for long, so that both sides notice when a gateway in
+/// between forgets it.
+fn keep_alive(socket: &TcpStream) -> io::Result<()> {
+    SockRef::from(socket).set_tcp_keepalive(&TcpKeepalive::new().with_time(KEEPALIVE_TIME))
+}
+
+fn refused(reason: String) -> TransportError {
+    io::Error::new(ErrorKind::ConnectionRefused, format!("relay refused: {}", reason)).into()
+}
+
+/// Runs a relay that joins up connections between devices that can't reach each other directly.
+///
+/// Devices that can't be dialed wait on the relay, and others reach them through it. It also tells
+/// devices at what address it sees their UDP sockets, on the same port, so that two devices behind
+/// gateways can open a direct path between them, which it only passes the addresses for. The relay
+/// only forwards bytes: devices encrypt and authenticate the connection between them end to end,
+/// so the relay can't read or change what they sync. A device that waits on the relay under
+/// another's id only gets connections it can't complete, and can't take the place of the device
+/// while it waits.
+#[derive(Debug, StructOpt)]
+pub struct RelayCommand {
+    /// Address to accept connections from devices on.
+    #[structopt(long, default_value = DEFAULT_LISTEN)]
+    listen: SocketAddr,
+
+    /// Only let devices with this password wait on the relay, given as `keyring:<name>` to read it
+    /// from the OS keyring. Devices set it with `relay-password`.
+    #[structopt(long, value_name("PASSWORD"), env = "SIMPLE_SYNC_RELAY_SERVER_PASSWORD")]
+    password: Option<Secret>,
+}
+
+/// The relay's waiting devices and the connections waiting for them to join.
+#[derive(Debug, Default)]
+struct Relay {
+    /// The connection each waiting device waits on.
+    listeners: Mutex<HashMap<String, TcpStream>>,
+    /// Connections from devices that are to be joined by the device they want to reach, or that
+    /// wait for its address to punch through to, with when they came in.
+    sessions: Mutex<HashMap<u64, Session>>,
+    /// Password waiting devices need, if any.
+    password: Option<String>,
+}
+
+#[derive(Debug)]
+struct Session {
+    socket: TcpStream,
+    since: Instant,
+    punch: bool,
+}
+
+impl RelayCommand {
+    pub fn run(&self) -> io::Result<()> {
+        let mut password = self.password.clone();
+        if let Some(password) = &mut password {
+            password.resolve();
+            if password.value().is_none() {
+                return Err(io::Error::other("the password is not in the keyring"));
+            }
+        }
+        let listener = TcpListener::bind(self.listen)?;
+        let reflector = UdpSocket::bind(self.listen)?;
+        info!("Relay listening on {}", self.listen);
+        spawn(move || reflect(&reflector));
+        let relay = Arc::new(Relay { password: password.as_ref().and_then(Secret::value).map(str::to_string),
+            ..Relay::default() });
+        for socket in listener.incoming() {
+            match socket {
+                Ok(socket) => {
+                    let relay = relay.clone();
+                    spawn(move || relay.handle(socket));
+                }
+                Err(e) => debug!("Unable to accept a connection: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Relay {
+    fn handle(&self, mut socket: TcpStream) {
+        let address = socket.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+        let handled = socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(ProtocolError::from)
+            .and_then(|()| RelayMessage::receive(&mut socket))
+            .and_then(|message| match message {
+                RelayMessage::Listen { password, .. } if !self.allows(password.as_deref()) => {
+                    RelayMessage::Refused { reason: "wrong password".to_string() }.send(&mut socket)
+                }
+                RelayMessage::Listen { device_id, .. } => self.listen(socket, device_id),
+                RelayMessage::Connect { from, to } =>
+                    self.invite(socket, &to, false, |session| RelayMessage::Invitation { session, from }),
+                RelayMessage::Join { session } => self.join(socket, session),
+                RelayMessage::Punch { from, to, address } =>
+                    self.invite(socket, &to, true, |session| RelayMessage::PunchInvitation { session, from, address }),
+                RelayMessage::PunchReply { session, address } => self.punch(socket, session, address),
+                _ => Err(ProtocolError::Decode("unexpected relay message".to_string())),
+            });
+        if let Err(e) = handled {
+            debug!("Dropping connection from {}: {}", address, e);
+        }
+    }
+
+    /// Whether a waiting device may use the relay with `password`. It is compared in constant
+    /// time, so how soon a wrong one is refused doesn't tell how much of it is right.
+    fn allows(&self, password: Option<&str>) -> bool {
+        match (&self.password, password) {
+            (None, _) => true,
+            (Some(expected), Some(password)) => bool::from(expected.as_bytes().ct_eq(password.as_bytes())),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Keeps the connection of a waiting device until it closes. Another connection waiting under
+    /// the same id is refused meanwhile, so a device can't take the place of one already waiting.
+    fn listen(&self, mut socket: TcpStream, device_id: String) -> Result<(), ProtocolError> {
+        socket.set_read_timeout(None)?;
+        keep_alive(&socket)?;
+        {
+            let mut listeners = self.listeners.lock().expect("relay lock poisoned");
+            if listeners.contains_key(&device_id) {
+                drop(listeners);
+                let reason = format!("device {} is already waiting on this relay", device_id);
+                return RelayMessage::Refused { reason }.send(&mut socket);
+            }
+            listeners.insert(device_id.clone(), socket.try_clone()?);
+        }
+        debug!("Device {} is waiting on the relay", device_id);
+        // Waiting devices have nothing more to say, reading only notices when they go away.
+        let _ = io::copy(&mut socket, &mut io::sink());
+        self.listeners.lock().expect("relay lock poisoned").remove(&device_id);
+        debug!("Device {} stopped waiting on the relay", device_id);
+        Ok(())
+    }
+
+    /// Invites the waiting device `to` to the session of a device that wants to reach it.
+    fn invite(&self, mut socket: TcpStream, to: &str, punch: bool, invitation: impl FnOnce(u64) -> RelayMessage)
+        -> Result<(), ProtocolError> {
+        // Guessing the session would let another device take the connection, though not read it.
+        let session = rand::random();
+        {
+            // The session is there before the invitation, which may be answered straight away.
+            let mut sessions = self.sessions.lock().expect("relay lock poisoned");
+            sessions.retain(|_, session| session.since.elapsed() < HANDSHAKE_TIMEOUT);
+            sessions.insert(session, Session { socket: socket.try_clone()?, since: Instant::now(), punch });
+        }
+        let invited = match self.listeners.lock().expect("relay lock poisoned").get_mut(to) {
+            Some(listener) => invitation(session).send(listener).is_ok(),
+            None => false,
+        };
+        if !invited {
+            self.sessions.lock().expect("relay lock poisoned").remove(&session);
+            let reason = format!("device {} is not waiting on this relay", to);
+            return RelayMessage::Refused { reason }.send(&mut socket);
+        }
+        debug!("Invited device {} to session {}", to, session);
+        Ok(())
+    }
+
+    /// The session an invited device answers, if it is still waiting.
+    fn session(&self, session: u64, punch: bool) -> Option<TcpStream> {
+        match self.sessions.lock().expect("relay lock poisoned").remove(&session) {
+            Some(waiting) if waiting.punch == punch && waiting.since.elapsed() < HANDSHAKE_TIMEOUT => Some(waiting.socket),
+            _ => None,
+        }
+    }
+
+    /// Tells the device that wants to punch through to an invited device where it sends from.
+    fn punch(&self, mut socket: TcpStream, session: u64, address: SocketAddr) -> Result<(), ProtocolError> {
+        match self.session(session, true) {
+            Some(mut other) => RelayMessage::PunchReady { address }.send(&mut other),
+            None => RelayMessage::Refused { reason: format!("no session {}", session) }.send(&mut socket),
+        }
+    }
+
+    /// Joins the connection of an invited device up with the one that invited it.
+    fn join(&self, mut socket: TcpStream, session: u64) -> Result<(), ProtocolError> {
+        let mut other = match self.session(session, false) {
+            Some(other) => other,
+            None => return RelayMessage::Refused { reason: format!("no session {}", session) }.send(&mut socket),
+        };
+        RelayMessage::Ready.send(&mut other)?;
+        RelayMessage::Ready.send(&mut socket)?;
+        for socket in [&socket, &other] {
+            socket.set_read_timeout(None)?;
+            socket.set_write_timeout(None)?;
+            keep_alive(socket)?;
+        }
+        debug!("Relaying session {}", session);
+        let (reader, writer) = (socket.try_clone()?, other.try_clone()?);
+        let back = spawn(move || forward(reader, writer));
+        forward(other, socket);
+        let _ = back.join();
+        debug!("Session {} closed", session);
+        Ok(())
+    }
+}
+
+/// Answers every datagram asking at what address its sender is seen.
+fn reflect(socket: &UdpSocket) {
+    let mut buffer = [0; 256];
+    loop {
+        let (read, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Unable to receive a datagram: {}", e);
+                continue;
+            }
+        };
+        if RelayMessage::from_datagram(&buffer[..read]).ok() != Some(RelayMessage::Observe) {
+            continue;
+        }
+        let address = SocketAddr::new(from.ip().to_canonical(), from.port());
+        match (RelayMessage::Observed { address }).to_datagram() {
+            Ok(datagram) => {
+                if let Err(e) = socket.send_to(&datagram, from) {
+                    debug!("Unable to tell {} its address: {}", from, e);
+                }
+            }
+            Err(e) => debug!("Unable to tell {} its address: {}", from, e),
+        }
+    }
+}
+
+/// Copies what comes in on `from` to `to` until `from` closes, then closes `to` for writing.
+fn forward(mut from: TcpStream, mut to: TcpStream) {
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        match from.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                if to.write_all(&buffer[..read]).is_err() {
+                    let _ = from.shutdown(Shutdown::Read);
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                warn!("Relayed connection failed: {}", e);
+                break;
+            }
+        }
+    }
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    use crate::config::device_id_of;
+    use crate::transport::quic::bind_socket;
+    use crate::transport::{Connection, Tls};
+
+    /// A relay on a free local port.
+    fn relay() -> SocketAddr {
+        relay_with(Relay::default())
+    }
+
+    fn relay_with(relay: Relay) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let reflector = UdpSocket::bind(address).unwrap();
+        spawn(move || reflect(&reflector));
+        let relay = Arc::new(relay);
+        spawn(move || for socket in listener.incoming() {
+            let relay = relay.clone();
+            spawn(move || relay.handle(socket.unwrap()));
+        });
+        address
+    }
+
+    #[test]
+    fn joins_up_a_device_with_one_waiting() {
+        let address = relay();
+        let mut waiting = wait(None, address, "b", None).unwrap();
+        // The relay may not have taken in the waiting device yet.
+        let dialing = spawn(move || loop {
+            match connect(None, address, "a", "b") {
+                Ok(socket) => break socket,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        });
+        let session = match waiting.invitation(HANDSHAKE_TIMEOUT).unwrap().unwrap() {
+            Invitation::Relay { session, from } if from == "a" => session,
+            invitation => panic!("unexpected {:?}", invitation),
+        };
+        let mut accepted = join(None, address, session).unwrap();
+        let mut dialed = dialing.join().unwrap();
+
+        dialed.write_all(b"hello").unwrap();
+        let mut received = [0; 5];
+        accepted.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello");
+        drop(accepted);
+        assert_eq!(dialed.read(&mut received).unwrap(), 0);
+    }
+
+    #[test]
+    fn tells_devices_where_to_punch_through_to() {
+        let address = relay();
+        let mut waiting = wait(None, address, "b", None).unwrap();
+        let (a, b) = (bind_socket(0).unwrap(), bind_socket(0).unwrap());
+        let seen_a = observe(&a, address).unwrap();
+        assert_eq!(seen_a.port(), a.local_addr().unwrap().port());
+        let requesting = spawn(move || loop {
+            match request_punch(address, "a", "b", seen_a) {
+                Ok(found) => break found,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        });
+        let session = match waiting.invitation(HANDSHAKE_TIMEOUT).unwrap().unwrap() {
+            Invitation::Punch { session, from, address } if from == "a" && address == seen_a => session,
+            invitation => panic!("unexpected {:?}", invitation),
+        };
+        let seen_b = observe(&b, address).unwrap();
+        answer_punch(address, session, seen_b).unwrap();
+        assert_eq!(requesting.join().unwrap(), seen_b);
+    }
+
+    #[test]
+    fn punches_a_direct_connection() {
+        let address = relay();
+        let (a_key, b_key) = (SigningKey::generate(&mut OsRng), SigningKey::generate(&mut OsRng));
+        let a_public = hex::encode(a_key.verifying_key().as_bytes());
+        let a_id = device_id_of(a_key.verifying_key().as_bytes()).to_string();
+        let b_id = device_id_of(b_key.verifying_key().as_bytes()).to_string();
+        let (a, b) = (Tls::new(&a_id, &a_key).unwrap(), Tls::new(&b_id, &b_key).unwrap());
+        let mut waiting = wait(None, address, &b_id, None).unwrap();
+        let (dialing_id, dialed_id) = (a_id.clone(), b_id.clone());
+        let dialing = spawn(move || loop {
+            match Connection::dial_punched(&a, address, &dialing_id, &dialed_id) {
+                Ok(dialed) => break dialed,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        });
+        let (session, peer) = match waiting.invitation(HANDSHAKE_TIMEOUT).unwrap().unwrap() {
+            Invitation::Punch { session, address, .. } => (session, address),
+            invitation => panic!("unexpected {:?}", invitation),
+        };
+        let (accepted, mut accepted_stream) = Connection::accept_punched(&b, address, session, peer).unwrap();
+        let (dialed, mut dialed_stream) = dialing.join().unwrap();
+        assert_eq!((accepted.device_id.as_str(), accepted.public_key.as_str()), (a_id.as_str(), a_public.as_str()));
+        assert_eq!((dialed.transport, accepted.transport), ("quic", "quic"));
+
+        dialed_stream.write_all(b"hello").unwrap();
+        dialed_stream.flush().unwrap();
+        let mut received = [0; 5];
+        accepted_stream.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello");
+    }
+
+    #[test]
+    fn keeps_the_device_already_waiting() {
+        let address = relay();
+        let mut waiting = wait(None, address, "b", None).unwrap();
+        let invite = move || spawn(move || loop {
+            match connect(None, address, "a", "b") {
+                Ok(socket) => break socket,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        });
+        let answer = |waiting: &mut Waiting| match waiting.invitation(HANDSHAKE_TIMEOUT).unwrap().unwrap() {
+            Invitation::Relay { session, .. } => join(None, address, session).unwrap(),
+            invitation => panic!("unexpected {:?}", invitation),
+        };
+        // Once invited, the device is known to be waiting.
+        let dialing = invite();
+        answer(&mut waiting);
+        dialing.join().unwrap();
+
+        let mut other = wait(None, address, "b", None).unwrap();
+        assert!(other.invitation(HANDSHAKE_TIMEOUT).is_err());
+        let dialing = invite();
+        answer(&mut waiting);
+        dialing.join().unwrap();
+    }
+
+    #[test]
+    fn refuses_devices_that_are_not_waiting() {
+        let address = relay();
+        assert!(connect(None, address, "a", "b").is_err());
+        assert!(join(None, address, 12345).is_err());
+    }
+
+    #[test]
+    fn refuses_waiting_devices_without_the_password() {
+        let address = relay_with(Relay { password: Some("secret".to_string()), ..Relay::default() });
+        for password in [None, Some("guess")] {
+            let mut waiting = wait(None, address, "b", password).unwrap();
+            assert!(waiting.invitation(HANDSHAKE_TIMEOUT).is_err());
+        }
+        let mut waiting = wait(None, address, "b", Some("secret")).unwrap();
+        assert!(matches!(waiting.invitation(Duration::from_millis(100)), Ok(None)));
+    }
+}
@@ -0,0 +1,213 @@
+use std::io;
+use std::net::SocketAddr;
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::broadcast::Peer;
+use crate::config::Options;
+use crate::protocol::Traffic;
+use crate::transport::manager::{ConnectionManager, ConnectionStatus};
+
+/// Path the running process serves the status of its connections on.
+const STATUS_PATH: &str = "/status";
+/// How long the `status` command waits for the running process to answer.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the connection to a device stands, as the running process serves it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub device_id: String,
+    pub device_name: String,
+    #[serde(flatten)]
+    pub connection: Connection,
+}
+
+/// The parts of a [`ConnectionStatus`] that outlive the process, with times as seconds from now.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum Connection {
+    /// Not connected, dialed again in `retry_in` seconds.
+    Waiting { retry_in: u64, failures: u32, last_error: Option<String> },
+    Dialing { failures: u32 },
+    /// Connected for `seconds`, with `rtt_ms` the round trip time of the last ping the device
+    /// answered, and the rates transfers to and from it are limited to in bytes a second, 0 where
+    /// they aren't.
+    Connected {
+        address: SocketAddr,
+        transport: String,
+        path: String,
+        outbound: bool,
+        seconds: u64,
+        rtt_ms: Option<u64>,
+        traffic: Traffic,
+        send_limit: u64,
+        receive_limit: u64,
+    },
+}
+
+impl DeviceStatus {
+    fn of(peer: &Peer, status: ConnectionStatus, (send_limit, receive_limit): (u64, u64)) -> Self {
+        let connection = match status {
+            ConnectionStatus::Waiting { until, failures, last_error } => Connection::Waiting {
+                retry_in: until.saturating_duration_since(Instant::now()).as_secs(),
+                failures,
+                last_error,
+            },
+            ConnectionStatus::Dialing { failures } => Connection::Dialing { failures },
+            ConnectionStatus::Connected { address, transport, path, outbound, since, rtt, traffic } =>
+                Connection::Connected {
+                    address,
+                    transport: transport.to_string(),
+                    path: path.to_string(),
+                    outbound,
+                    seconds: since.elapsed().as_secs(),
+                    rtt_ms: rtt.map(|rtt| rtt.as_millis() as u64),
+                    traffic,
+                    send_limit,
+                    receive_limit,
+                },
+        };
+        DeviceStatus { device_id: peer.device_id.clone(), device_name: peer.device_name.clone(), connection }
+    }
+}
+
+/// Spawns a thread that serves the status of every connection `manager` keeps on the status
+/// address, as JSON for the `status` command.
+pub fn spawn_status_server(options: &Options, manager: ConnectionManager) {
+    let address = options.status_address();
+    match Server::http(address) {
+        Ok(server) => {
+            debug!("Serving the status on {}", address);
+            spawn(move || serve(&server, &manager));
+        }
+        Err(e) => warn!("Unable to serve the status on {}, the status command won't work: {}", address, e),
+    }
+}
+
+fn serve(server: &Server, manager: &ConnectionManager) {
+    for request in server.incoming_requests() {
+        let response = match (request.method(), request.url()) {
+            (Method::Get, STATUS_PATH) => {
+                let statuses: Vec<DeviceStatus> = manager.statuses().into_iter()
+                    .map(|(peer, status)| {
+                        let limits = manager.bandwidth().rates(&peer.device_id);
+                        DeviceStatus::of(&peer, status, limits)
+                    })
+                    .collect();
+                match serde_json::to_string(&statuses) {
+                    Ok(json) => Response::from_string(json)
+                        .with_header(Header::from_bytes("Content-Type", "application/json").expect("valid header"))
+                        .boxed(),
+                    Err(e) => Response::from_string(e.to_string()).with_status_code(500).boxed(),
+                }
+            }
+            _ => Response::from_string("not found").with_status_code(404).boxed(),
+        };
+        if let Err(e) = request.respond(response) {
+            debug!("Unable to respond to a status request: {}", e);
+        }
+    }
+}
+
+/// The status of every connection the process serving it on `address` keeps.
+fn fetch(address: SocketAddr) -> io::Result<Vec<DeviceStatus>> {
+    let url = format!("http://{}{}", address, STATUS_PATH);
+    let response = ureq::get(&url).timeout(STATUS_TIMEOUT).call().map_err(|e| {
+        io::Error::other(format!("unable to get the status at {}, is simple-sync running? {}", address, e))
+    })?;
+    serde_json::from_str(&response.into_string()?).map_err(io::Error::other)
+}
+
+/// Asks the running process where the connection to every device it knows of stands, and prints
+/// it with the traffic that went over each connection.
+#[derive(Debug, StructOpt)]
+pub struct StatusCommand {
+    /// Print each device as a line of JSON.
+    #[structopt(long)]
+    json: bool,
+}
+
+impl StatusCommand {
+    pub fn run(&self, options: &Options) -> io::Result<()> {
+        let statuses = fetch(options.status_address())?;
+        if statuses.is_empty() && !self.json {
+            eprintln!("No devices found yet");
+        }
+        for status in &statuses {
+            if self.json {
+                println!("{}", serde_json::to_string(status).map_err(io::Error::other)?);
+            } else {
+                println!("{}  {}  {}", status.device_id, status.device_name, describe(&status.connection));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn describe(connection: &Connection) -> String {
+    match connection {
+        Connection::Waiting { retry_in, failures, last_error } => format!(
+            "dialing again in {}s after {} failures, the last with {}", retry_in, failures,
+            last_error.as_deref().unwrap_or("no error")),
+        Connection::Dialing { failures } => format!("dialing after {} failures", failures),
+        Connection::Connected { address, transport, path, seconds, rtt_ms, traffic, send_limit, receive_limit, .. } => {
+            let rtt = rtt_ms.map_or_else(|| "not measured yet".to_string(), |rtt| format!("{}ms", rtt));
+            format!("connected at {} over {} ({}) for {}s, round trip {}, sent {} bytes in {} messages at {} B/s \
+                (limit {}), received {} bytes in {} messages at {} B/s (limit {})", address, transport, path, seconds,
+                rtt, traffic.bytes_sent, traffic.frames_sent, traffic.send_rate, send_limit, traffic.bytes_received,
+                traffic.frames_received, traffic.receive_rate, receive_limit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::transport::Path;
+
+    #[test]
+    fn serves_the_status_of_every_connection() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let manager = ConnectionManager::default();
+        spawn(move || serve(&server, &manager));
+        assert_eq!(fetch(address).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn reads_back_as_it_was_served() {
+        let now = SystemTime::now();
+        let peer = Peer {
+            device_id: "5752cd3e-7bf9-4675-8378-f46ea962a772".to_string(),
+            device_name: "laptop".to_string(),
+            port: 11530,
+            addresses: Vec::new(),
+            external_address: None,
+            public_key: None,
+            capabilities: Vec::new(),
+            sources: vec![("192.0.2.2:11530".parse().unwrap(), now)],
+            last_seen: now,
+            expires: now,
+        };
+        let connected = ConnectionStatus::Connected {
+            address: "192.0.2.2:11530".parse().unwrap(),
+            transport: "tcp",
+            path: Path::Lan,
+            outbound: true,
+            since: Instant::now(),
+            rtt: Some(Duration::from_millis(12)),
+            traffic: Traffic { bytes_sent: 100, frames_sent: 3, ..Traffic::default() },
+        };
+        let status = DeviceStatus::of(&peer, connected, (0, 0));
+        let json = serde_json::to_string(&status).unwrap();
+        assert!(json.contains(r#""state":"connected""#) && json.contains(r#""rtt_ms":12"#), "{}", json);
+        assert_eq!(serde_json::from_str::<DeviceStatus>(&json).unwrap(), status);
+    }
+}
@@ -0,0 +1,333 @@
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use ed25519_dalek::pkcs8::EncodePrivateKey;
+use ed25519_dalek::SigningKey;
+use rcgen::{CertificateParams, DnType, KeyPair, PKCS_ED25519};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, DistinguishedName, ServerConfig, ServerConnection,
+    SignatureScheme,
+};
+use x509_parser::oid_registry::OID_SIG_ED25519;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::device_id_of;
+use crate::transport::{Stream, TransportError, HANDSHAKE_TIMEOUT};
+
+/// Protocol both sides ask for during the handshake, so connections from other programs fail.
+pub const ALPN_PROTOCOL: &[u8] = b"simple-sync/1";
+/// Name the certificates are made out to. Peers are not told apart by name but by the device id
+/// in their certificates, which is derived from the certificate key.
+pub const SERVER_NAME: &str = "simple-sync";
+
+/// The device a certificate was made for, or that a Noise handshake was run with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate {
+    pub device_id: String,
+    /// Hex encoded key, the same one the device signs its announcements with.
+    pub public_key: String,
+}
+
+impl PeerCertificate {
+    /// The device `device_id` with the key `key`, refused unless the id is the one derived from
+    /// the key, so a device can't pass itself off as another.
+    pub fn new(device_id: &str, key: &[u8]) -> Result<PeerCertificate, String> {
+        if device_id_of(key).to_string() != device_id {
+            return Err(format!("device id {} is not the one of the device key", device_id));
+        }
+        Ok(PeerCertificate { device_id: device_id.to_string(), public_key: hex::encode(key) })
+    }
+
+    pub fn parse(certificate: &CertificateDer<'_>) -> Result<PeerCertificate, String> {
+        let (_, certificate) = X509Certificate::from_der(certificate)
+            .map_err(|e| format!("invalid certificate: {}", e))?;
+        let key = certificate.public_key();
+        if key.algorithm.algorithm != OID_SIG_ED25519 {
+            return Err("the certificate key is not an Ed25519 key".to_string());
+        }
+        let device_id = certificate.subject().iter_common_name().next().and_then(|name| name.as_str().ok())
+            .ok_or_else(|| "the certificate names no device".to_string())?;
+        PeerCertificate::new(device_id, &key.subject_public_key.data)
+    }
+}
+
+/// The TLS 1.3 setup of this device, with a self-signed certificate that has the device id as its
+/// common name and the device key as its key. A peer trusts the certificate when the device id
+/// is the one derived from the key, which ties the session to the device id.
+#[derive(Clone)]
+pub struct Tls {
+    client: Arc<ClientConfig>,
+    server: Arc<ServerConfig>,
+}
+
+impl Tls {
+    pub fn new(device_id: &str, key: &SigningKey) -> Result<Tls, TransportError> {
+        let certificate_error = |e: rcgen::Error| TransportError::Certificate(e.to_string());
+        let pkcs8 = key.to_pkcs8_der().map_err(|e| TransportError::Certificate(e.to_string()))?;
+        let pkcs8 = PrivatePkcs8KeyDer::from(pkcs8.as_bytes().to_vec());
+        let key_pair = KeyPair::from_pkcs8_der_and_sign_algo(&pkcs8, &PKCS_ED25519).map_err(certificate_error)?;
+        let mut params = CertificateParams::new(vec![SERVER_NAME.to_string()]).map_err(certificate_error)?;
+        params.distinguished_name.push(DnType::CommonName, device_id);
+        let certificate = params.self_signed(&key_pair).map_err(certificate_error)?;
+        let chain = vec![certificate.der().clone()];
+        let key = PrivateKeyDer::from(pkcs8);
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier = Arc::new(DeviceCertificate(provider.clone()));
+        let mut client = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous().with_custom_certificate_verifier(verifier.clone())
+            .with_client_auth_cert(chain.clone(), key.clone_key())?;
+        client.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+        let mut server = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(chain, key)?;
+        server.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+        Ok(Tls { client: Arc::new(client), server: Arc::new(server) })
+    }
+
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.client.clone()
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.server.clone()
+    }
+
+    /// Runs the handshake as the side that dialed, returning who answered.
+    pub fn connect(&self, socket: TcpStream) -> Result<(PeerCertificate, Stream), TransportError> {
+        let name = ServerName::try_from(SERVER_NAME).expect("invalid server name");
+        let mut session = ClientConnection::new(self.client.clone(), name)?;
+        let mut socket = socket;
+        let peer = handshake(&mut session, &mut socket)?;
+        Ok((peer, split(session.into(), socket)?))
+    }
+
+    /// Runs the handshake as the side that was dialed, returning who dialed.
+    pub fn accept(&self, socket: TcpStream) -> Result<(PeerCertificate, Stream), TransportError> {
+        let mut session = ServerConnection::new(self.server.clone())?;
+        let mut socket = socket;
+        let peer = handshake(&mut session, &mut socket)?;
+        Ok((peer, split(session.into(), socket)?))
+    }
+}
+
+fn handshake<S>(session: &mut rustls::ConnectionCommon<S>, socket: &mut TcpStream) -> Result<PeerCertificate, TransportError>
+where
+    S: rustls::SideData,
+{
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while session.is_handshaking() {
+        session.complete_io(socket)?;
+    }
+    socket.set_read_timeout(None)?;
+    socket.set_write_timeout(None)?;
+
+    if session.alpn_protocol() != Some(ALPN_PROTOCOL) {
+        return Err(TransportError::BadProtocol);
+    }
+    let certificate = session.peer_certificates().and_then(|chain| chain.first())
+        .ok_or_else(|| TransportError::Certificate("the other side sent no certificate".to_string()))?;
+    PeerCertificate::parse(certificate).map_err(TransportError::Certificate)
+}
+
+/// A session shared by the two halves of a stream, so one thread can wait for data while another
+/// writes. Records are written to the socket under its lock, which keeps them in order without
+/// holding the session while a write blocks.
+struct Shared {
+    session: Mutex<rustls::Connection>,
+    socket: Mutex<TcpStream>,
+}
+
+impl Shared {
+    fn session(&self) -> std::sync::MutexGuard<'_, rustls::Connection> {
+        self.session.lock().expect("TLS session lock poisoned")
+    }
+
+    /// Writes out the records the session has ready.
+    fn send(&self) -> io::Result<()> {
+        let mut socket = self.socket.lock().expect("TLS socket lock poisoned");
+        let mut records = Vec::new();
+        {
+            let mut session = self.session();
+            while session.wants_write() {
+                session.write_tls(&mut records)?;
+            }
+        }
+        socket.write_all(&records)
+    }
+}
+
+fn split(session: rustls::Connection, socket: TcpStream) -> io::Result<Stream> {
+    let shared = Arc::new(Shared { session: Mutex::new(session), socket: Mutex::new(socket.try_clone()?) });
+    Ok(Stream::new(TlsReader { shared: shared.clone(), socket }, TlsWriter { shared }))
+}
+
+struct TlsReader {
+    shared: Arc<Shared>,
+    socket: TcpStream,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = [0; 16 * 1024];
+        loop {
+            match self.shared.session().reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                read => return read,
+            }
+            let read = self.socket.read(&mut incoming)?;
+            let mut records = &incoming[..read];
+            let wants_write = {
+                let mut session = self.shared.session();
+                // An empty read tells the session the socket closed.
+                loop {
+                    session.read_tls(&mut records)?;
+                    session.process_new_packets().map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                    if records.is_empty() {
+                        break;
+                    }
+                }
+                session.wants_write()
+            };
+            if wants_write {
+                self.shared.send()?;
+            }
+        }
+    }
+}
+
+struct TlsWriter {
+    shared: Arc<Shared>,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.shared.session().writer().write(buf)?;
+        self.shared.send()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.send()
+    }
+}
+
+/// Takes any well formed device certificate, checking that the other side holds its key. Whether
+/// the key belongs to the device is checked once the handshake is done.
+#[derive(Debug)]
+struct DeviceCertificate(Arc<CryptoProvider>);
+
+impl DeviceCertificate {
+    fn check(&self, certificate: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        PeerCertificate::parse(certificate).map(|_| ()).map_err(rustls::Error::General)
+    }
+}
+
+impl ServerCertVerifier for DeviceCertificate {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity).map(|()| ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl ClientCertVerifier for DeviceCertificate {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity).map(|()| ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread::spawn;
+
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    fn id_of(key: &SigningKey) -> String {
+        device_id_of(key.verifying_key().as_bytes()).to_string()
+    }
+
+    #[test]
+    fn certificates_name_the_device_and_its_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (server_key, client_key) = (SigningKey::generate(&mut OsRng), SigningKey::generate(&mut OsRng));
+        let server = Tls::new(&id_of(&server_key), &server_key).unwrap();
+        let accepted = spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let (peer, mut stream) = server.accept(socket).unwrap();
+            let mut message = [0; 5];
+            stream.read_exact(&mut message).unwrap();
+            (peer, message)
+        });
+
+        let client = Tls::new(&id_of(&client_key), &client_key).unwrap();
+        let (peer, mut stream) = client.connect(TcpStream::connect(address).unwrap()).unwrap();
+        stream.write_all(b"hello").unwrap();
+        stream.flush().unwrap();
+        assert_eq!(peer, PeerCertificate {
+            device_id: id_of(&server_key),
+            public_key: hex::encode(server_key.verifying_key().as_bytes()),
+        });
+        let (peer, message) = accepted.join().unwrap();
+        assert_eq!(peer.device_id, id_of(&client_key));
+        assert_eq!(peer.public_key, hex::encode(client_key.verifying_key().as_bytes()));
+        assert_eq!(&message, b"hello");
+    }
+
+    #[test]
+    fn refuses_a_certificate_naming_another_device() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (server_key, client_key) = (SigningKey::generate(&mut OsRng), SigningKey::generate(&mut OsRng));
+        let server = Tls::new(&id_of(&client_key), &server_key).unwrap();
+        spawn(move || server.accept(listener.accept().unwrap().0).map(|(peer, _)| peer));
+
+        let client = Tls::new(&id_of(&client_key), &client_key).unwrap();
+        assert!(client.connect(TcpStream::connect(address).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::fs::{copy, create_dir_all, hard_link, read_dir, remove_file, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+
+use crate::config::{ConfigHandle, Folder, Options, Versioning};
+use crate::index::{parse_timestamp, timestamp};
+
+/// Directory in the root of a folder that the old copies of its files are moved to, laid out like
+/// the folder.
+pub const VERSIONS_DIRECTORY: &str = ".sync-versions";
+/// Between the stem of an old copy of a file and the time it was replaced.
+const VERSION_MARKER: char = '~';
+/// Length of the time in the name of an old copy, `YYYYMMDD-HHMMSS`.
+const TIMESTAMP_LENGTH: usize = 15;
+/// Time between two cleanups of the old copies of every folder.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+/// How far apart the copies of a file that staggered versioning keeps are, by how old they are
+/// up to, past which they are a week apart.
+const STAGGER: [(Duration, Duration); 3] = [
+    (HOUR, Duration::ZERO),
+    (DAY, HOUR),
+    (Duration::from_secs(30 * 24 * 60 * 60), DAY),
+];
+const WEEK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Where the old copies of the files of a folder go when a remote change replaces or deletes
+/// them, such as `.sync-versions/dir/notes~20210314-152653.txt` for `dir/notes.txt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versions {
+    root: PathBuf,
+    pub versioning: Versioning,
+    /// Old copies kept of each file with `trash` versioning, 0 for all of them.
+    pub keep: u32,
+    /// How old the copies kept with `staggered` versioning get.
+    pub max_age: Option<Duration>,
+    /// Bytes the old copies may take up, the oldest removed past that.
+    pub max_size: Option<u64>,
+    /// Keep the files peers delete, as backup folders do.
+    pub keep_deleted: bool,
+}
+
+/// An old copy of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Version {
+    path: PathBuf,
+    time: SystemTime,
+}
+
+impl Versions {
+    /// Old copies of the files of the folder at `root`, with no limit to how many are kept.
+    pub fn new(root: &Path, versioning: Versioning) -> Versions {
+        Versions { root: root.to_path_buf(), versioning, keep: 0, max_age: None, max_size: None, keep_deleted: false }
+    }
+
+    pub fn of(folder: &Folder, options: &Options) -> Versions {
+        let settings = folder.settings(options);
+        Versions {
+            keep: settings.keep_versions,
+            max_age: Some(Duration::from_secs(settings.max_version_age.saturating_mul(DAY.as_secs())))
+                .filter(|age| !age.is_zero()),
+            max_size: Some(settings.max_versions_mb.saturating_mul(1024 * 1024)).filter(|size| *size > 0),
+            keep_deleted: folder.mode.keeps_deleted(),
+            ..Versions::new(&folder.path, settings.versioning)
+        }
+    }
+
+    /// Runs `replace`, which renames another version of the file at `path` over it as a pull
+    /// does, keeping the copy there was. It is linked to where it goes first, since a pull reads
+    /// from it, and only kept if `replace` succeeds.
+    pub fn replace<T, E: From<io::Error>>(&self, path: &str, replace: impl FnOnce() -> Result<T, E>)
+        -> Result<T, E> {
+        let current = self.root.join(path);
+        if self.versioning == Versioning::None || !current.is_file() {
+            return replace();
+        }
+        let version = self.version_path(path, SystemTime::now());
+        // The file was already replaced this second, and that copy is the one kept.
+        if version.exists() {
+            return replace();
+        }
+        create_parent(&version)?;
+        if let Err(e) = hard_link(&current, &version) {
+            debug!("Unable to link {} to {}, copying it instead: {}", current.display(), version.display(), e);
+            copy(&current, &version)?;
+        }
+        match replace() {
+            Ok(replaced) => {
+                self.prune(path);
+                Ok(replaced)
+            }
+            Err(e) => {
+                if let Err(e) = remove_file(&version) {
+                    warn!("Unable to remove {}: {}", version.display(), e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Deletes the file at `path`, keeping it as an old copy.
+    pub fn remove(&self, path: &str) -> io::Result<()> {
+        let current = self.root.join(path);
+        if self.versioning == Versioning::None {
+            return remove_file(current);
+        }
+        let version = self.version_path(path, SystemTime::now());
+        create_parent(&version)?;
+        rename(&current, &version)?;
+        self.prune(path);
+        Ok(())
+    }
+
+    /// Deletes the file at `path` that a peer deleted as [`Versions::remove`] does, unless the
+    /// folder keeps those, where it is still kept as an old copy with versioning but left as it is
+    /// without. Returns whether it is gone, and the deletion to take note of.
+    pub fn remove_deleted(&self, path: &str) -> io::Result<bool> {
+        if self.keep_deleted && self.versioning == Versioning::None {
+            return Ok(false);
+        }
+        self.remove(path)?;
+        Ok(true)
+    }
+
+    /// The old copies of the file at `path`, oldest first.
+    pub fn versions(&self, path: &str) -> io::Result<Vec<PathBuf>> {
+        let path = Path::new(path);
+        let directory = self.root.join(VERSIONS_DIRECTORY).join(path.parent().unwrap_or(Path::new("")));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let versions = self.in_directory(&directory)?.remove(name.as_ref()).unwrap_or_default();
+        Ok(versions.into_iter().map(|version| version.path).collect())
+    }
+
+    /// Removes the old copies past the ones kept, of every file and past the most they may take
+    /// up, returning how many.
+    pub fn clean(&self, now: SystemTime) -> io::Result<usize> {
+        let mut all = Vec::new();
+        let mut directories = vec![self.root.join(VERSIONS_DIRECTORY)];
+        if !directories[0].is_dir() {
+            return Ok(0);
+        }
+        while let Some(directory) = directories.pop() {
+            for mut versions in self.in_directory(&directory)?.into_values() {
+                let expired = self.expired(&versions, now);
+                versions.retain(|version| !expired.contains(version));
+                all.extend(versions.into_iter().map(|version| (version, false)));
+                all.extend(expired.into_iter().map(|version| (version, true)));
+            }
+            for entry in read_dir(&directory)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    directories.push(entry.path());
+                }
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            // The oldest copies go first until the rest fit.
+            all.sort_by_key(|(version, expired)| (!expired, version.time));
+            let mut size: u64 = all.iter().filter(|(_, expired)| !expired)
+                .filter_map(|(version, _)| version.path.metadata().ok()).map(|metadata| metadata.len()).sum();
+            for (version, expired) in &mut all {
+                if size <= max_size {
+                    break;
+                }
+                if !*expired {
+                    size -= version.path.metadata().map_or(0, |metadata| metadata.len());
+                    *expired = true;
+                }
+            }
+        }
+        let mut removed = 0;
+        for (version, _) in all.iter().filter(|(_, expired)| *expired) {
+            match remove_file(&version.path) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Unable to remove {}: {}", version.path.display(), e),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes the old copies of the file at `path` past the ones kept.
+    fn prune(&self, path: &str) {
+        let path = Path::new(path);
+        let directory = self.root.join(VERSIONS_DIRECTORY).join(path.parent().unwrap_or(Path::new("")));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let versions = match self.in_directory(&directory) {
+            Ok(mut versions) => versions.remove(name.as_ref()).unwrap_or_default(),
+            Err(e) => return warn!("Unable to list the old copies of {}: {}", path.display(), e),
+        };
+        for version in self.expired(&versions, SystemTime::now()) {
+            if let Err(e) = remove_file(&version.path) {
+                warn!("Unable to remove {}: {}", version.path.display(), e);
+            }
+        }
+    }
+
+    /// Which of `versions`, the old copies of a file oldest first, aren't kept at `now`.
+    fn expired(&self, versions: &[Version], now: SystemTime) -> Vec<Version> {
+        match self.versioning {
+            Versioning::None => Vec::new(),
+            Versioning::Trash if self.keep == 0 => Vec::new(),
+            Versioning::Trash => versions[..versions.len().saturating_sub(self.keep as usize)].to_vec(),
+            Versioning::Staggered => {
+                // Going from the newest, a copy too close to the last one kept goes.
+                let mut expired = Vec::new();
+                let mut kept: Option<SystemTime> = None;
+                for version in versions.iter().rev() {
+                    let age = now.duration_since(version.time).unwrap_or_default();
+                    let apart = STAGGER.iter().find(|(up_to, _)| age < *up_to).map_or(WEEK, |(_, apart)| *apart);
+                    let too_close = kept
+                        .is_some_and(|kept| kept.duration_since(version.time).unwrap_or_default() < apart);
+                    if self.max_age.is_some_and(|max_age| age > max_age) || too_close {
+                        expired.push(version.clone());
+                    } else {
+                        kept = Some(version.time);
+                    }
+                }
+                expired.reverse();
+                expired
+            }
+        }
+    }
+
+    /// The old copies in `directory`, oldest first by the name of the file they are of.
+    fn in_directory(&self, directory: &Path) -> io::Result<HashMap<String, Vec<Version>>> {
+        let entries = match read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        let mut by_file: HashMap<String, Vec<Version>> = HashMap::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some((name, time)) = parse_version(&entry.file_name().to_string_lossy()) {
+                by_file.entry(name).or_default().push(Version { path: entry.path(), time });
+            }
+        }
+        for versions in by_file.values_mut() {
+            versions.sort_by_key(|version| version.time);
+        }
+        Ok(by_file)
+    }
+
+    /// Where the copy of the file at `path` replaced at `time` goes.
+    fn version_path(&self, path: &str, time: SystemTime) -> PathBuf {
+        let path = Path::new(path);
+        let name = path.file_name().unwrap_or_default();
+        let name_path = Path::new(name);
+        let stem = name_path.file_stem().unwrap_or(name).to_string_lossy();
+        let extension = name_path.extension().map(|extension| format!(".{}", extension.to_string_lossy()));
+        let version = format!("{}{}{}{}", stem, VERSION_MARKER, timestamp(time), extension.unwrap_or_default());
+        self.root.join(VERSIONS_DIRECTORY).join(path).with_file_name(version)
+    }
+}
+
+/// Removes the old copies of the files of every folder with versioning past the ones kept, when
+/// starting and then every hour.
+pub fn spawn_version_cleaner(config: ConfigHandle) {
+    spawn(move || loop {
+        let options = config.current();
+        for folder in options.folders() {
+            let versions = Versions::of(folder, &options);
+            if versions.versioning == Versioning::None {
+                continue;
+            }
+            match versions.clean(SystemTime::now()) {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {} old copies of files in folder {}", removed, folder.id),
+                Err(e) => warn!("Unable to clean up the old copies of files in folder {}: {}", folder.id, e),
+            }
+        }
+        sleep(CLEANUP_INTERVAL);
+    });
+}
+
+/// The name of the file that `name` is an old copy of, and the time it was replaced.
+fn parse_version(name: &str) -> Option<(String, SystemTime)> {
+    // The extension has no dot but may have the marker, so try each from the end.
+    for (marker, _) in name.rmatch_indices(VERSION_MARKER) {
+        let (stem, rest) = (&name[..marker], &name[marker + 1..]);
+        let extension = match rest.get(TIMESTAMP_LENGTH..) {
+            Some(extension) if extension.is_empty() || (extension.starts_with('.')
+                && !extension[1..].contains('.')) => extension,
+            _ => continue,
+        };
+        if let Some(time) = parse_timestamp(&rest[..TIMESTAMP_LENGTH]) {
+            return Some((format!("{}{}", stem, extension), time));
+        }
+    }
+    None
+}
+
+/// Whether `path`, relative to the root of a folder, holds old copies of its files rather than
+/// files that are synced.
+pub fn is_versions(path: &Path) -> bool {
+    path.iter().next().is_some_and(|first| first == VERSIONS_DIRECTORY)
+}
+
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, write};
+    use std::time::UNIX_EPOCH;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn keeps_old_copies_of_replaced_and_deleted_files() {
+        let directory = tempdir().unwrap();
+        let root = directory.path();
+        let versions = Versions { keep: 2, ..Versions::new(root, Versioning::Trash) };
+        let time = UNIX_EPOCH + Duration::from_secs(1_615_735_613);
+        assert_eq!(versions.version_path("dir/notes.txt", time),
+            root.join(".sync-versions/dir/notes~20210314-152653.txt"));
+        assert_eq!(parse_version("notes~20210314-152653.txt"), Some(("notes.txt".to_string(), time)));
+        assert_eq!(parse_version("a~b~20210314-152653.c~"), Some(("a~b.c~".to_string(), time)));
+        assert_eq!(parse_version("notes~other.txt"), None);
+        assert!(is_versions(Path::new(".sync-versions/dir/notes~20210314-152653.txt")));
+
+        create_dir_all(root.join("dir")).unwrap();
+        write(root.join("dir/notes.txt"), b"first").unwrap();
+        let pull = || {
+            write(root.join("dir/pulled"), b"second")?;
+            rename(root.join("dir/pulled"), root.join("dir/notes.txt"))
+        };
+        versions.replace("dir/notes.txt", pull).unwrap();
+        let kept = versions.versions("dir/notes.txt").unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(read(&kept[0]).unwrap(), b"first");
+
+        // A replacement that fails keeps no copy.
+        let failed: io::Result<()> = versions.replace("dir/notes.txt", || Err(io::Error::other("failed")));
+        assert!(failed.is_err());
+        assert_eq!(versions.versions("dir/notes.txt").unwrap(), kept);
+
+        // Stand in for the copies of earlier times, the oldest of which is pruned.
+        for seconds in [0, 1] {
+            write(versions.version_path("dir/notes.txt", UNIX_EPOCH + Duration::from_secs(seconds)), b"").unwrap();
+        }
+        write(root.join(".sync-versions/dir/notes~other.txt"), b"").unwrap();
+        versions.remove("dir/notes.txt").unwrap();
+        assert!(!root.join("dir/notes.txt").exists());
+        let kept = versions.versions("dir/notes.txt").unwrap();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(read(&kept[1]).unwrap(), b"second");
+        assert!(versions.versions("other").unwrap().is_empty());
+
+        write(root.join("dir/deleted.txt"), b"deleted").unwrap();
+        let backup = Versions { keep_deleted: true, ..Versions::new(root, Versioning::None) };
+        assert!(!backup.remove_deleted("dir/deleted.txt").unwrap());
+        assert!(root.join("dir/deleted.txt").exists());
+        assert!(Versions { keep_deleted: true, ..versions.clone() }.remove_deleted("dir/deleted.txt").unwrap());
+        assert_eq!(versions.versions("dir/deleted.txt").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn staggers_old_copies_and_caps_their_size() {
+        let directory = tempdir().unwrap();
+        let root = directory.path();
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let versions = Versions {
+            max_age: Some(DAY * 60),
+            ..Versions::new(root, Versioning::Staggered)
+        };
+        // Minutes ago that copies of a file were made at.
+        let minutes = [1, 2, 90, 100, 150, 60 * 30, 60 * 31, 60 * 24 * 40, 60 * 24 * 42, 60 * 24 * 50, 60 * 24 * 70];
+        create_dir_all(root.join(".sync-versions/dir")).unwrap();
+        for minute in minutes {
+            let time = now - Duration::from_secs(minute * 60);
+            write(versions.version_path("dir/file", time), vec![0; 10]).unwrap();
+        }
+        write(versions.version_path("other", now - HOUR * 2), vec![0; 10]).unwrap();
+        assert_eq!(versions.clean(now).unwrap(), 4);
+        let ages: Vec<u64> = versions.versions("dir/file").unwrap().iter().rev()
+            .map(|path| parse_version(&path.file_name().unwrap().to_string_lossy()).unwrap().1)
+            .map(|time| now.duration_since(time).unwrap().as_secs() / 60).collect();
+        // Within an hour all are kept, an hour apart within a day, a day apart and then a week.
+        assert_eq!(ages, vec![1, 2, 90, 150, 60 * 30, 60 * 24 * 40, 60 * 24 * 50]);
+
+        // Past the size cap the oldest copies go, whatever file they are of.
+        let capped = Versions { max_size: Some(35), ..versions };
+        assert_eq!(capped.clean(now).unwrap(), 5);
+        assert_eq!(capped.versions("dir/file").unwrap().len(), 3);
+        assert!(capped.versions("other").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,392 @@
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::config::{ConfigHandle, Folder, Options};
+use crate::ignore::IgnorePatterns;
+
+use self::schedule::Schedule;
+
+mod schedule;
+
+/// How long a folder has to go without changes before the changes to it are passed on, so that a
+/// file being written is passed on once it is done.
+const QUIET_PERIOD: Duration = Duration::from_secs(1);
+/// Longest a change is held back while more keep coming, as they do in a busy folder.
+const MAX_DELAY: Duration = Duration::from_secs(5);
+/// How often the watcher checks for changes to pass on and for the watched folders changing.
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A change to a shared folder, with paths relative to its root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderChange {
+    pub folder: String,
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed { from: PathBuf, to: PathBuf },
+    /// The whole folder has to be scanned, as it is every scan interval or when changes were
+    /// missed, such as when the system dropped events.
+    Rescan,
+}
+
+impl Display for FolderChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.change {
+            Change::Created(path) => write!(f, "{} created in folder {}", path.display(), self.folder),
+            Change::Modified(path) => write!(f, "{} modified in folder {}", path.display(), self.folder),
+            Change::Removed(path) => write!(f, "{} removed from folder {}", path.display(), self.folder),
+            Change::Renamed { from, to } => write!(f, "{} renamed to {} in folder {}", from.display(), to.display(),
+                self.folder),
+            Change::Rescan => write!(f, "folder {} to be scanned", self.folder),
+        }
+    }
+}
+
+/// Watches the folders that have `watch` set and sends what changes in them, once each file is
+/// quiet for a moment. Ignored files and the partial files of pulls are left out. The folders
+/// are watched again whenever the config changes which are watched, and stop being watched once
+/// the receiver is dropped. Every folder also gets a [`Change::Rescan`] when it is added and then
+/// every `scan-interval`, for what watching missed.
+pub fn spawn_watcher(config: ConfigHandle) -> Receiver<FolderChange> {
+    let (sender, receiver) = channel();
+    spawn(move || watch(&config, &sender));
+    receiver
+}
+
+/// A folder being watched.
+#[derive(Debug, Clone, PartialEq)]
+struct Watched {
+    id: String,
+    /// The path of the folder as the system reports changes to it, which can differ from the
+    /// configured one through symbolic links.
+    root: PathBuf,
+    ignore: IgnorePatterns,
+}
+
+impl Watched {
+    /// The folders in `options` that are watched, which paused ones aren't.
+    fn of(options: &Options) -> Vec<Watched> {
+        let watched = |folder: &&Folder| folder.settings(options).watch && !folder.paused;
+        options.folders().iter().filter(watched).map(|folder| Watched {
+            id: folder.id.clone(),
+            root: folder.path.canonicalize().unwrap_or_else(|_| folder.path.clone()),
+            ignore: folder.ignore_patterns(options),
+        }).collect()
+    }
+
+    /// `path` relative to the root, `None` if it is outside the folder or not synced.
+    fn relative(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        self.ignore.is_synced(relative, path.is_dir()).then(|| relative.to_path_buf())
+    }
+}
+
+fn watch(config: &ConfigHandle, sender: &Sender<FolderChange>) {
+    let mut folders = Vec::new();
+    let mut watcher: Option<RecommendedWatcher> = None;
+    let (events, received) = channel();
+    let mut debouncer = Debouncer::default();
+    let mut schedule = Schedule::default();
+    loop {
+        let options = config.current();
+        schedule.update(&options, Instant::now());
+        for folder in schedule.due(Instant::now()) {
+            debouncer.add(FolderChange { folder, change: Change::Rescan }, Instant::now());
+        }
+        let wanted = Watched::of(&options);
+        let roots = |folders: &[Watched]| -> Vec<(String, PathBuf)> {
+            folders.iter().map(|folder| (folder.id.clone(), folder.root.clone())).collect()
+        };
+        if roots(&wanted) != roots(&folders) {
+            let events = events.clone();
+            watcher = match start(&wanted, move |event| drop(events.send(event))) {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    warn!("Unable to watch folders for changes, they only sync when scanned: {}", e);
+                    None
+                }
+            };
+            folders = wanted;
+        }
+
+        match received.recv_timeout(TICK_INTERVAL) {
+            Ok(Ok(event)) => {
+                for change in changes(&folders, &event) {
+                    if let Change::Created(path) | Change::Modified(path) = &change.change {
+                        let watched = folders.iter().find(|folder| folder.id == change.folder);
+                        if watched.is_some_and(|folder| folder.ignore.is_pattern_file(path)) {
+                            // The patterns apply from the next change on.
+                            folders = Watched::of(&options);
+                        }
+                    }
+                    debouncer.add(change, Instant::now());
+                }
+            }
+            Ok(Err(e)) => {
+                // The error doesn't say which folder it is about, so all of them are scanned.
+                warn!("Error watching folders for changes, scanning them: {}", e);
+                for folder in &folders {
+                    debouncer.add(FolderChange { folder: folder.id.clone(), change: Change::Rescan }, Instant::now());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => unreachable!("the watcher holds a sender"),
+        }
+
+        for change in debouncer.due(Instant::now()) {
+            debug!("{}", change);
+            if change.change == Change::Rescan {
+                schedule.scanned(&change.folder, Instant::now());
+            }
+            if sender.send(change).is_err() {
+                drop(watcher);
+                return;
+            }
+        }
+    }
+}
+
+/// Watches every folder in `folders`, leaving out the ones that can't be watched, such as a
+/// folder that doesn't exist yet.
+fn start<F>(folders: &[Watched], handler: F) -> notify::Result<RecommendedWatcher>
+where
+    F: Fn(notify::Result<Event>) + Send + 'static,
+{
+    let mut watcher = RecommendedWatcher::new(handler, notify::Config::default())?;
+    for folder in folders {
+        match watcher.watch(&folder.root, RecursiveMode::Recursive) {
+            Ok(()) => debug!("Watching folder {} at {}", folder.id, folder.root.display()),
+            Err(e) => warn!("Unable to watch folder {} at {}, it only syncs when scanned: {}", folder.id,
+                folder.root.display(), e),
+        }
+    }
+    Ok(watcher)
+}
+
+/// What `event` changed in `folders`.
+fn changes(folders: &[Watched], event: &Event) -> Vec<FolderChange> {
+    let find = |path: &Path| folders.iter().find_map(|folder| Some((&folder.id, folder.relative(path)?)));
+    let change = |folder: &String, change: Change| FolderChange { folder: folder.clone(), change };
+    if event.need_rescan() {
+        return folders.iter().filter(|folder| event.paths.iter().all(|path| path.starts_with(&folder.root)))
+            .map(|folder| change(&folder.id, Change::Rescan)).collect();
+    }
+
+    match (&event.kind, event.paths.as_slice()) {
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => match (find(from), find(to)) {
+            (Some((folder, from)), Some((other, to))) if folder == other =>
+                vec![change(folder, Change::Renamed { from, to })],
+            // Moving a file between folders, or between synced and ignored, creates it in one and
+            // removes it from the other.
+            (from, to) => from.map(|(folder, path)| change(folder, Change::Removed(path))).into_iter()
+                .chain(to.map(|(folder, path)| change(folder, Change::Created(path)))).collect(),
+        },
+        (EventKind::Modify(ModifyKind::Name(mode)), paths) => paths.iter().filter_map(|path| {
+            let (folder, relative) = find(path)?;
+            let gone = match mode {
+                RenameMode::From => true,
+                RenameMode::To => false,
+                // Some systems don't say which side of the rename a path is.
+                _ => !path.exists(),
+            };
+            Some(change(folder, if gone { Change::Removed(relative) } else { Change::Created(relative) }))
+        }).collect(),
+        (kind, paths) => paths.iter().filter_map(|path| {
+            let (folder, relative) = find(path)?;
+            let change_kind = match kind {
+                EventKind::Create(_) => Change::Created(relative),
+                EventKind::Modify(_) => Change::Modified(relative),
+                EventKind::Remove(_) => Change::Removed(relative),
+                EventKind::Any | EventKind::Other => Change::Modified(relative),
+                EventKind::Access(_) => return None,
+            };
+            Some(change(folder, change_kind))
+        }).collect(),
+    }
+}
+
+/// Holds changes back until their folder is quiet, merging the changes to the same file, such as
+/// the many writes of one save, into one.
+#[derive(Debug, Default)]
+struct Debouncer {
+    /// Changes in the order they came.
+    pending: Vec<FolderChange>,
+    /// When the first and the last pending change came, for each folder with any.
+    times: Vec<(String, Instant, Instant)>,
+}
+
+impl Debouncer {
+    fn add(&mut self, change: FolderChange, now: Instant) {
+        match self.times.iter_mut().find(|(id, ..)| *id == change.folder) {
+            Some((_, _, last)) => *last = now,
+            None => self.times.push((change.folder.clone(), now, now)),
+        }
+        self.merge(change);
+    }
+
+    fn merge(&mut self, new: FolderChange) {
+        let FolderChange { folder, change } = new;
+        let same = |pending: &FolderChange, path: &Path| pending.folder == folder && match &pending.change {
+            Change::Created(pending) | Change::Modified(pending) | Change::Removed(pending) => pending == path,
+            _ => false,
+        };
+        if self.pending.iter().any(|pending| pending.folder == folder && pending.change == Change::Rescan) {
+            return;
+        }
+
+        let change = match change {
+            Change::Rescan => {
+                // Scanning the folder finds everything else that changed in it.
+                self.pending.retain(|pending| pending.folder != folder);
+                Change::Rescan
+            }
+            Change::Renamed { from, to } => {
+                // The two sides of a rename may have come on their own before it.
+                let find = |path: &Path, created: bool| self.pending.iter().position(|pending| same(pending, path)
+                    && match pending.change {
+                        Change::Created(_) => created,
+                        Change::Removed(_) => !created,
+                        _ => false,
+                    });
+                let (removed_from, created_from, created_to) = (find(&from, false), find(&from, true), find(&to, true));
+                if removed_from.is_none() && created_to.is_some() {
+                    // A file created then renamed, the removing side of the rename having
+                    // cancelled out its creation, is just created.
+                    return;
+                }
+                if let Some(index) = created_from {
+                    self.pending.remove(index);
+                    Change::Created(to)
+                } else {
+                    self.pending.retain(|pending| !same(pending, &from) && !same(pending, &to));
+                    Change::Renamed { from, to }
+                }
+            }
+            change => {
+                let path = match &change {
+                    Change::Created(path) | Change::Modified(path) | Change::Removed(path) => path.clone(),
+                    _ => unreachable!("renames and rescans are merged above"),
+                };
+                match self.pending.iter().position(|pending| same(pending, &path)) {
+                    None => change,
+                    Some(index) => {
+                        let merged = match (&self.pending[index].change, change) {
+                            (Change::Created(_), Change::Removed(_)) => None,
+                            (Change::Created(_), _) => Some(Change::Created(path)),
+                            (Change::Removed(_), Change::Removed(_)) => Some(Change::Removed(path)),
+                            (Change::Removed(_), _) => Some(Change::Modified(path)),
+                            (_, change) => Some(change),
+                        };
+                        self.pending.remove(index);
+                        match merged {
+                            Some(merged) => merged,
+                            None => return,
+                        }
+                    }
+                }
+            }
+        };
+        self.pending.push(FolderChange { folder, change });
+    }
+
+    /// The changes of the folders that were quiet long enough, or waited too long.
+    fn due(&mut self, now: Instant) -> Vec<FolderChange> {
+        let (due, waiting): (Vec<_>, Vec<_>) = self.times.drain(..).partition(|(_, first, last)| {
+            now.duration_since(*last) >= QUIET_PERIOD || now.duration_since(*first) >= MAX_DELAY
+        });
+        self.times = waiting;
+        let (due, pending) = self.pending.drain(..).partition(|change| due.iter().any(|(id, ..)| *id == change.folder));
+        self.pending = pending;
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use notify::event::{CreateKind, DataChange, RemoveKind};
+
+    use super::*;
+
+    fn change(change: Change) -> FolderChange {
+        FolderChange { folder: "folder".to_string(), change }
+    }
+
+    #[test]
+    fn tells_changes_apart() {
+        let folders = vec![
+            Watched { id: "folder".to_string(), root: PathBuf::from("/sync"), ignore: IgnorePatterns::new(&["*.tmp"]) },
+            Watched { id: "other".to_string(), root: PathBuf::from("/other"), ignore: IgnorePatterns::default() },
+        ];
+        let event = |kind: EventKind, paths: &[&str]| {
+            paths.iter().fold(Event::new(kind), |event, path| event.add_path(PathBuf::from(path)))
+        };
+
+        let created = event(EventKind::Create(CreateKind::File), &["/sync/a", "/sync/b.tmp", "/elsewhere/c"]);
+        assert_eq!(changes(&folders, &created), vec![change(Change::Created("a".into()))]);
+        let written = event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &["/sync/.ss-partial.a"]);
+        assert!(changes(&folders, &written).is_empty());
+        let renamed = event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &["/sync/a", "/sync/dir/b"]);
+        assert_eq!(changes(&folders, &renamed), vec![change(Change::Renamed { from: "a".into(), to: "dir/b".into() })]);
+        let moved = event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &["/sync/a", "/other/a"]);
+        assert_eq!(changes(&folders, &moved), vec![
+            change(Change::Removed("a".into())),
+            FolderChange { folder: "other".to_string(), change: Change::Created("a".into()) },
+        ]);
+        let removed = event(EventKind::Remove(RemoveKind::File), &["/sync/a"]);
+        assert_eq!(changes(&folders, &removed), vec![change(Change::Removed("a".into()))]);
+    }
+
+    #[test]
+    fn merges_changes_until_the_folder_is_quiet() {
+        let mut debouncer = Debouncer::default();
+        let start = Instant::now();
+        debouncer.add(change(Change::Created("a".into())), start);
+        debouncer.add(change(Change::Modified("a".into())), start);
+        debouncer.add(change(Change::Modified("b".into())), start);
+        debouncer.add(change(Change::Removed("c".into())), start);
+        debouncer.add(change(Change::Created("c".into())), start);
+        debouncer.add(change(Change::Created("d".into())), start);
+        debouncer.add(change(Change::Removed("d".into())), start);
+        debouncer.add(change(Change::Removed("e".into())), start);
+        debouncer.add(change(Change::Created("f".into())), start);
+        debouncer.add(change(Change::Renamed { from: "e".into(), to: "f".into() }), start);
+        debouncer.add(change(Change::Created("g".into())), start);
+        debouncer.add(change(Change::Removed("g".into())), start);
+        debouncer.add(change(Change::Created("h".into())), start);
+        debouncer.add(change(Change::Renamed { from: "g".into(), to: "h".into() }), start);
+        debouncer.add(change(Change::Created("i".into())), start);
+        debouncer.add(change(Change::Renamed { from: "i".into(), to: "j".into() }), start);
+        assert!(debouncer.due(start + QUIET_PERIOD / 2).is_empty());
+        assert_eq!(debouncer.due(start + QUIET_PERIOD), vec![
+            change(Change::Created("a".into())),
+            change(Change::Modified("b".into())),
+            change(Change::Modified("c".into())),
+            change(Change::Renamed { from: "e".into(), to: "f".into() }),
+            change(Change::Created("h".into())),
+            change(Change::Created("j".into())),
+        ]);
+
+        // A folder that never goes quiet still has its changes passed on.
+        for step in 0..=MAX_DELAY.as_millis() as u32 / 500 {
+            debouncer.add(change(Change::Modified("a".into())), start + Duration::from_millis(500) * step);
+        }
+        assert_eq!(debouncer.due(start + MAX_DELAY), vec![change(Change::Modified("a".into()))]);
+
+        debouncer.add(change(Change::Modified("a".into())), start);
+        debouncer.add(change(Change::Rescan), start);
+        debouncer.add(change(Change::Modified("b".into())), start);
+        assert_eq!(debouncer.due(start + QUIET_PERIOD), vec![change(Change::Rescan)]);
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::config::Options;
+
+/// Time between the first scans of the folders when starting, so they don't all read the disk at
+/// once.
+const STARTUP_STAGGER: Duration = Duration::from_secs(5);
+/// Scans are up to this fraction of the scan interval earlier or later, so folders that were
+/// scanned together drift apart.
+const SCAN_JITTER: f64 = 0.1;
+
+/// When each folder is next scanned in full, as a safety net for the changes watching misses,
+/// such as on network mounts or when the system drops events. Every folder is scanned soon after
+/// it is added, then every scan interval.
+#[derive(Debug, Default)]
+pub struct Schedule {
+    /// Folder id, its scan interval and when it is next scanned, `None` once a folder with an
+    /// interval of 0 had its first scan.
+    folders: Vec<(String, Duration, Option<Instant>)>,
+}
+
+impl Schedule {
+    /// Schedules the folders in `options` that aren't yet and the ones whose interval changed,
+    /// and forgets the ones that are gone or paused, so that one resumed is scanned soon.
+    pub fn update(&mut self, options: &Options, now: Instant) {
+        self.set(options.folders().iter().filter(|folder| !folder.paused)
+            .map(|folder| (folder.id.clone(), Duration::from_secs(folder.settings(options).scan_interval)))
+            .collect(), now);
+    }
+
+    /// Schedules the folders in `wanted`, each with its scan interval.
+    fn set(&mut self, wanted: Vec<(String, Duration)>, now: Instant) {
+        self.folders.retain(|(id, ..)| wanted.iter().any(|(other, _)| other == id));
+        let mut added = 0;
+        for (id, interval) in wanted {
+            match self.folders.iter_mut().find(|(other, ..)| *other == id) {
+                Some((_, current, next)) if *current != interval => {
+                    *current = interval;
+                    *next = after(interval, now);
+                }
+                Some(_) => {}
+                None => {
+                    self.folders.push((id, interval, Some(now + STARTUP_STAGGER * added)));
+                    added += 1;
+                }
+            }
+        }
+    }
+
+    /// The folders due to be scanned, which are scheduled again as if scanned now.
+    pub fn due(&mut self, now: Instant) -> Vec<String> {
+        let mut due = Vec::new();
+        for (id, interval, next) in &mut self.folders {
+            if next.is_some_and(|next| next <= now) {
+                due.push(id.clone());
+                *next = after(*interval, now);
+            }
+        }
+        due
+    }
+
+    /// Takes note of `folder` being scanned for another reason, putting its next scan off.
+    pub fn scanned(&mut self, folder: &str, now: Instant) {
+        if let Some((_, interval, next)) = self.folders.iter_mut().find(|(id, ..)| id == folder) {
+            if next.is_some() {
+                *next = after(*interval, now);
+            }
+        }
+    }
+}
+
+/// When a folder scanned `now` is next scanned, `None` for an interval of 0.
+fn after(interval: Duration, now: Instant) -> Option<Instant> {
+    if interval.is_zero() {
+        return None;
+    }
+    Some(now + interval.mul_f64(1.0 + rand::thread_rng().gen_range(-SCAN_JITTER..=SCAN_JITTER)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folders(intervals: &[(&str, u64)]) -> Vec<(String, Duration)> {
+        intervals.iter().map(|(id, interval)| (id.to_string(), Duration::from_secs(*interval))).collect()
+    }
+
+    #[test]
+    fn staggers_scans() {
+        let mut schedule = Schedule::default();
+        let start = Instant::now();
+        schedule.set(folders(&[("a", 100), ("b", 100), ("c", 0)]), start);
+        assert_eq!(schedule.due(start), vec!["a"]);
+        assert_eq!(schedule.due(start + STARTUP_STAGGER), vec!["b"]);
+        assert_eq!(schedule.due(start + STARTUP_STAGGER * 2), vec!["c"]);
+        assert!(schedule.due(start + Duration::from_secs(80)).is_empty());
+        schedule.scanned("b", start + Duration::from_secs(80));
+        assert_eq!(schedule.due(start + Duration::from_secs(120)), vec!["a"]);
+        assert_eq!(schedule.due(start + Duration::from_secs(200)), vec!["b"]);
+
+        // A folder with no interval is only scanned when added.
+        assert!(!schedule.due(start + Duration::from_secs(1_000_000)).contains(&"c".to_string()));
+        schedule.set(folders(&[("b", 100), ("c", 0)]), start);
+        assert_eq!(schedule.folders.len(), 2);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+
+use log::debug;
+
+/// The extended attributes of a file by name, such as the tags, quarantine and resource fork
+/// macOS keeps in them.
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Largest attribute that is synced, larger ones such as big resource forks are left out.
+pub const MAX_XATTR_SIZE: usize = 64 * 1024;
+
+/// The extended attributes of `file` that are synced, none where the system or the file system
+/// has no such thing.
+pub fn read(file: &File) -> io::Result<Xattrs> {
+    let names = match sys::list(file) {
+        Ok(names) => names,
+        Err(e) if unsupported(&e) => return Ok(Xattrs::new()),
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = Xattrs::new();
+    for name in names.split(|&byte| byte == 0).filter(|name| !name.is_empty()) {
+        let name = match std::str::from_utf8(name) {
+            Ok(name) if synced(name) => name,
+            _ => continue,
+        };
+        match sys::get(file, name) {
+            Ok(value) if value.len() > MAX_XATTR_SIZE => debug!("Not syncing the {} byte {}", value.len(), name),
+            Ok(value) => {
+                xattrs.insert(name.to_string(), value);
+            }
+            // Removed since it was listed.
+            Err(e) if e.raw_os_error() == Some(sys::NO_ATTRIBUTE) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Gives `file` the extended attributes `xattrs`, on top of any it has, leaving them out where
+/// the system or the file system has no such thing.
+pub fn write(file: &File, xattrs: &Xattrs) -> io::Result<()> {
+    for (name, value) in xattrs.iter().filter(|(name, _)| synced(name)) {
+        match sys::set(file, name, value) {
+            Err(e) if unsupported(&e) => {
+                debug!("Not setting the extended attributes of a file: {}", e);
+                break;
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Whether the attribute `name` is one that is synced, which on Linux are only those users set
+/// rather than the system's, such as ACLs and SELinux labels.
+fn synced(name: &str) -> bool {
+    !cfg!(target_os = "linux") || name.starts_with("user.")
+}
+
+fn unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported || e.raw_os_error() == Some(sys::NOT_SUPPORTED)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+
+    #[cfg(target_os = "linux")]
+    pub const NO_ATTRIBUTE: i32 = libc::ENODATA;
+    #[cfg(target_os = "macos")]
+    pub const NO_ATTRIBUTE: i32 = libc::ENOATTR;
+    pub const NOT_SUPPORTED: i32 = libc::ENOTSUP;
+
+    /// The names of the attributes of `file`, each ending in a nul.
+    pub fn list(file: &File) -> io::Result<Vec<u8>> {
+        let fd = file.as_raw_fd();
+        sized(|buffer, size| unsafe { flistxattr(fd, buffer as *mut c_char, size) })
+    }
+
+    pub fn get(file: &File, name: &str) -> io::Result<Vec<u8>> {
+        let (fd, name) = (file.as_raw_fd(), c_name(name)?);
+        sized(|buffer, size| unsafe { fgetxattr(fd, name.as_ptr(), buffer as *mut c_void, size) })
+    }
+
+    pub fn set(file: &File, name: &str, value: &[u8]) -> io::Result<()> {
+        let name = c_name(name)?;
+        let set = unsafe { fsetxattr(file.as_raw_fd(), name.as_ptr(), value.as_ptr() as *const c_void, value.len()) };
+        match set {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn c_name(name: &str) -> io::Result<CString> {
+        CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Calls `read` once for the size of what it reads and then to read it, again if it grew in
+    /// between.
+    fn sized(read: impl Fn(*mut u8, size_t) -> ssize_t) -> io::Result<Vec<u8>> {
+        loop {
+            let size = read(ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buffer = vec![0; size as usize];
+            let read = read(buffer.as_mut_ptr(), buffer.len());
+            if read >= 0 {
+                buffer.truncate(read as usize);
+                return Ok(buffer);
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ERANGE) {
+                return Err(e);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn flistxattr(fd: c_int, names: *mut c_char, size: size_t) -> ssize_t {
+        libc::flistxattr(fd, names, size)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn fgetxattr(fd: c_int, name: *const c_char, value: *mut c_void, size: size_t) -> ssize_t {
+        libc::fgetxattr(fd, name, value, size)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn fsetxattr(fd: c_int, name: *const c_char, value: *const c_void, size: size_t) -> c_int {
+        libc::fsetxattr(fd, name, value, size, 0)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn flistxattr(fd: c_int, names: *mut c_char, size: size_t) -> ssize_t {
+        libc::flistxattr(fd, names, size, 0)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn fgetxattr(fd: c_int, name: *const c_char, value: *mut c_void, size: size_t) -> ssize_t {
+        libc::fgetxattr(fd, name, value, size, 0, 0)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn fsetxattr(fd: c_int, name: *const c_char, value: *const c_void, size: size_t) -> c_int {
+        libc::fsetxattr(fd, name, value, size, 0, 0)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+
+    pub const NO_ATTRIBUTE: i32 = 0;
+    pub const NOT_SUPPORTED: i32 = 0;
+
+    pub fn list(_file: &File) -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn get(_file: &File, _name: &str) -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn set(_file: &File, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn reads_back_the_attributes_written() {
+        let directory = tempdir().unwrap();
+        let file = File::create(directory.path().join("file")).unwrap();
+        let xattrs = Xattrs::from([("user.tag".to_string(), b"red".to_vec())]);
+        write(&file, &xattrs).unwrap();
+        // Not every file system temporary files are on has them.
+        let read = read(&file).unwrap();
+        assert!(read == xattrs || read.is_empty(), "{:?}", read);
+    }
+}